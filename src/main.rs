@@ -1,17 +1,55 @@
+use std::path::PathBuf;
+use std::sync::Arc;
 use structopt::StructOpt;
 
 mod server;
 mod page;
 
+use page::gc::GcPolicy;
+
 #[derive(Debug, StructOpt)]
 struct Options {
     /// Run on this port
     #[structopt(short, long, default_value = "1123")]
     port: u16,
+    /// Load pages from this file when starting (if it exists), and save them
+    /// to it when interrupted
+    #[structopt(long, parse(from_os_str))]
+    state: Option<PathBuf>,
+    /// Serve the page at this path, with a 404 status, in place of any page
+    /// with nothing on it (it starts with some default content, which can be
+    /// changed like any other page)
+    #[structopt(long)]
+    not_found: Option<String>,
+    /// Evict pages from memory by this policy: "empty" pages (the default),
+    /// "never", pages unmodified for "ttl=<seconds>", or the least recently
+    /// modified pages past "lru=<bytes>" in total
+    #[structopt(long, parse(try_from_str = page::gc::parse_policy))]
+    gc: Option<Arc<dyn GcPolicy>>,
+    /// Let pages be recorded into files in this directory, by POSTing
+    /// "?record=<name>" (and "?record=off" to stop), and played back from
+    /// them, by GETting "?replay=<name>"
+    #[structopt(long, parse(from_os_str))]
+    recordings: Option<PathBuf>,
+    /// Serve pages along with the myxine process at this URL (like
+    /// "http://10.0.0.2:1123"), forwarding every update to a page here to the
+    /// same page there, which should have this process as a peer in turn (can
+    /// be given more than once)
+    #[structopt(long = "peer", parse(try_from_str = server::parse_peer))]
+    peers: Vec<hyper::Uri>,
 }
 
 #[tokio::main]
 async fn main() {
     let options = Options::from_args();
-    server::run(([127, 0, 0, 1], options.port).into()).await;
+    if let Some(policy) = options.gc {
+        server::set_gc_policy(policy);
+    }
+    if let Some(dir) = options.recordings {
+        server::set_recording_dir(dir).await;
+    }
+    server::set_peers(options.peers);
+    server::run(([127, 0, 0, 1], options.port).into(),
+                options.state.as_deref(),
+                options.not_found).await;
 }
@@ -1,18 +1,39 @@
+use http::header::HeaderMap;
 use hyper::Body;
 use hyper_usse::EventBuilder;
 use std::io::Write;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 use std::collections::HashMap;
-use futures::join;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use futures::{join, Future, Stream};
 use serde_json::Value;
 use uuid::Uuid;
 
 pub mod sse;
 pub mod events;
+pub mod broadcast;
+pub mod batch;
+pub mod snapshot;
+pub mod recording;
+pub mod multiplex;
+pub mod compact;
+pub mod integrity;
+pub mod metrics;
+pub mod message;
+pub mod ndjson;
+pub mod gc;
+pub mod log;
+#[cfg(feature = "screenshot")]
+pub mod render;
 mod content;
 
 use events::{Subscribers, Subscription, AggregateSubscription, AbsolutePath, Path};
-use content::Content;
+use content::{Content, DynamicState};
+pub use content::{RootElement, NotNumeric, SeoMeta, AlertKind, ConfirmResult, StepStatus};
+pub use content::{BodyChange, BodyCursor, PreviewFrame};
+pub use log::LogLevel;
+pub use message::EventMessage;
 
 /// A `Page` pairs some page `Content` (either dynamic or static) with a set of
 /// `Subscribers` to the events on the page.
@@ -34,31 +55,115 @@ impl Page {
         }
     }
 
-    /// Render a whole page as HTML (for first page load).
-    pub async fn render(&self, base_url: &str, _this_url: &str) -> Vec<u8> {
-        match &*self.content.lock().await {
-            Content::Dynamic{title, body, ..} => {
+    /// Make a new page with the default content for a server's not-found page
+    /// (see `Content::not_found_default`)
+    pub async fn not_found_default() -> Page {
+        Page {
+            content: Mutex::new(Content::not_found_default().await),
+            subscribers: Mutex::new(Subscribers::new()),
+        }
+    }
+
+    /// Make an independent copy of this page, with the same content but no
+    /// clients or subscribers.
+    pub async fn fork(&self) -> Page {
+        Page {
+            content: Mutex::new(self.content.lock().await.fork().await),
+            subscribers: Mutex::new(Subscribers::new()),
+        }
+    }
+
+    /// Take a snapshot of the lasting state of this page's content, without
+    /// its clients or subscribers.
+    pub async fn snapshot(&self) -> snapshot::Snapshot {
+        self.content.lock().await.snapshot()
+    }
+
+    /// Make a new page from a snapshot taken by `snapshot`, with no clients or
+    /// subscribers. Returns an error if the snapshot is invalid.
+    pub async fn restore(snapshot: snapshot::Snapshot) -> Result<Page, String> {
+        Ok(Page {
+            content: Mutex::new(Content::restore(snapshot).await?),
+            subscribers: Mutex::new(Subscribers::new()),
+        })
+    }
+
+    /// Make a new page which plays back a recording made by `start_recording`,
+    /// along with the future which sends the recorded updates to its clients at
+    /// their original timing. Returns an error if the recording can't be read.
+    pub async fn replay_recording(path: &std::path::Path)
+                                  -> Result<(Page, impl Future<Output = ()>), String> {
+        let (content, playback) = Content::replay_recording(path).await?;
+        let page = Page {
+            content: Mutex::new(content),
+            subscribers: Mutex::new(Subscribers::new()),
+        };
+        Ok((page, playback))
+    }
+
+    /// Start recording every update sent to all clients of the page into a
+    /// file, until `stop_recording`. Returns an error if the file can't be
+    /// created.
+    pub async fn start_recording(&self, path: &std::path::Path) -> Result<(), String> {
+        self.content.lock().await.start_recording(path).await
+    }
+
+    /// Stop recording updates, if recording.
+    pub async fn stop_recording(&self) {
+        self.content.lock().await.stop_recording().await
+    }
+
+    /// Render a whole page as HTML (for first page load). If the page is being
+    /// shown at a path other than its own (like a not-found page), its updates
+    /// come from the path given.
+    pub async fn render(&self, base_url: &str, _this_url: &str, accept_language: Option<&str>,
+                        updates_path: Option<&str>) -> Vec<u8> {
+        let mut content = self.content.lock().await;
+        let meta = content.head_meta();
+        match &mut *content {
+            Content::Dynamic{state: DynamicState{title, body, lang_bodies, ..}, ..} => {
+                let body = content::body_for(body, lang_bodies, accept_language);
                 let subscribers = self.subscribers.lock().await;
                 let aggregate_subscription = subscribers.total_subscription();
                 let subscription =
                     serde_json::to_string(&aggregate_subscription).unwrap();
+                // The path goes in a script, so it can't close the script tag
+                let updates_path =
+                    serde_json::to_string(&updates_path).unwrap().replace('<', "\\u003c");
                 let mut bytes = Vec::with_capacity(TEMPLATE_SIZE);
                 write!(&mut bytes,
                        include_str!("page/dynamic.html"),
                        base_url = base_url,
                        subscription = subscription,
                        debug = cfg!(debug_assertions),
+                       updates_path = updates_path,
                        title = title,
+                       meta = meta,
                        body = body)
                     .expect("Internal error: write!() failed on a Vec<u8>");
                 bytes
             },
-            Content::Static{raw_contents, ..} => {
+            Content::Static{raw_contents, fetches, fetched, ..} => {
+                *fetches += 1;
+                *fetched = Some(Instant::now());
                 raw_contents.clone()
             },
         }
     }
 
+    /// Render the current state of the page as a complete static document,
+    /// without the script which keeps it up to date (for search engine
+    /// crawlers and the like).
+    pub async fn prerendered_document(&self, accept_language: Option<&str>) -> Vec<u8> {
+        self.content.lock().await.prerendered_document(accept_language)
+    }
+
+    /// Render the current state of the page as a standalone file to download
+    /// under the given name, with the headers to serve it with.
+    pub async fn download_document(&self, filename: &str) -> (HeaderMap, Vec<u8>) {
+        self.content.lock().await.download_document(filename)
+    }
+
     /// Subscribe another page event listener to this page, given a subscription
     /// specification for what events to listen to.
     pub async fn event_stream(
@@ -67,12 +172,8 @@ impl Page {
         let mut subscribers = self.subscribers.lock().await;
         let (total_subscription, new_details) =
             subscribers.add_subscriber(uuid, subscription).await;
-        let content = &mut *self.content.lock().await;
-        match content {
-            Content::Static{..} => { },
-            Content::Dynamic{ref mut updates, ..} => {
-                set_subscriptions(updates, total_subscription).await;
-            }
+        if let Some(updates) = self.content.lock().await.update_server() {
+            set_subscriptions(updates, total_subscription).await;
         }
         new_details
     }
@@ -84,40 +185,36 @@ impl Page {
                             event_type: &str,
                             event_path: &AbsolutePath,
                             event_data: &HashMap<Path, Value>) {
-        if let Some(total_subscription) =
-            self.subscribers.lock().await
-            .send_event(event_type, event_path, event_data).await {
-                let content = &mut *self.content.lock().await;
-                match content {
-                    Content::Static{..} => { },
-                    Content::Dynamic{ref mut updates, ..} => {
-                        set_subscriptions(updates, total_subscription).await;
-                    }
+        let mut subscribers = self.subscribers.lock().await;
+        let total_subscription =
+            subscribers.send_event(event_type, event_path, event_data).await;
+        if let Some(updates) = self.content.lock().await.update_server() {
+            updates.stats().note_activity();
+            if let Some(total_subscription) = total_subscription {
+                set_subscriptions(updates, total_subscription).await;
             }
         }
     }
 
     /// Send an empty "heartbeat" message to all clients of a page, if it is
-    /// dynamic. This has no effect if it is (currently) static, and returns
-    /// `None` if so, otherwise returns the current number of clients getting
-    /// live updates to the page.
-    pub async fn send_heartbeat(&self) -> Option<usize> {
+    /// dynamic, unless they were all sent an update within the `quiet` period.
+    /// This has no effect if it is (currently) static, and returns `None` if
+    /// so, otherwise returns the current number of clients getting live
+    /// updates to the page.
+    pub async fn send_heartbeat(&self, quiet: Duration) -> Option<usize> {
         let mut subscribers = self.subscribers.lock().await;
         let mut content = self.content.lock().await;
         let subscriber_heartbeat = async {
             subscribers.send_heartbeat().await
         };
         let content_heartbeat = async {
-            content.send_heartbeat().await
+            content.send_heartbeat(quiet).await
         };
         let (new_subscription, update_client_count) =
             join!(subscriber_heartbeat, content_heartbeat);
         if let Some(total_subscription) = new_subscription {
-            match *content {
-                Content::Dynamic{ref mut updates, ..} => {
-                    set_subscriptions(updates, total_subscription).await;
-                },
-                Content::Static{..} => { },
+            if let Some(updates) = content.update_server() {
+                set_subscriptions(updates, total_subscription).await;
             }
         }
         update_client_count
@@ -133,11 +230,136 @@ impl Page {
         content_empty && subscribers_empty
     }
 
+    /// Describe this page for a `GcPolicy`. The `more_recent_footprint` of the
+    /// result is zero, because it depends on other pages, so the caller must
+    /// fill it in.
+    pub async fn gc_inputs(&self) -> gc::GcInputs {
+        let empty = self.is_empty().await;
+        let mut content = self.content.lock().await;
+        gc::GcInputs {
+            empty,
+            clients: content.client_count().await,
+            footprint: content.footprint(),
+            last_modified: content.last_modified(),
+            last_client_activity: content.last_client_activity(),
+            more_recent_footprint: 0,
+        }
+    }
+
     /// Add a client to the dynamic content of a page, if it is dynamic. If it
     /// is static, this has no effect and returns None. Otherwise, returns the
-    /// Body stream to give to the new client.
-    pub async fn update_stream(&self) -> Option<Body> {
-        self.content.lock().await.update_stream().await
+    /// Body stream to give to the new client. If the client gives the hash of
+    /// a baseline body it already holds, it isn't sent the body again.
+    pub async fn update_stream(&self,
+                               options: sse::ClientOptions,
+                               baseline: Option<&str>,
+                               accept_language: Option<&str>) -> Option<Body> {
+        self.content.lock().await.update_stream(options, baseline, accept_language).await
+            .map(|(_id, body)| body)
+    }
+
+    /// Add a client to the page, if it is dynamic, forwarding everything it's
+    /// sent into a sink shared with other pages, tagged with the given path.
+    /// Returns `None` if the page is static.
+    pub async fn update_stream_into(&self,
+                                    sink: &multiplex::Sink,
+                                    path: &str) -> Option<multiplex::Forwarding> {
+        self.content.lock().await.update_stream_into(sse::ClientOptions::default(), sink, path).await
+            .map(|(_id, forwarding)| forwarding)
+    }
+
+    /// Send a one-shot custom event to all clients of the page, if it is
+    /// dynamic. Returns an error if the event type is invalid.
+    pub async fn send_custom_event(&self, event_type: &str, data: &str) -> Result<(), String> {
+        self.content.lock().await.send_event(event_type, data).await
+    }
+
+    /// Forward changes to the page to a broadcast backend, so that they reach
+    /// the clients of other processes serving the same page. With `None`, stop
+    /// forwarding.
+    pub async fn set_broadcast_backend(&self, backend: Option<Arc<dyn broadcast::BroadcastBackend>>) {
+        self.content.lock().await.set_broadcast_backend(backend)
+    }
+
+    /// Apply an update broadcast by another process serving the same page,
+    /// without forwarding it back to the broadcast backend.
+    pub async fn apply_broadcast(&self, update: broadcast::Broadcast) {
+        self.content.lock().await.apply_broadcast(update).await
+    }
+
+    /// Send a custom event to all clients of the page, if it is dynamic, which
+    /// the page reverts once the TTL has elapsed. Returns an error if the event
+    /// type is invalid.
+    pub async fn send_event_ttl(&self, event_type: &str, data: &str, ttl: Duration) -> Result<(), String> {
+        self.content.lock().await.send_event_ttl(event_type, data, ttl).await
+    }
+
+    /// Send a custom event built by an `EventMessage` builder to the clients of
+    /// the page, if it is dynamic.
+    pub async fn send_message(&self, message: EventMessage) {
+        self.content.lock().await.send_message(message).await
+    }
+
+    /// Tell all clients of the page, if it is dynamic, to vibrate or play a
+    /// sound.
+    pub async fn send_alert(&self, alert: AlertKind) {
+        self.content.lock().await.send_alert(alert).await
+    }
+
+    /// Tell all clients of the page, if it is dynamic, to move their
+    /// subscription to its updates to another URL. Returns an error if the URL
+    /// is invalid.
+    pub async fn send_reconnect_to(&self, url: &str) -> Result<(), String> {
+        self.content.lock().await.send_reconnect_to(url).await
+    }
+
+    /// Ask the user to confirm something with a dialog on the page, waiting
+    /// up to the timeout for an answer. The first client to answer decides.
+    pub async fn confirm(&self, message: &str, timeout: Duration) -> ConfirmResult {
+        let answer = self.content.lock().await.confirm(message, timeout).await;
+        answer.await
+    }
+
+    /// Answer a pending question asked by `confirm`. Returns `false` if there's
+    /// no such question.
+    pub async fn answer_confirm(&self, token: &str, yes: bool) -> bool {
+        self.content.lock().await.answer_confirm(token, yes)
+    }
+
+    /// Tell all clients to pass a message on to the window embedding (or
+    /// opening) them, if its origin matches. Returns an error if the channel
+    /// is empty or the origin is invalid.
+    pub async fn send_to_parent(&self,
+                                channel: &str,
+                                data: &str,
+                                origin: &str,
+                                persistent: bool) -> Result<(), String> {
+        self.content.lock().await.send_to_parent(channel, data, origin, persistent).await
+    }
+
+    /// Tell all clients to remember a value in their browser under the given
+    /// key, and apply it to their page, even before reconnecting (the server's
+    /// value always wins once they do). Returns an error if the key is invalid.
+    pub async fn send_persistent(&self, key: &str, data: &str) -> Result<(), String> {
+        self.content.lock().await.send_persistent(key, data).await
+    }
+
+    /// Tell all clients the status of one of the named steps of a job, which is
+    /// remembered for clients connecting later. Returns an error if the step's
+    /// name is empty.
+    pub async fn set_step(&self, step: &str, status: StepStatus) -> Result<(), String> {
+        self.content.lock().await.set_step(step, status).await
+    }
+
+    /// Tell all clients to forget the status of every step.
+    pub async fn clear_steps(&self) {
+        self.content.lock().await.clear_steps().await
+    }
+
+    /// Send a one-shot custom event only to the clients of the page in the given
+    /// room. Returns an error if the event type is invalid.
+    pub async fn send_to_room(&self, room: &str, event_type: &str, data: &str) -> Result<(), String> {
+        self.content.lock().await.send_to_room(room, event_type, data).await
     }
 
     /// Set the contents of the page to be a static raw set of bytes with no
@@ -157,6 +379,333 @@ impl Page {
         self.content.lock().await.content_type()
     }
 
+    /// Get a short hash of the static contents of a page, or `None` if it is
+    /// dynamic.
+    pub async fn content_hash(&self) -> Option<String> {
+        self.content.lock().await.content_hash()
+    }
+
+    /// Get the namespace the page has been put in, if any.
+    pub async fn namespace(&self) -> Option<String> {
+        self.content.lock().await.namespace().map(String::from)
+    }
+
+    /// Put the page in a namespace, or with `None`, take it out of one (see
+    /// `Content::set_namespace`).
+    pub async fn set_namespace(&self, namespace: Option<String>) {
+        self.content.lock().await.set_namespace(namespace)
+    }
+
+    /// Get the subresource integrity metadata (like `sha384-<base64 hash>`) of
+    /// the static contents of a page, or `None` if it is dynamic.
+    pub async fn integrity(&self) -> Option<String> {
+        self.content.lock().await.integrity()
+    }
+
+    /// Tell all clients to change the query string of their URL, without
+    /// navigating away from the page. Returns an error if the query is not a
+    /// well-formed query string.
+    pub async fn send_query_update(&self, query: &str, replace: bool) -> Result<(), String> {
+        self.content.lock().await.send_query_update(query, replace).await
+    }
+
+    /// Tell all clients to set (or, if `value` is `None`, remove) an attribute
+    /// on one of the root elements of the page. Returns an error if the
+    /// attribute name isn't legal.
+    pub async fn set_root_attr(&self,
+                               element: RootElement,
+                               name: &str,
+                               value: Option<&str>) -> Result<(), String> {
+        self.content.lock().await.set_root_attr(element, name, value).await
+    }
+
+    /// Tell all clients to set (or, if `value` is `None`, remove) an attribute
+    /// on the element matching a CSS selector. Returns an error if the
+    /// selector is empty, the attribute name isn't legal, or too many
+    /// attributes are set already.
+    pub async fn set_attr(&self, selector: &str, name: &str, value: Option<&str>) -> Result<(), String> {
+        self.content.lock().await.set_attr(selector, name, value).await
+    }
+
+    /// Forget every attribute set on elements, so clients connecting later
+    /// don't set them.
+    pub async fn clear_attrs(&self) {
+        self.content.lock().await.clear_attrs()
+    }
+
+    /// Tell all clients to mark form fields invalid with the given messages,
+    /// replacing any errors shown before.
+    pub async fn send_validation(&self, errors: HashMap<String, String>) {
+        self.content.lock().await.send_validation(errors).await
+    }
+
+    /// Apply a batch of changes all together, or none of them if any is
+    /// invalid. Returns the number of changes applied.
+    pub async fn apply_validated(&self, ops: Vec<batch::Op>) -> Result<usize, batch::ValidationError> {
+        self.content.lock().await.apply_validated(ops).await
+    }
+
+    /// Set the rules which every change in a batch must follow.
+    pub async fn set_validator(&self, validator: batch::Validator) {
+        self.content.lock().await.set_validator(validator)
+    }
+
+    /// Tell all clients to remove every attribute set on the root elements of
+    /// the page.
+    pub async fn clear_root_attrs(&self) {
+        self.content.lock().await.clear_root_attrs().await
+    }
+
+    /// Tell all clients to append some HTML to the end of the body. This
+    /// converts the page into a dynamic page, overwriting any static content
+    /// that previously existed, if any.
+    pub async fn append_body(&self, text: &str) {
+        self.content.lock().await.append_body(text).await
+    }
+
+    /// Follow what's appended to the body of the page, starting from the body
+    /// as it is now, which is returned along with the cursor. Returns `None` if
+    /// the page is static.
+    pub async fn body_cursor(&self) -> Option<(String, BodyCursor)> {
+        self.content.lock().await.body_cursor()
+    }
+
+    /// Stream the page's updates going into and out of backpressure (see
+    /// `Content::set_backpressure_callback`) as `backpressure` events, whose
+    /// data is `begin` or `end`, so that a producer can throttle itself. Only
+    /// the latest stream is sent these: starting another ends this one, as
+    /// does the page becoming static. Returns `None` if the page is static.
+    pub async fn backpressure_stream(&self) -> Option<Body> {
+        let mut content = self.content.lock().await;
+        content.update_server()?;
+        let (sender, mut transitions) = watch::channel(None);
+        content.set_backpressure_callback(move |state| {
+            // Nobody is listening once the stream is dropped
+            let _ = sender.broadcast(Some(state));
+        });
+        let (mut stream, body) = Body::channel();
+        tokio::spawn(async move {
+            while let Some(transition) = transitions.recv().await {
+                let data = match transition {
+                    Some(sse::BackpressureState::Begin) => "begin",
+                    Some(sse::BackpressureState::End) => "end",
+                    None => continue,
+                };
+                let event = EventBuilder::new(data).event_type("backpressure").build();
+                if stream.send_data(event.into()).await.is_err() {
+                    break
+                }
+            }
+        });
+        Some(body)
+    }
+
+    /// Watch the body of the page from the server side, as a stream of whole
+    /// bodies starting with the current one. Returns `None` if the page is
+    /// static.
+    pub async fn watch_body(&self) -> Option<impl Stream<Item = String>> {
+        self.content.lock().await.watch_body()
+    }
+
+    /// Tell all clients to append some text to the element matching a CSS
+    /// selector. Returns an error if the selector is empty.
+    pub async fn append_to(&self, selector: &str, text: &str) -> Result<(), String> {
+        self.content.lock().await.append_to(selector, text).await
+    }
+
+    /// Tell all clients to empty the element matching a CSS selector.
+    pub async fn clear_element(&self, selector: &str) {
+        self.content.lock().await.clear_element(selector).await
+    }
+
+    /// Tell only those clients which haven't reported local changes to change
+    /// the body, offering it to the rest. This converts the page into a
+    /// dynamic page, overwriting any static content that previously existed,
+    /// if any.
+    pub async fn set_body_to_clean(&self, new_body: impl Into<String>) {
+        self.content.lock().await.set_body_to_clean(new_body).await
+    }
+
+    /// Record whether a client has reported local changes which a body update
+    /// could clobber. Returns `false` if there is no such client.
+    pub async fn set_client_dirty(&self, client: sse::ClientId, dirty: bool) -> bool {
+        self.content.lock().await.set_client_dirty(client, dirty).await
+    }
+
+    /// Get every event type with a stored value which is replayed to new clients
+    /// of the page, with its current payload.
+    pub async fn stored_events(&self) -> Vec<(String, String)> {
+        self.content.lock().await.stored_events()
+    }
+
+    /// Send a client whatever it needs to bring a baseline body it holds up to
+    /// date, as a patch if possible. Returns `false` if there is no such
+    /// client.
+    pub async fn send_body_diff(&self, id: sse::ClientId, baseline: &str) -> bool {
+        self.content.lock().await.send_body_diff(id, baseline).await
+    }
+
+    /// Disconnect a single client of the page. Returns `false` if there's no
+    /// such client, or if it's a protected follower (which can't be
+    /// disconnected).
+    pub async fn disconnect_client(&self, id: sse::ClientId) -> bool {
+        self.content.lock().await.disconnect_client(id).await
+    }
+
+    /// Note that a client is still active, so it isn't disconnected for being
+    /// idle. Returns `false` if there is no such client.
+    pub async fn touch_client(&self, id: sse::ClientId) -> bool {
+        self.content.lock().await.touch_client(id).await
+    }
+
+    /// Record how far behind a client is in rendering updates, which may adjust
+    /// the coalescing window. Returns `false` if there is no such client.
+    pub async fn report_lag(&self, id: sse::ClientId, lag: Duration) -> bool {
+        self.content.lock().await.report_lag(id, lag).await
+    }
+
+    /// Get what a client has been sent so far: the highest version of the title
+    /// or body, and every type of event. Returns `None` if there is no such
+    /// client, or the page is static.
+    pub async fn client_sync_state(&self, id: sse::ClientId) -> Option<sse::SyncState> {
+        self.content.lock().await.client_sync_state(id).await
+    }
+
+    /// Flush the latest body of the page to its clients and tell them the server
+    /// is shutting down, giving up at the deadline. Returns `false` if this
+    /// didn't finish in time.
+    pub async fn prepare_shutdown(&self, deadline: Instant) -> bool {
+        self.content.lock().await.prepare_shutdown(deadline).await
+    }
+
+    /// Put a bounded mailbox of the given capacity in front of body updates to
+    /// the page, or take it away if `capacity` is `None` (see
+    /// `Content::enable_mailbox`).
+    pub async fn set_mailbox(&self, capacity: Option<usize>) {
+        let mut content = self.content.lock().await;
+        match capacity {
+            Some(capacity) => content.enable_mailbox(capacity).await,
+            None => content.disable_mailbox().await,
+        }
+    }
+
+    /// Change the number of updates which can be buffered for the page before
+    /// sending them experiences backpressure (see `Content::resize_buffer`).
+    pub async fn resize_buffer(&self, capacity: usize) {
+        self.content.lock().await.resize_buffer(capacity).await
+    }
+
+    /// Send body updates to the page larger than the given number of bytes
+    /// compressed, or stop compressing them if `threshold` is `None` (see
+    /// `Content::set_event_compression_threshold`).
+    pub async fn set_event_compression_threshold(&self, threshold: Option<usize>) {
+        self.content.lock().await.set_event_compression_threshold(threshold)
+    }
+
+    /// Disconnect any client of the page which doesn't read an update for the
+    /// given time, or never disconnect them if `timeout` is `None` (see
+    /// `Content::set_stuck_client_timeout`).
+    pub async fn set_stuck_client_timeout(&self, timeout: Option<Duration>) {
+        self.content.lock().await.set_stuck_client_timeout(timeout).await
+    }
+
+    /// Disconnect any client of the page which sends nothing upstream for the
+    /// given time, or never disconnect idle clients if `timeout` is `None` (see
+    /// `Content::set_client_idle_timeout`).
+    pub async fn set_client_idle_timeout(&self, timeout: Option<Duration>) {
+        self.content.lock().await.set_client_idle_timeout(timeout).await
+    }
+
+    /// Merge changes to the page made within the given window of each other
+    /// into one frame, or stop merging them if the window is zero (see
+    /// `Content::set_coalesce_window`).
+    pub async fn set_coalesce_window(&self, window: Duration) {
+        self.content.lock().await.set_coalesce_window(window).await
+    }
+
+    /// Keep adjusting the coalescing window to how far behind clients are,
+    /// aiming for the given lag, or stop adjusting it if `target_lag` is
+    /// `None` (see `Content::enable_adaptive_rate`).
+    pub async fn set_adaptive_rate(&self, target_lag: Option<Duration>) {
+        let mut content = self.content.lock().await;
+        match target_lag {
+            Some(target_lag) => content.enable_adaptive_rate(target_lag),
+            None => content.disable_adaptive_rate(),
+        }
+    }
+
+    /// Change how many line ranges of the page's static content are cached,
+    /// turning caching off if `size` is zero (see
+    /// `Content::set_render_cache_size`).
+    pub async fn set_render_cache_size(&self, size: usize) {
+        self.content.lock().await.set_render_cache_size(size)
+    }
+
+    /// Choose whether to hold back text appended to the page until the next
+    /// heartbeat (see `Content::set_append_flush_on_heartbeat`).
+    pub async fn set_append_flush_on_heartbeat(&self, enabled: bool) {
+        self.content.lock().await.set_append_flush_on_heartbeat(enabled).await
+    }
+
+    /// Choose whether to skip heartbeats to the page's clients while they're
+    /// being sent updates anyway (see `Content::set_heartbeat_throttle`).
+    pub async fn set_heartbeat_throttle(&self, throttle: bool) {
+        self.content.lock().await.set_heartbeat_throttle(throttle)
+    }
+
+    /// Limit how much element state is replayed to new clients of the page
+    /// apart from its body, or lift the limit if `max` is `None` (see
+    /// `Content::set_max_replay_state`).
+    pub async fn set_max_replay_state(&self, max: Option<usize>) {
+        self.content.lock().await.set_max_replay_state(max)
+    }
+
+    /// Change how many attributes set on elements can be remembered for new
+    /// clients of the page (see `Content::set_attr_cap`).
+    pub async fn set_attr_cap(&self, cap: usize) {
+        self.content.lock().await.set_attr_cap(cap)
+    }
+
+    /// Start streaming the server's log of this page to its clients, at the
+    /// given level of detail, or stop streaming it if `level` is `None`.
+    pub async fn set_log_streaming(&self, level: Option<LogLevel>) {
+        let mut content = self.content.lock().await;
+        match level {
+            Some(level) => content.enable_log_streaming(level),
+            None => content.disable_log_streaming(),
+        }
+    }
+
+    /// Extract a range of lines (numbered from 1, inclusive) from the page, if
+    /// it is static text. Otherwise returns `None`.
+    pub async fn static_lines(&self, start: usize, end: usize) -> Option<Vec<u8>> {
+        self.content.lock().await.static_lines(start, end)
+    }
+
+    /// Atomically add `delta` to the integer body of a dynamic page, telling all
+    /// clients about the new body and returning it.
+    pub async fn increment_body(&self, delta: i64) -> Result<i64, NotNumeric> {
+        self.content.lock().await.increment_body(delta).await
+    }
+
+    /// Render the page as a PNG image of the given size.
+    #[cfg(feature = "screenshot")]
+    pub async fn render_image(&self, width: u32, height: u32) -> Result<Vec<u8>, render::RenderError> {
+        self.content.lock().await.render_image(width, height).await
+    }
+
+    /// Get the current value of every metric this page reports, to export with
+    /// `metrics::exposition`.
+    pub async fn metrics(&self) -> metrics::Samples {
+        let mut content = self.content.lock().await;
+        let mut samples = content.metrics();
+        // Only the update server knows which of its clients are protected
+        if content.update_server().is_some() {
+            samples.push((&metrics::PROTECTED_CLIENTS, content.protected_client_count().await as u64));
+        }
+        samples
+    }
+
     /// Tell all clients to change the title, if necessary. This converts the
     /// page into a dynamic page, overwriting any static content that previously
     /// existed, if any.
@@ -164,12 +713,58 @@ impl Page {
         self.content.lock().await.set_title(new_title).await
     }
 
+    /// Tell all clients to show a title made from a template with named
+    /// `{slot}`s, filled in by `set_title_slot`. This converts the page into a
+    /// dynamic page, overwriting any static content that previously existed, if
+    /// any.
+    pub async fn set_title_template(&self, template: &str) {
+        self.content.lock().await.set_title_template(template).await
+    }
+
+    /// Tell all clients to change one slot of the title template. Returns
+    /// `false` if there is no title template, or it has no such slot.
+    pub async fn set_title_slot(&self, name: &str, value: &str) -> bool {
+        self.content.lock().await.set_title_slot(name, value).await
+    }
+
+    /// Test whether the title of the page is made from a template.
+    pub async fn has_title_template(&self) -> bool {
+        self.content.lock().await.has_title_template()
+    }
+
+    /// Compute the frames that `set_title` would send with this title, without
+    /// sending anything or changing the page (see `Content::preview_set_title`).
+    pub async fn preview_set_title(&self, new_title: &str) -> Vec<PreviewFrame> {
+        self.content.lock().await.preview_set_title(new_title)
+    }
+
+    /// Compute the frames that `set_body` would send with this body, without
+    /// sending anything or changing the page (see `Content::preview_set_body`).
+    pub async fn preview_set_body(&self, new_body: &str) -> Vec<PreviewFrame> {
+        self.content.lock().await.preview_set_body(new_body)
+    }
+
+    /// Tell all clients to change the title and head metadata of the page
+    /// together. This converts the page into a dynamic page, overwriting any
+    /// static content that previously existed, if any.
+    pub async fn set_seo(&self, seo: SeoMeta) {
+        self.content.lock().await.set_seo(seo).await
+    }
+
     /// Tell all clients to change the body, if necessary. This converts the
     /// page into a dynamic page, overwriting any static content that previously
     /// existed, if any.
     pub async fn set_body(&self, new_body: impl Into<String>) {
         self.content.lock().await.set_body(new_body).await
     }
+
+    /// Set the body of the page in a particular language, telling only those
+    /// clients shown that language to change it. This converts the page into a
+    /// dynamic page, overwriting any static content that previously existed,
+    /// if any.
+    pub async fn set_body_lang(&self, lang: &str, new_body: &str) {
+        self.content.lock().await.set_body_lang(lang, new_body).await
+    }
 }
 
 /// Send a new total set of subscriptions to the page, so it can update its
@@ -178,7 +773,7 @@ impl Page {
 /// sending an event, or sending a heartbeat! It will cause unexpected loss
 /// of messages if you arbitrarily set the subscriptions of a page outside
 /// of these contexts.
-async fn set_subscriptions<'a>(server: &'a mut sse::BufferedServer,
+async fn set_subscriptions<'a>(server: &'a sse::BufferedServer,
                                subscription: AggregateSubscription<'a>) {
     let data = serde_json::to_string(&subscription)
         .expect("Serializing subscriptions to JSON shouldn't fail");
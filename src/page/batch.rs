@@ -0,0 +1,157 @@
+use serde::Deserialize;
+use std::fmt;
+
+/// One of the changes to a dynamic page applied together by
+/// `Content::apply_validated`. As JSON, each is an object with a single key
+/// naming the change, like `{"title": "Hello"}` or `{"root-attr": {"element":
+/// "body", "name": "class", "value": "dark"}}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Op {
+    /// Set the title, like `Content::set_title`.
+    Title(String),
+    /// Set the body, like `Content::set_body`.
+    Body(String),
+    /// Set (or with no value, remove) an attribute on the `html` or `body`
+    /// element, like `Content::set_root_attr`.
+    RootAttr{element: String, name: String, #[serde(default)] value: Option<String>},
+    /// Change the query string of the page's URL, like
+    /// `Content::send_query_update`.
+    Query{query: String, #[serde(default)] replace: bool},
+}
+
+/// The checks made on every change applied by `Content::apply_validated`,
+/// beyond those any change to the page must pass (like attribute names being
+/// legal). By default, there are none. As JSON, it's an object with any of
+/// these fields, like `{"max-body-len": 4096, "forbid-scripts": true}`.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Validator {
+    /// The longest title allowed, in bytes.
+    pub max_title_len: Option<usize>,
+    /// The longest body allowed, in bytes.
+    pub max_body_len: Option<usize>,
+    /// Whether the body must be well-formed HTML, with its tags properly
+    /// nested and closed.
+    pub well_formed: bool,
+    /// Whether to reject bodies containing `<script>` elements or inline event
+    /// handler attributes (like `onclick`).
+    pub forbid_scripts: bool,
+}
+
+/// The reason a batch of changes was rejected by `Content::apply_validated`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    /// The position of the first invalid change in the batch, from zero.
+    pub index: usize,
+    /// What was wrong with it.
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid change at position {}: {}", self.index, self.message)
+    }
+}
+
+impl Validator {
+    /// Check a title against these rules.
+    pub fn check_title(&self, title: &str) -> Result<(), String> {
+        match self.max_title_len {
+            Some(max) if title.len() > max =>
+                Err(format!("Title is {} bytes long, over the limit of {}", title.len(), max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a body against these rules.
+    pub fn check_body(&self, body: &str) -> Result<(), String> {
+        match self.max_body_len {
+            Some(max) if body.len() > max =>
+                return Err(format!("Body is {} bytes long, over the limit of {}", body.len(), max)),
+            _ => { },
+        }
+        if self.well_formed || self.forbid_scripts {
+            check_html(body, self.well_formed, self.forbid_scripts)?;
+        }
+        Ok(())
+    }
+}
+
+/// The elements which never have a closing tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input",
+    "link", "meta", "param", "source", "track", "wbr",
+];
+
+/// The elements whose contents are raw text, not parsed as tags.
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style", "textarea", "title"];
+
+/// Scan the tags of a fragment of HTML, checking that they are properly nested
+/// and closed (if `nesting`), and that there are no scripts (if `no_scripts`).
+/// This is a lightweight check, not a full HTML parser: it doesn't know about
+/// the tags HTML allows to be left open, like `<p>` or `<li>`, so these must be
+/// closed explicitly.
+fn check_html(html: &str, nesting: bool, no_scripts: bool) -> Result<(), String> {
+    let mut open: Vec<String> = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        if let Some(comment) = rest.strip_prefix("!--") {
+            let end = comment.find("-->").ok_or("Unclosed comment")?;
+            rest = &comment[end + 3..];
+            continue;
+        }
+        let end = rest.find('>').ok_or("Unclosed tag")?;
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with('!') || tag.starts_with('?') {
+            continue; // a doctype or processing instruction
+        }
+        let closing = tag.starts_with('/');
+        let tag = tag.trim_start_matches('/');
+        let name_end = tag
+            .find(|c: char| c.is_whitespace() || c == '/')
+            .unwrap_or(tag.len());
+        let name = tag[..name_end].to_ascii_lowercase();
+        if name.is_empty() {
+            return Err("Tag without a name".to_string())
+        }
+        if no_scripts && !closing {
+            if name == "script" {
+                return Err("Scripts aren't allowed".to_string())
+            }
+            let handler = tag[name_end..]
+                .split(|c: char| c.is_whitespace() || c == '=' || c == '"' || c == '\'')
+                .any(|word| word.len() > 2 && word[..2].eq_ignore_ascii_case("on"));
+            if handler {
+                return Err(format!("Event handler attributes aren't allowed (in <{}>)", name))
+            }
+        }
+        if closing {
+            if nesting {
+                match open.pop() {
+                    Some(expected) if expected == name => { },
+                    Some(expected) =>
+                        return Err(format!("</{}> closes <{}>", name, expected)),
+                    None => return Err(format!("</{}> closes nothing", name)),
+                }
+            }
+        } else if tag.ends_with('/') || VOID_ELEMENTS.contains(&name.as_str()) {
+            continue;
+        } else if RAW_TEXT_ELEMENTS.contains(&name.as_str()) {
+            // Skip straight to the closing tag, ignoring what looks like tags
+            let close = format!("</{}", name);
+            let end = rest.to_ascii_lowercase().find(&close)
+                .ok_or_else(|| format!("Unclosed <{}>", name))?;
+            rest = &rest[end..];
+            open.push(name);
+        } else {
+            open.push(name);
+        }
+    }
+    match open.pop() {
+        Some(name) if nesting => Err(format!("Unclosed <{}>", name)),
+        _ => Ok(()),
+    }
+}
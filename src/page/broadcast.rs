@@ -0,0 +1,49 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use uuid::Uuid;
+
+lazy_static! {
+    /// The id of this process, which tags every broadcast it publishes.
+    static ref ORIGIN: String = Uuid::new_v4().to_simple().to_string();
+}
+
+/// Get the id of this process as the origin of broadcasts. Every process
+/// serving a page has a different origin, so a process can recognize (and
+/// ignore) its own broadcasts when its backend echoes them back.
+pub fn origin() -> &'static str {
+    &ORIGIN
+}
+
+/// An update to a page which is broadcast to the other processes serving it,
+/// so they can apply it for their own clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Update {
+    Title{title: String},
+    Body{body: String},
+    CleanBody{body: String},
+    AppendBody{text: String},
+    Event{event_type: String, data: String},
+}
+
+/// An update tagged with the process it came from. This is what's passed to
+/// and from a `BroadcastBackend`, and it can be serialized as JSON to go over
+/// the wire.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Broadcast {
+    pub origin: String,
+    pub update: Update,
+}
+
+/// An integration point for an external pub/sub system (like Redis or NATS),
+/// through which several myxine processes can serve the same page: updates
+/// made on one process are published through the backend, and the backend
+/// should pass what it receives to `Page::apply_broadcast` on the other
+/// processes. A backend is set per page, so it knows which page (and channel)
+/// it's publishing for.
+pub trait BroadcastBackend: Debug + Send + Sync {
+    /// Publish an update made by this process. This is called with the page
+    /// locked, so it must not wait for the update to be delivered.
+    fn publish(&self, broadcast: Broadcast);
+}
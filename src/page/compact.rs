@@ -0,0 +1,216 @@
+use std::ops::Range;
+
+/// The elements which never have a closing tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input",
+    "link", "meta", "param", "source", "track", "wbr",
+];
+
+/// The elements whose contents are raw text, not parsed as tags.
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style", "textarea", "title"];
+
+/// Get the id picked out by a selector, if it's just an id, like `#log`: these
+/// are the only elements whose state can be folded back into a page's body (as
+/// by `Content::set_max_replay_state`), since the body isn't parsed as a whole,
+/// but only scanned for the tags of the element with that id.
+pub fn selector_id(selector: &str) -> Option<&str> {
+    let id = selector.trim().strip_prefix('#')?;
+    if !id.is_empty() && id.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+/// Append some text (not HTML) to the contents of the element with the given
+/// id, returning the new body, or `None` if there's no such element (or it
+/// can't be found for sure).
+pub fn append_text(body: &str, id: &str, text: &str) -> Option<String> {
+    let element = find_element(body, id)?;
+    let end = element.contents?.end;
+    let escaped = text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    Some([&body[..end], &escaped, &body[end..]].concat())
+}
+
+/// Set (or with no value, remove) an attribute on the element with the given
+/// id, returning the new body, or `None` if there's no such element (or it
+/// can't be found for sure).
+pub fn set_attr(body: &str, id: &str, name: &str, value: Option<&str>) -> Option<String> {
+    let element = find_element(body, id)?;
+    let tag = &body[element.start_tag.clone()];
+    // Rebuild the start tag without the attribute, then add it back at the end
+    let inner = tag.trim_start_matches('<').trim_end_matches('>');
+    let self_closing = inner.ends_with('/');
+    let inner = inner.trim_end_matches('/');
+    let attrs = attributes(inner);
+    // The tag's id must stay, since it's how the element is found
+    if name.eq_ignore_ascii_case("id") {
+        return None
+    }
+    let mut new_tag = format!("<{}", attrs[0].name);
+    for attr in attrs.iter().skip(1).filter(|attr| !attr.name.eq_ignore_ascii_case(name)) {
+        new_tag.push(' ');
+        new_tag.push_str(attr.source);
+    }
+    if let Some(value) = value {
+        new_tag.push_str(&format!(" {}=\"{}\"", name,
+                                  value.replace('&', "&amp;").replace('"', "&quot;")));
+    }
+    new_tag.push_str(if self_closing { " />" } else { ">" });
+    Some([&body[..element.start_tag.start], &new_tag, &body[element.start_tag.end..]].concat())
+}
+
+/// Where an element is in a body.
+struct Element {
+    /// The whole start tag, from `<` to `>`.
+    start_tag: Range<usize>,
+    /// The contents, between the start and end tags, if it can have any.
+    contents: Option<Range<usize>>,
+}
+
+/// Find the element with the given id, if there's exactly one.
+fn find_element(body: &str, id: &str) -> Option<Element> {
+    let mut found = None;
+    let mut position = 0;
+    while let Some(tag) = next_tag(body, position) {
+        position = tag.range.end;
+        if tag.closing {
+            continue;
+        }
+        let inner = &body[tag.range.start + 1..tag.range.end - 1];
+        let has_id = attributes(inner.trim_end_matches('/')).iter().skip(1)
+            .any(|attr| attr.name.eq_ignore_ascii_case("id") && attr.value == Some(id));
+        if has_id {
+            if found.is_some() {
+                return None // Ids should be unique, but this one isn't
+            }
+            found = Some(tag);
+        }
+    }
+    let tag = found?;
+    let contents = if tag.void {
+        None
+    } else {
+        let end = closing_tag(body, &tag)?;
+        Some(tag.range.end..end)
+    };
+    Some(Element{start_tag: tag.range, contents})
+}
+
+/// A tag found in a body.
+struct Tag {
+    range: Range<usize>,
+    name: String,
+    closing: bool,
+    /// Whether the tag has no closing tag (it's a void element, or closes
+    /// itself).
+    void: bool,
+}
+
+/// Find the next tag starting at or after a position, skipping comments and
+/// the contents of raw text elements.
+fn next_tag(body: &str, position: usize) -> Option<Tag> {
+    let mut position = position;
+    loop {
+        let start = position + body[position..].find('<')?;
+        let rest = &body[start + 1..];
+        if let Some(comment) = rest.strip_prefix("!--") {
+            position = start + 4 + comment.find("-->")? + 3;
+            continue;
+        }
+        let end = start + 1 + rest.find('>')? + 1;
+        let inner = &body[start + 1..end - 1];
+        if inner.starts_with('!') || inner.starts_with('?') {
+            position = end;
+            continue;
+        }
+        let closing = inner.starts_with('/');
+        let name: String = inner.trim_start_matches('/')
+            .chars()
+            .take_while(|c| !c.is_whitespace() && *c != '/')
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if name.is_empty() {
+            position = end;
+            continue;
+        }
+        let void = !closing && (inner.ends_with('/') || VOID_ELEMENTS.contains(&name.as_str()));
+        return Some(Tag{range: start..end, name, closing, void})
+    }
+}
+
+/// Find where the contents of an element end: the start of its closing tag.
+fn closing_tag(body: &str, open: &Tag) -> Option<usize> {
+    if RAW_TEXT_ELEMENTS.contains(&open.name.as_str()) {
+        let close = format!("</{}", open.name);
+        return Some(open.range.end + body[open.range.end..].to_ascii_lowercase().find(&close)?)
+    }
+    let mut depth = 0;
+    let mut position = open.range.end;
+    while let Some(tag) = next_tag(body, position) {
+        position = tag.range.end;
+        if tag.closing {
+            if depth == 0 {
+                // Anything but the matching tag means the nesting is off
+                return if tag.name == open.name { Some(tag.range.start) } else { None }
+            }
+            depth -= 1;
+        } else if RAW_TEXT_ELEMENTS.contains(&tag.name.as_str()) {
+            let close = format!("</{}", tag.name);
+            position = tag.range.end + body[tag.range.end..].to_ascii_lowercase().find(&close)?;
+            depth += 1;
+        } else if !tag.void {
+            depth += 1;
+        }
+    }
+    None
+}
+
+/// An attribute in a start tag (or, first, the tag's name).
+struct Attribute<'a> {
+    name: &'a str,
+    /// The value, unquoted but still escaped, if it has one.
+    value: Option<&'a str>,
+    /// The whole of the attribute as written.
+    source: &'a str,
+}
+
+/// Split the inside of a start tag into its name (first, with no value) and its
+/// attributes.
+fn attributes(inner: &str) -> Vec<Attribute<'_>> {
+    let mut attrs = Vec::new();
+    let inner = inner.trim();
+    let mut rest = inner;
+    while !rest.is_empty() {
+        let start = inner.len() - rest.len();
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+        let mut end = inner.len() - rest.len();
+        let value = match rest.strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start();
+                let (value, remaining) = match after.chars().next() {
+                    Some(quote) if quote == '"' || quote == '\'' => {
+                        let close = after[1..].find(quote).map_or(after.len(), |close| close + 1);
+                        (&after[1..close], after.get(close + 1..).unwrap_or(""))
+                    },
+                    _ => {
+                        let close = after.find(char::is_whitespace).unwrap_or(after.len());
+                        (&after[..close], &after[close..])
+                    },
+                };
+                end = inner.len() - remaining.len();
+                rest = remaining.trim_start();
+                Some(value)
+            },
+            None => None,
+        };
+        if !name.is_empty() {
+            attrs.push(Attribute{name, value, source: inner[start..end].trim_end()});
+        }
+    }
+    attrs
+}
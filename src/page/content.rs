@@ -1,8 +1,39 @@
+use http::header::{HeaderMap, HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE};
 use hyper::Body;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use hyper_usse::EventBuilder;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::future::Future;
+use flate2::{Compression, write::GzEncoder};
+use std::fmt::Write;
+use std::io::Write as _;
 use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use futures::Stream;
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::time;
+use uuid::Uuid;
 
 use super::sse;
+use super::broadcast::{self as pubsub, Broadcast, BroadcastBackend, Update};
+use super::batch::{Op, ValidationError, Validator};
+use super::log::{LogLevel, ServerLog};
+use super::recording::{self, Recorder};
+use super::multiplex;
+use super::message::EventMessage;
+use super::integrity;
+use super::metrics;
+use super::compact;
+use super::snapshot::{AttrSnapshot, DynamicSnapshot, ParentMessageSnapshot, RootAttrSnapshot,
+                      StepSnapshot, TitleTemplateSnapshot,
+                      Snapshot, StaticSnapshot};
+#[cfg(feature = "screenshot")]
+use super::render::{self, RenderError};
 
 /// The `Content` of a page is either `Dynamic` or `Static`. If it's dynamic, it
 /// has a title, body, and a set of SSE event listeners who are waiting for
@@ -13,18 +44,304 @@ use super::sse;
 /// direction, it requires a manual refresh (because a static page has no
 /// injected javascript to make it update itself).
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Content {
     Dynamic {
-        title: String,
-        body: String,
-        updates: sse::BufferedServer,
+        state: DynamicState,
+        confirmations: HashMap<String, oneshot::Sender<bool>>,
+        body_changes: broadcast::Sender<BodyChange>,
+        body_watchers: Vec<watch::Sender<String>>,
+        updates: Arc<sse::BufferedServer>,
+        mailbox: Option<sse::Mailbox>,
+        coalescer: Option<sse::Coalescer>,
+        append_buffer: Option<AppendBuffer>,
+        adaptive_rate: Option<AdaptiveRate>,
+        heartbeat_throttle: bool,
+        log: Arc<ServerLog>,
+        backend: Option<Arc<dyn BroadcastBackend>>,
+        closed: bool,
+        refreshed: Option<Instant>,
+        modified: Instant,
     },
     Static {
         content_type: Option<String>,
         raw_contents: Vec<u8>,
+        hash: String,
+        integrity: String,
+        render_cache: RenderCache,
+        fetches: u64,
+        fetched: Option<Instant>,
+        refreshed: Option<Instant>,
+        modified: Instant,
+        namespace: Option<String>,
+        version: u64,
     }
 }
 
+/// The state of a dynamic page which can be copied as is: what its clients
+/// see, and the settings which don't depend on its update server. Everything
+/// else in `Content::Dynamic` belongs to the clients of one particular page
+/// (its update server, its mailbox, pending confirmations, and so on), and has
+/// to be made anew for a copy (see `Content::fork`).
+#[derive(Debug, Clone)]
+pub struct DynamicState {
+    pub title: String,
+    pub title_template: Option<TitleTemplate>,
+    pub body: String,
+    pub lang_bodies: BTreeMap<String, String>,
+    pub description: String,
+    pub og: BTreeMap<String, String>,
+    pub query: Option<String>,
+    pub root_attrs: BTreeMap<(RootElement, String), String>,
+    pub validation: HashMap<String, String>,
+    pub validator: Validator,
+    pub streams: BTreeMap<String, String>,
+    pub parent_messages: BTreeMap<String, ParentMessage>,
+    pub persistent: BTreeMap<String, String>,
+    pub steps: Vec<(String, StepStatus)>,
+    pub attrs: BTreeMap<(String, String), Option<String>>,
+    pub attr_cap: usize,
+    pub max_replay_state: Option<usize>,
+    pub timed_events: Vec<TimedEvent>,
+    pub compression_threshold: Option<usize>,
+    pub namespace: Option<String>,
+    pub version: u64,
+}
+
+impl Default for DynamicState {
+    fn default() -> DynamicState {
+        DynamicState {
+            title: String::new(),
+            title_template: None,
+            body: String::new(),
+            lang_bodies: BTreeMap::new(),
+            description: String::new(),
+            og: BTreeMap::new(),
+            query: None,
+            root_attrs: BTreeMap::new(),
+            validation: HashMap::new(),
+            validator: Validator::default(),
+            streams: BTreeMap::new(),
+            parent_messages: BTreeMap::new(),
+            persistent: BTreeMap::new(),
+            steps: Vec::new(),
+            attrs: BTreeMap::new(),
+            attr_cap: DEFAULT_ATTR_CAP,
+            max_replay_state: None,
+            timed_events: Vec::new(),
+            compression_threshold: None,
+            namespace: None,
+            version: 0,
+        }
+    }
+}
+
+/// One of the two elements at the root of a dynamic page, whose attributes can
+/// be set independently of the page body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RootElement {
+    Html,
+    Body,
+}
+
+impl RootElement {
+    fn as_str(self) -> &'static str {
+        match self {
+            RootElement::Html => "html",
+            RootElement::Body => "body",
+        }
+    }
+}
+
+impl TryFrom<&str> for RootElement {
+    type Error = String;
+    fn try_from(string: &str) -> Result<RootElement, String> {
+        match string {
+            "html" => Ok(RootElement::Html),
+            "body" => Ok(RootElement::Body),
+            _ => Err(format!("Invalid root element (must be html or body): {}", string)),
+        }
+    }
+}
+
+/// The state of one of the named steps of a job shown on a dynamic page, set by
+/// `Content::set_step`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepStatus {
+    Pending,
+    Active,
+    Done,
+    /// The step failed, perhaps with a message saying why.
+    Failed(Option<String>),
+}
+
+impl StepStatus {
+    /// Make a status from its name (`pending`, `active`, `done`, or `failed`),
+    /// with the error message for a failed step. Returns an error if the name
+    /// is invalid, or there's a message for a step which didn't fail.
+    pub fn parse(status: &str, error: Option<String>) -> Result<StepStatus, String> {
+        match (status, error) {
+            ("failed", error) => Ok(StepStatus::Failed(error)),
+            (_, Some(_)) => Err(format!("Only a failed step can have an error, not a {} one", status)),
+            ("pending", None) => Ok(StepStatus::Pending),
+            ("active", None) => Ok(StepStatus::Active),
+            ("done", None) => Ok(StepStatus::Done),
+            _ => Err(format!("Invalid step status (must be pending, active, done, or failed): {}", status)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StepStatus::Pending => "pending",
+            StepStatus::Active => "active",
+            StepStatus::Done => "done",
+            StepStatus::Failed(_) => "failed",
+        }
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            StepStatus::Failed(error) => error.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// A frame which a setter would send to clients, as computed by a dry run like
+/// `Content::preview_set_body`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreviewFrame {
+    /// The SSE event type of the frame.
+    pub event_type: String,
+    /// The frame exactly as it would be sent, in `text/event-stream` format.
+    pub frame: String,
+}
+
+/// The metadata which search engines and link previews read from the head of a
+/// page, set all at once by `Content::set_seo`.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default)]
+pub struct SeoMeta {
+    /// The title of the page.
+    pub title: String,
+    /// The `description` meta tag, or none if this is empty.
+    pub description: String,
+    /// The Open Graph meta tags, keyed by the part of their property name after
+    /// `og:` (so `image` sets `og:image`). Tags with empty values are left out.
+    pub og: HashMap<String, String>,
+}
+
+/// A way of getting the attention of someone looking at a page, sent by
+/// `Content::send_alert`. As JSON, this is `{"vibrate": [200, 100, 200]}`,
+/// `{"sound": "/alarm.mp3"}`, or `"beep"`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertKind {
+    /// Vibrate the device in the given pattern: alternating durations, in
+    /// milliseconds, of vibrating and pausing.
+    Vibrate(Vec<u32>),
+    /// Play the sound at the given URL.
+    Sound(String),
+    /// Play a short beep.
+    Beep,
+}
+
+/// How the user answered a question asked by `Content::confirm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfirmResult {
+    Yes,
+    No,
+    /// Nobody answered in time (or there was nobody to ask).
+    Timeout,
+}
+
+impl ConfirmResult {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConfirmResult::Yes => "yes",
+            ConfirmResult::No => "no",
+            ConfirmResult::Timeout => "timeout",
+        }
+    }
+}
+
+/// The error returned by `Content::increment_body` when the page body isn't a
+/// number which can be incremented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotNumeric;
+
+/// The difference between some baseline body and the current body of a page,
+/// as computed by `Content::body_diff_from`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodyDiff {
+    /// The baseline is the current body.
+    Unchanged,
+    /// The current body is the baseline, with the text between `start` and
+    /// `end` replaced by `text`. The offsets count UTF-16 code units, as a
+    /// JavaScript string would, so they can be used directly by the page.
+    Patch{start: usize, end: usize, text: String},
+    /// The baseline doesn't resemble the current body closely enough for a
+    /// patch to be worthwhile, so here's the whole body.
+    Full(String),
+}
+
+/// A change to the body of a dynamic page, as seen by a `BodyCursor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodyChange {
+    /// This text was appended to the end of the body.
+    Appended(Arc<str>),
+    /// The body was replaced (or the cursor fell too far behind to know what
+    /// was appended), so the reader should fetch the whole body again.
+    Reset,
+}
+
+/// A cursor over the body of a dynamic page, made by `Content::body_cursor`,
+/// which yields only what has changed since its last read.
+#[derive(Debug)]
+pub struct BodyCursor {
+    changes: broadcast::Receiver<BodyChange>,
+}
+
+impl BodyCursor {
+    /// Wait for the next change to the body since the last one read. Returns
+    /// `None` once the page is no longer dynamic, or has been dropped.
+    pub async fn next(&mut self) -> Option<BodyChange> {
+        match self.changes.recv().await {
+            Ok(change) => Some(change),
+            Err(broadcast::RecvError::Lagged(_)) => Some(BodyChange::Reset),
+            Err(broadcast::RecvError::Closed) => None,
+        }
+    }
+}
+
+/// The number of changes to a body which a `BodyCursor` can fall behind by
+/// before it misses some, and is told to reset instead.
+const BODY_CURSOR_BUFFER_SIZE: usize = 64;
+
+/// The number of attributes set on elements by `Content::set_attr` which are
+/// remembered for new clients, unless changed by `Content::set_attr_cap`.
+const DEFAULT_ATTR_CAP: usize = 256;
+
+/// The number of line ranges of static content cached by default (see
+/// `Content::set_render_cache_size`).
+const DEFAULT_RENDER_CACHE_SIZE: usize = 16;
+
+/// The number of bytes of appended text which may be held back until the next
+/// heartbeat (see `Content::set_append_flush_on_heartbeat`) before it's all sent
+/// anyway.
+const APPEND_BUFFER_CAP: usize = 64 * 1024;
+
+/// The shortest coalescing window chosen by `Content::enable_adaptive_rate`,
+/// short of turning coalescing off.
+const MIN_ADAPTIVE_WINDOW: Duration = Duration::from_millis(10);
+
+/// The longest coalescing window chosen by `Content::enable_adaptive_rate`.
+const MAX_ADAPTIVE_WINDOW: Duration = Duration::from_secs(1);
+
+/// How long the lag reported by a client counts towards adapting the
+/// coalescing window (see `Content::enable_adaptive_rate`).
+const LAG_REPORT_LIFETIME: Duration = Duration::from_secs(15);
+
 /// The maximum number of messages to buffer before blocking a send. This means
 /// a client can send a burst of up to this many "frames" of HTML before it
 /// experiences backpressure.
@@ -39,71 +356,607 @@ const UPDATE_BUFFER_SIZE: usize = 1;
 impl Content {
     /// Make a new empty (dynamic) page
     pub async fn new() -> Content {
+        Content::with_state(DynamicState::default()).await
+    }
+
+    /// Make a new dynamic page with the given state, a brand-new update server,
+    /// and no clients.
+    async fn with_state(state: DynamicState) -> Content {
+        let updates = Arc::new(sse::BufferedServer::new(UPDATE_BUFFER_SIZE).await);
+        let log = Arc::new(ServerLog::default());
+        let backpressure_log = log.clone();
+        updates.set_backpressure_callback(move |state| log_backpressure(&backpressure_log, state));
         Content::Dynamic {
-            title: String::new(),
-            body: String::new(),
-            updates: sse::BufferedServer::new(UPDATE_BUFFER_SIZE).await,
+            state,
+            confirmations: HashMap::new(),
+            body_changes: broadcast::channel(BODY_CURSOR_BUFFER_SIZE).0,
+            body_watchers: Vec::new(),
+            updates,
+            mailbox: None,
+            coalescer: None,
+            append_buffer: None,
+            adaptive_rate: None,
+            heartbeat_throttle: true,
+            log,
+            backend: None,
+            closed: false,
+            refreshed: None,
+            modified: Instant::now(),
+        }
+    }
+
+    /// Make the content served by default in place of pages with nothing on
+    /// them, when the server has a not-found page: a dynamic page saying
+    /// there's nothing there, which can be changed like any other.
+    pub async fn not_found_default() -> Content {
+        let mut content = Content::new().await;
+        if let Content::Dynamic{state: DynamicState{title, body, ..}, ..} = &mut content {
+            *title = "Not found".to_string();
+            *body = "<h1>Not found</h1>\n<p>There's nothing at this path yet.</p>".to_string();
+        }
+        content
+    }
+
+    /// Make an independent copy of this content, with the same `DynamicState`
+    /// (everything its clients see, and its settings) or static contents, but
+    /// none of the clients: a dynamic fork gets a brand-new update server, so
+    /// later updates to either copy don't affect the other. A fork keeps the
+    /// coalescing window, adaptive rate target (though no reported lag), append
+    /// buffering (though nothing buffered), and mailbox capacity of the
+    /// original, but not its pending confirmations or body cursors and
+    /// watchers. It also drops the original's heartbeat throttling setting and
+    /// broadcast backend, going back to the defaults of throttling heartbeats
+    /// and broadcasting only to its own clients, and doesn't stream its server
+    /// log until that's enabled again.
+    pub async fn fork(&self) -> Content {
+        match self {
+            Content::Dynamic{state, mailbox, coalescer, append_buffer, adaptive_rate, ..} => {
+                let mut fork = Content::with_state(state.clone()).await;
+                if let Content::Dynamic{mailbox: new_mailbox, coalescer: new_coalescer,
+                                        append_buffer: new_append_buffer,
+                                        adaptive_rate: new_adaptive_rate, updates, ..} = &mut fork {
+                    *new_mailbox = mailbox.as_ref()
+                        .map(|mailbox| sse::Mailbox::new(updates.clone(), mailbox.capacity()));
+                    *new_coalescer = coalescer.as_ref()
+                        .map(|coalescer| sse::Coalescer::new(updates.clone(), coalescer.window()));
+                    *new_append_buffer = append_buffer.as_ref().map(|_| AppendBuffer::default());
+                    *new_adaptive_rate = adaptive_rate.as_ref()
+                        .map(|adaptive| AdaptiveRate::new(adaptive.target_lag));
+                }
+                fork
+            },
+            Content::Static{content_type, raw_contents, hash, integrity, render_cache, namespace,
+                            version, ..} => Content::Static {
+                content_type: content_type.clone(),
+                raw_contents: raw_contents.clone(),
+                hash: hash.clone(),
+                integrity: integrity.clone(),
+                render_cache: RenderCache::new(render_cache.capacity),
+                fetches: 0,
+                fetched: None,
+                refreshed: None,
+                modified: Instant::now(),
+                namespace: namespace.clone(),
+                version: *version,
+            },
+        }
+    }
+
+    /// Take a snapshot of the lasting state of this content, which can be
+    /// serialized and turned back into content by `restore`.
+    pub fn snapshot(&self) -> Snapshot {
+        match self {
+            // Every field is named, so that new state has to be either
+            // snapshotted or deliberately left out.
+            Content::Dynamic{state: DynamicState{title, title_template, body, lang_bodies,
+                                                 description, og, query, root_attrs, validation,
+                                                 attrs, streams, parent_messages, persistent,
+                                                 steps, namespace, version, validator: _,
+                                                 attr_cap: _, max_replay_state: _,
+                                                 timed_events: _, compression_threshold: _},
+                             ..} =>
+                Snapshot::Dynamic(DynamicSnapshot {
+                    title: title.clone(),
+                    title_template: title_template.as_ref().map(|template| TitleTemplateSnapshot {
+                        template: template.template.clone(),
+                        slots: template.slots.clone(),
+                    }),
+                    body: body.clone(),
+                    lang_bodies: lang_bodies.clone(),
+                    description: description.clone(),
+                    og: og.clone(),
+                    query: query.clone(),
+                    root_attrs: root_attrs.iter()
+                        .map(|((element, name), value)| RootAttrSnapshot {
+                            element: element.as_str().to_string(),
+                            name: name.clone(),
+                            value: value.clone(),
+                        })
+                        .collect(),
+                    validation: validation.clone(),
+                    attrs: attrs.iter()
+                        .map(|((selector, name), value)| AttrSnapshot {
+                            selector: selector.clone(),
+                            name: name.clone(),
+                            value: value.clone(),
+                        })
+                        .collect(),
+                    streams: streams.clone(),
+                    parent_messages: parent_messages.iter()
+                        .map(|(channel, message)| (channel.clone(), ParentMessageSnapshot {
+                            data: message.data.clone(),
+                            origin: message.origin.clone(),
+                        }))
+                        .collect(),
+                    persistent: persistent.clone(),
+                    steps: steps.iter()
+                        .map(|(name, status)| StepSnapshot {
+                            name: name.clone(),
+                            status: status.as_str().to_string(),
+                            error: status.error().map(str::to_string),
+                        })
+                        .collect(),
+                    namespace: namespace.clone(),
+                    version: *version,
+                }),
+            Content::Static{content_type, raw_contents, namespace, version, ..} =>
+                Snapshot::Static(StaticSnapshot {
+                    content_type: content_type.clone(),
+                    contents: base64::encode(raw_contents),
+                    namespace: namespace.clone(),
+                    version: *version,
+                }),
+        }
+    }
+
+    /// Turn a snapshot taken by `snapshot` back into content, with a brand-new
+    /// update server and no clients. Returns an error if the snapshot is
+    /// invalid, like one with a root attribute on an element other than `html`
+    /// or `body`.
+    pub async fn restore(snapshot: Snapshot) -> Result<Content, String> {
+        match snapshot {
+            Snapshot::Dynamic(snapshot) => {
+                let mut restored_root_attrs = BTreeMap::new();
+                for attr in snapshot.root_attrs {
+                    validate_attr_name(&attr.name)?;
+                    let element = RootElement::try_from(attr.element.as_str())?;
+                    restored_root_attrs.insert((element, attr.name), attr.value);
+                }
+                let mut restored_attrs = BTreeMap::new();
+                for attr in snapshot.attrs {
+                    validate_attr_name(&attr.name)?;
+                    restored_attrs.insert((attr.selector, attr.name), attr.value);
+                }
+                if let Some(query) = &snapshot.query {
+                    validate_query(query)?;
+                }
+                for message in snapshot.parent_messages.values() {
+                    validate_origin(&message.origin)?;
+                }
+                for key in snapshot.persistent.keys() {
+                    validate_persistent_key(key)?;
+                }
+                let mut restored_steps = Vec::new();
+                for step in snapshot.steps {
+                    restored_steps.push((step.name, StepStatus::parse(&step.status, step.error)?));
+                }
+                let state = DynamicState {
+                    title: snapshot.title,
+                    title_template: snapshot.title_template.map(|template| TitleTemplate {
+                        template: template.template,
+                        slots: template.slots,
+                    }),
+                    body: snapshot.body,
+                    lang_bodies: snapshot.lang_bodies,
+                    description: snapshot.description,
+                    og: snapshot.og,
+                    query: snapshot.query,
+                    root_attrs: restored_root_attrs,
+                    validation: snapshot.validation,
+                    validator: Validator::default(),
+                    streams: snapshot.streams,
+                    parent_messages: snapshot.parent_messages.into_iter()
+                        .map(|(channel, message)| (channel, ParentMessage {
+                            data: message.data,
+                            origin: message.origin,
+                        }))
+                        .collect(),
+                    persistent: snapshot.persistent,
+                    steps: restored_steps,
+                    attrs: restored_attrs,
+                    attr_cap: DEFAULT_ATTR_CAP,
+                    max_replay_state: None,
+                    timed_events: Vec::new(),
+                    compression_threshold: None,
+                    namespace: snapshot.namespace,
+                    version: snapshot.version,
+                };
+                Ok(Content::with_state(state).await)
+            },
+            Snapshot::Static(snapshot) => {
+                let raw_contents = base64::decode(&snapshot.contents)
+                    .map_err(|err| format!("Invalid base64 in static contents: {}", err))?;
+                Ok(Content::Static {
+                    content_type: snapshot.content_type,
+                    hash: hash_contents(&raw_contents),
+                    integrity: integrity::integrity(&raw_contents),
+                    raw_contents,
+                    render_cache: RenderCache::new(DEFAULT_RENDER_CACHE_SIZE),
+                    fetches: 0,
+                    fetched: None,
+                    refreshed: None,
+                    modified: Instant::now(),
+                    namespace: snapshot.namespace,
+                    version: snapshot.version,
+                })
+            },
         }
     }
 
+    /// Add a client to a dynamic page like `update_stream`, but instead of
+    /// returning its own stream, forward everything it's sent into a sink
+    /// shared with other pages, tagged with the given path (see
+    /// `multiplex::Sink`). Returns `None` if the page is static or closed.
+    pub async fn update_stream_into(&mut self,
+                                    options: sse::ClientOptions,
+                                    sink: &multiplex::Sink,
+                                    path: &str) -> Option<(sse::ClientId, multiplex::Forwarding)> {
+        let (id, body) = self.update_stream(options, None, None).await?;
+        Some((id, multiplex::forward(sink, path, body)))
+    }
+
     /// Test if this page is empty, where "empty" means that it is dynamic, with
     /// an empty title, empty body, and no subscribers waiting on its page
     /// events: that is, it's identical to `Content::new()`.
     pub async fn is_empty(&mut self) -> bool {
         match self {
-            Content::Dynamic{title, body, ref mut updates}
-            if title == "" && body == "" => updates.connections().await == 0,
+            Content::Dynamic{state: DynamicState{title, body, lang_bodies, ..}, ref mut updates, ..}
+            if title.is_empty() && body.is_empty() && lang_bodies.is_empty() =>
+                updates.connections().await == 0,
             _ => false,
         }
     }
 
+    /// Get the server streaming updates to the clients of a dynamic page, for
+    /// using its lower-level methods directly, or `None` if the page is static.
+    /// This only needs `&self`, and lends the server by shared reference: the
+    /// server is behind an `Arc` shared with whatever else sends on behalf of
+    /// the page (like its coalescer and server log), so it can't be lent mutably,
+    /// and every one of its methods only takes `&self` anyway, since they all
+    /// just send commands to the task serving its clients. `Page` uses this to
+    /// tell clients which events to report.
+    ///
+    /// This is an escape hatch, and a footgun: frames sent straight to the
+    /// server bypass the state kept here, so clients can be told things that
+    /// clients connecting later won't be, and the next update from `Content`
+    /// may not mean what clients expect (for example, a `body-patch` event
+    /// against a body they weren't sent this way).
+    pub fn update_server(&self) -> Option<&sse::BufferedServer> {
+        match self {
+            Content::Dynamic{updates, ..} => Some(updates),
+            Content::Static{..} => None,
+        }
+    }
+
     /// Add a client to the dynamic content of a page, if it is dynamic. If it
     /// is static, this has no effect and returns None. Otherwise, returns the
-    /// Body stream to give to the new client.
-    pub async fn update_stream(&mut self) -> Option<Body> {
+    /// id of the new client and the Body stream to give to it.
+    ///
+    /// If the client is `protected`, it is a read-only follower: it can't be
+    /// kicked by `disconnect_client`, and it is exempt from all per-client
+    /// load-shedding, meaning it receives every frame sent to the page. The
+    /// trade-off is that a slow follower is never shed to relieve backpressure,
+    /// so it holds up the page's broadcasts for as long as it takes to read
+    /// them.
+    ///
+    /// If the client has a `filter`, it only receives those custom events (see
+    /// `send_event`) whose data is JSON matching the filter. Custom events whose
+    /// data isn't JSON, and all the events which keep the page itself up to
+    /// date (title, body, etc.), bypass the filter.
+    ///
+    /// If the client joins a `room`, it also receives the events sent to that
+    /// room by `send_to_room`. A client can be in at most one room.
+    ///
+    /// If the client already holds a `baseline` body (say, from before it was
+    /// disconnected), it can give its hash to save being sent the whole body
+    /// again. If the hash matches the current body, the body is left out of
+    /// the replay; if not, it's replaced by a `baseline-mismatch` event, to
+    /// which the client should respond by sending its baseline, so that it can
+    /// be sent only the difference (see `send_body_diff`).
+    ///
+    /// If the client gives an `Accept-Language` header, and the page has a
+    /// body in one of the languages it accepts (see `set_body_lang`), it's
+    /// shown that body, and sent only that body's updates, rather than the
+    /// default body's. The client's language is chosen when it connects.
+    ///
+    /// The client is greeted, in one frame, by a `ready` event listing the
+    /// page's capabilities (see `capabilities`), then its `client-id`, then
+    /// the replay of the page's state, and finally a `connected` event, after
+    /// which it gets live updates like every other client.
+    pub async fn update_stream(&mut self,
+                               mut options: sse::ClientOptions,
+                               baseline: Option<&str>,
+                               accept_language: Option<&str>) -> Option<(sse::ClientId, Body)> {
+        // The replay includes everything appended so far, so existing clients
+        // must be sent what's buffered before the new client joins them, and
+        // the bodies in the mailbox are older than the one in the replay, so
+        // the new client mustn't be sent them afterwards
+        self.flush_appends().await;
+        self.flush_mailbox().await;
+        let mut replay = self.replay_events();
+        let ready = ready_event_data(&self.capabilities());
+        if let Content::Dynamic{state: DynamicState{body, lang_bodies, version,
+                                compression_threshold, ..}, ..} = &*self {
+            options.lang = accept_language.and_then(|accept| negotiate_lang(lang_bodies, accept));
+            let body = match &options.lang {
+                Some(lang) => &lang_bodies[lang],
+                None => body,
+            };
+            let is_body = |event: &Event|
+                matches!(event.event_type.as_str(), "body" | "body:gzip" | "clear-body");
+            if let Some(index) = replay.iter().position(is_body) {
+                match baseline {
+                    Some(baseline) if baseline == hash_contents(body.as_bytes()) => {
+                        replay.remove(index);
+                    },
+                    // Patches are only computed from the default body
+                    Some(_) if options.lang.is_none() =>
+                        replay[index] = Event::new("baseline-mismatch", "."),
+                    _ => replay[index] = body_event(body, *version, *compression_threshold),
+                }
+            }
+        }
         match self {
-            Content::Dynamic{updates, title, body} => {
+            Content::Dynamic{closed: true, ..} => None,
+            Content::Dynamic{updates, log, ..} => {
                 let (channel, stream_body) = Body::channel();
-                let title_event = if *title != "" {
-                    EventBuilder::new(&title).event_type("title")
-                } else {
-                    EventBuilder::new(".").event_type("clear-title")
-                }.build();
-                let body_event = if *body != "" {
-                    EventBuilder::new(&body).event_type("body")
-                } else {
-                    EventBuilder::new(".").event_type("clear-body")
-                }.build();
-                updates.add_client(channel).await;
-                // We're ignoring these futures because we don't care what
-                // number of clients there are
-                let _unused = updates.send_to_clients(title_event).await;
-                let _unused = updates.send_to_clients(body_event).await;
-                Some(stream_body)
+                let protected = options.protected;
+                // Greet the new client with what the page can do and its id, so
+                // it can report its own state, followed by everything it needs
+                // to catch up, all in one frame sent only to it
+                let id = updates.add_client_with_greeting(channel, options, |id| {
+                    let mut greeting = Event::new("ready", ready).build();
+                    greeting.push_str(&Event::new("client-id", id.to_string()).build());
+                    for event in replay {
+                        greeting.push_str(&event.build());
+                    }
+                    greeting.push_str(&Event::new("connected", ".").build());
+                    greeting.into()
+                }).await;
+                tracing::info!(parent: log.span(), client = id, protected, "Client connected");
+                Some((id, stream_body))
             },
             Content::Static{..} => None
         }
     }
 
+    /// List the optional features which this page currently has turned on, as
+    /// advertised to each new client in its `ready` event, so a client can
+    /// adapt to what it will be sent: `gzip` if large bodies are compressed
+    /// (see `set_event_compression_threshold`), `coalesce` if updates are
+    /// coalesced (see `set_coalesce_window`), `mailbox` if body updates go
+    /// through a mailbox, `lang` if there are bodies in particular languages,
+    /// `validate` if batches have rules to follow (see `set_validator`),
+    /// `broadcast` if updates are broadcast to other processes, and
+    /// `adaptive-rate` if clients should report how far behind they are in
+    /// rendering (see `enable_adaptive_rate`). Static pages have no clients, so
+    /// no features.
+    pub fn capabilities(&self) -> Vec<&'static str> {
+        let mut capabilities = Vec::new();
+        if let Content::Dynamic{state: DynamicState{compression_threshold, lang_bodies,
+                                validator, ..}, coalescer, mailbox, backend,
+                                adaptive_rate, ..} = self {
+            let features = [
+                ("gzip", compression_threshold.is_some()),
+                ("coalesce", coalescer.is_some()),
+                ("mailbox", mailbox.is_some()),
+                ("lang", !lang_bodies.is_empty()),
+                ("validate", *validator != Validator::default()),
+                ("broadcast", backend.is_some()),
+                ("adaptive-rate", adaptive_rate.is_some()),
+            ];
+            capabilities.extend(features.iter().filter(|(_, on)| *on).map(|(name, _)| *name));
+        }
+        capabilities
+    }
+
+    /// Get every `(event_type, data)` pair which a newly subscribed client
+    /// receives to bring it up to date with the current state of the page, in
+    /// the order they are sent. This is exactly what `update_stream` sends
+    /// between telling the client its id and that it's connected (if the
+    /// client gives no baseline or language), but it doesn't add a client or
+    /// change anything. Static pages have no
+    /// clients to bring up to date, so for them this is empty.
+    pub fn replay_snapshot(&self) -> Vec<(String, String)> {
+        self.replay_events().into_iter()
+            .map(|event| (event.event_type, event.data))
+            .collect()
+    }
+
+    /// Get every event type which currently has a stored value that's replayed
+    /// to new clients, with its current payload, in the order they're replayed:
+    /// this is the durable state of the page, leaving out one-shot events. It's
+    /// what `replay_snapshot` gives, except that the body is always given as it
+    /// is, even if it's sent compressed. Static pages have no such state, so for
+    /// them this is empty.
+    pub fn stored_events(&self) -> Vec<(String, String)> {
+        let body = match self {
+            Content::Dynamic{state: DynamicState{body, ..}, ..} => body,
+            Content::Static{..} => return Vec::new(),
+        };
+        self.replay_snapshot().into_iter()
+            .map(|(event_type, data)| match event_type.as_str() {
+                "body:gzip" => ("body".to_string(), body.clone()),
+                _ => (event_type, data),
+            })
+            .collect()
+    }
+
+    /// Build the events which bring a new client up to date with the current
+    /// state of the page.
+    fn replay_events(&self) -> Vec<Event> {
+        match self {
+            Content::Dynamic{state: DynamicState{title, title_template, body, description, og,
+                             query, root_attrs, validation, attrs, streams, parent_messages,
+                             persistent, steps, timed_events, version,
+                             compression_threshold, ..}, ..} => {
+                let mut events = vec![
+                    match title_template {
+                        Some(template) =>
+                            Event::new("title-template", template.event_data()).id(*version),
+                        None => title_event(title, *version),
+                    },
+                    body_event(body, *version, *compression_threshold),
+                ];
+                if !description.is_empty() || !og.is_empty() {
+                    events.push(Event::new("seo", seo_event_data(title, description, og))
+                                .id(*version));
+                }
+                if let Some(query) = query {
+                    // New clients should sync their URL without adding to
+                    // their history
+                    events.push(Event::new("query", query_event_data(query, true)));
+                }
+                if !root_attrs.is_empty() {
+                    events.push(Event::new("root-attrs", root_attrs_event_data(root_attrs)));
+                }
+                if !validation.is_empty() {
+                    events.push(Event::new("validation", validation_event_data(validation)));
+                }
+                events.extend(attrs.iter().map(|((selector, name), value)| {
+                    Event::new("set-attr", attr_event_data(selector, name, value.as_deref()))
+                }));
+                // Late joiners get everything streamed so far in one go
+                events.extend(streams.iter().map(|(selector, text)| {
+                    Event::new("append-to", append_to_event_data(selector, text))
+                }));
+                events.extend(parent_messages.iter().map(|(channel, message)| {
+                    Event::new("parent-message", message.event_data(channel))
+                }));
+                // These override whatever the client remembered from before
+                events.extend(persistent.iter().map(|(key, data)| {
+                    Event::new("persistent", persistent_event_data(key, data))
+                }));
+                events.extend(steps.iter().map(|(name, status)| {
+                    Event::new("step", step_event_data(name, status))
+                }));
+                // Timed events are sent with whatever's left of their TTL
+                let now = Instant::now();
+                events.extend(timed_events.iter().filter_map(|timed| timed.event(now)));
+                events
+            },
+            Content::Static{..} => vec![],
+        }
+    }
+
+    /// Forward every change to the title or body of a dynamic page, and every
+    /// custom event sent to all its clients, to the given backend, so that
+    /// other processes serving the same page can apply them for their own
+    /// clients (see `apply_broadcast`). With `None`, stop forwarding. The
+    /// backend is dropped if the page becomes static, and this has no effect
+    /// if the page is (currently) static.
+    pub fn set_broadcast_backend(&mut self, new_backend: Option<Arc<dyn BroadcastBackend>>) {
+        if let Content::Dynamic{backend, ..} = self {
+            *backend = new_backend;
+        }
+    }
+
+    /// Apply an update broadcast by another process serving the same page, as
+    /// if it had been made locally, except that it isn't forwarded back to the
+    /// broadcast backend (which would loop forever). Updates which this
+    /// process broadcast itself are ignored, since they were already applied.
+    pub async fn apply_broadcast(&mut self, broadcast: Broadcast) {
+        if broadcast.origin == pubsub::origin() {
+            return
+        }
+        let backend = match self {
+            Content::Dynamic{backend, ..} => backend.take(),
+            Content::Static{..} => None,
+        };
+        match broadcast.update {
+            Update::Title{title} => self.set_title(title).await,
+            Update::Body{body} => self.set_body(body).await,
+            Update::CleanBody{body} => self.set_body_to_clean(body).await,
+            Update::AppendBody{text} => self.append_body(&text).await,
+            Update::Event{event_type, data} =>
+                // The origin already checked the event type, and an invalid
+                // one is logged here anyway
+                self.send_event(&event_type, &data).await.unwrap_or(()),
+        }
+        if let Content::Dynamic{backend: restored, ..} = self {
+            *restored = backend;
+        }
+    }
+
+    /// Disconnect a single client of a dynamic page. Returns `false` if the page
+    /// is static, if there's no such client, or if the client is a protected
+    /// follower (which can't be disconnected).
+    pub async fn disconnect_client(&mut self, id: sse::ClientId) -> bool {
+        match self {
+            Content::Dynamic{updates, log, ..} => {
+                let disconnected = updates.disconnect_client(id).await;
+                if disconnected {
+                    tracing::info!(parent: log.span(), client = id, "Client disconnected");
+                }
+                disconnected
+            },
+            Content::Static{..} => false,
+        }
+    }
+
+    /// Count the clients getting live updates to a page, including protected
+    /// followers. Static pages have no such clients.
+    pub async fn client_count(&mut self) -> usize {
+        match self {
+            Content::Dynamic{updates, ..} => updates.connections().await,
+            Content::Static{..} => 0,
+        }
+    }
+
+    /// Count only the protected followers getting live updates to a page.
+    pub async fn protected_client_count(&mut self) -> usize {
+        match self {
+            Content::Dynamic{updates, ..} => updates.protected_connections().await,
+            Content::Static{..} => 0,
+        }
+    }
+
     /// Send an empty "heartbeat" message to all clients of a page, if it is
-    /// dynamic. This has no effect if it is (currently) static, and returns
-    /// `None` if so, otherwise returns the current number of clients getting
-    /// live updates to the page.
-    pub async fn send_heartbeat(&mut self) -> Option<usize> {
+    /// dynamic (after any appends held back until now; see
+    /// `set_append_flush_on_heartbeat`), unless every client was sent an update
+    /// within the `quiet` period (and heartbeats are throttled, as they are by
+    /// default; see `set_heartbeat_throttle`), which kept their connections
+    /// alive already. This has no effect if it is (currently) static, and
+    /// returns `None` if so, otherwise returns the current number of clients
+    /// getting live updates to the page.
+    pub async fn send_heartbeat(&mut self, quiet: Duration) -> Option<usize> {
+        self.flush_appends().await;
         match self {
-            Content::Dynamic{updates, ..} => {
+            Content::Dynamic{updates, heartbeat_throttle, ..} => {
                 // Send a heartbeat to pages waiting on <body> updates
-                Some(updates.send_heartbeat().await.await)
+                let quiet = if *heartbeat_throttle { Some(quiet) } else { None };
+                Some(updates.send_heartbeat(quiet).await.await)
             },
             Content::Static{..} => None,
         }
     }
 
+    /// Choose whether to skip heartbeats while updates are being sent to every
+    /// client anyway (see `send_heartbeat`). How many were skipped is reported
+    /// by `metrics`. This has no effect if the page is (currently)
+    /// static.
+    pub fn set_heartbeat_throttle(&mut self, throttle: bool) {
+        if let Content::Dynamic{heartbeat_throttle, ..} = self {
+            *heartbeat_throttle = throttle;
+        }
+    }
+
     /// Tell all clients to refresh the contents of a page, if it is dynamic.
     /// This has no effect if it is (currently) static.
     pub async fn refresh(&mut self) {
         match self {
-            Content::Dynamic{updates, ..} => {
+            Content::Dynamic{updates, refreshed, ..} => {
+                *refreshed = Some(Instant::now());
                 let event = EventBuilder::new(".").event_type("refresh").build();
                 // We're ignoring this future because we don't care what number
                 // of clients there are
@@ -113,84 +966,3156 @@ impl Content {
         }
     }
 
-    /// Set the contents of the page to be a static raw set of bytes with no
-    /// self-refreshing functionality. All clients will be told to refresh their
-    /// page to load the new static content (which will not be able to update
-    /// itself until a client refreshes their page again).
-    pub async fn set_static(&mut self,
-                            content_type: Option<String>,
-                            raw_contents: impl Into<Vec<u8>>) {
-        let mut page =
-            Content::Static{content_type, raw_contents: raw_contents.into()};
-        mem::swap(&mut page, self);
-        page.refresh().await;
+    /// Tell all clients to pass a message on to the window which embeds them in
+    /// a frame (or, failing that, the window which opened them), through
+    /// `postMessage`, as `{"myxine": channel, "data": data}`. The message is
+    /// only delivered if that window's origin matches `origin`, which should be
+    /// the host page's origin (like `https://example.com`), or `*` to deliver
+    /// it to any origin. A persistent message is remembered, replacing the last
+    /// one on its channel, and passed on again by every client connecting
+    /// later (useful for state like a requested frame size); otherwise, the
+    /// message is one-shot, and also forgets any persistent message on its
+    /// channel. This has no effect if the page is (currently) static, and
+    /// returns an error if the channel is empty or the origin isn't valid.
+    pub async fn send_to_parent(&mut self,
+                                channel: &str,
+                                data: &str,
+                                origin: &str,
+                                persistent: bool) -> Result<(), String> {
+        if channel.is_empty() {
+            return Err("Empty channel for message to parent window".to_string())
+        }
+        if let Err(err) = validate_origin(origin) {
+            self.log(LogLevel::Warn, &err);
+            return Err(err)
+        }
+        if let Content::Dynamic{state: DynamicState{parent_messages, ..}, updates, ..} = self {
+            let message = ParentMessage{data: data.to_string(), origin: origin.to_string()};
+            let event = Event::new("parent-message", message.event_data(channel)).build();
+            if persistent {
+                parent_messages.insert(channel.to_string(), message);
+            } else {
+                parent_messages.remove(channel);
+            }
+            // We're ignoring this future because we don't care how many
+            // clients there are
+            let _unused = updates.send_to_clients(event).await;
+        }
+        Ok(())
     }
 
-    /// Get the content type of a page, or return `None` if none has been set
-    /// (as in the case of a dynamic page, where the content type is not
-    /// client-configurable).
-    pub fn content_type(&self) -> Option<String> {
-        match self {
-            Content::Dynamic{..} => None,
-            Content::Static{content_type, ..} => content_type.clone(),
+    /// Tell all clients to remember a value under the given key in their
+    /// browser's `localStorage`, and to apply it as the `data-myxine-<key>`
+    /// attribute of their `html` element (where a stylesheet can pick it up, as
+    /// with `[data-myxine-theme="dark"]`) and with a `myxine-persistent` event.
+    /// A client loading the page again applies its remembered values straight
+    /// away, before it has even connected, so stateful UI like a chosen theme
+    /// doesn't flash back to its default. The server remembers the value too,
+    /// and always wins: every client connecting is sent the server's values,
+    /// replacing its own, and forgets any remembered values the server no
+    /// longer has (as after a restart without a state file). This has no
+    /// effect if the page is (currently) static, and returns an error if the
+    /// key isn't usable in an attribute name.
+    pub async fn send_persistent(&mut self, key: &str, data: &str) -> Result<(), String> {
+        if let Err(err) = validate_persistent_key(key) {
+            self.log(LogLevel::Warn, &err);
+            return Err(err)
+        }
+        if let Content::Dynamic{state: DynamicState{persistent, ..}, updates, ..} = self {
+            let event = Event::new("persistent", persistent_event_data(key, data)).build();
+            persistent.insert(key.to_string(), data.to_string());
+            // We're ignoring this future because we don't care how many
+            // clients there are
+            let _unused = updates.send_to_clients(event).await;
         }
+        Ok(())
     }
 
-    /// Tell all clients to change the title, if necessary. This converts the
-    /// page into a dynamic page, overwriting any static content that previously
-    /// existed, if any.
-    pub async fn set_title(&mut self, new_title: impl Into<String>) {
-        loop {
-            match self {
-                Content::Dynamic{ref mut title, ref mut updates, ..} => {
-                    let new_title = new_title.into();
-                    if new_title != *title {
-                        *title = new_title.clone();
-                        let event = if *title != "" {
-                            EventBuilder::new(&new_title).event_type("title")
-                        } else {
-                            EventBuilder::new(".").event_type("clear-title")
-                        };
-                        // We're ignoring this future because we don't care how
-                        // many clients there are
-                        let _unused = updates.send_to_clients(event.build()).await;
-                    }
-                    break; // title has been set
-                },
-                Content::Static{..} => {
-                    *self = Content::new().await;
-                    // and loop again to actually set the title
+    /// Send a one-shot custom event with the given type and data to all clients
+    /// of a page, if it is dynamic. If the data is JSON, clients with a filter
+    /// only receive the event if the data matches it; otherwise, the event goes
+    /// to every client. This has no effect if the page is (currently) static,
+    /// and returns an error, without sending anything, if the event type is
+    /// empty or contains a line break (which would corrupt the event stream).
+    pub async fn send_event(&mut self, event_type: &str, data: &str) -> Result<(), String> {
+        if let Err(err) = validate_event_type(event_type) {
+            self.log(LogLevel::Warn, &err);
+            return Err(err)
+        }
+        match self {
+            Content::Dynamic{updates, backend, ..} => {
+                let event = EventBuilder::new(data).event_type(event_type).build();
+                // We're ignoring these futures because we don't care what
+                // number of clients there are
+                match serde_json::from_str(data) {
+                    Ok(json) => {
+                        let _unused = updates.send_data_to_clients(event, json).await;
+                    },
+                    Err(_) => {
+                        let _unused = updates.send_to_clients(event).await;
+                    },
                 }
+                publish(backend, || Update::Event{
+                    event_type: event_type.to_string(),
+                    data: data.to_string(),
+                });
+            },
+            Content::Static{..} => { },
+        }
+        Ok(())
+    }
+
+    /// Send a custom event which only lasts for the given time to all clients of
+    /// a dynamic page, for transient states like a "Saved!" badge. It's sent as
+    /// a `timed-event` event, whose data is a JSON object giving the `type`,
+    /// `data`, and `ttl` (in milliseconds) of the event, and the page's script
+    /// sets a `data-myxine-<type>` attribute on the `<html>` element to the
+    /// data, removing it again once the TTL has elapsed, so the page reverts
+    /// without a second event. A client which connects before then is sent the
+    /// event with whatever is left of the TTL. Sending a timed event of the
+    /// same type again replaces it. Returns an error if the event type is
+    /// invalid.
+    pub async fn send_event_ttl(&mut self, event_type: &str, data: &str, ttl: Duration) -> Result<(), String> {
+        if let Err(err) = validate_event_type(event_type) {
+            self.log(LogLevel::Warn, &err);
+            return Err(err)
+        }
+        if let Content::Dynamic{state: DynamicState{timed_events, ..}, updates, ..} = self {
+            let now = Instant::now();
+            timed_events.retain(|timed| timed.expires > now && timed.event_type != event_type);
+            let timed = TimedEvent {
+                event_type: event_type.to_string(),
+                data: data.to_string(),
+                expires: now + ttl,
+            };
+            if let Some(event) = timed.event(now) {
+                timed_events.push(timed);
+                // We're ignoring this future because we don't care how many
+                // clients there are
+                let _unused = updates.send_to_clients(event.build()).await;
             }
         }
+        Ok(())
     }
 
-    /// Tell all clients to change the body, if necessary. This converts the
-    /// page into a dynamic page, overwriting any static content that previously
-    /// existed, if any.
-    pub async fn set_body(&mut self, new_body: impl Into<String>) {
-        loop {
-            match self {
-                Content::Dynamic{ref mut body, ref mut updates, ..} => {
-                    let new_body = new_body.into();
-                    if new_body != *body {
-                        *body = new_body.clone();
-                        let event = if *body != "" {
-                            EventBuilder::new(&new_body).event_type("body")
-                        } else {
-                            EventBuilder::new(".").event_type("clear-body")
-                        };
-                        // We're ignoring this future because we don't care how
-                        // many clients of the page there are
-                        let _unused = updates.send_to_clients(event.build()).await;
+    /// Send a one-shot custom event, like `send_event`, but only to those clients
+    /// of the page which joined the given room when they connected. Sending to
+    /// a room no client has joined does nothing.
+    pub async fn send_to_room(&mut self, room: &str, event_type: &str, data: &str) -> Result<(), String> {
+        if let Err(err) = validate_event_type(event_type) {
+            self.log(LogLevel::Warn, &err);
+            return Err(err)
+        }
+        if let Content::Dynamic{updates, ..} = self {
+            let event = EventBuilder::new(data).event_type(event_type).build();
+            // We're ignoring this future because we don't care what number of
+            // clients there are
+            let _unused = updates.send_to_room(room, event, serde_json::from_str(data).ok()).await;
+        }
+        Ok(())
+    }
+
+    /// Send a custom event built (and checked) by an `EventMessage` builder to
+    /// the clients of a dynamic page: to all of them, or only those in its
+    /// room, and if it has a TTL, as a timed event (see `send_event_ttl`)
+    /// which clients connecting before it expires are sent too. If the data is
+    /// JSON, clients with a filter only receive the event if it matches. Only
+    /// events to every client without a TTL are published to the broadcast
+    /// backend, as by `send_event`. This has no effect if the page is
+    /// (currently) static.
+    pub async fn send_message(&mut self, message: EventMessage) {
+        if let Content::Dynamic{state: DynamicState{timed_events, ..}, updates,
+                                backend, ..} = self {
+            let EventMessage{event_type, data, id, retry, ttl, room} = message;
+            let json: Option<serde_json::Value> = serde_json::from_str(&data).ok();
+            let event = match ttl {
+                Some(ttl) => {
+                    let now = Instant::now();
+                    timed_events.retain(|timed| timed.expires > now && timed.event_type != event_type);
+                    let timed = TimedEvent{event_type, data, expires: now + ttl};
+                    match timed.event(now) {
+                        Some(event) => {
+                            timed_events.push(timed);
+                            event
+                        },
+                        None => return,
                     }
-                    break; // body has been set
                 },
-                Content::Static{..} => {
-                    *self = Content::new().await;
-                    // and loop again to actually set the body
-                }
+                None => {
+                    if room.is_none() {
+                        publish(backend, || Update::Event{
+                            event_type: event_type.clone(),
+                            data: data.clone(),
+                        });
+                    }
+                    Event::new(event_type, data)
+                },
+            };
+            let event = match id {
+                Some(id) => event.id(id),
+                None => event,
+            };
+            let mut frame = event.build();
+            if let Some(retry) = retry {
+                frame.insert_str(0, &format!("retry: {}\n", retry.as_millis()));
+            }
+            // We're ignoring these futures because we don't care what number
+            // of clients there are
+            match (room, json) {
+                (Some(room), json) => {
+                    let _unused = updates.send_to_room(&room, frame, json).await;
+                },
+                (None, Some(json)) => {
+                    let _unused = updates.send_data_to_clients(frame, json).await;
+                },
+                (None, None) => {
+                    let _unused = updates.send_to_clients(frame).await;
+                },
             }
         }
     }
+
+    /// Tell all clients to get the attention of whoever's looking at the page,
+    /// by vibrating their device or playing a sound. This is sent as a one-shot
+    /// `alert` event, which isn't replayed to clients connecting later. Clients
+    /// which can't vibrate just don't, and since browsers only play sounds on
+    /// pages the user has interacted with, a sound may be silently blocked.
+    /// This has no effect if the page is (currently) static.
+    pub async fn send_alert(&mut self, alert: AlertKind) {
+        if let Content::Dynamic{updates, ..} = self {
+            let data = serde_json::to_string(&alert)
+                .expect("Serializing alert to JSON shouldn't fail");
+            let event = EventBuilder::new(&data).event_type("alert").build();
+            // We're ignoring this future because we don't care what number of
+            // clients there are
+            let _unused = updates.send_to_clients(event).await;
+        }
+    }
+
+    /// Tell all clients to move their subscription to this page's updates to
+    /// another URL, without reloading, as when the update endpoint is being
+    /// migrated to another server. The URL is the page's URL there (a path,
+    /// or an absolute `http` or `https` URL), to which clients add `?updates`
+    /// as usual; they catch up from there as if they'd reconnected, and keep
+    /// reconnecting to the new URL from then on. A client which can't connect
+    /// to the new URL falls back to this page's own. This is sent as a one-shot
+    /// `reconnect-to` event, which isn't replayed to clients connecting later.
+    /// This has no effect if the page is (currently) static, and returns an
+    /// error, without sending anything, if the URL is invalid.
+    pub async fn send_reconnect_to(&mut self, url: &str) -> Result<(), String> {
+        validate_reconnect_url(url)?;
+        if let Content::Dynamic{updates, log, ..} = self {
+            tracing::info!(parent: log.span(), url, "Told clients to reconnect");
+            let event = EventBuilder::new(url).event_type("reconnect-to").build();
+            // We're ignoring this future because we don't care what number of
+            // clients there are
+            let _unused = updates.send_to_clients(event).await;
+        }
+        Ok(())
+    }
+
+    /// Ask the user to confirm something, by telling all clients to show a
+    /// dialog with the message and yes/no buttons. This returns a future (so
+    /// the page needn't stay locked while waiting) which resolves with the
+    /// answer, or with `ConfirmResult::Timeout` if nobody answers in time. If
+    /// several clients are connected, the first to answer decides, and the
+    /// dialog is then dismissed on all the others; a client connecting while
+    /// the question is pending isn't asked. If the page is (currently) static
+    /// there's nobody to ask, so the future resolves with a timeout at once.
+    pub async fn confirm(&mut self, message: &str, timeout: Duration) -> impl Future<Output = ConfirmResult> {
+        let mut waiting = None;
+        if let Content::Dynamic{updates, confirmations, ..} = self {
+            // Forget the questions whose askers have stopped waiting
+            confirmations.retain(|_, answer| !answer.is_closed());
+            let token = Uuid::new_v4().to_simple().to_string();
+            let (answer, answered) = oneshot::channel();
+            confirmations.insert(token.clone(), answer);
+            let data = serde_json::to_string(&serde_json::json!({
+                "token": token,
+                "message": message,
+            })).expect("Serializing confirmation to JSON shouldn't fail");
+            let event = EventBuilder::new(&data).event_type("confirm").build();
+            // We're ignoring this future because we don't care how many
+            // clients there are
+            let _unused = updates.send_to_clients(event).await;
+            waiting = Some((token, answered, updates.clone()));
+        }
+        async move {
+            let (token, answered, updates) = match waiting {
+                Some(waiting) => waiting,
+                None => return ConfirmResult::Timeout,
+            };
+            let result = match time::timeout(timeout, answered).await {
+                Ok(Ok(true)) => ConfirmResult::Yes,
+                Ok(Ok(false)) => ConfirmResult::No,
+                // Either time ran out, or the page stopped being dynamic
+                _ => ConfirmResult::Timeout,
+            };
+            let data = serde_json::to_string(&serde_json::json!({"token": token}))
+                .expect("Serializing confirmation to JSON shouldn't fail");
+            let event = EventBuilder::new(&data).event_type("confirm-done").build();
+            let _unused = updates.send_to_clients(event).await;
+            result
+        }
+    }
+
+    /// Answer a pending question asked by `confirm`, identified by the token
+    /// sent with it. Returns `false` if there's no such question, as when it
+    /// has already been answered or has timed out.
+    pub fn answer_confirm(&mut self, token: &str, yes: bool) -> bool {
+        match self {
+            Content::Dynamic{confirmations, ..} => match confirmations.remove(token) {
+                Some(answer) => answer.send(yes).is_ok(),
+                None => false,
+            },
+            Content::Static{..} => false,
+        }
+    }
+
+    /// Change the number of updates which can be buffered for a dynamic page
+    /// before sending them experiences backpressure. Clients connected during
+    /// the change see every update exactly once, in order. This has no effect
+    /// if the page is (currently) static.
+    pub async fn resize_buffer(&mut self, capacity: usize) {
+        if let Content::Dynamic{updates, ..} = self {
+            updates.resize(capacity).await;
+        }
+    }
+
+    /// Set a callback to be notified when updates to a dynamic page begin to
+    /// experience backpressure (`Begin`), and when that backpressure clears
+    /// (`End`). A producer can use this to throttle its updates. Transitions are
+    /// debounced, so the callback isn't invoked on every rapid toggle. This has
+    /// no effect if the page is (currently) static.
+    pub fn set_backpressure_callback(&mut self,
+                                     callback: impl Fn(sse::BackpressureState) + Send + Sync + 'static) {
+        if let Content::Dynamic{updates, log, ..} = self {
+            let log = log.clone();
+            updates.set_backpressure_callback(move |state| {
+                log_backpressure(&log, state);
+                callback(state);
+            });
+        }
+    }
+
+    /// Disconnect any client of a dynamic page which, when sent an update,
+    /// doesn't read anything for the given time. Because every update waits
+    /// for all clients, a client which connects but never reads (whether buggy
+    /// or malicious) would otherwise stall the page for everyone else once its
+    /// connection's buffers fill up. If `timeout` is `None`, wait for every
+    /// client however long it takes. This has no effect if the page is
+    /// (currently) static.
+    pub async fn set_stuck_client_timeout(&mut self, timeout: Option<Duration>) {
+        if let Content::Dynamic{updates, ..} = self {
+            updates.set_stuck_timeout(timeout).await;
+        }
+    }
+
+    /// Disconnect any client of a dynamic page which hasn't sent anything
+    /// upstream for the given time: no interface events, reports of local
+    /// changes, or pings (see `touch_client`), counting from when it connected
+    /// if it hasn't sent anything yet. This reclaims the connections of
+    /// abandoned tabs, and is checked at every heartbeat. Unlike the heartbeat
+    /// itself, which checks that the server can reach the client, this checks
+    /// that the client is still interested. Clients which connected as
+    /// receive-only are exempt, since they never send anything upstream. If
+    /// `timeout` is `None`, idle clients are never disconnected. This has no
+    /// effect if the page is (currently) static.
+    pub async fn set_client_idle_timeout(&mut self, timeout: Option<Duration>) {
+        if let Content::Dynamic{updates, ..} = self {
+            updates.set_idle_timeout(timeout).await;
+        }
+    }
+
+    /// Compute the difference between some baseline body and the current body
+    /// of the page, so that a client which already holds the baseline can be
+    /// sent only what changed. The patch covers the span between the longest
+    /// common prefix and suffix of the two, and if that span is more than half
+    /// the current body, the whole body is given instead. Static pages have no
+    /// body, so for them this is always an empty `Full` body.
+    pub fn body_diff_from(&self, baseline: &str) -> BodyDiff {
+        let body = match self {
+            Content::Dynamic{state: DynamicState{body, ..}, ..} => body,
+            Content::Static{..} => return BodyDiff::Full(String::new()),
+        };
+        if baseline == body {
+            return BodyDiff::Unchanged
+        }
+        let prefix = baseline.char_indices().zip(body.chars())
+            .find(|((_, a), b)| a != b)
+            .map(|((i, _), _)| i)
+            .unwrap_or_else(|| baseline.len().min(body.len()));
+        // The suffix can't overlap the prefix in either string
+        let suffix = baseline[prefix..].chars().rev().zip(body[prefix..].chars().rev())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum::<usize>();
+        let text = &body[prefix .. body.len() - suffix];
+        if text.len() > body.len() / 2 {
+            return BodyDiff::Full(body.clone())
+        }
+        BodyDiff::Patch{
+            start: baseline[.. prefix].encode_utf16().count(),
+            end: baseline[.. baseline.len() - suffix].encode_utf16().count(),
+            text: text.to_string(),
+        }
+    }
+
+    /// Send a single client of a dynamic page whatever it needs to bring the
+    /// baseline body it holds up to date: nothing if it's already current, a
+    /// `body-patch` event if the baseline is close enough to the current body,
+    /// or else the whole body. The patch names the hash of the baseline it
+    /// applies to, so the client can ignore it if its body has changed since.
+    /// Returns `false` if the page is static or there is no such client.
+    pub async fn send_body_diff(&mut self, id: sse::ClientId, baseline: &str) -> bool {
+        let diff = self.body_diff_from(baseline);
+        match self {
+            Content::Dynamic{state: DynamicState{version, compression_threshold, ..},
+                             updates, ..} => {
+                let frame = match diff {
+                    // There's nothing to send, but a comment checks that the
+                    // client is there
+                    BodyDiff::Unchanged => ":\n\n".to_string(),
+                    BodyDiff::Patch{start, end, text} => {
+                        let data = serde_json::to_string(&serde_json::json!({
+                            "baseline": hash_contents(baseline.as_bytes()),
+                            "start": start,
+                            "end": end,
+                            "text": text,
+                        })).expect("Serializing body patch to JSON shouldn't fail");
+                        Event::new("body-patch", data).id(*version).build()
+                    },
+                    BodyDiff::Full(body) =>
+                        body_event(&body, *version, *compression_threshold).build(),
+                };
+                updates.send_to_client(id, frame).await
+            },
+            Content::Static{..} => false,
+        }
+    }
+
+    /// Note that a client of a dynamic page is still active, so it isn't
+    /// disconnected by the idle timeout. Returns `false` if the page is static
+    /// or there is no such client.
+    pub async fn touch_client(&mut self, id: sse::ClientId) -> bool {
+        match self {
+            Content::Dynamic{updates, ..} => updates.touch_client(id).await,
+            Content::Static{..} => false,
+        }
+    }
+
+    /// Get what a client of a dynamic page has been sent so far, as of the
+    /// last frame which reached it: the highest version of the title or body,
+    /// and the type of every event (including those replaying the page's state
+    /// when it connected). Returns `None` if the page is static or there is no
+    /// such client.
+    pub async fn client_sync_state(&mut self, id: sse::ClientId) -> Option<sse::SyncState> {
+        match self {
+            Content::Dynamic{updates, ..} => updates.client_sync_state(id).await,
+            Content::Static{..} => None,
+        }
+    }
+
+    /// Merge the frames sent by the stateful setters of a dynamic page (those
+    /// which set its title, body, head metadata, query, root or element
+    /// attributes, or validation errors) within the given window of each other
+    /// into one frame, sent to clients at the end of the window, in which only
+    /// the last change to each of these is kept. This cuts down on frames when
+    /// they churn rapidly, at the cost of delaying changes by up to the window.
+    /// The state itself changes right away, so clients connecting during the
+    /// window are brought up to date with the latest state. One-shot events
+    /// (like custom events) are sent right away as usual, so they can overtake
+    /// the changes being coalesced, and so are body updates which only some
+    /// clients get (such as to one language). A zero window turns coalescing
+    /// off. This has no effect if the page is (currently) static.
+    pub async fn set_coalesce_window(&mut self, window: Duration) {
+        if let Content::Dynamic{updates, coalescer, ..} = self {
+            // Don't let anything waiting in the old coalescer be overtaken
+            if let Some(frame) = coalescer.take().and_then(|coalescer| coalescer.take()) {
+                let _unused = updates.send_to_clients(frame).await;
+            }
+            if window > Duration::from_millis(0) {
+                *coalescer = Some(sse::Coalescer::new(updates.clone(), window));
+            }
+        }
+    }
+
+    /// Keep adjusting the coalescing window (see `set_coalesce_window`) to how
+    /// far behind clients are in rendering updates, as they report it (see
+    /// `report_lag`), so that slow clients are sent fewer frames and fast
+    /// ones more: whenever the average of the lag last reported by each
+    /// client exceeds the target, the window is doubled (up to a second), and
+    /// whenever it falls below half the target, the window is halved (until
+    /// coalescing turns off). Reports more than 15 seconds old don't count.
+    /// Clients are told to start reporting by the `adaptive-rate` capability,
+    /// so those connected before this is called don't. The current window is
+    /// where adjusting starts, and it's left wherever it got to if this is
+    /// turned off again by `disable_adaptive_rate`. This has no effect if the
+    /// page is (currently) static.
+    pub fn enable_adaptive_rate(&mut self, target_lag: Duration) {
+        if let Content::Dynamic{adaptive_rate, ..} = self {
+            *adaptive_rate = Some(AdaptiveRate::new(target_lag));
+        }
+    }
+
+    /// Stop adjusting the coalescing window to how far behind clients are,
+    /// leaving it as it is. This has no effect if the page is (currently)
+    /// static.
+    pub fn disable_adaptive_rate(&mut self) {
+        if let Content::Dynamic{adaptive_rate, ..} = self {
+            *adaptive_rate = None;
+        }
+    }
+
+    /// Record how far behind a client of a dynamic page is in rendering the
+    /// updates it's sent, adjusting the coalescing window to suit if that's
+    /// turned on by `enable_adaptive_rate` (and otherwise ignoring it). This
+    /// also counts as activity, like `touch_client`. Returns `false` if the
+    /// page is static or there is no such client.
+    pub async fn report_lag(&mut self, id: sse::ClientId, lag: Duration) -> bool {
+        let window = match self {
+            Content::Dynamic{updates, adaptive_rate, coalescer, log, ..} => {
+                if !updates.touch_client(id).await {
+                    return false
+                }
+                let adaptive = match adaptive_rate {
+                    Some(adaptive) => adaptive,
+                    None => return true,
+                };
+                let current = coalescer.as_ref().map_or(Duration::from_millis(0), sse::Coalescer::window);
+                if coalescer.is_some() && current == Duration::from_millis(0) {
+                    // A batch is being coalesced by hand, so leave it be
+                    adaptive.report(id, lag);
+                    return true
+                }
+                let average = adaptive.report(id, lag);
+                match adaptive.adjust(current, average) {
+                    Some(window) => {
+                        tracing::debug!(parent: log.span(), window_ms = window.as_millis() as u64,
+                                        "Adapted coalescing window");
+                        window
+                    },
+                    None => return true,
+                }
+            },
+            Content::Static{..} => return false,
+        };
+        self.set_coalesce_window(window).await;
+        true
+    }
+
+    /// Get the most frames a second which clients of a dynamic page are sent
+    /// the stateful updates in, as limited by coalescing (see
+    /// `set_coalesce_window` and `enable_adaptive_rate`), or `None` if there's
+    /// no limit (or the page is static).
+    pub fn effective_frame_rate(&self) -> Option<f64> {
+        match self {
+            Content::Dynamic{coalescer: Some(coalescer), ..}
+            if coalescer.window() > Duration::from_millis(0) =>
+                Some(1.0 / coalescer.window().as_secs_f64()),
+            _ => None,
+        }
+    }
+
+    /// Set the rules which every change applied by `apply_validated` must
+    /// follow. This has no effect if the page is (currently) static.
+    pub fn set_validator(&mut self, new_validator: Validator) {
+        if let Content::Dynamic{state: DynamicState{validator, ..}, ..} = self {
+            *validator = new_validator;
+        }
+    }
+
+    /// Apply a batch of changes all together, or none of them: every change is
+    /// checked against the validator set by `set_validator` (or, for a static
+    /// page, against no extra rules) before any is applied, and if one is
+    /// invalid, the page is left untouched. The changes are sent to clients as
+    /// one frame, so nobody sees some of them without the others (except that
+    /// a new body is sent separately to clients shown another language's body,
+    /// as by `set_body`). This converts the page into a dynamic page, if all
+    /// the changes are valid. Returns the number of changes applied.
+    pub async fn apply_validated(&mut self, ops: Vec<Op>) -> Result<usize, ValidationError> {
+        let rules = match self {
+            Content::Dynamic{state: DynamicState{validator, ..}, ..} => validator.clone(),
+            Content::Static{..} => Validator::default(),
+        };
+        for (index, op) in ops.iter().enumerate() {
+            let result = match op {
+                Op::Title(title) => rules.check_title(title),
+                Op::Body(body) => rules.check_body(body),
+                Op::RootAttr{element, name, ..} =>
+                    RootElement::try_from(element.as_str())
+                    .and_then(|_| validate_attr_name(name)),
+                Op::Query{query, ..} => validate_query(query),
+            };
+            result.map_err(|message| ValidationError{index, message})?;
+        }
+
+        if let Content::Static{..} = self {
+            self.become_dynamic().await;
+        }
+        // Hold every frame until the whole batch is applied
+        let mut previous = None;
+        if let Content::Dynamic{updates, coalescer, ..} = self {
+            previous = coalescer.replace(sse::Coalescer::manual(updates.clone()));
+        }
+        let count = ops.len();
+        for op in ops {
+            match op {
+                Op::Title(title) => self.set_title(title).await,
+                Op::Body(body) => self.set_body(body).await,
+                Op::RootAttr{element, name, value} => {
+                    let element = RootElement::try_from(element.as_str())
+                        .expect("Root element was already validated");
+                    self.set_root_attr(element, &name, value.as_deref()).await
+                        .expect("Attribute name was already validated");
+                },
+                Op::Query{query, replace} => {
+                    self.send_query_update(&query, replace).await
+                        .expect("Query was already validated");
+                },
+            }
+        }
+        if let Content::Dynamic{updates, coalescer, ..} = self {
+            let batch = mem::replace(coalescer, previous).and_then(|batch| batch.take());
+            let mut frame = Vec::new();
+            // Anything already waiting to be coalesced goes first, so the batch
+            // isn't overtaken by older changes
+            if let Some(waiting) = coalescer.as_ref().and_then(|coalescer| coalescer.take()) {
+                frame.extend_from_slice(&waiting);
+            }
+            if let Some(batch) = batch {
+                frame.extend_from_slice(&batch);
+            }
+            if !frame.is_empty() {
+                let _unused = updates.send_to_clients(frame).await;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Compress body updates to a dynamic page which are larger than the given
+    /// number of bytes, sending them gzipped and base64-encoded as `body:gzip`
+    /// events, which the page's script decompresses. Smaller updates are sent
+    /// as they are, because compressing tiny frames wastes time and can even
+    /// make them bigger. If `threshold` is `None`, stop compressing updates.
+    /// This has no effect if the page is (currently) static.
+    pub fn set_event_compression_threshold(&mut self, threshold: Option<usize>) {
+        if let Content::Dynamic{state: DynamicState{compression_threshold, ..}, ..} = self {
+            *compression_threshold = threshold;
+        }
+    }
+
+    /// Put a bounded mailbox of the given capacity in front of body updates, so
+    /// that `set_body` only enqueues its update rather than waiting for clients
+    /// to have room for it. A dedicated task drains the mailbox to clients; if
+    /// the mailbox is full, the newest update replaces the most recently queued
+    /// one, so memory use stays bounded and clients always end up with the
+    /// latest body. Clients connecting later are sent the latest body, but
+    /// never the older ones still in the mailbox. The task stops when the
+    /// content is dropped (or becomes static). This has no effect if the page
+    /// is (currently) static.
+    pub async fn enable_mailbox(&mut self, capacity: usize) {
+        self.flush_mailbox().await;
+        if let Content::Dynamic{updates, mailbox, ..} = self {
+            *mailbox = Some(sse::Mailbox::new(updates.clone(), capacity));
+        }
+    }
+
+    /// Take away the mailbox in front of body updates, if there is one, first
+    /// sending clients the latest body if it was holding any. This has no
+    /// effect if the page is (currently) static.
+    pub async fn disable_mailbox(&mut self) {
+        self.flush_mailbox().await;
+        if let Content::Dynamic{mailbox, ..} = self {
+            *mailbox = None;
+        }
+    }
+
+    /// Start streaming the server's log of what happens to this page, at the
+    /// given level of detail, to its clients as `server-log` events carrying a
+    /// JSON object with the `level` and `message` of each line, along with its
+    /// fields (like the `client` it's about) and those of the spans it's in:
+    /// clients connecting and disconnecting, backpressure, rejected updates,
+    /// and so on.
+    /// This is separate from both the page's content and the events its clients
+    /// send back. Logging never slows down the page: if clients lag behind,
+    /// lines are dropped. This has no effect if the page is (currently) static,
+    /// and the log stops streaming if the page becomes static.
+    pub fn enable_log_streaming(&mut self, level: LogLevel) {
+        if let Content::Dynamic{updates, log, ..} = self {
+            log.enable(level, updates.clone());
+        }
+    }
+
+    /// Stop streaming the server's log of this page to its clients.
+    pub fn disable_log_streaming(&mut self) {
+        if let Content::Dynamic{log, ..} = self {
+            log.disable();
+        }
+    }
+
+    /// Log a line to the server's log of this page, if it is dynamic.
+    fn log(&self, level: LogLevel, message: impl std::fmt::Display) {
+        if let Content::Dynamic{log, ..} = self {
+            log.log(level, message);
+        }
+    }
+
+    /// Tell all clients to change the query string of their URL to the given
+    /// one, without navigating away or reloading the page, either replacing the
+    /// current history entry or pushing a new one. The query is remembered so
+    /// that clients connecting later sync their URL to it. This has no effect
+    /// if the page is (currently) static, and returns an error, without sending
+    /// anything, if the query is not a well-formed query string.
+    pub async fn send_query_update(&mut self, new_query: &str, replace: bool) -> Result<(), String> {
+        if let Err(err) = validate_query(new_query) {
+            self.log(LogLevel::Warn, &err);
+            return Err(err)
+        }
+        match self {
+            Content::Dynamic{state: DynamicState{query, ..}, updates, coalescer, modified, ..} => {
+                *query = Some(new_query.to_string());
+                *modified = Instant::now();
+                let data = query_event_data(new_query, replace);
+                let event = EventBuilder::new(&data).event_type("query").build();
+                send_state(updates, coalescer, "query", event).await;
+            },
+            Content::Static{..} => { },
+        }
+        Ok(())
+    }
+
+    /// Tell all clients to set (or, if `value` is `None`, remove) an attribute
+    /// on the `<html>` or `<body>` element of the page, without touching the
+    /// body's contents. Root attributes are remembered so that clients
+    /// connecting later receive them too. This has no effect if the page is
+    /// (currently) static, and returns an error, without sending anything, if
+    /// the attribute name is not a legal attribute name.
+    pub async fn set_root_attr(&mut self,
+                               element: RootElement,
+                               name: &str,
+                               value: Option<&str>) -> Result<(), String> {
+        if let Err(err) = validate_attr_name(name) {
+            self.log(LogLevel::Warn, &err);
+            return Err(err)
+        }
+        match self {
+            Content::Dynamic{state: DynamicState{root_attrs, ..}, updates, coalescer,
+                             modified, ..} => {
+                let key = (element, name.to_string());
+                *modified = Instant::now();
+                match value {
+                    Some(value) => { root_attrs.insert(key, value.to_string()); },
+                    None => { root_attrs.remove(&key); },
+                }
+                let data = serde_json::to_string(&serde_json::json!({
+                    "element": element.as_str(),
+                    "name": name,
+                    "value": value,
+                })).expect("Serializing root attribute to JSON shouldn't fail");
+                let event = EventBuilder::new(&data).event_type("root-attr").build();
+                let state = format!("root-attr:{}:{}", element.as_str(), name);
+                send_state(updates, coalescer, &state, event).await;
+            },
+            Content::Static{..} => { },
+        }
+        Ok(())
+    }
+
+    /// Tell all clients to remove every attribute previously set on the root
+    /// elements of the page by `set_root_attr`. This has no effect if the page
+    /// is (currently) static.
+    pub async fn clear_root_attrs(&mut self) {
+        if let Content::Dynamic{state: DynamicState{root_attrs, ..}, updates, coalescer,
+                                modified, ..} = self {
+            root_attrs.clear();
+            *modified = Instant::now();
+            let data = root_attrs_event_data(root_attrs);
+            let event = EventBuilder::new(&data).event_type("root-attrs").build();
+            send_state(updates, coalescer, "root-attrs", event).await;
+        }
+    }
+
+    /// Tell all clients to set (or, if `value` is `None`, remove) an attribute
+    /// on the element matching a CSS selector, without re-rendering anything
+    /// else. Setting `value` also sets the current value of a form field, even
+    /// after the user has edited it. Each attribute set this way is remembered
+    /// so that clients connecting later set it too, until the whole body is
+    /// replaced (when the new body's own attributes take over) or the
+    /// remembered attributes are cleared by `clear_attrs`. Since every one of
+    /// them is replayed to each new client, there's a cap on how many can be
+    /// remembered (see `set_attr_cap`). This has no effect if the page is
+    /// (currently) static, and returns an error, without sending anything, if
+    /// the selector is empty, the attribute name is not a legal attribute
+    /// name, or setting a new attribute would go over the cap.
+    pub async fn set_attr(&mut self,
+                          selector: &str,
+                          name: &str,
+                          value: Option<&str>) -> Result<(), String> {
+        if selector.trim().is_empty() {
+            return Err("Empty selector to set an attribute on".to_string())
+        }
+        if let Err(err) = validate_attr_name(name) {
+            self.log(LogLevel::Warn, &err);
+            return Err(err)
+        }
+        if let Content::Dynamic{state: DynamicState{attrs, attr_cap, ..}, updates, coalescer,
+                                modified, log, ..} = self {
+            let key = (selector.to_string(), name.to_string());
+            if attrs.len() >= *attr_cap && !attrs.contains_key(&key) {
+                let err = format!("Can't set more than {} element attributes \
+                                   (clear them, or replace the body)", attr_cap);
+                log.log(LogLevel::Warn, &err);
+                return Err(err)
+            }
+            attrs.insert(key, value.map(String::from));
+            *modified = Instant::now();
+            let event = EventBuilder::new(&attr_event_data(selector, name, value))
+                .event_type("set-attr").build();
+            let state = format!("attr:{}:{}", selector, name);
+            send_state(updates, coalescer, &state, event).await;
+        }
+        self.compact_replay_state();
+        Ok(())
+    }
+
+    /// Forget every attribute set on elements by `set_attr`, so they aren't
+    /// set by clients connecting later. Clients already connected aren't told
+    /// anything: their elements keep the attributes set on them. This has no
+    /// effect if the page is (currently) static.
+    pub fn clear_attrs(&mut self) {
+        if let Content::Dynamic{state: DynamicState{attrs, ..}, ..} = self {
+            attrs.clear();
+        }
+    }
+
+    /// Change the number of attributes set on elements by `set_attr` which can
+    /// be remembered for clients connecting later. If more are remembered
+    /// already, they're kept, but no new ones can be set until there are fewer.
+    /// This has no effect if the page is (currently) static.
+    pub fn set_attr_cap(&mut self, cap: usize) {
+        if let Content::Dynamic{state: DynamicState{attr_cap, ..}, ..} = self {
+            *attr_cap = cap;
+        }
+    }
+
+    /// Limit the number of bytes of element state (the attributes set by
+    /// `set_attr` and the text streamed by `append_to`) which are remembered
+    /// separately from the body, to be replayed to each new client one event
+    /// at a time. Whenever there's more than this, as much of it as possible
+    /// is compacted into the body itself, so new clients are sent it as part
+    /// of one body event instead. Only the state of elements picked out by a
+    /// plain id selector (like `#log`) which appears exactly once in a properly
+    /// nested body can be compacted; the rest is kept as it was. Clients
+    /// already connected aren't sent anything, since they're showing the same
+    /// thing already, and the page's version doesn't change. Nothing is
+    /// compacted while the page has bodies in other languages. There's no
+    /// limit until this is called, or after it's called with `None`. This has
+    /// no effect if the page is (currently) static.
+    pub fn set_max_replay_state(&mut self, max: Option<usize>) {
+        if let Content::Dynamic{state: DynamicState{max_replay_state, ..}, ..} = self {
+            *max_replay_state = max;
+        }
+        self.compact_replay_state();
+    }
+
+    /// Get the number of bytes of element state remembered separately from the
+    /// body (see `set_max_replay_state`).
+    fn replay_state_size(&self) -> usize {
+        match self {
+            Content::Dynamic{state: DynamicState{attrs, streams, ..}, ..} =>
+                attrs.iter()
+                .map(|((selector, name), value)|
+                     selector.len() + name.len() + value.as_ref().map_or(0, String::len))
+                .sum::<usize>()
+                + streams.iter()
+                .map(|(selector, text)| selector.len() + text.len())
+                .sum::<usize>(),
+            Content::Static{..} => 0,
+        }
+    }
+
+    /// If there's more element state than the maximum set by
+    /// `set_max_replay_state`, fold what can be folded into the body.
+    fn compact_replay_state(&mut self) {
+        let size = self.replay_state_size();
+        let over = match self {
+            Content::Dynamic{state: DynamicState{max_replay_state: Some(max),
+                             lang_bodies, ..}, ..} =>
+                lang_bodies.is_empty() && size > *max,
+            _ => false,
+        };
+        if !over {
+            return
+        }
+        if let Content::Dynamic{state: DynamicState{body, attrs, streams, ..}, body_changes,
+                                body_watchers, modified, log, ..} = self {
+            let mut compacted = body.clone();
+            let mut count = 0;
+            attrs.retain(|(selector, name), value| {
+                let new_body = compact::selector_id(selector).and_then(|id| {
+                    compact::set_attr(&compacted, id, name, value.as_deref())
+                });
+                match new_body {
+                    Some(new_body) => {
+                        compacted = new_body;
+                        count += 1;
+                        false
+                    },
+                    None => true,
+                }
+            });
+            streams.retain(|selector, text| {
+                let new_body = compact::selector_id(selector).and_then(|id| {
+                    compact::append_text(&compacted, id, text)
+                });
+                match new_body {
+                    Some(new_body) => {
+                        compacted = new_body;
+                        count += 1;
+                        false
+                    },
+                    None => true,
+                }
+            });
+            if count > 0 {
+                *body = compacted;
+                *modified = Instant::now();
+                // Nobody may be watching the body, which is fine
+                body_changes.send(BodyChange::Reset).unwrap_or(0);
+                notify_watchers(body_watchers, body);
+                log.log(LogLevel::Debug,
+                        format!("Compacted the state of {} elements into the body", count));
+            }
+        }
+    }
+
+    /// Tell all clients to show the given validation errors, which map the names
+    /// of form fields (or CSS selectors for them) to error messages, replacing
+    /// any errors shown before: the page's script marks each field invalid with
+    /// its message, without touching the body. An empty map clears all errors.
+    /// The errors are remembered so that clients connecting later show them
+    /// too, including after the body changes. This has no effect if the page is
+    /// (currently) static.
+    pub async fn send_validation(&mut self, errors: HashMap<String, String>) {
+        if let Content::Dynamic{state: DynamicState{validation, ..}, updates, coalescer,
+                                modified, ..} = self {
+            *validation = errors;
+            *modified = Instant::now();
+            let data = validation_event_data(validation);
+            let event = EventBuilder::new(&data).event_type("validation").build();
+            send_state(updates, coalescer, "validation", event).await;
+        }
+    }
+
+    /// Tell all clients the status of one of the named steps of a job (like
+    /// `fetch`, `parse`, and `render`), for showing the job's progress: the
+    /// page's script sets the `data-myxine-step-status` attribute (and, for a
+    /// failed step with a message, `data-myxine-step-error`) on every element
+    /// with `data-myxine-step` set to the step's name, and dispatches a
+    /// `myxine-step` event. The status of every step is remembered, in the
+    /// order they were first set, so that clients connecting later see them
+    /// all, until `clear_steps`. This has no effect if the page is (currently)
+    /// static, and returns an error if the step's name is empty.
+    pub async fn set_step(&mut self, step: &str, status: StepStatus) -> Result<(), String> {
+        if step.is_empty() {
+            return Err("Empty step name".to_string())
+        }
+        if let Content::Dynamic{state: DynamicState{steps, ..}, updates, coalescer,
+                                modified, ..} = self {
+            let event = Event::new("step", step_event_data(step, &status)).build();
+            match steps.iter_mut().find(|(name, _)| name == step) {
+                Some((_, old_status)) => *old_status = status,
+                None => steps.push((step.to_string(), status)),
+            }
+            *modified = Instant::now();
+            send_state(updates, coalescer, &format!("step:{}", step), event).await;
+        }
+        Ok(())
+    }
+
+    /// Tell all clients to forget the status of every step set by `set_step`,
+    /// as when starting a new job. This has no effect if the page is
+    /// (currently) static.
+    pub async fn clear_steps(&mut self) {
+        if let Content::Dynamic{state: DynamicState{steps, ..}, updates, modified, ..} = self {
+            steps.clear();
+            *modified = Instant::now();
+            let event = EventBuilder::new(".").event_type("clear-steps").build();
+            // We're ignoring this future because we don't care how many
+            // clients there are
+            let _unused = updates.send_to_clients(event).await;
+        }
+    }
+
+    /// Set the contents of the page to be a static raw set of bytes with no
+    /// self-refreshing functionality. All clients will be told to refresh their
+    /// page to load the new static content (which will not be able to update
+    /// itself until a client refreshes their page again).
+    pub async fn set_static(&mut self,
+                            content_type: Option<String>,
+                            raw_contents: impl Into<Vec<u8>>) {
+        let raw_contents = raw_contents.into();
+        // The new contents start with an empty cache, of the same size as before
+        let cache_size = match self {
+            Content::Static{render_cache, ..} => render_cache.capacity,
+            Content::Dynamic{..} => DEFAULT_RENDER_CACHE_SIZE,
+        };
+        let mut page = Content::Static {
+            content_type,
+            hash: hash_contents(&raw_contents),
+            integrity: integrity::integrity(&raw_contents),
+            raw_contents,
+            render_cache: RenderCache::new(cache_size),
+            fetches: 0,
+            fetched: None,
+            refreshed: Some(Instant::now()),
+            modified: Instant::now(),
+            namespace: self.namespace().map(String::from),
+            version: self.version() + 1,
+        };
+        mem::swap(&mut page, self);
+        page.refresh().await;
+        page.stop_recording().await;
+    }
+
+    /// Replace static content with a new empty dynamic page, noting the time of
+    /// the transition. Clients which load the page after this point get the
+    /// dynamic page with its self-updating script, and its subscription to
+    /// updates immediately receives whatever the title and body are by then.
+    async fn become_dynamic(&mut self) {
+        let mut page = Content::new().await;
+        if let Content::Dynamic{state: DynamicState{namespace, version, ..},
+                                refreshed, ..} = &mut page {
+            *refreshed = Some(Instant::now());
+            *namespace = self.namespace().map(String::from);
+            *version = self.version() + 1;
+        }
+        *self = page;
+    }
+
+    /// Get the version of this page: a number which increases every time its
+    /// title, body, or static contents change. Static and dynamic content share
+    /// one version space, so the version never decreases over the life of a
+    /// page. The version is sent to clients as the `id` of title and body
+    /// events, so they can ignore frames which arrive out of order.
+    pub fn version(&self) -> u64 {
+        match self {
+            Content::Dynamic{state: DynamicState{version, ..}, ..} | Content::Static{version, ..} => *version,
+        }
+    }
+
+    /// Get the namespace this page has been put in by `set_namespace`, if any.
+    pub fn namespace(&self) -> Option<&str> {
+        match self {
+            Content::Dynamic{state: DynamicState{namespace, ..}, ..} | Content::Static{namespace, ..} =>
+                namespace.as_deref(),
+        }
+    }
+
+    /// Put this page in a namespace (or, with `None`, take it out of one), so
+    /// that it can be found along with the other pages in the same namespace,
+    /// as by `server::pages_in_namespace`, to act on them all at once. The
+    /// namespace is only known to the server, never sent to clients, and stays
+    /// the same when the page switches between static and dynamic. It doesn't
+    /// make an otherwise empty page non-empty.
+    pub fn set_namespace(&mut self, new_namespace: Option<String>) {
+        match self {
+            Content::Dynamic{state: DynamicState{namespace, ..}, ..} | Content::Static{namespace, ..} =>
+                *namespace = new_namespace,
+        }
+    }
+
+    /// Test whether this page's existing clients have been invalidated since
+    /// the given instant: that is, whether clients have been told to refresh,
+    /// or the page has switched between static and dynamic, since then. Tests
+    /// use this to confirm that a transition has happened before checking that
+    /// reconnecting clients see the new content.
+    #[cfg(test)]
+    pub fn was_refreshed_since(&self, since: Instant) -> bool {
+        match self {
+            Content::Dynamic{refreshed, ..} | Content::Static{refreshed, ..} =>
+                matches!(refreshed, Some(refreshed) if *refreshed >= since),
+        }
+    }
+
+    /// Get the time at which the content of this page last changed: its title,
+    /// body, query, root attributes, or static contents.
+    pub fn last_modified(&self) -> Instant {
+        match self {
+            Content::Dynamic{modified, ..} | Content::Static{modified, ..} => *modified,
+        }
+    }
+
+    /// Get the last time a client did anything with this page, as opposed to
+    /// when the page was last changed (see `last_modified`): for a dynamic
+    /// page, when a client connected, was found to have disconnected, or sent
+    /// something upstream (an event, a ping, or a report of local changes); for
+    /// a static page, when it was last fetched. Returns `None` if no client
+    /// has done anything since the page became what it is now.
+    pub fn last_client_activity(&self) -> Option<Instant> {
+        match self {
+            Content::Dynamic{updates, ..} => *updates.stats().last_activity.lock().unwrap(),
+            Content::Static{fetched, ..} => *fetched,
+        }
+    }
+
+    /// Get the approximate number of bytes of content held by this page. This
+    /// doesn't count the fixed overhead of every page, or anything buffered
+    /// for clients.
+    pub fn footprint(&self) -> usize {
+        match self {
+            Content::Dynamic{state: DynamicState{title, title_template, body, lang_bodies,
+                             description, og, query, root_attrs, validation, attrs, streams,
+                             parent_messages, persistent, steps, timed_events, ..}, ..} =>
+                title.len() + body.len() + description.len()
+                + title_template.as_ref().map_or(0, |template| {
+                    template.template.len()
+                        + template.slots.iter()
+                        .map(|(name, value)| name.len() + value.len())
+                        .sum::<usize>()
+                })
+                + lang_bodies.iter()
+                .map(|(lang, body)| lang.len() + body.len())
+                .sum::<usize>()
+                + og.iter()
+                .map(|(property, content)| property.len() + content.len())
+                .sum::<usize>()
+                + query.as_ref().map_or(0, String::len)
+                + root_attrs.iter()
+                .map(|((_, name), value)| name.len() + value.len())
+                .sum::<usize>()
+                + validation.iter()
+                .map(|(field, message)| field.len() + message.len())
+                .sum::<usize>()
+                + attrs.iter()
+                .map(|((selector, name), value)|
+                     selector.len() + name.len() + value.as_ref().map_or(0, String::len))
+                .sum::<usize>()
+                + streams.iter()
+                .map(|(selector, text)| selector.len() + text.len())
+                .sum::<usize>()
+                + parent_messages.iter()
+                .map(|(channel, message)| channel.len() + message.data.len() + message.origin.len())
+                .sum::<usize>()
+                + persistent.iter()
+                .map(|(key, data)| key.len() + data.len())
+                .sum::<usize>()
+                + steps.iter()
+                .map(|(name, status)| name.len() + status.error().map_or(0, str::len))
+                .sum::<usize>()
+                + timed_events.iter()
+                .map(|timed| timed.event_type.len() + timed.data.len())
+                .sum::<usize>(),
+            Content::Static{content_type, raw_contents, ..} =>
+                content_type.as_ref().map_or(0, String::len) + raw_contents.len(),
+        }
+    }
+
+    /// Get a short hash of the static contents of a page, which is the same for
+    /// identical contents and changes when they change, so it can be used for
+    /// cache busting (as in `asset.css?v=<hash>`) or as an `ETag`. The hash is
+    /// computed once, when the contents are set. Dynamic pages return `None`.
+    pub fn content_hash(&self) -> Option<String> {
+        match self {
+            Content::Dynamic{..} => None,
+            Content::Static{hash, ..} => Some(hash.clone()),
+        }
+    }
+
+    /// Get the subresource integrity metadata of the static contents of a
+    /// page, like `sha384-<base64 hash>`, so that a page loading them in a
+    /// `<script>` or `<link>` can give it as the `integrity` attribute, and
+    /// browsers will refuse them if they're not what the page expected. Like
+    /// `content_hash`, this is computed once, when the contents are set.
+    /// Dynamic pages return `None`.
+    pub fn integrity(&self) -> Option<String> {
+        match self {
+            Content::Dynamic{..} => None,
+            Content::Static{integrity, ..} => Some(integrity.clone()),
+        }
+    }
+
+    /// Get the content type of a page, or return `None` if none has been set
+    /// (as in the case of a dynamic page, where the content type is not
+    /// client-configurable).
+    pub fn content_type(&self) -> Option<String> {
+        match self {
+            Content::Dynamic{..} => None,
+            Content::Static{content_type, ..} => content_type.clone(),
+        }
+    }
+
+    /// Extract a range of lines from static text content, numbered from 1 and
+    /// including both ends of the range, with their line endings. The range is
+    /// clamped to the lines which exist, so asking for lines past the end
+    /// returns only those up to the end (or nothing). Returns `None` if the page
+    /// is dynamic, or if its static content isn't text (that is, if it doesn't
+    /// have a `text/*` content type or no content type at all, or isn't valid
+    /// UTF-8), in which case only the full content can be fetched. The most
+    /// recently requested ranges are cached (see `set_render_cache_size`), until
+    /// the content changes. Like fetching the full content, this counts as a
+    /// fetch of the page.
+    pub fn static_lines(&mut self, start: usize, end: usize) -> Option<Vec<u8>> {
+        match self {
+            Content::Static{content_type, raw_contents, render_cache, fetches, fetched, ..} => {
+                let lines = match render_cache.get((start, end)) {
+                    Some(lines) => lines,
+                    None => {
+                        let is_text = match content_type {
+                            None => true,
+                            Some(content_type) => content_type.starts_with("text/"),
+                        };
+                        let text = std::str::from_utf8(raw_contents).ok().filter(|_| is_text)?;
+                        let first = start.max(1);
+                        let lines = text.split_inclusive('\n')
+                            .skip(first - 1)
+                            .take(end.saturating_add(1).saturating_sub(first))
+                            .collect::<String>()
+                            .into_bytes();
+                        render_cache.insert((start, end), lines.clone());
+                        lines
+                    },
+                };
+                *fetches += 1;
+                *fetched = Some(Instant::now());
+                Some(lines)
+            },
+            Content::Dynamic{..} => None,
+        }
+    }
+
+    /// Change how many of the most recently requested line ranges of static
+    /// content (see `static_lines`) are kept, so requesting the same range again
+    /// needn't extract it again. Zero turns caching off. The cache is emptied
+    /// whenever the content changes, but keeps its size. This has no effect if
+    /// the page is (currently) dynamic.
+    pub fn set_render_cache_size(&mut self, size: usize) {
+        if let Content::Static{render_cache, ..} = self {
+            render_cache.resize(size);
+        }
+    }
+
+    /// Get the current value of every metric this page reports. Dynamic pages
+    /// report their client count, peak client count, total bytes sent, buffer
+    /// occupancy, how many sends have experienced backpressure, how many stuck
+    /// or idle clients were disconnected, how many heartbeats were skipped, the
+    /// most frames a second they're sent (rounded), if coalescing limits that,
+    /// and how many body updates are waiting in the mailbox, if there is one;
+    /// static pages report only their size and how many times they have been
+    /// fetched. Both report the seconds since the last client activity, if
+    /// there has been any, and since clients were last told to refresh or the
+    /// page switched between static and dynamic (see `was_refreshed_since`), if
+    /// either has happened. This reads running counters, so it's cheap and does
+    /// not send anything to clients.
+    pub fn metrics(&self) -> metrics::Samples {
+        let mut samples = metrics::Samples::new();
+        match self {
+            Content::Dynamic{updates, coalescer, mailbox, ..} => {
+                let stats = updates.stats();
+                samples.push((&metrics::COALESCE_WINDOW,
+                              coalescer.as_ref().map_or(0, |coalescer| coalescer.window().as_millis() as u64)));
+                samples.push((&metrics::CLIENTS, stats.clients.load(Ordering::Relaxed) as u64));
+                samples.push((&metrics::PEAK_CLIENTS, stats.peak_clients.load(Ordering::Relaxed) as u64));
+                samples.push((&metrics::BYTES_SENT, stats.bytes_sent.load(Ordering::Relaxed)));
+                samples.push((&metrics::BUFFER_OCCUPANCY, stats.buffered.load(Ordering::Relaxed) as u64));
+                samples.push((&metrics::BACKPRESSURE, stats.backpressure.load(Ordering::Relaxed)));
+                samples.push((&metrics::STUCK_DISCONNECTS, stats.stuck.load(Ordering::Relaxed)));
+                samples.push((&metrics::IDLE_DISCONNECTS, stats.idle.load(Ordering::Relaxed)));
+                samples.push((&metrics::HEARTBEATS_SKIPPED, stats.heartbeats_skipped.load(Ordering::Relaxed)));
+                if let Some(rate) = self.effective_frame_rate() {
+                    samples.push((&metrics::FRAME_RATE, rate.round() as u64));
+                }
+                if let Some(mailbox) = mailbox {
+                    samples.push((&metrics::MAILBOX_FRAMES, mailbox.len() as u64));
+                }
+            },
+            Content::Static{raw_contents, fetches, ..} => {
+                samples.push((&metrics::STATIC_SIZE, raw_contents.len() as u64));
+                samples.push((&metrics::STATIC_FETCHES, *fetches));
+            },
+        }
+        if let Some(activity) = self.last_client_activity() {
+            samples.push((&metrics::CLIENT_IDLE, activity.elapsed().as_secs()));
+        }
+        if let Content::Dynamic{refreshed: Some(refreshed), ..}
+             | Content::Static{refreshed: Some(refreshed), ..} = self {
+            samples.push((&metrics::SINCE_REFRESH, refreshed.elapsed().as_secs()));
+        }
+        samples
+    }
+
+    /// Render the page as a PNG image of the given size, as a headless browser
+    /// shows it: for a dynamic page, that's its current title and body, with
+    /// the attributes set on its root elements (but without running its
+    /// script). Static pages can be rendered too, if they're HTML. Rendering is
+    /// slow, so recently rendered images are cached, and rendering the same
+    /// page at the same size again returns the cached image.
+    #[cfg(feature = "screenshot")]
+    pub async fn render_image(&self, width: u32, height: u32) -> Result<Vec<u8>, RenderError> {
+        let html = match self {
+            Content::Dynamic{state: DynamicState{title, body, root_attrs, ..}, ..} => {
+                let attrs = |element| root_attrs.iter()
+                    .filter(|((e, _), _)| *e == element)
+                    .map(|((_, name), value)| format!(" {}=\"{}\"", name, escape_attr_value(value)))
+                    .collect::<String>();
+                format!("<!DOCTYPE html>\n<html{}><head><title>{}</title>\
+                         <meta charset=\"utf-8\" /></head>\
+                         <body style=\"margin: 0px; padding: 0px\"{}>{}</body></html>",
+                        attrs(RootElement::Html), escape_text(title), attrs(RootElement::Body), body)
+            },
+            Content::Static{content_type, raw_contents, ..} => {
+                let is_html = match content_type {
+                    None => true,
+                    Some(content_type) => content_type.starts_with("text/html"),
+                };
+                match std::str::from_utf8(raw_contents) {
+                    Ok(html) if is_html => html.to_string(),
+                    _ => return Err(RenderError::NotHtml),
+                }
+            },
+        };
+        Ok(render::render_png(&html, width, height).await?.to_vec())
+    }
+
+    /// Parse the body of a dynamic page as an integer, add `delta` to it, and
+    /// tell all clients to change the body to the result, which is returned.
+    /// An empty body counts as zero. Because this happens under a single
+    /// mutable borrow, concurrent increments can't race each other. If the page
+    /// is static, or the body isn't an integer (surrounding whitespace aside),
+    /// or the addition would overflow, this returns `NotNumeric` and changes
+    /// nothing.
+    pub async fn increment_body(&mut self, delta: i64) -> Result<i64, NotNumeric> {
+        let current = match self {
+            Content::Dynamic{state: DynamicState{body, ..}, ..} if body.trim().is_empty() => 0,
+            Content::Dynamic{state: DynamicState{body, ..}, log, ..} =>
+                body.trim().parse::<i64>().map_err(|_| {
+                    log.log(LogLevel::Warn, "Can't increment a body which isn't an integer");
+                    NotNumeric
+                })?,
+            Content::Static{..} => return Err(NotNumeric),
+        };
+        let new = match current.checked_add(delta) {
+            Some(new) => new,
+            None => {
+                self.log(LogLevel::Warn, "Incrementing the body overflowed");
+                return Err(NotNumeric)
+            },
+        };
+        self.set_body(new.to_string()).await;
+        Ok(new)
+    }
+
+    /// Compute, without sending anything or changing any state, the frames that
+    /// `set_title` would send to clients if called with this title. An empty
+    /// list means the call would be a no-op, because the title is unchanged and
+    /// no template is being shown.
+    pub fn preview_set_title(&self, new_title: &str) -> Vec<PreviewFrame> {
+        let (title, templated, version) = match self {
+            Content::Dynamic{state: DynamicState{title, title_template, version, ..}, ..} =>
+                (title.as_str(), title_template.is_some(), *version),
+            Content::Static{version, ..} => ("", false, version + 1),
+        };
+        if !templated && new_title == title {
+            vec![]
+        } else {
+            vec![title_event(new_title, version + 1).preview()]
+        }
+    }
+
+    /// Compute, without sending anything or changing any state, the frames that
+    /// `set_body` would send to clients if called with this body. An empty list
+    /// means the call would be a no-op, because the body is unchanged.
+    pub fn preview_set_body(&self, new_body: &str) -> Vec<PreviewFrame> {
+        // If the page is static, the setter first makes it an empty dynamic page
+        let (body, version, compression_threshold) = match self {
+            Content::Dynamic{state: DynamicState{body, version, compression_threshold, ..}, ..} =>
+                (body.as_str(), *version, *compression_threshold),
+            Content::Static{version, ..} => ("", version + 1, None),
+        };
+        if new_body == body {
+            vec![]
+        } else {
+            vec![body_event(new_body, version + 1, compression_threshold).preview()]
+        }
+    }
+
+    /// Tell all clients to change the title, if necessary. This converts the
+    /// page into a dynamic page, overwriting any static content that previously
+    /// existed, if any.
+    pub async fn set_title(&mut self, new_title: impl Into<String>) {
+        loop {
+            match self {
+                Content::Dynamic{state: DynamicState{ref mut title, ref mut title_template,
+                                 ref mut version, ..}, ref mut updates, ref mut modified, ref log,
+                                 ref backend, ref coalescer, ..} => {
+                    let new_title = new_title.into();
+                    // Clients showing a template have to be told to stop
+                    let templated = title_template.take().is_some();
+                    if templated || new_title != *title {
+                        *title = new_title;
+                        *version += 1;
+                        *modified = Instant::now();
+                        tracing::debug!(parent: log.span(), version = *version, "Sent title");
+                        let event = title_event(title, *version).build();
+                        send_state(updates, coalescer, "title", event).await;
+                        publish(backend, || Update::Title{title: title.clone()});
+                    }
+                    break; // title has been set
+                },
+                Content::Static{..} => {
+                    self.become_dynamic().await;
+                    // and loop again to actually set the title
+                }
+            }
+        }
+    }
+
+    /// Tell all clients to show a title made from a template, in which each
+    /// `{name}` (a name made of letters, digits, `-`, and `_`) is a named slot,
+    /// filled in by `set_title_slot`, like `{unread} unread · My App`. Slots
+    /// which haven't been filled in are left empty, and any other braces are
+    /// shown as they are. Slots keep their values when the template changes,
+    /// if the new template has them too. Setting the title in any other way
+    /// drops the template. This converts the page into a dynamic page,
+    /// overwriting any static content that previously existed, if any.
+    pub async fn set_title_template(&mut self, template: &str) {
+        if let Content::Static{..} = self {
+            self.become_dynamic().await;
+        }
+        if let Content::Dynamic{state: DynamicState{title, title_template, version, ..}, updates,
+                                coalescer, modified, log, backend, ..} = self {
+            let mut new_template = TitleTemplate::new(template);
+            if let Some(old_template) = title_template.take() {
+                new_template.slots = old_template.slots.into_iter()
+                    .filter(|(name, _)| new_template.has_slot(name))
+                    .collect();
+            }
+            *title = new_template.render();
+            *version += 1;
+            *modified = Instant::now();
+            tracing::debug!(parent: log.span(), version = *version, "Sent title template");
+            let event = Event::new("title-template", new_template.event_data()).id(*version).build();
+            *title_template = Some(new_template);
+            send_state(updates, coalescer, "title", event).await;
+            publish(backend, || Update::Title{title: title.clone()});
+        }
+    }
+
+    /// Fill in one of the slots of the title template set by
+    /// `set_title_template`, telling clients to change only that slot, rather
+    /// than sending the whole title. Returns `false` and does nothing if there
+    /// is no title template, or it has no such slot.
+    pub async fn set_title_slot(&mut self, name: &str, value: &str) -> bool {
+        if let Content::Dynamic{state: DynamicState{title, title_template: Some(template),
+                                version, ..}, updates, coalescer, modified, log,
+                                backend, ..} = self {
+            if !template.has_slot(name) {
+                return false
+            }
+            if template.slots.get(name).map(String::as_str) != Some(value) {
+                template.slots.insert(name.to_string(), value.to_string());
+                *title = template.render();
+                *version += 1;
+                *modified = Instant::now();
+                tracing::debug!(parent: log.span(), version = *version, "Sent title slot");
+                // The coalescer keeps only the latest title, so it has to be told
+                // the whole thing
+                let event = match coalescer {
+                    Some(_) => Event::new("title-template", template.event_data()),
+                    None => Event::new("title-slot", title_slot_event_data(name, value)),
+                }.id(*version).build();
+                send_state(updates, coalescer, "title", event).await;
+                publish(backend, || Update::Title{title: title.clone()});
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Test whether the title of a dynamic page is made from a template (see
+    /// `set_title_template`). Static pages have no title.
+    pub fn has_title_template(&self) -> bool {
+        matches!(self, Content::Dynamic{state: DynamicState{title_template: Some(_), ..}, ..})
+    }
+
+    /// Tell all clients to change the title and the metadata in the head of the
+    /// page (its description and Open Graph tags) together, in one event, so
+    /// they're never seen out of sync. The metadata replaces whatever was set
+    /// before: empty fields remove the corresponding tags. It's also included
+    /// in the page as first loaded, for crawlers which don't run scripts. This
+    /// converts the page into a dynamic page, overwriting any static content
+    /// that previously existed, if any.
+    pub async fn set_seo(&mut self, seo: SeoMeta) {
+        if let Content::Static{..} = self {
+            self.become_dynamic().await;
+        }
+        if let Content::Dynamic{state: DynamicState{title, title_template, description, og,
+                                version, ..}, updates, coalescer, modified, ..} = self {
+            let new_og: BTreeMap<String, String> = seo.og.into_iter()
+                .filter(|(_, content)| !content.is_empty())
+                .collect();
+            let templated = title_template.take().is_some();
+            if templated || seo.title != *title || seo.description != *description || new_og != *og {
+                *title = seo.title;
+                *description = seo.description;
+                *og = new_og;
+                *version += 1;
+                *modified = Instant::now();
+                let event = Event::new("seo", seo_event_data(title, description, og))
+                    .id(*version).build();
+                send_state(updates, coalescer, "seo", event).await;
+            }
+        }
+    }
+
+    /// Render the description and Open Graph meta tags for the head of a
+    /// dynamic page. Static pages have none.
+    pub fn head_meta(&self) -> String {
+        let mut meta = String::new();
+        if let Content::Dynamic{state: DynamicState{description, og, ..}, ..} = self {
+            if !description.is_empty() {
+                write!(&mut meta, "<meta name=\"description\" content=\"{}\" />",
+                       escape_attr_value(description))
+                    .expect("Internal error: write!() failed on a String");
+            }
+            for (property, content) in og {
+                write!(&mut meta, "<meta property=\"og:{}\" content=\"{}\" />",
+                       escape_attr_value(property), escape_attr_value(content))
+                    .expect("Internal error: write!() failed on a String");
+            }
+        }
+        meta
+    }
+
+    /// Render the current state of a dynamic page as a complete static HTML
+    /// document, without the script which keeps it up to date, for clients
+    /// which won't run that script, like search engine crawlers: its title,
+    /// head metadata, root attributes, and latest body (in the language best
+    /// suiting the `Accept-Language` header, if given). Updates to particular
+    /// elements, like attributes set by `set_attr` or text streamed by
+    /// `append_to`, are left out. Static pages are rendered as their contents.
+    pub fn prerendered_document(&self, accept_language: Option<&str>) -> Vec<u8> {
+        match self {
+            Content::Dynamic{state: DynamicState{body, lang_bodies, ..}, ..} =>
+                self.static_document(body_for(body, lang_bodies, accept_language)),
+            Content::Static{raw_contents, ..} => raw_contents.clone(),
+        }
+    }
+
+    /// Render the current state of a page as a standalone file to download,
+    /// saved under the given name, with the headers to serve it with (its
+    /// content type, and a `Content-Disposition` saying it's an attachment).
+    /// A dynamic page is rendered as a complete HTML document without the
+    /// script which keeps it up to date, like `prerendered_document`, but with
+    /// the attributes set by `set_attr` and the text streamed by `append_to`
+    /// included wherever they can be put into the body (see
+    /// `set_max_replay_state`), so it looks as it does to a client connected
+    /// now. Styles in the body are kept as they are, but stylesheets it links
+    /// to aren't fetched. A static page downloads as its contents, with its
+    /// content type (or as `application/octet-stream` if it has none).
+    pub fn download_document(&self, filename: &str) -> (HeaderMap, Vec<u8>) {
+        let (content_type, bytes) = match self {
+            Content::Dynamic{state: DynamicState{body, attrs, streams, ..}, ..} => {
+                let mut body = body.clone();
+                for ((selector, name), value) in attrs {
+                    if let Some(new_body) = compact::selector_id(selector)
+                        .and_then(|id| compact::set_attr(&body, id, name, value.as_deref()))
+                    {
+                        body = new_body;
+                    }
+                }
+                for (selector, text) in streams {
+                    if let Some(new_body) = compact::selector_id(selector)
+                        .and_then(|id| compact::append_text(&body, id, text))
+                    {
+                        body = new_body;
+                    }
+                }
+                ("text/html; charset=utf-8".to_string(), self.static_document(&body))
+            },
+            Content::Static{content_type, raw_contents, ..} => (
+                content_type.clone().unwrap_or_else(|| "application/octet-stream".to_string()),
+                raw_contents.clone(),
+            ),
+        };
+        let mut headers = HeaderMap::new();
+        if let Ok(content_type) = HeaderValue::from_str(&content_type) {
+            headers.insert(CONTENT_TYPE, content_type);
+        }
+        headers.insert(CONTENT_DISPOSITION, attachment_disposition(filename));
+        (headers, bytes)
+    }
+
+    /// Render a dynamic page as a complete HTML document with the given body,
+    /// its title, head metadata, and root attributes, and no script. Static
+    /// pages are rendered as nothing.
+    fn static_document(&self, body: &str) -> Vec<u8> {
+        match self {
+            Content::Dynamic{state: DynamicState{title, root_attrs, ..}, ..} => {
+                let attrs_of = |element| {
+                    let mut attrs = String::new();
+                    for ((_, name), value) in root_attrs.range((element, String::new())..)
+                        .take_while(|((attr_element, _), _)| *attr_element == element)
+                    {
+                        write!(&mut attrs, " {}=\"{}\"", name, escape_attr_value(value))
+                            .expect("Internal error: write!() failed on a String");
+                    }
+                    attrs
+                };
+                let mut body_attrs = attrs_of(RootElement::Body);
+                // The same style as the self-updating page, unless overridden
+                if !root_attrs.contains_key(&(RootElement::Body, "style".to_string())) {
+                    body_attrs.insert_str(0, " style=\"margin: 0px; padding: 0px\"");
+                }
+                let mut bytes = Vec::new();
+                write!(&mut bytes,
+                       include_str!("prerendered.html"),
+                       html_attrs = attrs_of(RootElement::Html),
+                       title = escape_text(title),
+                       meta = self.head_meta(),
+                       body_attrs = body_attrs,
+                       body = body)
+                    .expect("Internal error: write!() failed on a Vec<u8>");
+                bytes
+            },
+            Content::Static{..} => Vec::new(),
+        }
+    }
+
+    /// Tell all clients to change the body, if necessary. This converts the
+    /// page into a dynamic page, overwriting any static content that previously
+    /// existed, if any.
+    pub async fn set_body(&mut self, new_body: impl Into<String>) {
+        loop {
+            match self {
+                Content::Dynamic{state: DynamicState{ref mut body, ref mut version, ref mut attrs,
+                                 compression_threshold, ref lang_bodies, ..}, ref mut updates,
+                                 ref mut mailbox, ref mut modified, ref log, ref body_changes,
+                                 ref mut body_watchers, ref backend, ref coalescer, ..} => {
+                    let new_body = new_body.into();
+                    if new_body != *body {
+                        *body = new_body;
+                        *version += 1;
+                        *modified = Instant::now();
+                        // Nobody may be watching the body, which is fine
+                        body_changes.send(BodyChange::Reset).unwrap_or(0);
+                        notify_watchers(body_watchers, body);
+                        // The new body's attributes are whatever it says
+                        attrs.clear();
+                        tracing::debug!(parent: log.span(), version = *version, "Sent body");
+                        let event = body_event(body, *version, *compression_threshold).build();
+                        if !lang_bodies.is_empty() {
+                            // Clients shown another language don't want this,
+                            // and the mailbox can't tell them apart
+                            let _unused = updates.send_to_lang(None, event).await;
+                        } else if let Some(coalescer) = coalescer {
+                            coalescer.post("body", event);
+                        } else if let Some(mailbox) = mailbox {
+                            mailbox.post(event);
+                        } else {
+                            // We're ignoring this future because we don't care
+                            // how many clients of the page there are
+                            let _unused = updates.send_to_clients(event).await;
+                        }
+                        publish(backend, || Update::Body{body: body.clone()});
+                    }
+                    break; // body has been set
+                },
+                Content::Static{..} => {
+                    self.become_dynamic().await;
+                    // and loop again to actually set the body
+                }
+            }
+        }
+    }
+
+    /// Set the body of the page in a particular language (as named in the
+    /// `Accept-Language` header, like `fr` or `pt-BR`), telling only those
+    /// clients shown that language to change it. The body set by `set_body` is
+    /// the default, shown to clients who accept none of the languages with a
+    /// body of their own, and updating one language never touches the others.
+    /// A client accepting a regional variant (like `fr-CH`) is shown the body
+    /// of its primary language (`fr`) if there's none for the variant itself.
+    /// Only `set_body` and `append_body` distinguish languages: other ways of
+    /// changing the body reach every client. This converts the page into a
+    /// dynamic page, overwriting any static content that previously existed,
+    /// if any.
+    pub async fn set_body_lang(&mut self, lang: &str, new_body: &str) {
+        if let Content::Static{..} = self {
+            self.become_dynamic().await;
+        }
+        if let Content::Dynamic{state: DynamicState{lang_bodies, version,
+                                compression_threshold, ..}, updates, modified, log, ..} = self {
+            let lang = lang.to_ascii_lowercase();
+            if lang_bodies.get(&lang).map(String::as_str) != Some(new_body) {
+                *version += 1;
+                *modified = Instant::now();
+                tracing::debug!(parent: log.span(), version = *version, lang, "Sent body");
+                let event = body_event(new_body, *version, *compression_threshold).build();
+                // We're ignoring this future because we don't care how many
+                // clients there are
+                let _unused = updates.send_to_lang(Some(&lang), event).await;
+                lang_bodies.insert(lang, new_body.to_string());
+            }
+        }
+    }
+
+    /// Tell all clients to append some HTML to the end of the body, without
+    /// resending the rest of it. This converts the page into a dynamic page,
+    /// overwriting any static content that previously existed, if any. If
+    /// there's a mailbox in front of body updates, the whole new body goes
+    /// through it instead, because appends can't replace one another.
+    pub async fn append_body(&mut self, text: &str) {
+        if let Content::Static{..} = self {
+            self.become_dynamic().await;
+        }
+        if text.is_empty() {
+            return
+        }
+        if let Content::Dynamic{state: DynamicState{body, version, compression_threshold,
+                                lang_bodies, ..}, updates, mailbox, modified, log, body_changes,
+                                body_watchers, backend, coalescer, append_buffer, ..} = self {
+            body.push_str(text);
+            *version += 1;
+            *modified = Instant::now();
+            // Nobody may be watching the body, which is fine
+            body_changes.send(BodyChange::Appended(text.into())).unwrap_or(0);
+            notify_watchers(body_watchers, body);
+            tracing::debug!(parent: log.span(), version = *version, "Appended to body");
+            if !lang_bodies.is_empty() {
+                let event = Event::new("append-body", text).id(*version).build();
+                let _unused = updates.send_to_lang(None, event).await;
+            } else if let Some(buffer) = append_buffer {
+                buffer.body.push_str(text);
+                buffer.version = *version;
+                if buffer.len() >= APPEND_BUFFER_CAP {
+                    flush_append_buffer(updates, coalescer, buffer).await;
+                }
+            } else if let Some(coalescer) = coalescer {
+                // Appends can't replace one another, but whole bodies can
+                coalescer.post("body", body_event(body, *version, *compression_threshold).build());
+            } else if let Some(mailbox) = mailbox {
+                mailbox.post(body_event(body, *version, *compression_threshold).build());
+            } else {
+                let event = Event::new("append-body", text).id(*version).build();
+                // We're ignoring this future because we don't care how many
+                // clients of the page there are
+                let _unused = updates.send_to_clients(event).await;
+            }
+            publish(backend, || Update::AppendBody{text: text.to_string()});
+        }
+    }
+
+    /// Tell all clients to append some text (not HTML) to the contents of the
+    /// element matching a CSS selector, without resending anything else, as
+    /// when streaming a response into a chat bubble piece by piece. All the
+    /// text streamed to each selector is remembered until `clear_element`, so
+    /// clients connecting later are sent all of it at once. Anything waiting
+    /// in the coalescer is sent first, so the text can't arrive before the
+    /// element it's appended to. This has no effect if the page is (currently)
+    /// static, and returns an error if the selector is empty.
+    pub async fn append_to(&mut self, selector: &str, text: &str) -> Result<(), String> {
+        if selector.trim().is_empty() {
+            return Err("Empty selector to append to".to_string())
+        }
+        if let Content::Dynamic{state: DynamicState{streams, ..}, updates, coalescer,
+                                append_buffer, modified, ..} = self {
+            streams.entry(selector.to_string()).or_default().push_str(text);
+            *modified = Instant::now();
+            if let Some(buffer) = append_buffer {
+                buffer.push_stream(selector, text);
+                if buffer.len() >= APPEND_BUFFER_CAP {
+                    flush_append_buffer(updates, coalescer, buffer).await;
+                }
+            } else {
+                let event = Event::new("append-to", append_to_event_data(selector, text)).build();
+                let mut frame = coalescer.as_ref().and_then(|coalescer| coalescer.take())
+                    .map_or_else(Vec::new, |pending| pending.to_vec());
+                frame.extend_from_slice(event.as_bytes());
+                // We're ignoring this future because we don't care how many
+                // clients there are
+                let _unused = updates.send_to_clients(frame).await;
+            }
+        }
+        self.compact_replay_state();
+        Ok(())
+    }
+
+    /// Tell all clients to empty the element matching a CSS selector, and
+    /// forget the text streamed to it by `append_to`. This has no effect if
+    /// the page is (currently) static.
+    pub async fn clear_element(&mut self, selector: &str) {
+        if let Content::Dynamic{state: DynamicState{streams, ..}, updates, append_buffer,
+                                modified, ..} = self {
+            streams.remove(selector);
+            if let Some(buffer) = append_buffer {
+                buffer.streams.retain(|(streamed, _)| streamed != selector);
+            }
+            *modified = Instant::now();
+            let event = EventBuilder::new(selector).event_type("clear-element").build();
+            let _unused = updates.send_to_clients(event).await;
+        }
+    }
+
+    /// Choose whether to hold back the text appended by `append_body` and
+    /// `append_to` until the next heartbeat, so that however many appends are
+    /// made in between, clients are sent them all together, in at most one
+    /// event for the body and one for each element (in the order they were
+    /// first appended to). This makes for far fewer events on a rapidly growing
+    /// log, at the cost of up to a heartbeat's delay. Held-back text is also
+    /// sent as soon as there's more than 64 KiB of it, before a new client
+    /// connects, and when the page shuts down; other updates aren't held back,
+    /// so may reach clients before appends made earlier. Turning this off sends
+    /// whatever was held back straight away. Appends to the body aren't held
+    /// back while the page has bodies in other languages. This has no effect if
+    /// the page is (currently) static.
+    pub async fn set_append_flush_on_heartbeat(&mut self, enabled: bool) {
+        if let Content::Dynamic{append_buffer, ..} = self {
+            match (enabled, append_buffer.is_some()) {
+                (true, false) => *append_buffer = Some(AppendBuffer::default()),
+                (false, true) => {
+                    self.flush_appends().await;
+                    if let Content::Dynamic{append_buffer, ..} = self {
+                        *append_buffer = None;
+                    }
+                },
+                _ => { },
+            }
+        }
+    }
+
+    /// Send all clients whatever appends have been held back, if any.
+    async fn flush_appends(&mut self) {
+        if let Content::Dynamic{updates, coalescer, append_buffer: Some(buffer), ..} = self {
+            flush_append_buffer(updates, coalescer, buffer).await;
+        }
+    }
+
+    /// Send all clients the latest body if the mailbox in front of body
+    /// updates is holding any, superseding them, so that nothing can be sent
+    /// an older body after whatever is sent next.
+    async fn flush_mailbox(&mut self) {
+        if let Content::Dynamic{state: DynamicState{body, version, compression_threshold, ..},
+                                updates, mailbox: Some(mailbox), ..} = self {
+            if mailbox.clear().await {
+                let event = body_event(body, *version, *compression_threshold).build();
+                let _unused = updates.send_to_clients(event).await;
+            }
+        }
+    }
+
+    /// Make a cursor over the body of a dynamic page, returned along with the
+    /// current body, which starts at the end of that body and yields each piece
+    /// of text appended to it after that (by `append_body`). If the body is
+    /// replaced rather than appended to, or the reader falls too far behind,
+    /// the cursor yields a reset, after which the reader should fetch the whole
+    /// body again. Every cursor keeps its own position. Returns `None` if the
+    /// page is (currently) static.
+    pub fn body_cursor(&mut self) -> Option<(String, BodyCursor)> {
+        match self {
+            Content::Dynamic{state: DynamicState{body, ..}, body_changes, ..} =>
+                Some((body.clone(), BodyCursor{changes: body_changes.subscribe()})),
+            Content::Static{..} => None,
+        }
+    }
+
+    /// Watch the body of a dynamic page from the server side: the stream yields
+    /// the current body right away, and then the whole body again after each
+    /// change. A watcher which falls behind skips to the latest body, rather
+    /// than seeing every one in between. Watchers aren't clients, so they
+    /// don't keep the page from being empty; a watcher is forgotten once its
+    /// stream is dropped, and the stream ends when the page stops being
+    /// dynamic. Returns `None` if the page is (currently) static.
+    pub fn watch_body(&mut self) -> Option<impl Stream<Item = String>> {
+        match self {
+            Content::Dynamic{state: DynamicState{body, ..}, body_watchers, ..} => {
+                let (watcher, bodies) = watch::channel(body.clone());
+                body_watchers.push(watcher);
+                Some(bodies)
+            },
+            Content::Static{..} => None,
+        }
+    }
+
+    /// Record whether a client of a dynamic page has local changes, like user
+    /// input into a form, which a body update could clobber. Clients start out
+    /// clean. If a client which missed a body update from `set_body_to_clean`
+    /// becomes clean, it's sent the current body. Returns `false` if the page
+    /// is static or there is no such client.
+    pub async fn set_client_dirty(&mut self, id: sse::ClientId, dirty: bool) -> bool {
+        match self {
+            Content::Dynamic{state: DynamicState{body, version, compression_threshold, ..},
+                             updates, ..} => {
+                match updates.set_dirty(id, dirty).await {
+                    Some(true) => {
+                        let event = body_event(body, *version, *compression_threshold).build();
+                        updates.send_to_client(id, event).await
+                    },
+                    Some(false) => true,
+                    None => false,
+                }
+            },
+            Content::Static{..} => false,
+        }
+    }
+
+    /// Change the body, like `set_body`, but only tell those clients which
+    /// haven't reported local changes (see `set_client_dirty`) to change it.
+    /// Dirty clients are sent a `refresh-available` event instead, and are
+    /// sent the new body later if they become clean (choosing to accept it).
+    /// This way, an update can't destroy a user's unsaved edits. Bodies still
+    /// waiting in the mailbox are dropped, since this supersedes them, and
+    /// whatever is waiting to be coalesced is sent first, so that nothing
+    /// older can overtake it.
+    pub async fn set_body_to_clean(&mut self, new_body: impl Into<String>) {
+        if let Content::Static{..} = self {
+            self.become_dynamic().await;
+        }
+        if let Content::Dynamic{state: DynamicState{body, version, attrs,
+                                compression_threshold, ..}, updates, modified, log, body_changes,
+                                body_watchers, mailbox, coalescer, backend, ..} = self {
+            let new_body = new_body.into();
+            if new_body != *body {
+                if let Some(mailbox) = mailbox {
+                    mailbox.clear().await;
+                }
+                if let Some(waiting) = coalescer.as_ref().and_then(|coalescer| coalescer.take()) {
+                    let _unused = updates.send_to_clients(waiting).await;
+                }
+                *body = new_body;
+                *version += 1;
+                *modified = Instant::now();
+                // Nobody may be watching the body, which is fine
+                body_changes.send(BodyChange::Reset).unwrap_or(0);
+                notify_watchers(body_watchers, body);
+                attrs.clear();
+                tracing::debug!(parent: log.span(), version = *version, "Sent body to clean clients");
+                let clean = body_event(body, *version, *compression_threshold).build();
+                let dirty = Event::new("refresh-available", ".").id(*version).build();
+                // We're ignoring this future because we don't care how many
+                // clients of the page there are
+                let _unused = updates.send_to_clean_clients(clean, dirty).await;
+                publish(backend, || Update::CleanBody{body: body.clone()});
+            }
+        }
+    }
+
+    /// Start recording every update sent to all clients of a page into a file
+    /// (replacing whatever it held before), with the time each was sent, so
+    /// the session can be played back later by `replay_recording`. Updates
+    /// sent only to some clients, like those in a room or a particular
+    /// language, aren't recorded. Recording stops with `stop_recording`, when
+    /// the page becomes static or shuts down, or if the file can't be written.
+    /// This has no effect if the page is (currently) static, and returns an
+    /// error if the file can't be created.
+    pub async fn start_recording(&mut self, path: &Path) -> Result<(), String> {
+        if let Content::Dynamic{updates, log, ..} = self {
+            let recorder = Recorder::create(path, log.span())?;
+            updates.set_recorder(Some(recorder)).await;
+        }
+        Ok(())
+    }
+
+    /// Stop recording updates, if recording, making sure everything recorded
+    /// so far is in the file. This has no effect if the page is (currently)
+    /// static.
+    pub async fn stop_recording(&mut self) {
+        if let Content::Dynamic{updates, ..} = self {
+            updates.set_recorder(None).await;
+        }
+    }
+
+    /// Make a new empty (dynamic) page which plays back a recording made by
+    /// `start_recording`, along with the future which does so: when it's
+    /// run, it sends every recorded update to the page's clients at the same
+    /// times, relative to its start, as they were originally sent. Because
+    /// updates are played back exactly as they were recorded, the page's own
+    /// title and body stay empty, so clients should connect before playback
+    /// starts to see everything. Returns an error if the recording can't be
+    /// read.
+    pub async fn replay_recording(path: &Path)
+                                  -> Result<(Content, impl Future<Output = ()>), String> {
+        let frames = recording::read_recording(path)?;
+        let content = Content::new().await;
+        let updates = match &content {
+            Content::Dynamic{updates, ..} => updates.clone(),
+            Content::Static{..} => unreachable!("New content is always dynamic"),
+        };
+        let playback = async move {
+            let start = time::Instant::now();
+            for (at, frame) in frames {
+                time::delay_until(start + at).await;
+                // We're ignoring this future because we don't care how many
+                // clients there are
+                let _unused = updates.send_to_clients(frame).await;
+            }
+        };
+        Ok((content, playback))
+    }
+
+    /// Get a dynamic page ready for the server to shut down, within the given
+    /// deadline: send every client the latest body if there are updates still
+    /// waiting in the mailbox (superseding them), then a `server-shutdown`
+    /// event, then disconnect them all. Afterwards, the content is closed, so
+    /// new clients can't connect to it. Returns `false` if clients didn't take
+    /// all this by the deadline, in which case the content should be dropped
+    /// anyway. This has no effect if the page is (currently) static.
+    pub async fn prepare_shutdown(&mut self, deadline: Instant) -> bool {
+        match self {
+            Content::Dynamic{state: DynamicState{body, version, compression_threshold, ..},
+                             updates, mailbox, coalescer, append_buffer, closed, ..} => {
+                *closed = true;
+                // Appends still held back go out with everything else
+                let appended = append_buffer.take().and_then(|mut buffer| buffer.take_frame());
+                let mailbox = mailbox.take();
+                // Whatever is waiting to be coalesced goes out now
+                let coalesced = coalescer.take().and_then(|coalescer| coalescer.take());
+                let flush = async {
+                    // The latest body supersedes whatever the mailbox was still
+                    // holding, if anything
+                    let pending = match &mailbox {
+                        Some(mailbox) => mailbox.clear().await,
+                        None => false,
+                    };
+                    if let Some(frame) = coalesced {
+                        updates.send_to_clients(frame).await.await;
+                    }
+                    if let Some(frame) = appended {
+                        updates.send_to_clients(frame).await.await;
+                    }
+                    if pending {
+                        let event = body_event(body, *version, *compression_threshold).build();
+                        updates.send_to_clients(event).await.await;
+                    }
+                    let event = EventBuilder::new(".").event_type("server-shutdown").build();
+                    updates.send_to_clients(event).await.await;
+                    updates.set_recorder(None).await;
+                    updates.disconnect_all().await;
+                };
+                time::timeout_at(time::Instant::from_std(deadline), flush).await.is_ok()
+            },
+            Content::Static{..} => true,
+        }
+    }
+}
+
+/// Hash some static contents, as 16 hex digits. This uses 64-bit FNV-1a rather
+/// than the standard library's hasher, because the standard hasher's output
+/// isn't guaranteed to be the same across builds, and these hashes end up in
+/// URLs cached by clients.
+fn hash_contents(contents: &[u8]) -> String {
+    let hash = contents.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// The characters to percent-encode in the UTF-8 name of a downloaded file: all
+/// but those allowed as they are (in RFC 5987).
+const FILENAME_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// Make the value of a `Content-Disposition` header for downloading a file
+/// under the given name: the name as plain ASCII (with anything else replaced
+/// by `_`) for old clients, and percent-encoded UTF-8 for the rest.
+fn attachment_disposition(filename: &str) -> HeaderValue {
+    let ascii: String = filename.chars()
+        .map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' { c } else { '_' })
+        .collect();
+    let encoded = utf8_percent_encode(filename, FILENAME_ENCODE_SET);
+    HeaderValue::from_str(&format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", ascii, encoded))
+        .expect("Internal error: Content-Disposition isn't a valid header value")
+}
+
+/// Escape a string for use as HTML text, like a title (which browsers show as
+/// it is, rather than parsing it as HTML).
+fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Escape a string for use as a double-quoted HTML attribute value.
+fn escape_attr_value(value: &str) -> String {
+    value.replace('&', "&amp;").replace('"', "&quot;")
+}
+
+/// Get whichever of the default body and the language bodies of a dynamic page
+/// best suits a client with the given `Accept-Language` header.
+pub fn body_for<'a>(body: &'a str,
+                    lang_bodies: &'a BTreeMap<String, String>,
+                    accept_language: Option<&str>) -> &'a str {
+    match accept_language.and_then(|accept| negotiate_lang(lang_bodies, accept)) {
+        Some(lang) => &lang_bodies[&lang],
+        None => body,
+    }
+}
+
+/// Choose which of a set of language bodies best suits an `Accept-Language`
+/// header, trying each language it accepts in order of preference (then in the
+/// order given), and for each one, first the language itself and then its
+/// primary subtag. Returns `None` if there's none in any accepted language.
+fn negotiate_lang(lang_bodies: &BTreeMap<String, String>, accept_language: &str) -> Option<String> {
+    if lang_bodies.is_empty() {
+        return None
+    }
+    let mut accepted: Vec<(String, f32)> = accept_language.split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let lang = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .next()
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            if lang.is_empty() || lang == "*" || quality <= 0.0 {
+                None
+            } else {
+                Some((lang, quality))
+            }
+        })
+        .collect();
+    // This sort is stable, so equally preferred languages stay in order
+    accepted.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+    accepted.into_iter().find_map(|(lang, _)| {
+        let primary = lang.split('-').next().unwrap_or(&lang);
+        if lang_bodies.contains_key(&lang) {
+            Some(lang)
+        } else if lang_bodies.contains_key(primary) {
+            Some(primary.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Tell every watcher of a body (see `Content::watch_body`) about its new value,
+/// forgetting those whose streams have been dropped.
+fn notify_watchers(watchers: &mut Vec<watch::Sender<String>>, body: &str) {
+    watchers.retain(|watcher| watcher.broadcast(body.to_string()).is_ok());
+}
+
+/// Send a frame which sets some piece of the state of a page to all its
+/// clients, through its coalescer if it has one.
+async fn send_state(updates: &sse::BufferedServer,
+                    coalescer: &Option<sse::Coalescer>,
+                    state: &str,
+                    frame: String) {
+    match coalescer {
+        Some(coalescer) => coalescer.post(state, frame),
+        None => {
+            // We're ignoring this future because we don't care how many
+            // clients there are
+            let _unused = updates.send_to_clients(frame).await;
+        },
+    }
+}
+
+/// Publish an update through a broadcast backend, if there is one. The update
+/// is only built if it's going to be published.
+fn publish(backend: &Option<Arc<dyn BroadcastBackend>>, update: impl FnOnce() -> Update) {
+    if let Some(backend) = backend {
+        backend.publish(Broadcast{origin: pubsub::origin().to_string(), update: update()});
+    }
+}
+
+/// Serialize a page's title and head metadata as the data of an `seo` event.
+fn seo_event_data(title: &str, description: &str, og: &BTreeMap<String, String>) -> String {
+    serde_json::to_string(&serde_json::json!({
+        "title": title,
+        "description": description,
+        "og": og,
+    })).expect("Serializing head metadata to JSON shouldn't fail")
+}
+
+/// Serialize the status of a step as the data of a `step` event.
+fn step_event_data(step: &str, status: &StepStatus) -> String {
+    serde_json::to_string(&serde_json::json!({
+        "step": step,
+        "status": status.as_str(),
+        "error": status.error(),
+    })).expect("Serializing step status to JSON shouldn't fail")
+}
+
+/// Serialize a set of validation errors as the data of a `validation` event.
+fn validation_event_data(validation: &HashMap<String, String>) -> String {
+    serde_json::to_string(validation)
+        .expect("Serializing validation errors to JSON shouldn't fail")
+}
+
+/// Check that an event type can be sent: it can't be empty, or contain a line
+/// break (which would corrupt the event stream).
+fn validate_event_type(event_type: &str) -> Result<(), String> {
+    if event_type.is_empty() || event_type.contains(&['\n', '\r'][..]) {
+        Err(format!("Invalid event type: {:?}", event_type))
+    } else {
+        Ok(())
+    }
+}
+
+/// Log a transition into or out of backpressure.
+fn log_backpressure(log: &ServerLog, state: sse::BackpressureState) {
+    match state {
+        sse::BackpressureState::Begin =>
+            log.log(LogLevel::Warn, "Updates are waiting for slow clients"),
+        sse::BackpressureState::End =>
+            log.log(LogLevel::Info, "Updates are no longer waiting for clients"),
+    }
+}
+
+/// A bounded cache of what was extracted from static content for particular
+/// parameters (the start and end of a line range), which evicts the least
+/// recently used entry when full.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderCache {
+    capacity: usize,
+    /// The cached entries, least recently used first.
+    entries: VecDeque<((usize, usize), Vec<u8>)>,
+}
+
+impl RenderCache {
+    fn new(capacity: usize) -> RenderCache {
+        RenderCache{capacity, entries: VecDeque::with_capacity(capacity)}
+    }
+
+    /// Get the cached entry for some parameters, marking it most recently used.
+    fn get(&mut self, key: (usize, usize)) -> Option<Vec<u8>> {
+        let index = self.entries.iter().position(|(k, _)| *k == key)?;
+        let entry = self.entries.remove(index)?;
+        let value = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(value)
+    }
+
+    fn insert(&mut self, key: (usize, usize), value: Vec<u8>) {
+        if self.capacity == 0 {
+            return
+        }
+        self.entries.retain(|(k, _)| *k != key);
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, value));
+    }
+
+    fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// A title made from a template with named slots, set by
+/// `Content::set_title_template`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TitleTemplate {
+    template: String,
+    /// The values of the slots which have been filled in.
+    slots: BTreeMap<String, String>,
+}
+
+impl TitleTemplate {
+    fn new(template: &str) -> TitleTemplate {
+        TitleTemplate{template: template.to_string(), slots: BTreeMap::new()}
+    }
+
+    /// Split the template into its pieces of text and slots.
+    fn pieces(&self) -> Vec<TitlePiece<'_>> {
+        let mut pieces = Vec::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            let after = &rest[start + 1..];
+            let slot = after.find('}').map(|end| &after[..end]).filter(|name| {
+                !name.is_empty()
+                    && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+            });
+            match slot {
+                Some(name) => {
+                    pieces.push(TitlePiece::Text(&rest[..start]));
+                    pieces.push(TitlePiece::Slot(name));
+                    rest = &after[name.len() + 1..];
+                },
+                None => {
+                    pieces.push(TitlePiece::Text(&rest[..=start]));
+                    rest = after;
+                },
+            }
+        }
+        pieces.push(TitlePiece::Text(rest));
+        pieces
+    }
+
+    fn has_slot(&self, name: &str) -> bool {
+        self.pieces().contains(&TitlePiece::Slot(name))
+    }
+
+    /// Fill in the slots of the template, leaving those without values empty.
+    fn render(&self) -> String {
+        self.pieces().into_iter()
+            .map(|piece| match piece {
+                TitlePiece::Text(text) => text,
+                TitlePiece::Slot(name) => self.slots.get(name).map_or("", String::as_str),
+            })
+            .collect()
+    }
+
+    /// Serialize this template and its slots as the data of a `title-template`
+    /// event.
+    fn event_data(&self) -> String {
+        serde_json::to_string(&serde_json::json!({
+            "template": self.template,
+            "slots": self.slots,
+        })).expect("Serializing title template to JSON shouldn't fail")
+    }
+}
+
+/// A piece of a `TitleTemplate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TitlePiece<'a> {
+    Text(&'a str),
+    /// A slot, by name.
+    Slot(&'a str),
+}
+
+/// Serialize the value of a slot of the title template as the data of a
+/// `title-slot` event.
+fn title_slot_event_data(name: &str, value: &str) -> String {
+    serde_json::to_string(&serde_json::json!({"name": name, "value": value}))
+        .expect("Serializing title slot to JSON shouldn't fail")
+}
+
+/// How far behind each client of a page has said it is in rendering updates,
+/// for adjusting the coalescing window to suit, as turned on by
+/// `Content::enable_adaptive_rate`.
+#[derive(Debug, Clone)]
+pub struct AdaptiveRate {
+    target_lag: Duration,
+    /// The last lag reported by each client, with when it was reported.
+    lags: HashMap<sse::ClientId, (Duration, Instant)>,
+}
+
+impl AdaptiveRate {
+    fn new(target_lag: Duration) -> AdaptiveRate {
+        AdaptiveRate{target_lag, lags: HashMap::new()}
+    }
+
+    /// Record the lag reported by a client, forgetting reports too old to
+    /// count, and return the average lag of all the clients which have
+    /// reported recently.
+    fn report(&mut self, id: sse::ClientId, lag: Duration) -> Duration {
+        let now = Instant::now();
+        self.lags.insert(id, (lag, now));
+        self.lags.retain(|_, (_, reported)| now.duration_since(*reported) < LAG_REPORT_LIFETIME);
+        let total: Duration = self.lags.values().map(|(lag, _)| *lag).sum();
+        total / self.lags.len() as u32
+    }
+
+    /// Choose a new coalescing window given the current one and the average
+    /// lag, or return `None` if it should stay as it is.
+    fn adjust(&self, window: Duration, average: Duration) -> Option<Duration> {
+        let new_window = if average > self.target_lag {
+            (window * 2).max(MIN_ADAPTIVE_WINDOW).min(MAX_ADAPTIVE_WINDOW)
+        } else if average < self.target_lag / 2 && window / 2 >= MIN_ADAPTIVE_WINDOW {
+            window / 2
+        } else if average < self.target_lag / 2 {
+            Duration::from_millis(0)
+        } else {
+            window
+        };
+        if new_window != window { Some(new_window) } else { None }
+    }
+}
+
+/// The appends held back until the next heartbeat by
+/// `Content::set_append_flush_on_heartbeat`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AppendBuffer {
+    /// The text appended to the body.
+    body: String,
+    /// The version of the body after the last of those appends.
+    version: u64,
+    /// The text appended to each element, by selector, in the order they were
+    /// first appended to.
+    streams: Vec<(String, String)>,
+}
+
+impl AppendBuffer {
+    /// The number of bytes of text held back.
+    fn len(&self) -> usize {
+        self.body.len() + self.streams.iter().map(|(_, text)| text.len()).sum::<usize>()
+    }
+
+    fn push_stream(&mut self, selector: &str, text: &str) {
+        match self.streams.iter_mut().find(|(streamed, _)| streamed == selector) {
+            Some((_, streamed)) => streamed.push_str(text),
+            None => self.streams.push((selector.to_string(), text.to_string())),
+        }
+    }
+
+    /// Empty the buffer, returning the events for everything that was in it
+    /// as a single frame, or `None` if there was nothing in it. The body comes
+    /// first, since the text appended to elements may be meant for elements
+    /// appended to the body.
+    fn take_frame(&mut self) -> Option<Vec<u8>> {
+        let mut frame = Vec::new();
+        if !self.body.is_empty() {
+            let body = mem::take(&mut self.body);
+            frame.extend_from_slice(Event::new("append-body", body).id(self.version).build().as_bytes());
+        }
+        for (selector, text) in self.streams.drain(..) {
+            let event = Event::new("append-to", append_to_event_data(&selector, &text)).build();
+            frame.extend_from_slice(event.as_bytes());
+        }
+        if frame.is_empty() { None } else { Some(frame) }
+    }
+}
+
+/// Send all clients whatever is in an append buffer, after anything waiting in
+/// the coalescer (so the text can't arrive before the elements it's appended
+/// to).
+async fn flush_append_buffer(updates: &sse::BufferedServer,
+                             coalescer: &Option<sse::Coalescer>,
+                             buffer: &mut AppendBuffer) {
+    if let Some(appended) = buffer.take_frame() {
+        let mut frame = coalescer.as_ref().and_then(|coalescer| coalescer.take())
+            .map_or_else(Vec::new, |pending| pending.to_vec());
+        frame.extend_from_slice(&appended);
+        // We're ignoring this future because we don't care how many clients
+        // there are
+        let _unused = updates.send_to_clients(frame).await;
+    }
+}
+
+/// A persistent message sent by `Content::send_to_parent`, kept so it can be
+/// replayed to new clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParentMessage {
+    data: String,
+    origin: String,
+}
+
+impl ParentMessage {
+    /// Serialize this message as the data of a `parent-message` event.
+    fn event_data(&self, channel: &str) -> String {
+        serde_json::to_string(&serde_json::json!({
+            "channel": channel,
+            "data": self.data,
+            "origin": self.origin,
+        })).expect("Serializing message to parent window to JSON shouldn't fail")
+    }
+}
+
+/// A custom event sent by `Content::send_event_ttl`, kept until it expires so
+/// it can be replayed to new clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedEvent {
+    event_type: String,
+    data: String,
+    expires: Instant,
+}
+
+impl TimedEvent {
+    /// Build the `timed-event` event for this, with the TTL remaining as of the
+    /// given time, or `None` if it has expired by then.
+    fn event(&self, now: Instant) -> Option<Event> {
+        let remaining = self.expires.checked_duration_since(now)
+            .filter(|remaining| *remaining > Duration::from_millis(0))?;
+        let data = serde_json::to_string(&serde_json::json!({
+            "type": self.event_type,
+            "data": self.data,
+            // Round up, so an event with any time left doesn't expire at once
+            "ttl": (remaining + Duration::from_nanos(999_999)).as_millis() as u64,
+        })).expect("Serializing timed event to JSON shouldn't fail");
+        Some(Event::new("timed-event", data))
+    }
+}
+
+/// An event to be sent to the clients of a dynamic page, before it's serialized
+/// into the `text/event-stream` format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Event {
+    event_type: String,
+    data: String,
+    id: Option<String>,
+}
+
+impl Event {
+    fn new(event_type: impl Into<String>, data: impl Into<String>) -> Event {
+        Event{event_type: event_type.into(), data: data.into(), id: None}
+    }
+
+    fn id(self, id: impl ToString) -> Event {
+        Event{id: Some(id.to_string()), ..self}
+    }
+
+    /// Serialize the event in the `text/event-stream` format.
+    fn build(&self) -> String {
+        let event = EventBuilder::new(&self.data).event_type(&self.event_type);
+        match &self.id {
+            Some(id) => event.id(id).build(),
+            None => event.build(),
+        }
+    }
+
+    fn preview(self) -> PreviewFrame {
+        let frame = self.build();
+        PreviewFrame{event_type: self.event_type, frame}
+    }
+}
+
+/// Build the event which sets a page's title, tagged with the page version.
+fn title_event(title: &str, version: u64) -> Event {
+    if !title.is_empty() {
+        Event::new("title", title)
+    } else {
+        Event::new("clear-title", ".")
+    }.id(version)
+}
+
+/// Build the event which sets a page's body, tagged with the page version. If
+/// there's a compression threshold and the body is longer than it, the body is
+/// sent compressed.
+fn body_event(body: &str, version: u64, compression_threshold: Option<usize>) -> Event {
+    if body.is_empty() {
+        Event::new("clear-body", ".")
+    } else if let Some(compressed) =
+        compression_threshold.and_then(|threshold| compress_above(body, threshold)) {
+            Event::new("body:gzip", compressed)
+    } else {
+        Event::new("body", body)
+    }.id(version)
+}
+
+/// If some data is longer than the threshold, gzip it and encode the result in
+/// base64 (so it can be sent as event data); otherwise return `None`.
+fn compress_above(data: &str, threshold: usize) -> Option<String> {
+    if data.len() <= threshold {
+        return None
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data.as_bytes())
+        .expect("Internal error: compressing into a Vec<u8> failed");
+    let compressed = encoder.finish()
+        .expect("Internal error: compressing into a Vec<u8> failed");
+    Some(base64::encode(&compressed))
+}
+
+/// Serialize the data of a `query` event.
+fn query_event_data(query: &str, replace: bool) -> String {
+    serde_json::to_string(&serde_json::json!({"query": query, "replace": replace}))
+        .expect("Serializing query event to JSON shouldn't fail")
+}
+
+/// Serialize a page's capabilities as the data of a `ready` event.
+fn ready_event_data(capabilities: &[&str]) -> String {
+    serde_json::to_string(&serde_json::json!({"capabilities": capabilities}))
+        .expect("Serializing capabilities to JSON shouldn't fail")
+}
+
+/// Serialize an attribute set on an element as the data of a `set-attr` event.
+fn attr_event_data(selector: &str, name: &str, value: Option<&str>) -> String {
+    serde_json::to_string(&serde_json::json!({
+        "selector": selector,
+        "name": name,
+        "value": value,
+    })).expect("Serializing element attribute to JSON shouldn't fail")
+}
+
+/// Serialize a persistent client-side value as the data of a `persistent`
+/// event.
+fn persistent_event_data(key: &str, data: &str) -> String {
+    serde_json::to_string(&serde_json::json!({"key": key, "data": data}))
+        .expect("Serializing persistent value to JSON shouldn't fail")
+}
+
+/// Serialize some text streamed to an element as the data of an `append-to`
+/// event.
+fn append_to_event_data(selector: &str, text: &str) -> String {
+    serde_json::to_string(&serde_json::json!({"selector": selector, "text": text}))
+        .expect("Serializing streamed text to JSON shouldn't fail")
+}
+
+/// Check that a string is a target origin which `postMessage` accepts: either
+/// `*`, or a scheme, host, and optional port, with no path.
+fn validate_origin(origin: &str) -> Result<(), String> {
+    let invalid = || Err(format!("Invalid origin (must be * or like https://example.com): {}", origin));
+    if origin == "*" {
+        return Ok(())
+    }
+    let (scheme, host) = match origin.find("://") {
+        Some(index) => (&origin[..index], &origin[index + 3..]),
+        None => return invalid(),
+    };
+    let scheme_valid = matches!(scheme.chars().next(), Some(c) if c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.');
+    let host_valid = !host.is_empty()
+        && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == ':'
+                            || c == '[' || c == ']');
+    if scheme_valid && host_valid { Ok(()) } else { invalid() }
+}
+
+/// Check that a string is a URL which clients can be told to reconnect to for a
+/// page's updates: either a path (starting with a single `/`), or an absolute
+/// `http` or `https` URL with a host, without whitespace, control characters,
+/// or a fragment.
+fn validate_reconnect_url(url: &str) -> Result<(), String> {
+    let invalid = || Err(format!("Invalid URL to reconnect to (must be a path, or an http or https URL): {}", url));
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) || url.contains('#') {
+        return invalid()
+    }
+    if url.starts_with('/') && !url.starts_with("//") {
+        return Ok(())
+    }
+    let host = match url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")) {
+        Some(rest) => rest.split(['/', '?']).next().unwrap_or(""),
+        None => return invalid(),
+    };
+    if host.is_empty() { invalid() } else { Ok(()) }
+}
+
+/// Check that a string is a well-formed URL query string (without its leading
+/// `?`): it must consist only of printable ASCII characters permitted in a URL
+/// query, every `%` must begin a valid percent-escape, and no `&`-separated
+/// parameter may have an empty key. The empty string is a valid (empty) query.
+fn validate_query(query: &str) -> Result<(), String> {
+    let bytes = query.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                if bytes.len() < i + 3
+                    || !bytes[i + 1].is_ascii_hexdigit()
+                    || !bytes[i + 2].is_ascii_hexdigit() {
+                        return Err(format!("Invalid percent-escape in query: {}", query))
+                    }
+                i += 3;
+                continue;
+            },
+            b'#' | b'"' | b'<' | b'>' | b'\\' | b'^' | b'`' | b'{' | b'|' | b'}' =>
+                return Err(format!("Invalid character in query: {}", query)),
+            c if !c.is_ascii_graphic() =>
+                return Err(format!("Invalid character in query: {}", query)),
+            _ => { },
+        }
+        i += 1;
+    }
+    if !query.is_empty() && query.split('&').any(|param| param.split('=').next() == Some("")) {
+        return Err(format!("Empty parameter name in query: {}", query))
+    }
+    Ok(())
+}
+
+/// Serialize the complete set of root attributes of a page as the data of a
+/// `root-attrs` event, which tells clients to set exactly these attributes.
+fn root_attrs_event_data(root_attrs: &BTreeMap<(RootElement, String), String>) -> String {
+    let mut elements = serde_json::Map::new();
+    for element in &[RootElement::Html, RootElement::Body] {
+        let attrs: serde_json::Map<String, serde_json::Value> = root_attrs.iter()
+            .filter(|((e, _), _)| e == element)
+            .map(|((_, name), value)| (name.clone(), value.clone().into()))
+            .collect();
+        elements.insert(element.as_str().to_string(), attrs.into());
+    }
+    serde_json::to_string(&elements)
+        .expect("Serializing root attributes to JSON shouldn't fail")
+}
+
+/// Check that a key for a persistent client-side value is non-empty and makes a
+/// legal attribute name after `data-myxine-`.
+fn validate_persistent_key(key: &str) -> Result<(), String> {
+    if key.is_empty() || validate_attr_name(&format!("data-myxine-{}", key)).is_err() {
+        return Err(format!("Invalid persistent key: {:?}", key))
+    }
+    Ok(())
+}
+
+/// Check that a string is a legal HTML attribute name, which can be set from
+/// JavaScript: it must be non-empty, start with a letter, `_`, or `:`, and
+/// consist only of letters, digits, `-`, `_`, `.`, and `:`.
+fn validate_attr_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let legal = match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' || c == ':' =>
+            chars.all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.' || c == ':'),
+        _ => false,
+    };
+    if legal {
+        Ok(())
+    } else {
+        Err(format!("Invalid attribute name: {:?}", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::sse::testing::{read_for, read_slowly, read_until};
+    use std::io::Read as _;
+
+    #[tokio::test]
+    async fn rendered_titles_are_escaped() {
+        let mut content = Content::new().await;
+        content.set_title("</title><script>&").await;
+        let document = String::from_utf8(content.prerendered_document(None)).unwrap();
+        assert!(document.contains("<title>&lt;/title&gt;&lt;script&gt;&amp;</title>"));
+    }
+
+    #[tokio::test]
+    async fn previews_of_the_same_title_replace_a_template() {
+        let mut content = Content::new().await;
+        content.set_title("Inbox").await;
+        assert!(content.preview_set_title("Inbox").is_empty());
+        // The template shows the same title, but clients have to be told to drop it
+        content.set_title_template("Inbox").await;
+        assert_eq!(content.preview_set_title("Inbox").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn line_ranges_count_as_fetches() {
+        let mut content = Content::new().await;
+        content.set_static(None, "one\ntwo\nthree\n").await;
+        assert_eq!(content.static_lines(2, 2), Some(b"two\n".to_vec()));
+        // The second time it's cached, but still fetched
+        assert_eq!(content.static_lines(2, 2), Some(b"two\n".to_vec()));
+        assert!(content.metrics().contains(&(&metrics::STATIC_FETCHES, 2)));
+        assert!(content.last_client_activity().is_some());
+    }
+
+    #[tokio::test]
+    async fn line_ranges_can_run_to_the_largest_line_number() {
+        let mut content = Content::new().await;
+        content.set_static(None, "one\ntwo\nthree\n").await;
+        assert_eq!(content.static_lines(2, usize::MAX), Some(b"two\nthree\n".to_vec()));
+        assert_eq!(content.static_lines(usize::MAX, usize::MAX), Some(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn cached_line_ranges_are_dropped_when_the_content_changes() {
+        let mut content = Content::new().await;
+        content.set_static(None, "one\ntwo\n").await;
+        content.set_render_cache_size(4);
+        assert_eq!(content.static_lines(1, 1), Some(b"one\n".to_vec()));
+        content.set_static(None, "uno\ndos\n").await;
+        assert_eq!(content.static_lines(1, 1), Some(b"uno\n".to_vec()));
+        assert!(matches!(content, Content::Static{render_cache: RenderCache{capacity: 4, ..}, ..}));
+    }
+
+    #[tokio::test]
+    async fn shutdown_resends_the_body_only_if_the_mailbox_held_one() {
+        let mut content = Content::new().await;
+        content.enable_mailbox(4).await;
+        let (_id, mut updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        read_until(&mut updates, "event: connected").await;
+        content.set_body("<p>Posted</p>").await;
+        read_until(&mut updates, "<p>Posted</p>").await;
+
+        let reader = tokio::spawn(async move {
+            read_until(&mut updates, "event: server-shutdown").await
+        });
+        let deadline = Instant::now() + Duration::from_secs(1);
+        assert!(content.prepare_shutdown(deadline).await);
+        let received = reader.await.unwrap();
+        assert!(!received.contains("event: body"), "Body sent again: {:?}", received);
+    }
+
+    #[tokio::test]
+    async fn shutdown_respects_the_deadline_despite_a_slow_client() {
+        let mut pages = Vec::new();
+        let mut readers = Vec::new();
+        for n in 0..20 {
+            let mut content = Content::new().await;
+            content.set_body(format!("<p>{}</p>", n)).await;
+            let (_id, mut updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+            pages.push(content);
+            readers.push(async move {
+                // The first page's only client never reads anything
+                if n > 0 {
+                    read_until(&mut updates, "event: server-shutdown").await;
+                }
+                updates
+            });
+        }
+        let readers = tokio::spawn(futures::future::join_all(readers));
+
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(300);
+        let finished = futures::future::join_all(pages.iter_mut().map(|page| async move {
+            time::timeout_at(time::Instant::from_std(deadline), page.prepare_shutdown(deadline))
+                .await.unwrap_or(false)
+        })).await;
+        assert!(started.elapsed() < Duration::from_millis(600));
+        assert!(!finished[0]);
+        assert!(finished[1..].iter().all(|finished| *finished));
+        readers.await.unwrap();
+    }
+
+    /// Get the data of every body event in what a client was sent.
+    fn bodies(received: &str) -> Vec<&str> {
+        received.split("\n\n")
+            .filter(|event| event.lines().any(|line| line == "event: body"))
+            .filter_map(|event| event.lines().find_map(|line| line.strip_prefix("data: ")))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn only_bodies_above_the_threshold_are_compressed() {
+        let mut content = Content::new().await;
+        content.set_event_compression_threshold(Some(64));
+        let (_id, mut updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        read_until(&mut updates, "event: connected").await;
+
+        content.set_body("<p>Small</p>").await;
+        let received = read_until(&mut updates, "<p>Small</p>").await;
+        assert_eq!(bodies(&received), vec!["<p>Small</p>"]);
+
+        let large = format!("<p>{}</p>", "Large ".repeat(100));
+        content.set_body(large.clone()).await;
+        let received = read_until(&mut updates, "event: body:gzip").await;
+        let compressed = received.lines()
+            .skip_while(|line| *line != "event: body:gzip")
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&base64::decode(compressed).unwrap()[..])
+            .read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, large);
+        assert!(bodies(&received).is_empty());
+    }
+
+    #[tokio::test]
+    async fn stuck_clients_are_disconnected_without_stalling_the_rest() {
+        let mut content = Content::new().await;
+        content.set_stuck_client_timeout(Some(Duration::from_millis(100))).await;
+        let (_id, _stuck) = content.update_stream(Default::default(), None, None).await.unwrap();
+        let (_id, updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        let reader = read_slowly(updates, Duration::from_millis(0));
+
+        let started = Instant::now();
+        for n in 1..=20 {
+            content.set_body(format!("<p>{}</p>", n)).await;
+        }
+        assert!(started.elapsed() < Duration::from_millis(500));
+        let samples = content.metrics();
+        assert!(samples.contains(&(&metrics::STUCK_DISCONNECTS, 1)));
+        assert!(samples.contains(&(&metrics::CLIENTS, 1)));
+
+        content.prepare_shutdown(Instant::now() + Duration::from_secs(1)).await;
+        let received = reader.await.unwrap();
+        let expected: Vec<String> = (1..=20).map(|n| format!("<p>{}</p>", n)).collect();
+        assert_eq!(bodies(&received), expected);
+    }
+
+    #[tokio::test]
+    async fn changes_within_the_window_are_sent_as_one_frame() {
+        let mut content = Content::new().await;
+        content.set_coalesce_window(Duration::from_millis(100)).await;
+        let (_id, mut updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        read_until(&mut updates, "event: connected").await;
+        for n in 1..=10 {
+            content.set_title(format!("Title {}", n)).await;
+            content.set_body(format!("<p>{}</p>", n)).await;
+        }
+        let received = read_for(&mut updates, Duration::from_millis(300)).await;
+        assert_eq!(bodies(&received), vec!["<p>10</p>"]);
+        assert_eq!(received.matches("event: title").count(), 1);
+        assert!(received.contains("data: Title 10"));
+    }
+
+    #[tokio::test]
+    async fn appends_between_heartbeats_are_sent_as_one_event() {
+        let mut content = Content::new().await;
+        content.set_append_flush_on_heartbeat(true).await;
+        let (_id, mut updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        read_until(&mut updates, "event: connected").await;
+        let reader = read_slowly(updates, Duration::from_millis(0));
+
+        let mut appended = String::new();
+        for n in 1..=100 {
+            let text = format!("{} ", n);
+            content.append_body(&text).await;
+            content.append_to("#log", &text).await.unwrap();
+            appended.push_str(&text);
+        }
+        content.send_heartbeat(Duration::from_secs(0)).await;
+        content.prepare_shutdown(Instant::now() + Duration::from_secs(1)).await;
+        let received = reader.await.unwrap();
+        assert_eq!(received.matches("event: append-body").count(), 1);
+        assert_eq!(received.matches("event: append-to").count(), 1);
+        assert!(received.contains(&format!("data: {}\n", appended)), "{:?}", received);
+    }
+
+    #[tokio::test]
+    async fn new_clients_see_the_same_page_after_compaction() {
+        let mut content = Content::new().await;
+        let body = "<div id=\"log\"></div><p id=\"x\">Hi</p>";
+        content.set_body(body).await;
+        content.append_to("#log", "a < b").await.unwrap();
+        content.set_attr("#x", "class", Some("big")).await.unwrap();
+        let (_id, mut before) = content.update_stream(Default::default(), None, None).await.unwrap();
+        let received = read_until(&mut before, "event: connected").await;
+        assert_eq!(bodies(&received), vec![body]);
+        assert!(received.contains("event: append-to") && received.contains("event: set-attr"));
+
+        content.set_max_replay_state(Some(0));
+        let (_id, mut after) = content.update_stream(Default::default(), None, None).await.unwrap();
+        let received = read_for(&mut after, Duration::from_millis(200)).await;
+        let expected = compact::append_text(body, "log", "a < b")
+            .and_then(|body| compact::set_attr(&body, "x", "class", Some("big")))
+            .unwrap();
+        assert_eq!(bodies(&received), vec![expected.as_str()]);
+        assert!(!received.contains("event: append-to"), "{:?}", received);
+        assert!(!received.contains("event: set-attr"), "{:?}", received);
+    }
+
+    #[tokio::test]
+    async fn invalid_batches_change_nothing() {
+        let mut content = Content::new().await;
+        content.set_title("Old").await;
+        content.set_body("<p>Old</p>").await;
+        content.set_validator(Validator{forbid_scripts: true, ..Validator::default()});
+        let (_id, mut updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        read_until(&mut updates, "event: connected").await;
+        let version = content.version();
+
+        let result = content.apply_validated(vec![
+            Op::Title("New".to_string()),
+            Op::RootAttr{element: "body".to_string(), name: "class".to_string(), value: Some("new".to_string())},
+            Op::Body("<p>New</p><script>alert(1)</script>".to_string()),
+        ]).await;
+        assert_eq!(result.map_err(|error| error.index), Err(2));
+        assert_eq!(content.version(), version);
+        assert_eq!(read_for(&mut updates, Duration::from_millis(200)).await, "");
+
+        let (_id, mut updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        let received = read_until(&mut updates, "event: connected").await;
+        assert_eq!(bodies(&received), vec!["<p>Old</p>"]);
+        assert!(received.contains("data: Old\n") && !received.contains("New") && !received.contains("class"),
+                "{:?}", received);
+    }
+
+    #[tokio::test]
+    async fn body_cursors_read_appends_until_the_body_is_replaced() {
+        let mut content = Content::new().await;
+        content.set_body("<p>Start</p>").await;
+        let (body, mut first) = content.body_cursor().unwrap();
+        assert_eq!(body, "<p>Start</p>");
+        content.append_body("<p>One</p>").await;
+        let (body, mut second) = content.body_cursor().unwrap();
+        assert_eq!(body, "<p>Start</p><p>One</p>");
+        content.append_body("<p>Two</p>").await;
+        content.set_body("<p>Replaced</p>").await;
+
+        let appended = |text: &str| Some(BodyChange::Appended(text.into()));
+        assert_eq!(first.next().await, appended("<p>One</p>"));
+        assert_eq!(first.next().await, appended("<p>Two</p>"));
+        assert_eq!(first.next().await, Some(BodyChange::Reset));
+        assert_eq!(second.next().await, appended("<p>Two</p>"));
+        assert_eq!(second.next().await, Some(BodyChange::Reset));
+
+        content.set_static(None, "Static").await;
+        assert_eq!(first.next().await, None);
+        assert!(content.body_cursor().is_none());
+    }
+
+    #[tokio::test]
+    async fn clients_reconnecting_after_becoming_dynamic_see_the_new_content() {
+        let mut content = Content::new().await;
+        content.set_static(None, "Static").await;
+        let before = Instant::now();
+        assert!(!content.was_refreshed_since(before));
+        content.set_title("Title").await;
+        content.set_body("<p>Body</p>").await;
+        assert!(content.was_refreshed_since(before));
+        assert!(content.metrics().contains(&(&metrics::SINCE_REFRESH, 0)));
+
+        let (_id, mut updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        let received = read_until(&mut updates, "event: connected").await;
+        assert!(received.contains("event: title\ndata: Title\n"), "{:?}", received);
+        assert_eq!(bodies(&received), vec!["<p>Body</p>"]);
+        content.set_body("<p>Live</p>").await;
+        read_until(&mut updates, "<p>Live</p>").await;
+    }
+
+    #[tokio::test]
+    async fn replay_snapshots_are_what_new_clients_are_sent() {
+        let mut content = Content::new().await;
+        content.set_title("Title").await;
+        content.set_body("<p>Body</p>").await;
+        content.set_attr("p", "class", Some("big")).await.unwrap();
+        let snapshot = content.replay_snapshot();
+        assert!(snapshot.contains(&("title".to_string(), "Title".to_string())), "{:?}", snapshot);
+
+        let (_id, mut updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        let received = read_until(&mut updates, "event: connected").await;
+        let replayed = received.split("\n\n")
+            .skip_while(|frame| !frame.starts_with("event: client-id\n"))
+            .skip(1)
+            .take_while(|frame| !frame.starts_with("event: connected\n"))
+            // Versions aren't part of the snapshot
+            .flat_map(|frame| frame.lines().filter(|line| !line.starts_with("id: ")).chain(Some("")))
+            .map(|line| line.to_string() + "\n")
+            .collect::<String>();
+        let expected = snapshot.iter()
+            .map(|(event_type, data)| EventBuilder::new(data).event_type(event_type).build())
+            .collect::<String>();
+        assert_eq!(replayed, expected);
+    }
+
+    /// A broadcast backend which keeps everything published through it.
+    #[derive(Debug, Default)]
+    struct Published(std::sync::Mutex<Vec<Update>>);
+
+    impl BroadcastBackend for Published {
+        fn publish(&self, broadcast: Broadcast) {
+            self.0.lock().unwrap().push(broadcast.update);
+        }
+    }
+
+    #[tokio::test]
+    async fn clean_bodies_arent_overtaken_by_older_ones() {
+        let mut content = Content::new().await;
+        let published = Arc::new(Published::default());
+        content.set_broadcast_backend(Some(published.clone()));
+        let (_id, mut updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        read_until(&mut updates, "event: connected").await;
+        content.set_coalesce_window(Duration::from_millis(100)).await;
+        content.set_body("<p>Old</p>").await;
+        content.set_body_to_clean("<p>New</p>").await;
+        let received = read_until(&mut updates, "<p>New</p>").await;
+        assert!(received.contains("<p>Old</p>"), "{}", received);
+        let received = read_for(&mut updates, Duration::from_millis(200)).await;
+        assert!(!received.contains("<p>Old</p>"), "{}", received);
+        assert_eq!(published.0.lock().unwrap().last(),
+                   Some(&Update::CleanBody{body: "<p>New</p>".to_string()}));
+    }
+
+    #[tokio::test]
+    async fn new_clients_are_greeted_alone() {
+        let mut content = Content::new().await;
+        content.set_title("Title").await;
+        content.set_body("<p>Body</p>").await;
+        let (_id, mut first) = content.update_stream(Default::default(), None, None).await.unwrap();
+        read_until(&mut first, "event: connected").await;
+
+        // Neither of these clients reads anything before the next connects
+        let (_id, mut second) = time::timeout(Duration::from_secs(1), async {
+            let (_id, _unread) = content.update_stream(Default::default(), None, None).await.unwrap();
+            content.update_stream(Default::default(), None, None).await.unwrap()
+        }).await.expect("Greeting a client waited for another to read");
+        let received = read_until(&mut second, "event: connected").await;
+        assert!(received.starts_with("event: ready\n"), "{:?}", received);
+        assert!(received.contains("event: title\ndata: Title\n"), "{:?}", received);
+        assert_eq!(bodies(&received), vec!["<p>Body</p>"]);
+        let received = read_for(&mut first, Duration::from_millis(100)).await;
+        assert!(!received.contains("event: "), "{:?}", received);
+
+        content.set_body("<p>Live</p>").await;
+        read_until(&mut first, "<p>Live</p>").await;
+        read_until(&mut second, "<p>Live</p>").await;
+    }
+
+    #[tokio::test]
+    async fn forks_and_their_originals_stay_independent() {
+        let mut original = Content::new().await;
+        original.set_title("Original").await;
+        original.set_body("<p>Shared</p>").await;
+        original.set_event_compression_threshold(Some(1024));
+        original.set_heartbeat_throttle(false);
+        let (_id, mut original_updates) =
+            original.update_stream(Default::default(), None, None).await.unwrap();
+        read_until(&mut original_updates, "event: connected").await;
+
+        let mut fork = original.fork().await;
+        assert!(matches!(fork, Content::Dynamic{
+            state: DynamicState{compression_threshold: Some(1024), ..},
+            heartbeat_throttle: true, ..
+        }));
+        let (_id, mut fork_updates) =
+            fork.update_stream(Default::default(), None, None).await.unwrap();
+        let received = read_until(&mut fork_updates, "event: connected").await;
+        assert!(received.contains("event: title\ndata: Original\n"), "{:?}", received);
+        assert_eq!(bodies(&received), vec!["<p>Shared</p>"]);
+
+        fork.set_title("Fork").await;
+        original.set_body("<p>Changed</p>").await;
+        let received = read_until(&mut original_updates, "<p>Changed</p>").await;
+        assert!(!received.contains("Fork"), "{:?}", received);
+        let received = read_until(&mut fork_updates, "data: Fork").await;
+        assert!(!received.contains("Changed"), "{:?}", received);
+        let received = read_for(&mut original_updates, Duration::from_millis(100)).await;
+        assert!(!received.contains("Fork"), "{:?}", received);
+        let received = read_for(&mut fork_updates, Duration::from_millis(100)).await;
+        assert!(!received.contains("Changed"), "{:?}", received);
+        assert!(matches!(original, Content::Dynamic{
+            state: DynamicState{ref title, ..}, ..
+        } if title == "Original"));
+        assert!(matches!(fork, Content::Dynamic{
+            state: DynamicState{ref body, ..}, ..
+        } if body == "<p>Shared</p>"));
+    }
+
+    #[tokio::test]
+    async fn recordings_play_back_what_was_recorded() {
+        let path = std::env::temp_dir()
+            .join(format!("myxine-recording-test-{}.rec", std::process::id()));
+        let mut content = Content::new().await;
+        let (_id, mut original) = content.update_stream(Default::default(), None, None).await.unwrap();
+        read_until(&mut original, "event: connected").await;
+        content.start_recording(&path).await.unwrap();
+        content.set_title("Recorded").await;
+        time::delay_for(Duration::from_millis(100)).await;
+        content.set_body("<p>One</p>").await;
+        content.append_body("<p>Two</p>").await;
+        let sent = read_until(&mut original, "<p>Two</p>").await;
+        content.stop_recording().await;
+
+        let (mut replay, playback) = Content::replay_recording(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (_id, mut replayed) = replay.update_stream(Default::default(), None, None).await.unwrap();
+        read_until(&mut replayed, "event: connected").await;
+        let started = Instant::now();
+        let (received, ()) = futures::join!(read_until(&mut replayed, "<p>Two</p>"), playback);
+        assert_eq!(received, sent);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn mailbox_stays_bounded_for_a_slow_client() {
+        let mut content = Content::new().await;
+        content.enable_mailbox(4).await;
+        let (_id, updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        let reader = read_slowly(updates, Duration::from_millis(20));
+
+        // Setting the body never waits for the client, however far behind
+        let started = Instant::now();
+        for n in 1..=1000 {
+            content.set_body(format!("<p>{}</p>", n)).await;
+            let waiting = content.metrics().into_iter()
+                .find(|(metric, _)| *metric == &metrics::MAILBOX_FRAMES);
+            assert!(matches!(waiting, Some((_, waiting)) if waiting <= 4));
+        }
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        time::delay_for(Duration::from_millis(500)).await;
+        content.prepare_shutdown(Instant::now() + Duration::from_secs(1)).await;
+        let received = reader.await.unwrap();
+        let bodies = bodies(&received);
+        assert_eq!(bodies.last(), Some(&"<p>1000</p>"));
+        assert!(bodies.len() < 20, "Sent {} bodies", bodies.len());
+    }
+
+    #[tokio::test]
+    async fn new_clients_are_never_sent_older_bodies_from_the_mailbox() {
+        let mut content = Content::new().await;
+        content.enable_mailbox(8).await;
+        let (_id, slow) = content.update_stream(Default::default(), None, None).await.unwrap();
+        let _slow = read_slowly(slow, Duration::from_millis(50));
+        for n in 1..=5 {
+            content.set_body(format!("<p>{}</p>", n)).await;
+        }
+        let (_id, mut updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        let received = read_for(&mut updates, Duration::from_millis(500)).await;
+        assert!(received.contains("event: connected"));
+        assert_eq!(bodies(&received), vec!["<p>5</p>"]);
+    }
 }
@@ -88,7 +88,7 @@ impl Subscribers {
         // after this, because each event subscription is potentially unique).
         let server = sse::BufferedServer::new(EVENT_BUFFER_SIZE).await;
         let (sender, body) = Body::channel();
-        server.add_client(sender).await;
+        server.add_client(sender, Default::default()).await;
         let server = Arc::new(server);
         // Add a reference to the server, with the appropriate property filter,
         // to each place corresponding to its desired subscription.
@@ -179,7 +179,7 @@ impl Subscribers {
     pub async fn send_heartbeat(&mut self) -> Option<AggregateSubscription<'_>> {
         let mut sent = future::join_all(self.servers.iter_mut().map(|(_, server)| {
             async move {
-                let remaining = server.send_heartbeat().await.await;
+                let remaining = server.send_heartbeat(None).await.await;
                 assert!(remaining <= 1, "Subscriber SSE exceeds 1 client");
                 1 == remaining
             }
@@ -0,0 +1,163 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A policy which decides, at every heartbeat, which pages to keep in memory
+/// and which to evict. Evicting a page is like resetting it to `Page::new()`:
+/// the next request for its path gets a fresh empty page.
+pub trait GcPolicy: Debug + Send + Sync {
+    /// Decide whether to keep a page, given the `GcInputs` describing it.
+    fn keep(&self, page: &GcInputs) -> bool;
+}
+
+/// Everything a `GcPolicy` knows about a page when deciding whether to keep it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcInputs {
+    /// Whether the page is identical to `Page::new()`, so evicting it loses
+    /// nothing.
+    pub empty: bool,
+    /// The number of clients getting live updates to the page.
+    pub clients: usize,
+    /// The approximate number of bytes of content held by the page.
+    pub footprint: usize,
+    /// When the content of the page last changed.
+    pub last_modified: Instant,
+    /// When a client last did anything with the page (see
+    /// `Content::last_client_activity`), if ever.
+    pub last_client_activity: Option<Instant>,
+    /// The total footprint of every page modified more recently than this one.
+    pub more_recent_footprint: usize,
+}
+
+/// Evict exactly those pages which are empty. This is the default policy, and
+/// never loses any content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IsEmpty;
+
+impl GcPolicy for IsEmpty {
+    fn keep(&self, page: &GcInputs) -> bool {
+        !page.empty
+    }
+}
+
+/// Evict pages which have not been modified for the given duration, as well as
+/// empty pages. A page with live clients is never evicted, because evicting it
+/// would drop their connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ttl(pub Duration);
+
+impl GcPolicy for Ttl {
+    fn keep(&self, page: &GcInputs) -> bool {
+        IsEmpty.keep(page)
+            && (page.clients > 0 || page.last_modified.elapsed() < self.0)
+    }
+}
+
+/// Keep the total footprint of all pages under the given number of bytes by
+/// evicting the least recently modified pages, as well as empty pages. A page
+/// with live clients is never evicted, because evicting it would drop their
+/// connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lru(pub usize);
+
+impl GcPolicy for Lru {
+    fn keep(&self, page: &GcInputs) -> bool {
+        IsEmpty.keep(page)
+            && (page.clients > 0
+                || page.more_recent_footprint + page.footprint <= self.0)
+    }
+}
+
+/// Never evict pages, not even empty ones. This is only suitable when the set
+/// of paths used is small and fixed, like a set of pinned dashboards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Never;
+
+impl GcPolicy for Never {
+    fn keep(&self, _page: &GcInputs) -> bool {
+        true
+    }
+}
+
+/// Parse a policy as given on the command line: `empty` for `IsEmpty`, `never`
+/// for `Never`, `ttl=<seconds>` for `Ttl`, or `lru=<bytes>` for `Lru`.
+pub fn parse_policy(policy: &str) -> Result<Arc<dyn GcPolicy>, String> {
+    let mut parts = policy.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let mut number = |what| match parts.next().map(str::parse::<u64>) {
+        Some(Ok(number)) => Ok(number),
+        _ => Err(format!("The {} policy needs a number of {}, like {}=1000", name, what, name)),
+    };
+    match name {
+        "empty" if policy == name => Ok(Arc::new(IsEmpty)),
+        "never" if policy == name => Ok(Arc::new(Never)),
+        "ttl" => Ok(Arc::new(Ttl(Duration::from_secs(number("seconds")?)))),
+        "lru" => Ok(Arc::new(Lru(number("bytes")? as usize))),
+        _ => Err(format!("Unknown garbage collection policy: {} \
+                          (expected empty, never, ttl=<seconds>, or lru=<bytes>)", policy)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A page which holds something, has no clients, and was last modified
+    /// the given time ago.
+    fn page(footprint: usize, age: Duration) -> GcInputs {
+        GcInputs {
+            empty: false,
+            clients: 0,
+            footprint,
+            last_modified: Instant::now() - age,
+            last_client_activity: None,
+            more_recent_footprint: 0,
+        }
+    }
+
+    #[test]
+    fn is_empty_evicts_only_empty_pages() {
+        let old = page(100, Duration::from_secs(3600));
+        assert!(IsEmpty.keep(&old));
+        assert!(!IsEmpty.keep(&GcInputs{empty: true, footprint: 0, ..old}));
+    }
+
+    #[test]
+    fn ttl_evicts_stale_pages_without_clients() {
+        let ttl = Ttl(Duration::from_secs(60));
+        let fresh = page(100, Duration::from_secs(1));
+        let stale = page(100, Duration::from_secs(120));
+        assert!(ttl.keep(&fresh));
+        assert!(!ttl.keep(&stale));
+        assert!(ttl.keep(&GcInputs{clients: 1, ..stale}));
+        assert!(!ttl.keep(&GcInputs{empty: true, ..fresh}));
+    }
+
+    #[test]
+    fn lru_evicts_pages_past_the_budget() {
+        let lru = Lru(1000);
+        let recent = page(600, Duration::from_secs(1));
+        let older = GcInputs{more_recent_footprint: 600, ..page(600, Duration::from_secs(10))};
+        assert!(lru.keep(&recent));
+        assert!(!lru.keep(&older));
+        assert!(lru.keep(&GcInputs{clients: 1, ..older}));
+        assert!(!lru.keep(&GcInputs{empty: true, ..recent}));
+    }
+
+    #[test]
+    fn never_evicts_anything() {
+        let empty = GcInputs{empty: true, ..page(0, Duration::from_secs(3600))};
+        assert!(Never.keep(&empty));
+    }
+
+    #[test]
+    fn parsing_policies() {
+        assert_eq!(format!("{:?}", parse_policy("empty").unwrap()), "IsEmpty");
+        assert_eq!(format!("{:?}", parse_policy("never").unwrap()), "Never");
+        assert_eq!(format!("{:?}", parse_policy("ttl=60").unwrap()), "Ttl(60s)");
+        assert_eq!(format!("{:?}", parse_policy("lru=1000").unwrap()), "Lru(1000)");
+        assert!(parse_policy("ttl").is_err());
+        assert!(parse_policy("never=1").is_err());
+        assert!(parse_policy("sometimes").is_err());
+    }
+}
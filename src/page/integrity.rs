@@ -0,0 +1,138 @@
+/// The round constants of SHA-384 (the same as for all of SHA-512).
+const ROUND_CONSTANTS: [u64; 80] = [
+    0x428a2f98d728ae22, 0x7137449123ef65cd, 0xb5c0fbcfec4d3b2f, 0xe9b5dba58189dbbc,
+    0x3956c25bf348b538, 0x59f111f1b605d019, 0x923f82a4af194f9b, 0xab1c5ed5da6d8118,
+    0xd807aa98a3030242, 0x12835b0145706fbe, 0x243185be4ee4b28c, 0x550c7dc3d5ffb4e2,
+    0x72be5d74f27b896f, 0x80deb1fe3b1696b1, 0x9bdc06a725c71235, 0xc19bf174cf692694,
+    0xe49b69c19ef14ad2, 0xefbe4786384f25e3, 0x0fc19dc68b8cd5b5, 0x240ca1cc77ac9c65,
+    0x2de92c6f592b0275, 0x4a7484aa6ea6e483, 0x5cb0a9dcbd41fbd4, 0x76f988da831153b5,
+    0x983e5152ee66dfab, 0xa831c66d2db43210, 0xb00327c898fb213f, 0xbf597fc7beef0ee4,
+    0xc6e00bf33da88fc2, 0xd5a79147930aa725, 0x06ca6351e003826f, 0x142929670a0e6e70,
+    0x27b70a8546d22ffc, 0x2e1b21385c26c926, 0x4d2c6dfc5ac42aed, 0x53380d139d95b3df,
+    0x650a73548baf63de, 0x766a0abb3c77b2a8, 0x81c2c92e47edaee6, 0x92722c851482353b,
+    0xa2bfe8a14cf10364, 0xa81a664bbc423001, 0xc24b8b70d0f89791, 0xc76c51a30654be30,
+    0xd192e819d6ef5218, 0xd69906245565a910, 0xf40e35855771202a, 0x106aa07032bbd1b8,
+    0x19a4c116b8d2d0c8, 0x1e376c085141ab53, 0x2748774cdf8eeb99, 0x34b0bcb5e19b48a8,
+    0x391c0cb3c5c95a63, 0x4ed8aa4ae3418acb, 0x5b9cca4f7763e373, 0x682e6ff3d6b2b8a3,
+    0x748f82ee5defb2fc, 0x78a5636f43172f60, 0x84c87814a1f0ab72, 0x8cc702081a6439ec,
+    0x90befffa23631e28, 0xa4506cebde82bde9, 0xbef9a3f7b2c67915, 0xc67178f2e372532b,
+    0xca273eceea26619c, 0xd186b8c721c0c207, 0xeada7dd6cde0eb1e, 0xf57d4f7fee6ed178,
+    0x06f067aa72176fba, 0x0a637dc5a2c898a6, 0x113f9804bef90dae, 0x1b710b35131c471b,
+    0x28db77f523047d84, 0x32caab7b40c72493, 0x3c9ebe0a15c9bebc, 0x431d67c49c100d4c,
+    0x4cc5d4becb3e42b6, 0x597f299cfc657e2a, 0x5fcb6fab3ad6faec, 0x6c44198c4a475817,
+];
+
+/// The initial state of SHA-384.
+const INITIAL_STATE: [u64; 8] = [
+    0xcbbb9d5dc1059ed8, 0x629a292a367cd507, 0x9159015a3070dd17, 0x152fecd8f70e5939,
+    0x67332667ffc00b31, 0x8eb44a8768581511, 0xdb0c2e0d64f98fa7, 0x47b5481dbefa4fa4,
+];
+
+/// Get the subresource integrity metadata for some contents, as used in the
+/// `integrity` attribute of a `<script>` or `<link>` which loads them: their
+/// SHA-384 hash in base64, prefixed by `sha384-`.
+pub fn integrity(contents: &[u8]) -> String {
+    format!("sha384-{}", base64::encode(sha384(contents)))
+}
+
+/// Compute the SHA-384 hash of some bytes.
+fn sha384(contents: &[u8]) -> Vec<u8> {
+    let mut state = INITIAL_STATE;
+    // The contents are padded with a single 1 bit, then zeros, then their
+    // length in bits as 128 bits, to a whole number of 128-byte blocks
+    let mut padded = contents.to_vec();
+    padded.push(0x80);
+    while padded.len() % 128 != 112 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((contents.len() as u128) * 8).to_be_bytes());
+    for block in padded.chunks(128) {
+        let mut schedule = [0u64; 80];
+        for (word, bytes) in schedule.iter_mut().zip(block.chunks(8)) {
+            let mut be_bytes = [0u8; 8];
+            be_bytes.copy_from_slice(bytes);
+            *word = u64::from_be_bytes(be_bytes);
+        }
+        for i in 16..80 {
+            let s0 = schedule[i - 15].rotate_right(1)
+                ^ schedule[i - 15].rotate_right(8)
+                ^ (schedule[i - 15] >> 7);
+            let s1 = schedule[i - 2].rotate_right(19)
+                ^ schedule[i - 2].rotate_right(61)
+                ^ (schedule[i - 2] >> 6);
+            schedule[i] = schedule[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(schedule[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for (constant, word) in ROUND_CONSTANTS.iter().zip(schedule.iter()) {
+            let s1 = e.rotate_right(14) ^ e.rotate_right(18) ^ e.rotate_right(41);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(*constant)
+                .wrapping_add(*word);
+            let s0 = a.rotate_right(28) ^ a.rotate_right(34) ^ a.rotate_right(39);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (word, new) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *word = word.wrapping_add(*new);
+        }
+    }
+    // SHA-384 is the first six words of the state
+    state.iter().take(6).flat_map(|word| word.to_be_bytes().to_vec()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn fips_180_2_test_vectors() {
+        assert_eq!(hex(&sha384(b"abc")),
+                   "cb00753f45a35e8bb5a03d699ac65007272c32ab0eded163\
+                    1a8b605a43ff5bed8086072ba1e7cc2358baeca134c825a7");
+        assert_eq!(hex(&sha384(b"")),
+                   "38b060a751ac96384cd9327eb1b1e36a21fdb71114be0743\
+                    4c0cc7bf63f6e1da274edebfe76f65fbd51ad2f14898b95b");
+        assert_eq!(hex(&sha384(b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                                  hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu")),
+                   "09330c33f71147e83d192fc782cd1b4753111b173b3b05d2\
+                    2fa08086e3b0f712fcc7c71a557e2db966c3e9fa91746039");
+    }
+
+    #[test]
+    fn padding_boundaries() {
+        // One byte short of needing another block for the length, and just
+        // long enough to need it
+        assert_eq!(hex(&sha384(&[b'a'; 111])),
+                   "3c37955051cb5c3026f94d551d5b5e2ac38d572ae4e07172\
+                    085fed81f8466b8f90dc23a8ffcdea0b8d8e58e8fdacc80a");
+        assert_eq!(hex(&sha384(&[b'a'; 112])),
+                   "187d4e07cb306103c69967bf544d0dfbe9042577599c73c3\
+                    30abc0cb64c61236d5ed565ee19119d8c31779a38f791fcd");
+        assert_eq!(hex(&sha384(&[b'a'; 1000])),
+                   "f54480689c6b0b11d0303285d9a81b21a93bca6ba5a1b447\
+                    2765dca4da45ee328082d469c650cd3b61b16d3266ab8ced");
+    }
+
+    #[test]
+    fn integrity_metadata() {
+        assert_eq!(integrity(b"alert(1)"),
+                   "sha384-HT2E9NfWiuQ/w1PRai+hTyqW16NIoCGA/m8VQDUopfAtcz6YQjtsMmQd5uRbVDpW");
+    }
+}
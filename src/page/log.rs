@@ -0,0 +1,351 @@
+use hyper_usse::EventBuilder;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Span, Subscriber};
+
+use super::sse;
+
+/// The number of log lines which can be waiting to be sent to clients before
+/// new lines start replacing the most recently queued one.
+const LOG_BUFFER_SIZE: usize = 64;
+
+/// The severity of a line of server log, from most to least severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+impl TryFrom<&str> for LogLevel {
+    type Error = String;
+    fn try_from(string: &str) -> Result<LogLevel, String> {
+        match string {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("Invalid log level (must be error, warn, info, or debug): {}", string)),
+        }
+    }
+}
+
+impl From<&Level> for LogLevel {
+    /// Trace events are as detailed as debug ones, since there's no finer level
+    /// to stream.
+    fn from(level: &Level) -> LogLevel {
+        match *level {
+            Level::ERROR => LogLevel::Error,
+            Level::WARN => LogLevel::Warn,
+            Level::INFO => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+}
+
+/// The server's log of what has happened to one page: every `tracing` event
+/// in the page's span (see `span`), or in any span within it, which can be
+/// streamed to the clients of that page as `server-log` events. Until streaming
+/// is enabled, events are dropped.
+#[derive(Debug)]
+pub struct ServerLog {
+    span: Span,
+    sink: Arc<Mutex<Option<Sink>>>,
+}
+
+#[derive(Debug)]
+struct Sink {
+    level: LogLevel,
+    mailbox: sse::Mailbox,
+}
+
+impl Default for ServerLog {
+    fn default() -> ServerLog {
+        install();
+        let span = tracing::info_span!("page");
+        let sink = Arc::new(Mutex::new(None));
+        span.with_subscriber(|(id, dispatch)| {
+            if let Some(logs) = dispatch.downcast_ref::<PageLogs>() {
+                logs.attach(id, &sink);
+            }
+        });
+        ServerLog{span, sink}
+    }
+}
+
+impl ServerLog {
+    /// Start streaming every line at least as severe as `level` to the clients
+    /// of the given server, or change the level if already streaming. Lines go
+    /// through a bounded mailbox, so if clients lag behind, lines are dropped
+    /// rather than piling up or slowing down the page.
+    pub fn enable(&self, level: LogLevel, server: Arc<sse::BufferedServer>) {
+        let mut sink = self.sink.lock().unwrap();
+        match &mut *sink {
+            Some(sink) => sink.level = level,
+            None => *sink = Some(Sink {
+                level,
+                mailbox: sse::Mailbox::new(server, LOG_BUFFER_SIZE),
+            }),
+        }
+    }
+
+    /// Stop streaming lines to clients. Lines which haven't been sent yet are
+    /// dropped.
+    pub fn disable(&self) {
+        *self.sink.lock().unwrap() = None;
+    }
+
+    /// The span of this page. Events with fields can be logged by emitting
+    /// them in this span (or one within it), like `tracing::info!(parent:
+    /// log.span(), client = id, "Client connected")`.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Log a line with no fields besides its message, sending it to clients if
+    /// streaming is enabled at this level. This never waits for clients.
+    pub fn log(&self, level: LogLevel, message: impl Display) {
+        match level {
+            LogLevel::Error => tracing::error!(parent: &self.span, "{}", message),
+            LogLevel::Warn => tracing::warn!(parent: &self.span, "{}", message),
+            LogLevel::Info => tracing::info!(parent: &self.span, "{}", message),
+            LogLevel::Debug => tracing::debug!(parent: &self.span, "{}", message),
+        }
+    }
+}
+
+/// Make the subscriber routing events to the logs of pages the default for
+/// the whole process, unless something else already is. In that case, whatever
+/// is the default gets the events instead, and pages' logs can't be streamed.
+fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        // Failing means another subscriber was set first, which is allowed
+        let _ = tracing::subscriber::set_global_default(PageLogs::default());
+    });
+}
+
+/// The `tracing` subscriber which routes each event to the log of the page
+/// whose span it's in, if any. Every span belongs to the same page as its
+/// parent, except for the span of a `ServerLog`, which makes a new page. The
+/// fields of each span (and those of its ancestors) become part of every line
+/// logged in it.
+#[derive(Debug, Default)]
+struct PageLogs {
+    /// The id of the last span made (ids start from 1).
+    last_id: AtomicU64,
+    spans: Mutex<HashMap<Id, SpanState>>,
+}
+
+#[derive(Debug)]
+struct SpanState {
+    /// How many handles to the span there are, so it's forgotten when they're
+    /// all dropped.
+    handles: usize,
+    sink: Option<Weak<Mutex<Option<Sink>>>>,
+    fields: Map<String, Value>,
+}
+
+thread_local! {
+    /// The spans entered on this thread, most recently entered last.
+    static ENTERED: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
+}
+
+fn current_span() -> Option<Id> {
+    ENTERED.with(|entered| entered.borrow().last().cloned())
+}
+
+impl PageLogs {
+    /// Make the span with the given id the span of a page, logging to the
+    /// given sink.
+    fn attach(&self, id: &Id, sink: &Arc<Mutex<Option<Sink>>>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(id) {
+            span.sink = Some(Arc::downgrade(sink));
+        }
+    }
+}
+
+impl Subscriber for PageLogs {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let id = Id::from_u64(self.last_id.fetch_add(1, Ordering::Relaxed) + 1);
+        let parent = if attributes.is_contextual() {
+            current_span()
+        } else {
+            attributes.parent().cloned()
+        };
+        let mut spans = self.spans.lock().unwrap();
+        let (sink, mut fields) = parent.and_then(|parent| spans.get(&parent))
+            .map(|parent| (parent.sink.clone(), parent.fields.clone()))
+            .unwrap_or_default();
+        attributes.record(&mut JsonFields(&mut fields));
+        spans.insert(id.clone(), SpanState{handles: 1, sink, fields});
+        id
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(span) {
+            values.record(&mut JsonFields(&mut span.fields));
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) { }
+
+    fn event(&self, event: &Event<'_>) {
+        let parent = if event.is_contextual() {
+            current_span()
+        } else {
+            event.parent().cloned()
+        };
+        let (sink, mut fields) = match parent.and_then(|parent| {
+            let spans = self.spans.lock().unwrap();
+            let span = spans.get(&parent)?;
+            Some((span.sink.as_ref()?.upgrade()?, span.fields.clone()))
+        }) {
+            Some(found) => found,
+            None => return,
+        };
+        let mut sink = sink.lock().unwrap();
+        if let Some(sink) = &mut *sink {
+            let level = LogLevel::from(event.metadata().level());
+            if level <= sink.level {
+                event.record(&mut JsonFields(&mut fields));
+                fields.insert("level".to_string(), level.as_str().into());
+                let data = serde_json::to_string(&fields)
+                    .expect("Serializing log line to JSON shouldn't fail");
+                sink.mailbox.post(EventBuilder::new(&data).event_type("server-log").build());
+            }
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.clone()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(index) = entered.iter().rposition(|entered| entered == span) {
+                entered.remove(index);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(state) = self.spans.lock().unwrap().get_mut(span) {
+            state.handles += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        match spans.get_mut(&span) {
+            Some(state) if state.handles > 1 => {
+                state.handles -= 1;
+                false
+            },
+            Some(_) => {
+                spans.remove(&span);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// Fields of spans and events as they're sent in a line of a page's log, each
+/// as a JSON value, or its debugging representation if it has no natural one.
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonFields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::sse::testing::{read_for, read_until};
+    use hyper::Body;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn events_in_a_pages_spans_are_streamed_to_its_clients() {
+        let server = Arc::new(sse::BufferedServer::new(1).await);
+        let (sender, mut body) = Body::channel();
+        server.add_client(sender, Default::default()).await;
+        let log = ServerLog::default();
+        let other = ServerLog::default();
+        log.log(LogLevel::Info, "Before streaming");
+        log.enable(LogLevel::Info, server.clone());
+        log.log(LogLevel::Debug, "Too detailed");
+        other.log(LogLevel::Error, "Another page");
+        tracing::error!("Outside any page");
+        tracing::info!(parent: log.span(), client = 3, protected = false, "Client connected");
+        let recording = tracing::info_span!(parent: log.span(), "recording", path = "page.rec");
+        tracing::error!(parent: &recording, error = %"disk full", "Stopped recording");
+        {
+            let _entered = log.span().enter();
+            tracing::warn!("Entered");
+        }
+
+        let received = read_until(&mut body, "Entered").await;
+        let lines: Vec<Value> = received.lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert_eq!(lines, vec![
+            json!({"level": "info", "message": "Client connected", "client": 3, "protected": false}),
+            json!({"level": "error", "message": "Stopped recording",
+                   "path": "page.rec", "error": "disk full"}),
+            json!({"level": "warn", "message": "Entered"}),
+        ]);
+        assert_eq!(received.matches("event: server-log\n").count(), 3);
+
+        log.disable();
+        log.log(LogLevel::Error, "After streaming");
+        let received = read_for(&mut body, Duration::from_millis(100)).await;
+        assert_eq!(received, "");
+    }
+}
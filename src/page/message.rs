@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+/// The event types which the page's own script handles, so a custom event
+/// can't have them.
+const RESERVED_EVENT_TYPES: &[&str] = &[
+    "alert", "append-body", "append-to", "baseline-mismatch", "body", "body-patch",
+    "clear-body", "clear-element", "clear-steps", "clear-title", "client-id", "confirm",
+    "confirm-done", "connected", "parent-message", "persistent", "query", "ready",
+    "reconnect-to", "refresh", "refresh-available", "root-attr", "root-attrs", "seo",
+    "server-log", "server-shutdown", "set-attr", "step", "subscribe", "timed-event",
+    "title", "title-slot", "title-template", "validation",
+];
+
+/// A custom event to send to the clients of a page with `Content::send_message`,
+/// checked to be valid when it's built, so that sending it can't fail. This is
+/// like `send_event`, `send_event_ttl`, and `send_to_room` in one, with the
+/// `id` and `retry` fields of the event stream format as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventMessage {
+    pub(super) event_type: String,
+    pub(super) data: String,
+    pub(super) id: Option<String>,
+    pub(super) retry: Option<Duration>,
+    pub(super) ttl: Option<Duration>,
+    pub(super) room: Option<String>,
+}
+
+impl EventMessage {
+    /// Start building an event of the given type, with empty data.
+    pub fn builder(event_type: &str) -> EventMessageBuilder {
+        EventMessageBuilder {
+            message: EventMessage {
+                event_type: event_type.to_string(),
+                data: String::new(),
+                id: None,
+                retry: None,
+                ttl: None,
+                room: None,
+            },
+        }
+    }
+}
+
+/// A builder for an `EventMessage`, made by `EventMessage::builder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventMessageBuilder {
+    message: EventMessage,
+}
+
+impl EventMessageBuilder {
+    /// Set the data of the event. If it's JSON, clients with a filter only
+    /// receive the event if it matches.
+    pub fn data(mut self, data: &str) -> Self {
+        self.message.data = data.to_string();
+        self
+    }
+
+    /// Set the `id` of the event, which becomes its `lastEventId` in the
+    /// browser.
+    pub fn id(mut self, id: &str) -> Self {
+        self.message.id = Some(id.to_string());
+        self
+    }
+
+    /// Tell the browser how long to wait before reconnecting if the connection
+    /// is lost, with the `retry` field.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.message.retry = Some(retry);
+        self
+    }
+
+    /// Make the event last only for the given time, and be replayed to
+    /// clients connecting before then, as sent by `Content::send_event_ttl`.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.message.ttl = Some(ttl);
+        self
+    }
+
+    /// Send the event only to the clients which joined the given room, as sent
+    /// by `Content::send_to_room`.
+    pub fn room(mut self, room: &str) -> Self {
+        self.message.room = Some(room.to_string());
+        self
+    }
+
+    /// Check the event and finish building it. Returns an error if the event
+    /// type is empty, contains a line break or other control character, or is
+    /// one the page's own script handles (like `body`, or anything ending in
+    /// `:gzip`); if the id contains a line break or a null character (which
+    /// browsers don't accept); or if the event has both a TTL and a room, since
+    /// timed events are replayed to every client.
+    pub fn build(self) -> Result<EventMessage, String> {
+        let message = self.message;
+        let event_type = &message.event_type;
+        if event_type.is_empty() || event_type.chars().any(char::is_control) {
+            return Err(format!("Invalid event type: {:?}", event_type))
+        }
+        if RESERVED_EVENT_TYPES.contains(&event_type.as_str()) || event_type.ends_with(":gzip") {
+            return Err(format!("Reserved event type: {}", event_type))
+        }
+        if let Some(id) = &message.id {
+            if id.contains(&['\n', '\r', '\0'][..]) {
+                return Err(format!("Invalid event id: {:?}", id))
+            }
+        }
+        if message.ttl.is_some() && message.room.is_some() {
+            return Err("An event with a TTL can't be sent to a room".to_string())
+        }
+        Ok(message)
+    }
+}
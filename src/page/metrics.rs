@@ -0,0 +1,188 @@
+use std::fmt::Write;
+
+/// Whether a metric only ever goes up (until the server restarts) or can go
+/// up and down, as Prometheus distinguishes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// A metric reported for pages, exported as `myxine_<name>` with a `path`
+/// label naming the page.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Metric {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub help: &'static str,
+}
+
+pub const COALESCE_WINDOW: Metric = Metric {
+    name: "coalesce_window_milliseconds",
+    kind: Kind::Gauge,
+    help: "How long changes are held to merge them into fewer frames.",
+};
+
+pub const CLIENTS: Metric = Metric {
+    name: "clients",
+    kind: Kind::Gauge,
+    help: "Clients getting live updates to the page.",
+};
+
+pub const PROTECTED_CLIENTS: Metric = Metric {
+    name: "protected_clients",
+    kind: Kind::Gauge,
+    help: "Protected followers among the clients of the page.",
+};
+
+pub const PEAK_CLIENTS: Metric = Metric {
+    name: "peak_clients",
+    kind: Kind::Gauge,
+    help: "The most clients the page has had at once.",
+};
+
+pub const BYTES_SENT: Metric = Metric {
+    name: "bytes_sent_total",
+    kind: Kind::Counter,
+    help: "Bytes of updates sent to clients of the page.",
+};
+
+pub const BUFFER_OCCUPANCY: Metric = Metric {
+    name: "buffer_occupancy",
+    kind: Kind::Gauge,
+    help: "Updates waiting in the page's buffer to be sent.",
+};
+
+pub const BACKPRESSURE: Metric = Metric {
+    name: "backpressure_total",
+    kind: Kind::Counter,
+    help: "Updates which had to wait for room in the page's buffer.",
+};
+
+pub const STUCK_DISCONNECTS: Metric = Metric {
+    name: "stuck_disconnects_total",
+    kind: Kind::Counter,
+    help: "Clients disconnected for not reading their updates.",
+};
+
+pub const IDLE_DISCONNECTS: Metric = Metric {
+    name: "idle_disconnects_total",
+    kind: Kind::Counter,
+    help: "Clients disconnected for not showing any activity.",
+};
+
+pub const HEARTBEATS_SKIPPED: Metric = Metric {
+    name: "heartbeats_skipped_total",
+    kind: Kind::Counter,
+    help: "Heartbeats not sent because clients had just been sent an update.",
+};
+
+pub const FRAME_RATE: Metric = Metric {
+    name: "frame_rate",
+    kind: Kind::Gauge,
+    help: "The most frames a second clients are sent, if coalescing limits it.",
+};
+
+pub const MAILBOX_FRAMES: Metric = Metric {
+    name: "mailbox_frames",
+    kind: Kind::Gauge,
+    help: "Body updates waiting in the page's mailbox to be sent.",
+};
+
+pub const STATIC_SIZE: Metric = Metric {
+    name: "static_size_bytes",
+    kind: Kind::Gauge,
+    help: "The size of the page's static contents.",
+};
+
+pub const STATIC_FETCHES: Metric = Metric {
+    name: "static_fetches_total",
+    kind: Kind::Counter,
+    help: "Times the page's static contents have been fetched.",
+};
+
+pub const CLIENT_IDLE: Metric = Metric {
+    name: "client_idle_seconds",
+    kind: Kind::Gauge,
+    help: "Seconds since a client last did anything with the page.",
+};
+
+pub const SINCE_REFRESH: Metric = Metric {
+    name: "since_refresh_seconds",
+    kind: Kind::Gauge,
+    help: "Seconds since the page last told its clients to reload or changed between static and dynamic.",
+};
+
+/// The values of the metrics reported by one page.
+pub type Samples = Vec<(&'static Metric, u64)>;
+
+/// Render the samples of every page, labeled with its path, in Prometheus
+/// exposition format. Samples are grouped by metric, in the order each metric
+/// first appears, so each gets one set of `# HELP` and `# TYPE` lines.
+pub fn exposition<'a>(pages: impl IntoIterator<Item = (&'a str, Samples)>) -> String {
+    let mut families: Vec<(&'static Metric, Vec<(String, u64)>)> = Vec::new();
+    for (path, samples) in pages {
+        for (metric, value) in samples {
+            let label = escape_label_value(path);
+            match families.iter_mut().find(|(family, _)| *family == metric) {
+                Some((_, family)) => family.push((label, value)),
+                None => families.push((metric, vec![(label, value)])),
+            }
+        }
+    }
+    let mut text = String::new();
+    for (metric, samples) in families {
+        let kind = match metric.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        writeln!(&mut text, "# HELP myxine_{} {}", metric.name, metric.help)
+            .and_then(|()| writeln!(&mut text, "# TYPE myxine_{} {}", metric.name, kind))
+            .expect("Internal error: writeln!() failed on a String");
+        for (label, value) in samples {
+            writeln!(&mut text, "myxine_{}{{path=\"{}\"}} {}", metric.name, label, value)
+                .expect("Internal error: writeln!() failed on a String");
+        }
+    }
+    text
+}
+
+/// Escape a string for use as a Prometheus label value, which is delimited by
+/// double quotes and must escape backslashes, quotes, and newlines.
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_metric_is_typed_once() {
+        let text = exposition(vec![
+            ("/a", vec![(&CLIENTS, 2), (&BYTES_SENT, 10)]),
+            ("/b\"", vec![(&CLIENTS, 1), (&STATIC_FETCHES, 3)]),
+        ]);
+        assert_eq!(text, "\
+# HELP myxine_clients Clients getting live updates to the page.
+# TYPE myxine_clients gauge
+myxine_clients{path=\"/a\"} 2
+myxine_clients{path=\"/b\\\"\"} 1
+# HELP myxine_bytes_sent_total Bytes of updates sent to clients of the page.
+# TYPE myxine_bytes_sent_total counter
+myxine_bytes_sent_total{path=\"/a\"} 10
+# HELP myxine_static_fetches_total Times the page's static contents have been fetched.
+# TYPE myxine_static_fetches_total counter
+myxine_static_fetches_total{path=\"/b\\\"\"} 3
+");
+    }
+}
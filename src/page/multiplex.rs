@@ -0,0 +1,209 @@
+use futures::{future, StreamExt};
+use hyper::Body;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, oneshot};
+
+use super::Page;
+
+/// One connection carrying the updates of several pages at once, as for a
+/// dashboard showing several pages side by side. Every event on it has its type
+/// prefixed by the path of the page it came from and a space, like `/cpu body`
+/// (events without a type become `/cpu message`), so a client can tell them
+/// apart; heartbeats are passed on as they are.
+#[derive(Debug, Clone)]
+pub struct Sink {
+    sender: Arc<Mutex<hyper::body::Sender>>,
+}
+
+impl Sink {
+    /// Make a new sink, along with the `Body` stream to give to its client.
+    pub fn new() -> (Sink, Body) {
+        let (sender, body) = Body::channel();
+        (Sink{sender: Arc::new(Mutex::new(sender))}, body)
+    }
+}
+
+/// The forwarding of one page's updates into a `Sink`, as started by
+/// `Content::update_stream_into`. Forwarding stops (and the page's client goes
+/// away) when the sink's client disconnects, or with `stop`, which leaves the
+/// sink and the other pages forwarding into it alone. Dropping this without
+/// stopping it leaves it forwarding for as long as the sink's client is there.
+#[derive(Debug)]
+pub struct Forwarding {
+    stop: oneshot::Sender<()>,
+}
+
+impl Forwarding {
+    pub fn stop(self) {
+        // If it's already stopped, so much the better
+        self.stop.send(()).unwrap_or(());
+    }
+
+    /// Whether forwarding has stopped on its own, because the sink's client
+    /// disconnected or the page stopped sending updates.
+    pub fn is_stopped(&self) -> bool {
+        self.stop.is_closed()
+    }
+}
+
+/// Start forwarding every frame of a page's update stream into a sink, tagged
+/// with the page's path.
+pub fn forward(sink: &Sink, path: &str, mut updates: Body) -> Forwarding {
+    let (stop, stopped) = oneshot::channel();
+    let sender = sink.sender.clone();
+    let path = path.to_string();
+    let copy = async move {
+        while let Some(Ok(frame)) = updates.next().await {
+            let tagged = tag_frame(&path, &frame);
+            if sender.lock().await.send_data(tagged.into()).await.is_err() {
+                break;
+            }
+        }
+    };
+    let stop_requested = async move {
+        // Only an explicit stop counts: a dropped `Forwarding` doesn't
+        if stopped.await.is_err() {
+            future::pending::<()>().await
+        }
+    };
+    tokio::spawn(async move {
+        futures::pin_mut!(copy, stop_requested);
+        future::select(copy, stop_requested).await;
+    });
+    Forwarding{stop}
+}
+
+/// Prefix the type of every event in a frame of SSE with a page's path.
+fn tag_frame(path: &str, frame: &[u8]) -> Vec<u8> {
+    let frame = String::from_utf8_lossy(frame);
+    let mut tagged = String::with_capacity(frame.len() + path.len() + 16);
+    let mut events = frame.split("\n\n").peekable();
+    while let Some(event) = events.next() {
+        let is_event = event.lines().any(|line| line.starts_with("data:") || line.starts_with("event:"));
+        let has_type = event.lines().any(|line| line.starts_with("event:"));
+        if is_event && !has_type {
+            tagged.push_str("event: ");
+            tagged.push_str(path);
+            tagged.push_str(" message\n");
+        }
+        for (index, line) in event.split('\n').enumerate() {
+            if index > 0 {
+                tagged.push('\n');
+            }
+            match line.strip_prefix("event:") {
+                Some(event_type) => {
+                    tagged.push_str("event: ");
+                    tagged.push_str(path);
+                    tagged.push(' ');
+                    tagged.push_str(event_type.trim_start());
+                },
+                None => tagged.push_str(line),
+            }
+        }
+        if events.peek().is_some() {
+            tagged.push_str("\n\n");
+        }
+    }
+    tagged.into_bytes()
+}
+
+/// A set of pages whose updates are all sent to one client, through a `Sink`,
+/// which pages can join and leave without disturbing the others.
+#[derive(Debug)]
+pub struct Multiplexer {
+    sink: Sink,
+    forwarding: HashMap<String, Forwarding>,
+}
+
+impl Multiplexer {
+    /// Make a new multiplexer with no pages, along with the `Body` stream to
+    /// give to its client.
+    pub fn new() -> (Multiplexer, Body) {
+        let (sink, body) = Sink::new();
+        (Multiplexer{sink, forwarding: HashMap::new()}, body)
+    }
+
+    /// Start sending the updates of the page at the given path to the client,
+    /// replacing any page subscribed to at the same path before. Returns
+    /// `false` if the page is static (or shutting down), in which case it isn't
+    /// subscribed to.
+    pub async fn subscribe(&mut self, path: &str, page: &Page) -> bool {
+        match page.update_stream_into(&self.sink, path).await {
+            Some(forwarding) => {
+                if let Some(old) = self.forwarding.insert(path.to_string(), forwarding) {
+                    old.stop();
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Tell the client the id of its stream, by which pages can be unsubscribed
+    /// from it later, as a `multiplex-id` event (which has no path, since it's
+    /// from no page). Returns `false` if the client has disconnected.
+    pub async fn send_id(&self, id: &str) -> bool {
+        let event = format!("event: multiplex-id\ndata: {}\n\n", id);
+        self.sink.sender.lock().await.send_data(event.into()).await.is_ok()
+    }
+
+    /// Stop sending the updates of the page at the given path to the client,
+    /// leaving the connection open for the rest. Returns `false` if that page
+    /// wasn't subscribed to.
+    pub fn unsubscribe(&mut self, path: &str) -> bool {
+        match self.forwarding.remove(path) {
+            Some(forwarding) => {
+                forwarding.stop();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The paths of the pages subscribed to whose updates are still being
+    /// forwarded. Once there are none, the client has either disconnected or
+    /// been sent everything it will be.
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.forwarding.iter()
+            .filter(|(_, forwarding)| !forwarding.is_stopped())
+            .map(|(path, _)| path.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::sse::testing::{read_for, read_until};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn pages_leave_without_closing_the_connection() {
+        let (mut multiplexer, mut body) = Multiplexer::new();
+        let mut pages = Vec::new();
+        for path in &["/a", "/b", "/c"] {
+            let page = Page::new().await;
+            page.set_body(format!("<p>{} 1</p>", path)).await;
+            assert!(multiplexer.subscribe(path, &page).await);
+            pages.push((path, page));
+        }
+        let mut received = String::new();
+        for (path, _) in &pages {
+            received += &read_until(&mut body, &format!("event: {} body\ndata: <p>{} 1</p>", path, path)).await;
+        }
+        assert_eq!(received.matches("event: /a connected").count(), 1);
+
+        assert!(multiplexer.unsubscribe("/b"));
+        assert!(!multiplexer.unsubscribe("/b"));
+        let mut paths: Vec<&str> = multiplexer.paths().collect();
+        paths.sort_unstable();
+        assert_eq!(paths, vec!["/a", "/c"]);
+        for (path, page) in &pages {
+            page.set_body(format!("<p>{} 2</p>", path)).await;
+        }
+        let mut received = read_until(&mut body, "event: /a body\ndata: <p>/a 2</p>").await;
+        received += &read_until(&mut body, "event: /c body\ndata: <p>/c 2</p>").await;
+        received += &read_for(&mut body, Duration::from_millis(100)).await;
+        assert!(!received.contains("/b 2"), "{:?}", received);
+    }
+}
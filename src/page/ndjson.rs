@@ -0,0 +1,141 @@
+use flate2::read::GzDecoder;
+use futures::StreamExt;
+use hyper::Body;
+use std::io::Read;
+
+/// Turn a stream of updates in SSE into newline-delimited JSON, for clients
+/// other than browsers (like `curl | jq`): every event becomes one line like
+/// `{"event": "body", "data": "<p>Hello</p>", "id": 3}`, where the `id` is
+/// `null` if the event has none, and a number if it's numeric (as page versions
+/// are). Compressed events (whose type ends in `:gzip`) are decompressed, so
+/// they look like any other, and heartbeats become `{"event": "heartbeat",
+/// "data": "", "id": null}`.
+pub fn from_sse(mut updates: Body) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut pending = String::new();
+        while let Some(Ok(chunk)) = updates.next().await {
+            pending.push_str(&String::from_utf8_lossy(&chunk));
+            let mut lines = String::new();
+            while let Some(end) = pending.find("\n\n") {
+                let event: String = pending.drain(..end + 2).collect();
+                if let Some(record) = record(&event) {
+                    lines.push_str(&record);
+                    lines.push('\n');
+                }
+            }
+            if !lines.is_empty() && sender.send_data(lines.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+    body
+}
+
+/// Convert a single SSE event into a JSON record, or `None` if it's something
+/// to skip.
+fn record(event: &str) -> Option<String> {
+    let mut event_type = None;
+    let mut data: Option<String> = None;
+    let mut id = None;
+    let mut comment = false;
+    for line in event.lines() {
+        if line.starts_with(':') {
+            comment = true;
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        let field = parts.next().unwrap_or("");
+        let value = parts.next().map_or("", |value| value.strip_prefix(' ').unwrap_or(value));
+        match field {
+            "event" => event_type = Some(value.to_string()),
+            "data" => match &mut data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                },
+                None => data = Some(value.to_string()),
+            },
+            "id" => id = Some(value.to_string()),
+            _ => { },
+        }
+    }
+    let (event_type, data) = match (event_type, data) {
+        (None, None) if comment => ("heartbeat".to_string(), String::new()),
+        (None, None) => return None,
+        (event_type, data) => (event_type.unwrap_or_else(|| "message".to_string()), data.unwrap_or_default()),
+    };
+    let (event_type, data) = match event_type.strip_suffix(":gzip") {
+        Some(base_type) => match decompress(&data) {
+            Some(data) => (base_type.to_string(), data),
+            None => (event_type.clone(), data),
+        },
+        None => (event_type, data),
+    };
+    let id = match id {
+        Some(id) => match id.parse::<u64>() {
+            Ok(number) => serde_json::json!(number),
+            Err(_) => serde_json::json!(id),
+        },
+        None => serde_json::Value::Null,
+    };
+    Some(serde_json::to_string(&serde_json::json!({
+        "event": event_type,
+        "data": data,
+        "id": id,
+    })).expect("Serializing event to JSON shouldn't fail"))
+}
+
+/// Decompress gzipped, base64-encoded event data, or return `None` if it's not
+/// valid.
+fn decompress(data: &str) -> Option<String> {
+    let bytes = base64::decode(data).ok()?;
+    let mut text = String::new();
+    GzDecoder::new(&bytes[..]).read_to_string(&mut text).ok()?;
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::content::Content;
+    use super::super::sse::testing::read_until;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    /// Parse every line of NDJSON read.
+    fn records(received: &str) -> Vec<Value> {
+        received.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn replays_and_heartbeats_become_records() {
+        let mut content = Content::new().await;
+        content.set_event_compression_threshold(Some(16));
+        content.set_body("<p>Hello, world!</p>").await;
+        let (_id, updates) = content.update_stream(Default::default(), None, None).await.unwrap();
+        let mut body = from_sse(updates);
+        let received = read_until(&mut body, "\"connected\"").await;
+        let replayed = records(&received);
+        assert!(replayed.contains(&json!({"event": "body", "data": "<p>Hello, world!</p>", "id": 1})),
+                "{:?}", replayed);
+        assert_eq!(replayed.last(), Some(&json!({"event": "connected", "data": ".", "id": null})));
+
+        content.send_heartbeat(Duration::from_secs(0)).await;
+        let received = read_until(&mut body, "\n").await;
+        assert_eq!(records(&received), vec![json!({"event": "heartbeat", "data": "", "id": null})]);
+    }
+
+    #[tokio::test]
+    async fn events_split_across_chunks_are_joined() {
+        let (mut sender, updates) = Body::channel();
+        let mut body = from_sse(updates);
+        sender.send_data("event: title\nda".into()).await.unwrap();
+        sender.send_data("ta: Split\n\ndata: one\ndata: two\n\n".into()).await.unwrap();
+        let received = read_until(&mut body, "two").await;
+        assert_eq!(records(&received), vec![
+            json!({"event": "title", "data": "Split", "id": null}),
+            json!({"event": "message", "data": "one\ntwo", "id": null}),
+        ]);
+    }
+}
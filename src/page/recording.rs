@@ -0,0 +1,89 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::Span;
+
+/// A file recording every frame a page's update server sends to all its
+/// clients, as started by `Content::start_recording`. Each frame is one line of
+/// JSON, like `{"at": 1500, "frame": "event: title\ndata: Hello\n\n"}`, where
+/// `at` is the number of milliseconds since recording started. Frames are
+/// buffered, so they may not reach the file until the recording stops. Failing
+/// to write the file is reported in the page's server log, in a `recording`
+/// span naming the file.
+#[derive(Debug)]
+pub struct Recorder {
+    file: BufWriter<File>,
+    started: Instant,
+    span: Span,
+}
+
+/// One line of a recording.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RecordedFrame {
+    at: u64,
+    frame: String,
+}
+
+impl Recorder {
+    /// Start recording into a file, replacing whatever it held before (or
+    /// creating it if need be), reporting failures within the given span (that
+    /// of the page being recorded).
+    pub fn create(path: &Path, page: &Span) -> Result<Recorder, String> {
+        let file = File::create(path)
+            .map_err(|err| format!("Couldn't record to {}: {}", path.display(), err))?;
+        let span = tracing::info_span!(parent: page, "recording", path = %path.display());
+        Ok(Recorder{file: BufWriter::new(file), started: Instant::now(), span})
+    }
+
+    /// Record a single frame, as sent now. Returns `false` if it couldn't be
+    /// written, in which case recording should stop.
+    pub fn record(&mut self, frame: &[u8]) -> bool {
+        let line = RecordedFrame {
+            at: self.started.elapsed().as_millis() as u64,
+            frame: String::from_utf8_lossy(frame).into_owned(),
+        };
+        let written = serde_json::to_writer(&mut self.file, &line)
+            .map_err(|err| err.to_string())
+            .and_then(|()| self.file.write_all(b"\n").map_err(|err| err.to_string()));
+        match written {
+            Ok(()) => true,
+            Err(err) => {
+                tracing::error!(parent: &self.span, error = %err, "Stopped recording");
+                false
+            },
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // The buffer would be flushed anyway, but without reporting failure
+        if let Err(err) = self.file.flush() {
+            tracing::error!(parent: &self.span, error = %err, "Couldn't finish recording");
+        }
+    }
+}
+
+/// Read back a recording made by a `Recorder`, as each frame with the time it
+/// was sent since the recording started, in order. Returns an error if the
+/// file can't be read or any line of it is invalid.
+pub fn read_recording(path: &Path) -> Result<Vec<(Duration, Bytes)>, String> {
+    let file = File::open(path)
+        .map_err(|err| format!("Couldn't read recording from {}: {}", path.display(), err))?;
+    let mut frames = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line
+            .map_err(|err| format!("Couldn't read recording from {}: {}", path.display(), err))?;
+        if line.is_empty() {
+            continue;
+        }
+        let recorded: RecordedFrame = serde_json::from_str(&line)
+            .map_err(|err| format!("Invalid recording in {} at line {}: {}",
+                                   path.display(), number + 1, err))?;
+        frames.push((Duration::from_millis(recorded.at), Bytes::from(recorded.frame)));
+    }
+    Ok(frames)
+}
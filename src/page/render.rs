@@ -0,0 +1,95 @@
+use lazy_static::lazy_static;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use tokio::process::Command;
+use uuid::Uuid;
+
+/// The number of rendered images to keep around, in case the same page is
+/// rendered again at the same size before it changes.
+const IMAGE_CACHE_SIZE: usize = 16;
+
+/// The environment variable naming the headless browser to render with, which
+/// must understand Chromium's `--headless` and `--screenshot` flags.
+const BROWSER_VARIABLE: &str = "MYXINE_BROWSER";
+
+/// The browser to render with if none is named in the environment.
+const DEFAULT_BROWSER: &str = "chromium";
+
+lazy_static! {
+    /// The most recently rendered images, most recent last, keyed by the
+    /// fingerprint of what was rendered.
+    static ref IMAGE_CACHE: Mutex<VecDeque<(u64, Arc<Vec<u8>>)>>
+        = Mutex::new(VecDeque::with_capacity(IMAGE_CACHE_SIZE));
+}
+
+/// The reasons a page can't be rendered as an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenderError {
+    /// The page is static, and isn't HTML.
+    NotHtml,
+    /// The headless browser couldn't be run, or didn't produce an image.
+    Browser(String),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::NotHtml => write!(f, "Only HTML pages can be rendered as images"),
+            RenderError::Browser(err) => write!(f, "Couldn't render page: {}", err),
+        }
+    }
+}
+
+/// Render a standalone HTML document as a PNG image of the given size, using a
+/// headless browser. If the same document was recently rendered at the same
+/// size, the cached image is returned instead.
+pub async fn render_png(html: &str, width: u32, height: u32) -> Result<Arc<Vec<u8>>, RenderError> {
+    let fingerprint = {
+        let mut hasher = DefaultHasher::new();
+        (html, width, height).hash(&mut hasher);
+        hasher.finish()
+    };
+    if let Some((_, image)) = IMAGE_CACHE.lock().unwrap().iter().find(|(f, _)| *f == fingerprint) {
+        return Ok(image.clone())
+    }
+
+    let image = Arc::new(run_browser(html, width, height).await?);
+    let mut cache = IMAGE_CACHE.lock().unwrap();
+    if cache.len() >= IMAGE_CACHE_SIZE {
+        cache.pop_front();
+    }
+    cache.push_back((fingerprint, image.clone()));
+    Ok(image)
+}
+
+/// Run the headless browser to take a screenshot of some HTML, by way of a pair
+/// of temporary files which are removed afterwards.
+async fn run_browser(html: &str, width: u32, height: u32) -> Result<Vec<u8>, RenderError> {
+    let browser = std::env::var(BROWSER_VARIABLE).unwrap_or_else(|_| DEFAULT_BROWSER.to_string());
+    let name = Uuid::new_v4().to_simple().to_string();
+    let html_path = std::env::temp_dir().join(format!("myxine-{}.html", name));
+    let png_path = std::env::temp_dir().join(format!("myxine-{}.png", name));
+    let browser_error = |err: std::io::Error| RenderError::Browser(err.to_string());
+
+    std::fs::write(&html_path, html).map_err(browser_error)?;
+    let status = Command::new(&browser)
+        .arg("--headless")
+        .arg("--disable-gpu")
+        .arg("--hide-scrollbars")
+        .arg(format!("--window-size={},{}", width, height))
+        .arg(format!("--screenshot={}", png_path.display()))
+        .arg(format!("file://{}", html_path.display()))
+        .status()
+        .await;
+    let image = match status {
+        Ok(status) if status.success() => std::fs::read(&png_path).map_err(browser_error),
+        Ok(status) => Err(RenderError::Browser(format!("{} exited with {}", browser, status))),
+        Err(err) => Err(RenderError::Browser(format!("{}: {}", browser, err))),
+    };
+    std::fs::remove_file(&html_path).unwrap_or(());
+    std::fs::remove_file(&png_path).unwrap_or(());
+    image
+}
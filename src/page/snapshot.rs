@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// The lasting state of a page, without any of its connections, which can be
+/// serialized (as by `server::dump_all`) and later turned back into a page with
+/// `Page::restore`. Anything transient, like timed events, pending
+/// confirmations, or buffered updates, is left out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+#[allow(clippy::large_enum_variant)]
+pub enum Snapshot {
+    Dynamic(DynamicSnapshot),
+    Static(StaticSnapshot),
+}
+
+/// The lasting state of a dynamic page. Every field may be left out of the
+/// serialized form, in which case it's empty.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct DynamicSnapshot {
+    pub title: String,
+    pub title_template: Option<TitleTemplateSnapshot>,
+    pub body: String,
+    /// The bodies set for particular languages, by language tag.
+    pub lang_bodies: BTreeMap<String, String>,
+    pub description: String,
+    /// The Open Graph tags, by property (without the `og:` prefix).
+    pub og: BTreeMap<String, String>,
+    pub query: Option<String>,
+    pub root_attrs: Vec<RootAttrSnapshot>,
+    /// The validation errors, by field.
+    pub validation: HashMap<String, String>,
+    pub attrs: Vec<AttrSnapshot>,
+    /// The text streamed to elements, by selector.
+    pub streams: BTreeMap<String, String>,
+    /// The persistent messages to the parent window, by channel.
+    pub parent_messages: BTreeMap<String, ParentMessageSnapshot>,
+    /// The persistent client-side values, by key.
+    pub persistent: BTreeMap<String, String>,
+    /// The status of each step of a job, in order.
+    pub steps: Vec<StepSnapshot>,
+    pub namespace: Option<String>,
+    pub version: u64,
+}
+
+/// The template of the title of a dynamic page, with the values of its slots.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TitleTemplateSnapshot {
+    pub template: String,
+    #[serde(default)]
+    pub slots: BTreeMap<String, String>,
+}
+
+/// An attribute set on the `html` or `body` element of a dynamic page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootAttrSnapshot {
+    pub element: String,
+    pub name: String,
+    pub value: String,
+}
+
+/// An attribute set (or, with no value, removed) on an element of a dynamic
+/// page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttrSnapshot {
+    pub selector: String,
+    pub name: String,
+    pub value: Option<String>,
+}
+
+/// A persistent message to the window embedding a dynamic page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParentMessageSnapshot {
+    pub data: String,
+    pub origin: String,
+}
+
+/// The status of one of the steps of a job shown on a dynamic page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepSnapshot {
+    pub name: String,
+    pub status: String,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// The contents of a static page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct StaticSnapshot {
+    pub content_type: Option<String>,
+    /// The raw contents, base64-encoded.
+    pub contents: String,
+    #[serde(default)]
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub version: u64,
+}
@@ -1,84 +1,1213 @@
-use hyper_usse;
 use bytes::Bytes;
-use futures::Future;
+use futures::{future, Future};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time;
+
+mod filter;
+pub use filter::Filter;
+
+use super::recording::Recorder;
 
 /// An SSE server implementing buffering, so "bursty" events can be sent without
 /// lagging from the sender.
 #[derive(Debug)]
 pub struct BufferedServer {
     commands: Mutex<mpsc::Sender<Command>>,
+    next_id: AtomicU64,
+    stats: Arc<Stats>,
+    backpressure: Arc<StdMutex<BackpressureMonitor>>,
+}
+
+/// A transition into or out of backpressure on a `BufferedServer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackpressureState {
+    /// A send found the buffer full and had to wait.
+    Begin,
+    /// Sends have stopped waiting for the buffer.
+    End,
+}
+
+/// The minimum time backpressure is considered to last once it begins. Ending
+/// backpressure is only reported after this long, so that rapid toggling
+/// doesn't lead to a flood of notifications. While backpressure lasts, the
+/// buffer is also checked this often to see if it has drained.
+const BACKPRESSURE_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Tracks whether a `BufferedServer` is experiencing backpressure, notifying a
+/// callback of each transition.
+#[derive(Default)]
+struct BackpressureMonitor {
+    callback: Option<Box<dyn Fn(BackpressureState) + Send + Sync>>,
+    began: Option<Instant>,
+}
+
+impl fmt::Debug for BackpressureMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BackpressureMonitor")
+            .field("callback", &self.callback.as_ref().map(|_| ".."))
+            .field("began", &self.began)
+            .finish()
+    }
+}
+
+impl BackpressureMonitor {
+    /// Note whether a send had to wait, reporting to the callback if this
+    /// begins backpressure, or ends it after the debounce interval. Returns
+    /// `true` if this began backpressure.
+    fn note(&mut self, waited: bool) -> bool {
+        if waited && self.began.is_none() {
+            self.began = Some(Instant::now());
+            self.report(BackpressureState::Begin);
+            true
+        } else {
+            if !waited {
+                self.end();
+            }
+            false
+        }
+    }
+
+    /// Report the end of backpressure, if it has lasted the debounce interval.
+    fn end(&mut self) {
+        if let Some(began) = self.began {
+            if began.elapsed() >= BACKPRESSURE_DEBOUNCE {
+                self.began = None;
+                self.report(BackpressureState::End);
+            }
+        }
+    }
+
+    fn report(&self, transition: BackpressureState) {
+        if let Some(callback) = &self.callback {
+            callback(transition);
+        }
+    }
+}
+
+/// Once backpressure begins, check the buffer after every debounce interval,
+/// and end backpressure as soon as the buffer has drained. Otherwise, the end
+/// would only be noticed by the next send, which may never come if whoever is
+/// sending waits for backpressure to end first.
+fn watch_backpressure(monitor: Weak<StdMutex<BackpressureMonitor>>, stats: Arc<Stats>) {
+    tokio::spawn(async move {
+        loop {
+            time::delay_for(BACKPRESSURE_DEBOUNCE).await;
+            let monitor = match monitor.upgrade() {
+                Some(monitor) => monitor,
+                None => break,
+            };
+            let mut monitor = monitor.lock().unwrap();
+            if monitor.began.is_some() && stats.buffered.load(Ordering::Relaxed) == 0 {
+                monitor.end();
+            }
+            if monitor.began.is_none() {
+                break;
+            }
+        }
+    });
+}
+
+/// Running statistics about a `BufferedServer`. These are updated as the server
+/// processes its commands, and can be read at any time without waiting on its
+/// command buffer.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// The number of clients as of the last command processed.
+    pub clients: AtomicUsize,
+    /// The largest number of clients ever connected at once.
+    pub peak_clients: AtomicUsize,
+    /// The total number of bytes successfully sent, summed over all clients.
+    pub bytes_sent: AtomicU64,
+    /// The number of commands waiting in the buffer to be processed.
+    pub buffered: AtomicUsize,
+    /// The number of commands which found the buffer full, and had to wait.
+    pub backpressure: AtomicU64,
+    /// The number of clients disconnected for being stuck.
+    pub stuck: AtomicU64,
+    /// The number of clients disconnected for being idle.
+    pub idle: AtomicU64,
+    /// The last time a client connected, was found to have disconnected, or
+    /// showed some activity upstream, if ever.
+    pub last_activity: StdMutex<Option<Instant>>,
+    /// The last time a frame was sent to every client (other than a
+    /// heartbeat), if ever.
+    pub last_broadcast: StdMutex<Option<Instant>>,
+    /// The number of heartbeats skipped because a frame had been sent to every
+    /// client recently enough.
+    pub heartbeats_skipped: AtomicU64,
+}
+
+impl Stats {
+    /// Record that a client has just done something.
+    pub fn note_activity(&self) {
+        *self.last_activity.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Record the current number of clients, updating the peak if necessary.
+    fn set_clients(&self, clients: usize) {
+        self.clients.store(clients, Ordering::Relaxed);
+        self.peak_clients.fetch_max(clients, Ordering::Relaxed);
+    }
 }
 
+/// An identifier for a client of a `BufferedServer`, unique within that server.
+pub type ClientId = u64;
+
 pub enum Command {
     Connections(oneshot::Sender<usize>),
-    SendToClients(Bytes, oneshot::Sender<usize>),
-    SendHeartbeat(oneshot::Sender<usize>),
+    ProtectedConnections(oneshot::Sender<usize>),
+    SendToClients(Bytes, Option<Value>, oneshot::Sender<usize>),
+    SendToRoom(String, Bytes, Option<Value>, oneshot::Sender<usize>),
+    SendToCleanClients(Bytes, Bytes, oneshot::Sender<usize>),
+    SendToLang(Option<String>, Bytes, oneshot::Sender<usize>),
+    SendToClient(ClientId, Bytes, oneshot::Sender<bool>),
+    SetDirty(ClientId, bool, oneshot::Sender<Option<bool>>),
+    Touch(ClientId, oneshot::Sender<bool>),
+    SyncState(ClientId, oneshot::Sender<Option<SyncState>>),
+    SendHeartbeat(Option<Duration>, oneshot::Sender<usize>),
     DisconnectAll,
-    AddClient(hyper::body::Sender),
+    SetStuckTimeout(Option<Duration>),
+    SetIdleTimeout(Option<Duration>),
+    DisconnectClient(ClientId, oneshot::Sender<bool>),
+    AddClient(Client, Option<Bytes>),
+    Handoff(oneshot::Sender<Clients>),
+    SetRecorder(Option<Recorder>, oneshot::Sender<()>),
 }
 
-impl BufferedServer {
-    pub async fn new(buffer_size: usize) -> BufferedServer {
-        let (commands, mut receiver) = mpsc::channel(buffer_size);
-        tokio::spawn(async move {
-            let mut server = hyper_usse::Server::new();
-            while let Some(command) = receiver.recv().await {
-                match command {
-                    Command::SendHeartbeat(ret) => {
-                        ret.send(server.send_heartbeat().await).unwrap_or(());
-                    },
-                    Command::SendToClients(bytes, ret) => {
-                        ret.send(server.send_to_clients(bytes).await).unwrap_or(());
-                    },
-                    Command::Connections(ret) => {
-                        ret.send(server.connections()).unwrap_or(());
+/// Options for a client connection to a `BufferedServer`.
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    /// A "protected" client is a read-only follower: it cannot be disconnected
+    /// individually, and it is exempt from any per-client load-shedding, so it
+    /// always receives every frame. Even a protected client is disconnected if
+    /// it stops reading altogether, though (see `set_stuck_timeout`).
+    pub protected: bool,
+    /// If set, the client only receives those data frames whose JSON data
+    /// matches this filter. Frames sent without JSON data always bypass it.
+    pub filter: Option<Filter>,
+    /// The room the client has joined, if any, so it receives the frames sent
+    /// to that room.
+    pub room: Option<String>,
+    /// A "receive-only" client never sends anything upstream, so it is exempt
+    /// from being disconnected for being idle (see `set_idle_timeout`).
+    pub receive_only: bool,
+    /// The language of the body the client is shown, or `None` for the
+    /// default, so it only receives the updates to that body which are sent
+    /// with `send_to_lang`.
+    pub lang: Option<String>,
+}
+
+/// A single client connection to a `BufferedServer`.
+pub struct Client {
+    id: ClientId,
+    sender: hyper::body::Sender,
+    options: ClientOptions,
+    /// Whether the client has reported local changes which an update could
+    /// clobber.
+    dirty: bool,
+    /// Whether the client has been passed over by an update because it was
+    /// dirty.
+    missed: bool,
+    /// When the client last showed any sign of activity upstream, or when it
+    /// connected if it hasn't yet.
+    last_active: Instant,
+    /// What the client has been sent so far.
+    sync: SyncState,
+}
+
+/// What a single client of a `BufferedServer` has been sent so far, as worked
+/// out from the frames which reached it (including its greeting), for
+/// diagnosing clients which show stale content.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SyncState {
+    /// The highest event id (for a page, the version of its title or body)
+    /// sent to the client, if any.
+    pub version: Option<u64>,
+    /// The type of every event sent to the client.
+    pub event_types: BTreeSet<String>,
+    /// The number of frames sent to the client.
+    pub frames: u64,
+}
+
+/// The event ids and types in a frame, as noted in a client's `SyncState`.
+#[derive(Debug, Default)]
+struct FrameSummary {
+    version: Option<u64>,
+    event_types: Vec<String>,
+}
+
+impl FrameSummary {
+    /// Find the event ids and types in a frame of SSE: heartbeats and events
+    /// without a type have none.
+    fn of(frame: &[u8]) -> FrameSummary {
+        let mut summary = FrameSummary::default();
+        for line in frame.split(|byte| *byte == b'\n') {
+            if let Some(id) = line.strip_prefix(b"id: ") {
+                let id = std::str::from_utf8(id).ok().and_then(|id| id.trim().parse().ok());
+                summary.version = summary.version.max(id);
+            } else if let Some(event_type) = line.strip_prefix(b"event: ") {
+                let event_type = String::from_utf8_lossy(event_type).trim().to_string();
+                if !summary.event_types.contains(&event_type) {
+                    summary.event_types.push(event_type);
+                }
+            }
+        }
+        summary
+    }
+}
+
+impl SyncState {
+    fn note(&mut self, summary: &FrameSummary) {
+        self.version = self.version.max(summary.version);
+        self.event_types.extend(summary.event_types.iter().cloned());
+        self.frames += 1;
+    }
+}
+
+impl Client {
+    /// Test whether the client wants a frame carrying the given JSON data (if
+    /// any), according to its filter.
+    fn wants(&self, data: Option<&Value>) -> bool {
+        match (&self.options.filter, data) {
+            (Some(filter), Some(data)) => filter.matches(data),
+            _ => true,
+        }
+    }
+}
+
+/// The set of clients currently connected to a `BufferedServer`, owned by the
+/// task which processes its commands.
+#[derive(Default)]
+pub struct Clients {
+    clients: Vec<Client>,
+    stuck_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    recorder: Option<Recorder>,
+}
+
+/// The ways a send to a single client can fail.
+enum SendError {
+    /// The client has disconnected.
+    Closed,
+    /// The client hasn't read anything for the stuck-client timeout.
+    Stuck,
+}
+
+impl Clients {
+    /// Send some bytes to every client, removing all those which have
+    /// disconnected, and returning the number of clients remaining. If the
+    /// bytes are accompanied by JSON data, clients with a filter only receive
+    /// them if the data matches their filter.
+    async fn send_to_clients(&mut self, bytes: Bytes, data: Option<&Value>, stats: &Stats) -> usize {
+        self.send_each(stats, |client| if client.wants(data) {
+            Some(bytes.clone())
+        } else {
+            None
+        }).await
+    }
+
+    /// Like `send_to_clients`, but only send to clients in the given room.
+    async fn send_to_room(&mut self, room: &str, bytes: Bytes, data: Option<&Value>, stats: &Stats) -> usize {
+        self.send_each(stats, |client| {
+            if client.options.room.as_deref() == Some(room) && client.wants(data) {
+                Some(bytes.clone())
+            } else {
+                None
+            }
+        }).await
+    }
+
+    /// Send one set of bytes to every clean client and another to every dirty
+    /// client, noting that the dirty clients missed out on the first. Returns
+    /// the number of clients remaining.
+    async fn send_to_clean_clients(&mut self, clean: Bytes, dirty: Bytes, stats: &Stats) -> usize {
+        self.send_each(stats, |client| if client.dirty {
+            client.missed = true;
+            Some(dirty.clone())
+        } else {
+            Some(clean.clone())
+        }).await
+    }
+
+    /// Send some bytes only to the clients shown the body in the given language
+    /// (or `None` for the default), returning the number of clients remaining.
+    async fn send_to_lang(&mut self, lang: Option<&str>, bytes: Bytes, stats: &Stats) -> usize {
+        self.send_each(stats, |client| if client.options.lang.as_deref() == lang {
+            Some(bytes.clone())
+        } else {
+            None
+        }).await
+    }
+
+    /// Send some bytes to a single client by id, returning `true` if it was
+    /// there to receive them.
+    async fn send_to_client(&mut self, id: ClientId, bytes: Bytes, stats: &Stats) -> bool {
+        let mut found = false;
+        self.send_each(stats, |client| if client.id == id {
+            found = true;
+            Some(bytes.clone())
+        } else {
+            None
+        }).await;
+        found && self.clients.iter().any(|client| client.id == id)
+    }
+
+    /// Send to each client whatever bytes the function chooses for it, if any,
+    /// removing all those which have disconnected, and returning the number of
+    /// clients remaining.
+    async fn send_each(&mut self, stats: &Stats, mut choose: impl FnMut(&mut Client) -> Option<Bytes>) -> usize {
+        let stuck_timeout = self.stuck_timeout;
+        // Clients are usually all sent the very same bytes, which only need
+        // summarizing once
+        let mut summarized: Option<(Bytes, Arc<FrameSummary>)> = None;
+        let sent = future::join_all(self.clients.iter_mut().map(|client| {
+            let bytes = choose(client);
+            let summary = bytes.as_ref().map(|bytes| match &summarized {
+                Some((last, summary)) if last.as_ptr() == bytes.as_ptr() && last.len() == bytes.len() =>
+                    summary.clone(),
+                _ => {
+                    let summary = Arc::new(FrameSummary::of(bytes));
+                    summarized = Some((bytes.clone(), summary.clone()));
+                    summary
+                },
+            });
+            async move {
+                match (bytes, summary) {
+                    (Some(bytes), Some(summary)) => {
+                        let len = bytes.len() as u64;
+                        let send = client.sender.send_data(bytes);
+                        let result = match stuck_timeout {
+                            Some(timeout) => time::timeout(timeout, send).await
+                                .map_err(|_| SendError::Stuck)?,
+                            None => send.await,
+                        };
+                        result.map_err(|_| SendError::Closed)?;
+                        client.sync.note(&summary);
+                        Ok(len)
                     },
-                    Command::AddClient(sender) =>
-                        server.add_client(sender),
-                    Command::DisconnectAll =>
-                        server.disconnect_all(),
+                    _ => Ok(0),
                 }
             }
-        });
-        BufferedServer{commands: Mutex::new(commands)}
+        })).await;
+        let mut bytes_sent = 0;
+        for (client, result) in mem::take(&mut self.clients).into_iter().zip(sent) {
+            match result {
+                Ok(len) => {
+                    bytes_sent += len;
+                    self.clients.push(client);
+                },
+                Err(SendError::Stuck) => {
+                    // Close the connection outright, rather than waiting for
+                    // the client to read the end of the stream
+                    stats.stuck.fetch_add(1, Ordering::Relaxed);
+                    client.sender.abort();
+                },
+                Err(SendError::Closed) => { },
+            }
+        }
+        stats.bytes_sent.fetch_add(bytes_sent, Ordering::Relaxed);
+        self.clients.len()
+    }
+
+    /// Record whether a client has local changes which an update could clobber.
+    /// Returns whether the client is now clean, but missed an update while it
+    /// was dirty, or `None` if there is no such client.
+    fn set_dirty(&mut self, id: ClientId, dirty: bool) -> Option<bool> {
+        let client = self.clients.iter_mut().find(|client| client.id == id)?;
+        client.dirty = dirty;
+        client.last_active = Instant::now();
+        Some(!dirty && mem::take(&mut client.missed))
+    }
+
+    /// Note that a client has shown some activity upstream, returning `false`
+    /// if there is no such client.
+    fn touch(&mut self, id: ClientId) -> bool {
+        match self.clients.iter_mut().find(|client| client.id == id) {
+            Some(client) => {
+                client.last_active = Instant::now();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Disconnect every client which hasn't been active within the idle
+    /// timeout, if there is one, except for receive-only clients.
+    fn disconnect_idle(&mut self, stats: &Stats) {
+        if let Some(timeout) = self.idle_timeout {
+            for client in mem::take(&mut self.clients) {
+                if client.options.receive_only || client.last_active.elapsed() < timeout {
+                    self.clients.push(client);
+                } else {
+                    stats.idle.fetch_add(1, Ordering::Relaxed);
+                    client.sender.abort();
+                }
+            }
+        }
+    }
+
+    /// Disconnect a single client by id, unless it is protected. Returns `true`
+    /// if a client was disconnected.
+    fn disconnect_client(&mut self, id: ClientId) -> bool {
+        match self.clients.iter().position(|c| c.id == id && !c.options.protected) {
+            Some(index) => {
+                self.clients.swap_remove(index).sender.abort();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Get what a single client has been sent so far, or `None` if there is no
+    /// such client.
+    fn sync_state(&self, id: ClientId) -> Option<SyncState> {
+        self.clients.iter().find(|client| client.id == id).map(|client| client.sync.clone())
+    }
+
+    fn disconnect_all(&mut self) {
+        for client in mem::take(&mut self.clients) {
+            client.sender.abort();
+        }
+    }
+}
+
+/// Process commands sent to a `BufferedServer`, until either the server is
+/// dropped or its clients are handed off to a new command buffer.
+async fn serve(mut receiver: mpsc::Receiver<Command>, mut server: Clients, stats: Arc<Stats>) {
+    while let Some(command) = receiver.recv().await {
+        stats.buffered.fetch_sub(1, Ordering::Relaxed);
+        // Any change in the number of clients is a connection or disconnection
+        let clients = server.clients.len();
+        match command {
+            Command::SendHeartbeat(quiet, ret) => {
+                server.disconnect_idle(&stats);
+                let last_broadcast = *stats.last_broadcast.lock().unwrap();
+                let busy = match (quiet, last_broadcast) {
+                    (Some(quiet), Some(last)) => last.elapsed() < quiet,
+                    _ => false,
+                };
+                if busy {
+                    // Every client was sent something recently, which kept
+                    // its connection alive just as well
+                    stats.heartbeats_skipped.fetch_add(1, Ordering::Relaxed);
+                    ret.send(server.clients.len()).unwrap_or(());
+                } else {
+                    let heartbeat = Bytes::from_static(b":\n\n");
+                    ret.send(server.send_to_clients(heartbeat, None, &stats).await)
+                        .unwrap_or(());
+                }
+            },
+            Command::SendToClients(bytes, data, ret) => {
+                // Frames with data may be filtered out for some clients
+                if data.is_none() {
+                    *stats.last_broadcast.lock().unwrap() = Some(Instant::now());
+                }
+                record(&mut server.recorder, &bytes);
+                ret.send(server.send_to_clients(bytes, data.as_ref(), &stats).await)
+                    .unwrap_or(());
+            },
+            Command::SendToRoom(room, bytes, data, ret) => {
+                ret.send(server.send_to_room(&room, bytes, data.as_ref(), &stats).await)
+                    .unwrap_or(());
+            },
+            Command::SendToCleanClients(clean, dirty, ret) => {
+                record(&mut server.recorder, &clean);
+                ret.send(server.send_to_clean_clients(clean, dirty, &stats).await)
+                    .unwrap_or(());
+            },
+            Command::SendToLang(lang, bytes, ret) => {
+                if lang.is_none() {
+                    record(&mut server.recorder, &bytes);
+                }
+                ret.send(server.send_to_lang(lang.as_deref(), bytes, &stats).await)
+                    .unwrap_or(());
+            },
+            Command::SendToClient(id, bytes, ret) => {
+                ret.send(server.send_to_client(id, bytes, &stats).await)
+                    .unwrap_or(());
+            },
+            Command::SetDirty(id, dirty, ret) => {
+                let result = server.set_dirty(id, dirty);
+                if result.is_some() {
+                    stats.note_activity();
+                }
+                ret.send(result).unwrap_or(());
+            },
+            Command::Touch(id, ret) => {
+                let touched = server.touch(id);
+                if touched {
+                    stats.note_activity();
+                }
+                ret.send(touched).unwrap_or(());
+            },
+            Command::SyncState(id, ret) => {
+                ret.send(server.sync_state(id)).unwrap_or(());
+            },
+            Command::Connections(ret) => {
+                ret.send(server.clients.len()).unwrap_or(());
+            },
+            Command::ProtectedConnections(ret) => {
+                let protected =
+                    server.clients.iter().filter(|c| c.options.protected).count();
+                ret.send(protected).unwrap_or(());
+            },
+            Command::AddClient(mut client, greeting) => {
+                // A new client's channel always has room for one frame, so this
+                // can't wait on the client
+                let greeted = match greeting {
+                    Some(greeting) => {
+                        let len = greeting.len() as u64;
+                        let summary = FrameSummary::of(&greeting);
+                        let sent = client.sender.send_data(greeting).await.is_ok();
+                        if sent {
+                            stats.bytes_sent.fetch_add(len, Ordering::Relaxed);
+                            client.sync.note(&summary);
+                        }
+                        sent
+                    },
+                    None => true,
+                };
+                if greeted {
+                    server.clients.push(client);
+                }
+            },
+            Command::DisconnectClient(id, ret) => {
+                ret.send(server.disconnect_client(id)).unwrap_or(());
+            },
+            Command::DisconnectAll =>
+                server.disconnect_all(),
+            Command::SetStuckTimeout(timeout) =>
+                server.stuck_timeout = timeout,
+            Command::SetIdleTimeout(timeout) =>
+                server.idle_timeout = timeout,
+            Command::SetRecorder(recorder, ret) => {
+                // Whatever was recorded before is finished once it's dropped
+                server.recorder = recorder;
+                ret.send(()).unwrap_or(());
+            },
+            Command::Handoff(ret) => {
+                // The clients take their settings and any recording with them;
+                // if the handoff fails, they are dropped, which closes their
+                // connections
+                ret.send(server).unwrap_or(());
+                return;
+            },
+        }
+        if server.clients.len() != clients {
+            stats.note_activity();
+        }
+        stats.set_clients(server.clients.len());
+    }
+}
+
+/// Record a frame sent to every client (or every clean client, or every client
+/// in the default language), if recording. If it can't be written, recording
+/// stops.
+fn record(recorder: &mut Option<Recorder>, frame: &[u8]) {
+    if let Some(active) = recorder {
+        if !active.record(frame) {
+            *recorder = None;
+        }
+    }
+}
+
+impl BufferedServer {
+    pub async fn new(buffer_size: usize) -> BufferedServer {
+        let (commands, receiver) = mpsc::channel(buffer_size);
+        let stats = Arc::new(Stats::default());
+        tokio::spawn(serve(receiver, Clients::default(), stats.clone()));
+        BufferedServer {
+            commands: Mutex::new(commands),
+            next_id: AtomicU64::new(0),
+            stats,
+            backpressure: Arc::new(StdMutex::new(BackpressureMonitor::default())),
+        }
+    }
+
+    /// Set a callback to be invoked whenever sends to this server begin or end
+    /// experiencing backpressure. Backpressure begins as soon as a send finds
+    /// the buffer full, and ends with the first send which doesn't, or as soon
+    /// as the buffer drains, once it has lasted at least a short debounce
+    /// interval. The callback runs on the task doing the send (or on a task
+    /// watching the buffer), so it should be quick.
+    pub fn set_backpressure_callback(&self, callback: impl Fn(BackpressureState) + Send + Sync + 'static) {
+        self.backpressure.lock().unwrap().callback = Some(Box::new(callback));
+    }
+
+    /// Change the number of commands which can be buffered before a send
+    /// experiences backpressure. Existing clients are migrated to the new
+    /// buffer without missing or repeating any frames: every command sent
+    /// before the resize is processed, in order, before the new buffer takes
+    /// effect, and no command can be sent while the handoff is in progress. The
+    /// buffer always has room for at least one command.
+    pub async fn resize(&self, buffer_size: usize) {
+        let mut commands = self.commands.lock().await;
+        let (handoff, clients) = oneshot::channel();
+        // The handoff gets in line behind everything already buffered
+        self.stats.buffered.fetch_add(1, Ordering::Relaxed);
+        if commands.send(Command::Handoff(handoff)).await.is_err() {
+            self.stats.buffered.fetch_sub(1, Ordering::Relaxed);
+        }
+        let clients = clients.await.unwrap_or_default();
+        let (new_commands, receiver) = mpsc::channel(buffer_size.max(1));
+        tokio::spawn(serve(receiver, clients, self.stats.clone()));
+        *commands = new_commands;
+    }
+
+    /// Get the running statistics for this server.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
     }
 
-    pub async fn add_client(&self, client: hyper::body::Sender) {
-        let mut commands = self.commands.lock().await.clone();
-        commands.send(Command::AddClient(client)).await.unwrap_or(())
+    /// Enqueue a command for the server, counting it as backpressure if the
+    /// buffer is full and we have to wait for space. The lock on the buffer is
+    /// held until the command is enqueued, so that `resize` can't swap out the
+    /// buffer from under a waiting command.
+    async fn command(&self, command: Command) {
+        let mut commands = self.commands.lock().await;
+        self.stats.buffered.fetch_add(1, Ordering::Relaxed);
+        let mut waited = false;
+        let result = match commands.try_send(command) {
+            Err(TrySendError::Full(command)) => {
+                waited = true;
+                self.stats.backpressure.fetch_add(1, Ordering::Relaxed);
+                commands.send(command).await.map_err(|_| ())
+            },
+            result => result.map_err(|_| ()),
+        };
+        if self.backpressure.lock().unwrap().note(waited) {
+            watch_backpressure(Arc::downgrade(&self.backpressure), self.stats.clone());
+        }
+        if result.is_err() {
+            self.stats.buffered.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Add a client to the server with the given options, returning its id.
+    pub async fn add_client(&self, client: hyper::body::Sender, options: ClientOptions) -> ClientId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let client = Client{
+            id, sender: client, options,
+            dirty: false, missed: false, last_active: Instant::now(),
+            sync: SyncState::default(),
+        };
+        self.command(Command::AddClient(client, None)).await;
+        id
+    }
+
+    /// Add a client to the server with the given options, like `add_client`,
+    /// first sending it a greeting built from its id. The greeting is the very
+    /// first thing the client receives, before any other frame, and sending it
+    /// never waits for the client to start reading (whereas sending several
+    /// frames to a client whose response hasn't been returned yet would).
+    pub async fn add_client_with_greeting(&self,
+                                          client: hyper::body::Sender,
+                                          options: ClientOptions,
+                                          greeting: impl FnOnce(ClientId) -> Bytes) -> ClientId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let greeting = greeting(id);
+        let client = Client{
+            id, sender: client, options,
+            dirty: false, missed: false, last_active: Instant::now(),
+            sync: SyncState::default(),
+        };
+        self.command(Command::AddClient(client, Some(greeting))).await;
+        id
     }
 
     pub async fn send_to_clients<B: Into<Bytes>>(&self, text: B) -> impl Future<Output = usize> {
         let (sender, receiver) = oneshot::channel();
-        let mut commands = self.commands.lock().await.clone();
-        commands.send(Command::SendToClients(text.into(), sender)).await.unwrap_or(());
+        self.command(Command::SendToClients(text.into(), None, sender)).await;
         async { receiver.await.expect("oneshot::Sender dropped before sending \
                                        response from BufferedServer, which \
                                        should be impossible") }
     }
 
-    pub async fn send_heartbeat(&self) -> impl Future<Output = usize> {
+    /// Send a frame carrying some JSON data to clients, skipping those clients
+    /// whose filter does not match the data.
+    pub async fn send_data_to_clients<B: Into<Bytes>>(&self, text: B, data: Value) -> impl Future<Output = usize> {
         let (sender, receiver) = oneshot::channel();
-        let mut commands = self.commands.lock().await.clone();
-        commands.send(Command::SendHeartbeat(sender)).await.unwrap_or(());
+        self.command(Command::SendToClients(text.into(), Some(data), sender)).await;
         async { receiver.await.expect("oneshot::Sender dropped before sending \
                                        response from BufferedServer, which \
                                        should be impossible") }
     }
 
-    #[allow(unused)]
+    /// Send a frame only to the clients in the given room, skipping those whose
+    /// filter does not match the JSON data accompanying it, if any.
+    pub async fn send_to_room<B: Into<Bytes>>(&self, room: &str, text: B, data: Option<Value>) -> impl Future<Output = usize> {
+        let (sender, receiver) = oneshot::channel();
+        self.command(Command::SendToRoom(room.to_string(), text.into(), data, sender)).await;
+        async { receiver.await.expect("oneshot::Sender dropped before sending \
+                                       response from BufferedServer, which \
+                                       should be impossible") }
+    }
+
+    /// Send one frame to every client which hasn't reported itself dirty, and
+    /// another to every client which has.
+    pub async fn send_to_clean_clients<B: Into<Bytes>>(&self, clean: B, dirty: B) -> impl Future<Output = usize> {
+        let (sender, receiver) = oneshot::channel();
+        self.command(Command::SendToCleanClients(clean.into(), dirty.into(), sender)).await;
+        async { receiver.await.expect("oneshot::Sender dropped before sending \
+                                       response from BufferedServer, which \
+                                       should be impossible") }
+    }
+
+    /// Send a frame only to the clients shown the body in the given language, or
+    /// with `None`, to those shown the default body.
+    pub async fn send_to_lang<B: Into<Bytes>>(&self, lang: Option<&str>, text: B) -> impl Future<Output = usize> {
+        let (sender, receiver) = oneshot::channel();
+        self.command(Command::SendToLang(lang.map(String::from), text.into(), sender)).await;
+        async { receiver.await.expect("oneshot::Sender dropped before sending \
+                                       response from BufferedServer, which \
+                                       should be impossible") }
+    }
+
+    /// Send a frame to a single client by id. Returns `false` if there is no
+    /// such client.
+    pub async fn send_to_client<B: Into<Bytes>>(&self, id: ClientId, text: B) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        self.command(Command::SendToClient(id, text.into(), sender)).await;
+        receiver.await.expect("oneshot::Sender dropped before sending \
+                               response from BufferedServer, which \
+                               should be impossible")
+    }
+
+    /// Record whether a client has local changes which an update could clobber.
+    /// Clients start out clean. Returns whether the client is now clean, but
+    /// was passed over by `send_to_clean_clients` while it was dirty, or `None`
+    /// if there is no such client.
+    pub async fn set_dirty(&self, id: ClientId, dirty: bool) -> Option<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.command(Command::SetDirty(id, dirty, sender)).await;
+        receiver.await.expect("oneshot::Sender dropped before sending \
+                               response from BufferedServer, which \
+                               should be impossible")
+    }
+
+    /// Note that a client has shown some activity upstream, so it isn't
+    /// disconnected for being idle. Returns `false` if there is no such client.
+    pub async fn touch_client(&self, id: ClientId) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        self.command(Command::Touch(id, sender)).await;
+        receiver.await.expect("oneshot::Sender dropped before sending \
+                               response from BufferedServer, which \
+                               should be impossible")
+    }
+
+    /// Get what a single client has been sent so far: the highest event id and
+    /// every event type which reached it. Returns `None` if there is no such
+    /// client.
+    pub async fn client_sync_state(&self, id: ClientId) -> Option<SyncState> {
+        let (sender, receiver) = oneshot::channel();
+        self.command(Command::SyncState(id, sender)).await;
+        receiver.await.expect("oneshot::Sender dropped before sending \
+                               response from BufferedServer, which \
+                               should be impossible")
+    }
+
+    /// Send a heartbeat to every client, to keep their connections alive,
+    /// first disconnecting idle clients. If `quiet` is given, the heartbeat is
+    /// skipped when a frame was sent to every client within that time, since
+    /// that did the same job; so on a busy server, heartbeats are only sent
+    /// during genuine silence.
+    pub async fn send_heartbeat(&self, quiet: Option<Duration>) -> impl Future<Output = usize> {
+        let (sender, receiver) = oneshot::channel();
+        self.command(Command::SendHeartbeat(quiet, sender)).await;
+        async { receiver.await.expect("oneshot::Sender dropped before sending \
+                                       response from BufferedServer, which \
+                                       should be impossible") }
+    }
+
+    /// Disconnect any client which, when sent a frame, doesn't read anything
+    /// for the given time (or if the timeout is `None`, never do this). A client
+    /// which never reads would otherwise hold up every send to the server
+    /// indefinitely, once its connection's buffers fill up.
+    pub async fn set_stuck_timeout(&self, timeout: Option<Duration>) {
+        self.command(Command::SetStuckTimeout(timeout)).await;
+    }
+
+    /// Disconnect any client which hasn't shown any activity upstream (see
+    /// `touch_client`) for the given time, checking at every heartbeat, or if
+    /// the timeout is `None`, never do this. Receive-only clients are exempt.
+    pub async fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        self.command(Command::SetIdleTimeout(timeout)).await;
+    }
+
+    /// Record every frame sent to all clients from now on (or, for a body
+    /// update, to all clean clients and those in the default language) with
+    /// the given recorder, replacing any other one, or if `None`, stop
+    /// recording. Once this returns, any previous recording is finished.
+    pub async fn set_recorder(&self, recorder: Option<Recorder>) {
+        let (sender, receiver) = oneshot::channel();
+        self.command(Command::SetRecorder(recorder, sender)).await;
+        // If the server is gone, so is the recorder
+        receiver.await.unwrap_or(());
+    }
+
     pub async fn disconnect_all(&self) {
-        let mut commands = self.commands.lock().await.clone();
-        commands.send(Command::DisconnectAll).await.unwrap_or(())
+        self.command(Command::DisconnectAll).await;
+    }
+
+    /// Disconnect a single client by id. Returns `false` if there is no such
+    /// client, or if the client is protected.
+    pub async fn disconnect_client(&self, id: ClientId) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        self.command(Command::DisconnectClient(id, sender)).await;
+        receiver.await.expect("oneshot::Sender dropped before sending \
+                               response from BufferedServer, which \
+                               should be impossible")
     }
 
     pub async fn connections(&self) -> usize {
         let (sender, receiver) = oneshot::channel();
-        let mut commands = self.commands.lock().await.clone();
-        commands.send(Command::Connections(sender)).await.unwrap_or(());
+        self.command(Command::Connections(sender)).await;
         receiver.await.expect("oneshot::Sender dropped before sending \
                                response from BufferedServer, which \
                                should be impossible")
     }
+
+    /// Count only those connections which are protected followers.
+    pub async fn protected_connections(&self) -> usize {
+        let (sender, receiver) = oneshot::channel();
+        self.command(Command::ProtectedConnections(sender)).await;
+        receiver.await.expect("oneshot::Sender dropped before sending \
+                               response from BufferedServer, which \
+                               should be impossible")
+    }
+}
+
+/// A coalescer in front of a `BufferedServer`, which merges frames posted in
+/// quick succession into one. The first frame posted opens a window, and when
+/// the window closes, everything posted during it is sent to all clients
+/// together, as a single frame. Each frame is posted under a key naming the
+/// piece of state it sets, and a frame replaces any pending frame with the same
+/// key, so only the last write to each piece of state is sent. The merged frame
+/// keeps the frames in the order of their last writes. A manual coalescer has
+/// no window: what's posted to it waits until it's taken.
+#[derive(Debug)]
+pub struct Coalescer {
+    pending: Arc<StdMutex<Vec<(String, Bytes)>>>,
+    window: Option<Duration>,
+    server: Arc<BufferedServer>,
+}
+
+impl Coalescer {
+    /// Make a new coalescer with the given window in front of the given server.
+    pub fn new(server: Arc<BufferedServer>, window: Duration) -> Coalescer {
+        Coalescer{pending: Arc::new(StdMutex::new(Vec::new())), window: Some(window), server}
+    }
+
+    /// Make a new manual coalescer in front of the given server, which never
+    /// sends anything itself.
+    pub fn manual(server: Arc<BufferedServer>) -> Coalescer {
+        Coalescer{pending: Arc::new(StdMutex::new(Vec::new())), window: None, server}
+    }
+
+    /// Get the length of the window during which frames are merged (zero for
+    /// a manual coalescer).
+    pub fn window(&self) -> Duration {
+        self.window.unwrap_or_else(|| Duration::from_millis(0))
+    }
+
+    /// Post a frame setting the piece of state named by the key, to be sent at
+    /// the end of the current window (opening one if there isn't one open).
+    pub fn post<B: Into<Bytes>>(&self, key: &str, frame: B) {
+        let mut pending = self.pending.lock().unwrap();
+        let opening = pending.is_empty();
+        pending.retain(|(pending_key, _)| pending_key != key);
+        pending.push((key.to_string(), frame.into()));
+        if let (true, Some(window)) = (opening, self.window) {
+            let pending = self.pending.clone();
+            let server = self.server.clone();
+            tokio::spawn(async move {
+                time::delay_for(window).await;
+                let frame = take_merged(&pending);
+                if let Some(frame) = frame {
+                    server.send_to_clients(frame).await.await;
+                }
+            });
+        }
+    }
+
+    /// Take everything posted during the current window as one frame, so it
+    /// can be sent right away instead of when the window closes. Returns `None`
+    /// if nothing is pending.
+    pub fn take(&self) -> Option<Bytes> {
+        take_merged(&self.pending)
+    }
+}
+
+/// Take all pending frames from a coalescer, merged into one frame.
+fn take_merged(pending: &StdMutex<Vec<(String, Bytes)>>) -> Option<Bytes> {
+    let frames = mem::take(&mut *pending.lock().unwrap());
+    if frames.is_empty() {
+        return None
+    }
+    let mut merged = Vec::with_capacity(frames.iter().map(|(_, frame)| frame.len()).sum());
+    for (_, frame) in frames {
+        merged.extend_from_slice(&frame);
+    }
+    Some(merged.into())
+}
+
+/// A bounded mailbox in front of a `BufferedServer`, decoupling whoever posts
+/// frames from the speed at which clients consume them. Posting is immediate:
+/// a dedicated task drains the mailbox into the server one frame at a time. If
+/// the mailbox is full when a frame is posted, the new frame replaces the most
+/// recently queued one, so the mailbox never holds more than its capacity and
+/// clients always eventually see the latest frame. This means it should only be
+/// used for frames which each supersede the last (like whole-body updates).
+///
+/// The draining task stops when the mailbox is dropped, and the frames still
+/// waiting in it are never sent (though one already being handed to the server
+/// may be); use `clear` first to find out whether there were any.
+#[derive(Debug)]
+pub struct Mailbox {
+    frames: Arc<StdMutex<VecDeque<Bytes>>>,
+    /// Held by the draining task while it hands a frame to the server
+    sending: Arc<Mutex<()>>,
+    capacity: usize,
+    wake: mpsc::Sender<()>,
+}
+
+impl Mailbox {
+    /// Make a new mailbox holding at most `capacity` frames (or one frame, if
+    /// `capacity` is zero) in front of the given server.
+    pub fn new(server: Arc<BufferedServer>, capacity: usize) -> Mailbox {
+        let capacity = capacity.max(1);
+        let frames = Arc::new(StdMutex::new(VecDeque::with_capacity(capacity)));
+        let sending = Arc::new(Mutex::new(()));
+        let (wake, mut woken) = mpsc::channel(1);
+        // Only the mailbox itself keeps its frames alive
+        let pending = Arc::downgrade(&frames);
+        let handing_over = sending.clone();
+        tokio::spawn(async move {
+            while let Some(()) = woken.recv().await {
+                loop {
+                    let handing_over = handing_over.lock().await;
+                    let frame = match pending.upgrade() {
+                        Some(pending) => pending.lock().unwrap().pop_front(),
+                        None => return,
+                    };
+                    match frame {
+                        // Wait for each frame to be sent before sending the
+                        // next, so frames coalesce here rather than queueing
+                        // up in the server
+                        Some(frame) => {
+                            let sent = server.send_to_clients(frame).await;
+                            drop(handing_over);
+                            sent.await;
+                        },
+                        None => break,
+                    }
+                }
+            }
+        });
+        Mailbox{frames, sending, capacity, wake}
+    }
+
+    /// Get the number of frames the mailbox can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Get the number of frames waiting in the mailbox.
+    pub fn len(&self) -> usize {
+        self.frames.lock().unwrap().len()
+    }
+
+    /// Throw away every frame waiting in the mailbox, returning whether there
+    /// were any. This waits for any frame already taken out of the mailbox to
+    /// be handed to the server, so afterwards, frames sent to the server
+    /// directly come after every frame from the mailbox.
+    pub async fn clear(&self) -> bool {
+        let _handing_over = self.sending.lock().await;
+        let mut frames = self.frames.lock().unwrap();
+        let pending = !frames.is_empty();
+        frames.clear();
+        pending
+    }
+
+    /// Post a frame to the mailbox, to be sent to the server as soon as all
+    /// frames posted before it have been sent (or replaced).
+    pub fn post<B: Into<Bytes>>(&mut self, frame: B) {
+        {
+            let mut frames = self.frames.lock().unwrap();
+            if frames.len() >= self.capacity {
+                frames.pop_back();
+            }
+            frames.push_back(frame.into());
+        }
+        // If there's already a wakeup pending, the drainer will pick up this
+        // frame when it gets to it
+        self.wake.try_send(()).unwrap_or(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::{read_for, read_slowly};
+    use super::super::log::ServerLog;
+    use super::super::recording::read_recording;
+    use std::sync::atomic::AtomicBool;
+    use hyper::Body;
+
+    #[tokio::test]
+    async fn dropped_mailboxes_stop_draining() {
+        let server = Arc::new(BufferedServer::new(1).await);
+        let (sender, mut body) = Body::channel();
+        server.add_client(sender, ClientOptions::default()).await;
+        let mut mailbox = Mailbox::new(server.clone(), 8);
+        for n in 1..=8 {
+            mailbox.post(format!("data: {}\n\n", n));
+        }
+        assert_eq!(mailbox.len(), 8);
+        drop(mailbox);
+        let received = read_for(&mut body, Duration::from_millis(300)).await;
+        assert!(!received.contains("data: 8"), "Still drained: {:?}", received);
+    }
+
+    #[tokio::test]
+    async fn backpressure_ends_when_the_buffer_drains() {
+        let server = BufferedServer::new(1).await;
+        let transitions = Arc::new(StdMutex::new(Vec::new()));
+        let noted = transitions.clone();
+        server.set_backpressure_callback(move |state| noted.lock().unwrap().push(state));
+        let (sender, body) = Body::channel();
+        server.add_client(sender, ClientOptions::default()).await;
+        read_slowly(body, Duration::from_millis(10));
+
+        // Send until the buffer is full, then wait for it to drain without
+        // sending anything more, as a producer throttling itself would
+        while transitions.lock().unwrap().is_empty() {
+            let _sent = server.send_to_clients("data: frame\n\n").await;
+        }
+        let ended = async {
+            while transitions.lock().unwrap().len() < 2 {
+                time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(2), ended).await
+            .expect("Backpressure should end once the buffer drains");
+        assert_eq!(*transitions.lock().unwrap(),
+                   vec![BackpressureState::Begin, BackpressureState::End]);
+    }
+
+    /// The numbers in the `data: <n>` frames of a stream, in order.
+    fn numbers(frames: &str) -> Vec<usize> {
+        frames.split("\n\n")
+            .filter_map(|frame| frame.strip_prefix("data: ")?.parse().ok())
+            .collect()
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn resizes_during_a_broadcast_neither_drop_nor_repeat_frames() {
+        const FRAMES: usize = 2000;
+        let server = Arc::new(BufferedServer::new(4).await);
+        let mut readers = Vec::new();
+        for _ in 0..3 {
+            let (sender, body) = Body::channel();
+            server.add_client(sender, ClientOptions::default()).await;
+            readers.push(read_slowly(body, Duration::from_millis(0)));
+        }
+        let path = std::env::temp_dir()
+            .join(format!("myxine-resize-test-{}.rec", std::process::id()));
+        let log = ServerLog::default();
+        server.set_recorder(Some(Recorder::create(&path, log.span()).unwrap())).await;
+
+        let resizing = Arc::new(AtomicBool::new(true));
+        let resizer = tokio::spawn({
+            let (server, resizing) = (server.clone(), resizing.clone());
+            async move {
+                let mut resizes = 0;
+                while resizing.load(Ordering::Relaxed) {
+                    server.resize(resizes % 8 + 1).await;
+                    resizes += 1;
+                }
+                resizes
+            }
+        });
+        for n in 0..FRAMES {
+            let _sent = server.send_to_clients(format!("data: {}\n\n", n)).await;
+        }
+        resizing.store(false, Ordering::Relaxed);
+        assert!(resizer.await.unwrap() > 1, "The buffer was never resized");
+        server.set_recorder(None).await;
+        drop(server);
+
+        let expected: Vec<usize> = (0..FRAMES).collect();
+        for reader in readers {
+            assert_eq!(numbers(&reader.await.unwrap()), expected);
+        }
+        let recording = read_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let recorded: String = recording.iter()
+            .map(|(_, frame)| String::from_utf8_lossy(frame).into_owned())
+            .collect();
+        assert_eq!(numbers(&recorded), expected);
+    }
+}
+
+/// Helpers for tests which read what a client is sent.
+#[cfg(test)]
+pub(crate) mod testing {
+    use futures::StreamExt;
+    use hyper::Body;
+    use std::time::Duration;
+    use tokio::time;
+
+    /// Read from a client's stream until what it's been sent contains the
+    /// given text, returning everything read. Panics if that doesn't happen
+    /// within a second.
+    pub async fn read_until(body: &mut Body, needle: &str) -> String {
+        let mut received = String::new();
+        let reading = async {
+            while !received.contains(needle) {
+                match body.next().await {
+                    Some(Ok(chunk)) => received.push_str(&String::from_utf8_lossy(&chunk)),
+                    _ => break,
+                }
+            }
+        };
+        time::timeout(Duration::from_secs(1), reading).await.unwrap_or(());
+        assert!(received.contains(needle), "Expected {:?} in {:?}", needle, received);
+        received
+    }
+
+    /// Read everything a client is sent for the given time.
+    pub async fn read_for(body: &mut Body, duration: Duration) -> String {
+        let mut received = String::new();
+        let reading = async {
+            while let Some(Ok(chunk)) = body.next().await {
+                received.push_str(&String::from_utf8_lossy(&chunk));
+            }
+        };
+        time::timeout(duration, reading).await.unwrap_or(());
+        received
+    }
+
+    /// Read a client's stream in the background a chunk at a time, pausing
+    /// after each chunk, and return everything it was sent once it's closed.
+    pub fn read_slowly(mut body: Body, pause: Duration) -> tokio::task::JoinHandle<String> {
+        tokio::spawn(async move {
+            let mut received = String::new();
+            while let Some(Ok(chunk)) = body.next().await {
+                received.push_str(&String::from_utf8_lossy(&chunk));
+                time::delay_for(pause).await;
+            }
+            received
+        })
+    }
 }
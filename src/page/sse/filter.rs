@@ -0,0 +1,108 @@
+use serde_json::Value;
+use std::convert::TryFrom;
+
+/// A filter over the JSON data of events, evaluated on the server so that a
+/// client only receives those events it's interested in. The syntax is a list
+/// of clauses joined by `&&`, each of which is either a comparison of a field
+/// to a JSON literal, like `level == "error"` or `count != 0`, or just a field
+/// on its own, like `urgent`, which matches when that field is present and is
+/// neither `null` nor `false`. Fields are dotted paths into the data, like
+/// `source.host`, where numeric segments index into arrays.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter(Vec<Clause>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Clause {
+    Truthy(Vec<String>),
+    Equal(Vec<String>, Value),
+    NotEqual(Vec<String>, Value),
+}
+
+impl Filter {
+    /// Test if some event data matches this filter.
+    pub fn matches(&self, data: &Value) -> bool {
+        self.0.iter().all(|clause| match clause {
+            Clause::Truthy(path) => match lookup(data, path) {
+                None | Some(Value::Null) | Some(Value::Bool(false)) => false,
+                Some(_) => true,
+            },
+            Clause::Equal(path, value) => lookup(data, path) == Some(value),
+            Clause::NotEqual(path, value) => lookup(data, path) != Some(value),
+        })
+    }
+}
+
+/// Look up a dotted path within some JSON data.
+fn lookup<'a>(data: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(data, |value, segment| match value {
+        Value::Object(map) => map.get(segment),
+        Value::Array(array) => segment.parse::<usize>().ok().and_then(|i| array.get(i)),
+        _ => None,
+    })
+}
+
+impl TryFrom<String> for Filter {
+    type Error = String;
+    fn try_from(string: String) -> Result<Filter, String> {
+        let mut clauses = Vec::new();
+        let mut rest = string.trim_start();
+        loop {
+            let (clause, after) = parse_clause(rest)
+                .ok_or_else(|| format!("Invalid filter: {}", string))?;
+            clauses.push(clause);
+            rest = after.trim_start();
+            if rest.is_empty() {
+                break;
+            } else if let Some(after) = rest.strip_prefix("&&") {
+                rest = after.trim_start();
+            } else {
+                return Err(format!("Invalid filter: {}", string))
+            }
+        }
+        Ok(Filter(clauses))
+    }
+}
+
+/// Parse a single clause from the front of a string, returning it and the
+/// remainder of the string.
+fn parse_clause(string: &str) -> Option<(Clause, &str)> {
+    let end = string
+        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '.'))
+        .unwrap_or(string.len());
+    let (path, rest) = string.split_at(end);
+    let path: Vec<String> = path.split('.').map(String::from).collect();
+    if path.iter().any(String::is_empty) {
+        return None
+    }
+    let rest = rest.trim_start();
+    let (equal, rest) = if let Some(rest) = rest.strip_prefix("==") {
+        (true, rest)
+    } else if let Some(rest) = rest.strip_prefix("!=") {
+        (false, rest)
+    } else {
+        return Some((Clause::Truthy(path), rest))
+    };
+    // Parse exactly one JSON literal, and find out where it ended: strings can
+    // contain anything, so let the JSON parser find their end, but other
+    // literals end at whitespace or the start of the next clause
+    let rest = rest.trim_start();
+    let (value, rest) = if rest.starts_with('"') {
+        let mut values = serde_json::Deserializer::from_str(rest).into_iter::<Value>();
+        let value = values.next()?.ok()?;
+        (value, &rest[values.byte_offset()..])
+    } else {
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '&')
+            .unwrap_or(rest.len());
+        let (literal, rest) = rest.split_at(end);
+        (serde_json::from_str(literal).ok()?, rest)
+    };
+    if value.is_object() || value.is_array() {
+        return None
+    }
+    Some((if equal {
+        Clause::Equal(path, value)
+    } else {
+        Clause::NotEqual(path, value)
+    }, rest))
+}
@@ -4,26 +4,51 @@ use http::request::Parts;
 use http::header::{HeaderMap, HeaderValue};
 use lazy_static::lazy_static;
 use std::sync::Arc;
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
 use std::convert::TryFrom;
+use std::mem;
 use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
-use futures::future::FutureExt;
+use tokio::time;
+use futures::future::{self, FutureExt};
 use futures::{select, pin_mut};
 use futures::stream::StreamExt;
 use itertools::Itertools;
+use percent_encoding::percent_decode_str;
+use uuid::Uuid;
 
 mod params;
 mod heartbeat;
+mod peers;
 
-use crate::page::Page;
-use params::{GetParams, PostParams};
+pub use heartbeat::set_gc_policy;
+pub use peers::{parse_peer, set_peers};
+
+use crate::page::{Page, BodyChange, EventMessage, RootElement, NotNumeric, LogLevel, StepStatus};
+use crate::page::metrics;
+use crate::page::multiplex::Multiplexer;
+use crate::page::ndjson;
+use crate::page::snapshot::Snapshot;
+use crate::page::sse::{ClientOptions, Filter};
+use params::{GetParams, MultiplexParams, NamespaceParams, PostParams, Setting, UnsubscribeParams};
 
 lazy_static! {
     /// The current contents of the server, indexed by path
     pub(crate) static ref PAGES:
     Mutex<HashMap<String, Arc<Page>>>
         = Mutex::new(HashMap::new());
+
+    /// The path of the page served in place of empty pages, if any
+    static ref NOT_FOUND: Mutex<Option<String>> = Mutex::new(None);
+
+    /// The directory pages can be recorded into and replayed from, if any
+    static ref RECORDINGS: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    /// The multiplexed streams whose clients are still there, by id, so that
+    /// pages can be unsubscribed from them
+    static ref MULTIPLEXERS: Mutex<HashMap<String, Multiplexer>> = Mutex::new(HashMap::new());
 }
 
 /// Try to unwrap a `Result`, returning it if it is `Ok`. If it is an `Err`,
@@ -42,9 +67,37 @@ macro_rules! unwrap_or_abort {
     };
 }
 
-/// Run the main server loop alongside the heartbeat to all SSE clients
+/// Run the main server loop alongside the heartbeat to all SSE clients. If
+/// there's a state file, the pages saved in it (if it exists) are loaded before
+/// starting, and every page is saved to it when shutting down. If there's a
+/// not-found path, the page there is served in place of empty pages (see
+/// `set_not_found_path`), starting with default content if it's empty itself.
 #[allow(clippy::unnecessary_mut_passed)]
-pub async fn run(socket_addr: SocketAddr) {
+pub async fn run(socket_addr: SocketAddr, state_file: Option<&Path>, not_found: Option<String>) {
+    // Load the saved pages, refusing to start (and later overwrite them) if
+    // they can't be loaded
+    if let Some(state_file) = state_file {
+        if state_file.exists() {
+            unwrap_or_abort!(load_all(state_file).await);
+        }
+    }
+
+    // Set up the not-found page, unless one was saved at its path
+    if let Some(not_found) = not_found {
+        let not_found = not_found.trim_end_matches('/').to_string();
+        unwrap_or_abort!(set_not_found_path(&not_found).await);
+        let saved = PAGES.lock().await.get(&not_found).cloned();
+        let empty = match saved {
+            Some(page) => page.is_empty().await,
+            None => true,
+        };
+        if empty {
+            let page = Arc::new(Page::not_found_default().await);
+            PAGES.lock().await.insert(not_found.clone(), page);
+        }
+        heartbeat::hold_path(not_found);
+    }
+
     // Bind the server to this socket address
     let listener   = unwrap_or_abort!(TcpListener::bind(socket_addr));
     let local_addr = unwrap_or_abort!(listener.local_addr());
@@ -69,13 +122,181 @@ pub async fn run(socket_addr: SocketAddr) {
                 }
         })).fuse();
 
-    pin_mut!(serve, heartbeat);
+    // Shut down gracefully when interrupted
+    let interrupt = tokio::signal::ctrl_c().fuse();
+
+    pin_mut!(serve, heartbeat, interrupt);
     select! {
         result = serve => result.unwrap_or_else(|err| eprintln!("{}", err)),
         () = heartbeat => (),
+        _ = interrupt => {
+            if let Some(state_file) = state_file {
+                if let Err(err) = dump_all(state_file).await {
+                    eprintln!("{}", err);
+                }
+            }
+            shutdown(Instant::now() + SHUTDOWN_GRACE_PERIOD).await
+        },
+    }
+}
+
+/// Save a snapshot of every page which isn't empty to a file, as a JSON object
+/// mapping paths to snapshots, replacing whatever the file held before.
+/// Connections aren't saved: clients reconnect once the pages are loaded again
+/// by `load_all`. Returns the number of pages saved.
+pub async fn dump_all(file: &Path) -> Result<usize, String> {
+    dump_pages(&PAGES, file).await
+}
+
+/// Save a snapshot of every page in this map which isn't empty to a file (see
+/// `dump_all`).
+async fn dump_pages(pages: &Mutex<HashMap<String, Arc<Page>>>, file: &Path) -> Result<usize, String> {
+    // Clone out the pages so we don't hold the lock on the map while we wait on
+    // each page
+    let pages: Vec<(String, Arc<Page>)> = pages.lock().await.iter()
+        .map(|(path, page)| (path.clone(), page.clone()))
+        .collect();
+    let mut snapshots: BTreeMap<String, Snapshot> = BTreeMap::new();
+    for (path, page) in pages {
+        if !page.is_empty().await {
+            snapshots.insert(path, page.snapshot().await);
+        }
+    }
+    let json = serde_json::to_vec(&snapshots)
+        .expect("Serializing page snapshots to JSON shouldn't fail");
+    // Write the whole file before replacing the old one, so a failure part way
+    // through doesn't lose anything
+    let temporary = file.with_extension("tmp");
+    std::fs::write(&temporary, json)
+        .and_then(|()| std::fs::rename(&temporary, file))
+        .map_err(|err| format!("Couldn't save pages to {}: {}", file.display(), err))?;
+    Ok(snapshots.len())
+}
+
+/// Load the pages saved to a file by `dump_all`, replacing any pages already at
+/// the same paths. Nothing is loaded if any of the pages are invalid. Returns
+/// the number of pages loaded.
+pub async fn load_all(file: &Path) -> Result<usize, String> {
+    load_pages(&PAGES, file).await
+}
+
+/// Load the pages saved to a file by `dump_all` into this map (see
+/// `load_all`).
+async fn load_pages(pages: &Mutex<HashMap<String, Arc<Page>>>, file: &Path) -> Result<usize, String> {
+    let json = std::fs::read(file)
+        .map_err(|err| format!("Couldn't read saved pages from {}: {}", file.display(), err))?;
+    let snapshots: BTreeMap<String, Snapshot> = serde_json::from_slice(&json)
+        .map_err(|err| format!("Invalid saved pages in {}: {}", file.display(), err))?;
+    let mut restored = Vec::with_capacity(snapshots.len());
+    for (path, snapshot) in snapshots {
+        if !path.starts_with('/') {
+            return Err(format!("Invalid path of saved page: {}", path))
+        }
+        let page = Page::restore(snapshot).await
+            .map_err(|err| format!("Invalid saved page at {}: {}", path, err))?;
+        restored.push((path, Arc::new(page)));
+    }
+    let count = restored.len();
+    let mut pages = pages.lock().await;
+    for (path, page) in restored {
+        heartbeat::hold_path(path.clone());
+        pages.insert(path, page);
+    }
+    Ok(count)
+}
+
+/// Serve the page at this path (with a 404 status) in place of any page
+/// which is empty, so that a server can show its own not-found page, which can
+/// be dynamic like any other. Its clients get updates from the not-found page's
+/// own path, so a client shown it in place of a page doesn't switch to that page
+/// when something is put there later, until it's reloaded. Returns an error if
+/// the path is reserved, or if there's a not-found path already: it can only be
+/// set once.
+pub async fn set_not_found_path(path: &str) -> Result<(), String> {
+    if !path.starts_with('/') && !path.is_empty() {
+        return Err(format!("Not-found path must start with '/': {}", path))
+    }
+    if path.starts_with("/.myxine/") {
+        return Err(format!("Not-found path is reserved: {}", path))
+    }
+    let mut not_found = NOT_FOUND.lock().await;
+    if let Some(existing) = &*not_found {
+        return Err(format!("Not-found path is already set to {}", existing))
+    }
+    *not_found = Some(path.to_string());
+    Ok(())
+}
+
+/// Let pages be recorded into files in this directory, and played back from
+/// them, by name (see `recording_path`). Until this is called, they can't be.
+pub async fn set_recording_dir(dir: PathBuf) {
+    *RECORDINGS.lock().await = Some(dir);
+}
+
+/// Get the file holding the recording with the given name. Returns an error if
+/// there's no directory for recordings, or if the name isn't a plain file name
+/// (made of letters, digits, `-`, `_`, and `.`, but not starting with `.`), so
+/// that no request can reach a file anywhere else.
+async fn recording_path(name: &str) -> Result<PathBuf, String> {
+    let plain = !name.is_empty() && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    if !plain {
+        return Err(format!("Invalid recording name: {}", name))
+    }
+    match &*RECORDINGS.lock().await {
+        Some(dir) => Ok(dir.join(name)),
+        None => Err("Recording isn't enabled (see the --recordings option)".to_string()),
+    }
+}
+
+/// Get every page in a namespace (see `Page::set_namespace`), with its path, in
+/// order of path, so they can all be acted on at once, like every page of one
+/// tenant.
+pub async fn pages_in_namespace(namespace: &str) -> Vec<(String, Arc<Page>)> {
+    // Clone out the pages so we don't hold the lock on PAGES while we wait on
+    // each page
+    let pages: Vec<(String, Arc<Page>)> = PAGES.lock().await.iter()
+        .map(|(path, page)| (path.clone(), page.clone()))
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .collect();
+    let mut in_namespace = Vec::new();
+    for (path, page) in pages {
+        if page.namespace().await.as_deref() == Some(namespace) {
+            in_namespace.push((path, page));
+        }
+    }
+    in_namespace
+}
+
+/// Choose the name to download the page at a path as: the last part of the
+/// path, with `.html` added for a dynamic page if it doesn't already end with
+/// it, or `index.html` (or `download` for a static page) at the root.
+fn download_filename(path: &str, dynamic: bool) -> String {
+    let name = path.rsplit('/').next().unwrap_or("");
+    let name = percent_decode_str(name).decode_utf8_lossy();
+    match (name.as_ref(), dynamic) {
+        ("", true) => "index.html".to_string(),
+        ("", false) => "download".to_string(),
+        (name, true) if !name.ends_with(".html") && !name.ends_with(".htm") =>
+            format!("{}.html", name),
+        (name, _) => name.to_string(),
     }
 }
 
+/// How long to give pages to flush their updates to clients when shutting down.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Flush the latest state of every page to its clients and tell them the server
+/// is shutting down, then drop all the pages. Pages which can't do this by the
+/// deadline are dropped anyway.
+async fn shutdown(deadline: Instant) {
+    let pages = mem::take(&mut *PAGES.lock().await);
+    future::join_all(pages.values().map(|page| async move {
+        time::timeout_at(time::Instant::from_std(deadline), page.prepare_shutdown(deadline))
+            .await.unwrap_or(false)
+    })).await;
+}
+
 /// Get a page from the global table (creating it if it does not yet exist) and
 /// make sure that it receives heartbeats in the future
 async fn get_page(path: &str) -> Arc<Page> {
@@ -110,10 +331,86 @@ macro_rules! static_asset {
 
 /// Process requests specific to the special '/.myxine/' path (the only path
 /// which is not useable as a normal endpoint). This is used for ser
-fn process_special_request(
-    method: Method, path: &str, _query: &str
+async fn process_special_request(
+    method: Method, path: &str, query: &str
 ) -> Result<Response<Body>, hyper::Error> {
     Ok(match (method, path) {
+        (Method::GET, "/metrics") => {
+            // Clone out the pages so we don't hold the lock on PAGES while we
+            // wait on each page
+            let pages: Vec<(String, Arc<Page>)> = PAGES.lock().await.iter()
+                .map(|(path, page)| (path.clone(), page.clone()))
+                .sorted_by(|(a, _), (b, _)| a.cmp(b))
+                .collect();
+            let mut samples = Vec::with_capacity(pages.len());
+            for (path, page) in &pages {
+                samples.push((path.as_str(), page.metrics().await));
+            }
+            let text = metrics::exposition(samples);
+            Response::builder()
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .header("Cache-Control", "no-cache")
+                .body(Body::from(text))
+                .unwrap()
+        },
+        (Method::GET, "/multiplex") => {
+            let pages = match MultiplexParams::parse(query) {
+                Some(params) => params.pages,
+                None => return Ok(bad_request("A multiplexed stream needs ?pages=/a,/b,...")),
+            };
+            let (mut multiplexer, body) = Multiplexer::new();
+            let id = Uuid::new_v4().to_simple().to_string();
+            multiplexer.send_id(&id).await;
+            for path in pages {
+                // Static pages have no updates to send
+                multiplexer.subscribe(&path, &*get_page(&path).await).await;
+            }
+            let mut multiplexers = MULTIPLEXERS.lock().await;
+            // Forget the streams which have nothing left to send
+            multiplexers.retain(|_, multiplexer| multiplexer.paths().next().is_some());
+            multiplexers.insert(id, multiplexer);
+            Response::builder()
+                .header("Content-Type", "text/event-stream")
+                .header("Cache-Control", "no-cache")
+                .header("Access-Control-Allow-Origin", "*")
+                .body(body)
+                .unwrap()
+        },
+        (Method::GET, "/namespace") => {
+            let name = match NamespaceParams::parse(query) {
+                Some(params) => params.name,
+                None => return Ok(bad_request("Pages in a namespace need ?name=<namespace>")),
+            };
+            let paths: Vec<String> = pages_in_namespace(&name).await.into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            let json = serde_json::to_string(&paths)
+                .expect("Serializing paths to JSON shouldn't fail");
+            Response::builder()
+                .header("Content-Type", "application/json")
+                .header("Cache-Control", "no-cache")
+                .body(Body::from(json))
+                .unwrap()
+        },
+        (Method::POST, "/multiplex") => {
+            let UnsubscribeParams{stream, path} = match UnsubscribeParams::parse(query) {
+                Some(params) => params,
+                None => return Ok(bad_request("Unsubscribing needs ?stream=<id>&unsubscribe=/a")),
+            };
+            let mut multiplexers = MULTIPLEXERS.lock().await;
+            let multiplexer = match multiplexers.get_mut(&stream) {
+                Some(multiplexer) => multiplexer,
+                None => return Ok(not_found(format!("No such multiplexed stream: {}", stream))),
+            };
+            if !multiplexer.unsubscribe(&path) {
+                return Ok(not_found(format!("The stream isn't subscribed to {}", path)))
+            }
+            // With no pages left, the stream is over
+            if multiplexer.paths().next().is_none() {
+                multiplexers.remove(&stream);
+            }
+            Response::new(Body::empty())
+        },
         (Method::GET, "/assets/diffhtml.min.js") =>
             static_asset!("application/javascript", "server/assets/diffhtml.min.js"),
         (Method::GET, "/assets/dynamic-page.js") =>
@@ -137,6 +434,18 @@ async fn process_request(
     // More disassembly
     let Parts{method, uri, headers, ..} = parts;
     let query = uri.query().unwrap_or("");
+    let accept_language = headers.get("Accept-Language")
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    // Clients other than browsers may prefer updates as newline-delimited JSON
+    let ndjson = headers.get("Accept")
+        .and_then(|value| value.to_str().ok())
+        .map(|accept| accept.contains("application/x-ndjson"))
+        .unwrap_or(false);
+    let crawler = headers.get("User-Agent")
+        .and_then(|value| value.to_str().ok())
+        .map(is_crawler)
+        .unwrap_or(false);
 
     // The path should be trimmed so it does not end with slashes. This is to
     // prevent confusion where /some/path/ contains different content than
@@ -155,7 +464,7 @@ async fn process_request(
 
     // Shortcut to special processing if the path is in our reserved namespace
     if path.starts_with("/.myxine/") {
-        return process_special_request(method, &path[8..], query);
+        return process_special_request(method, &path[8..], query).await;
     }
 
     // Get the page at this path
@@ -168,37 +477,266 @@ async fn process_request(
             let mut body = Body::empty();
             match GetParams::parse(query) {
                 // If client wants event stream of changes to page:
-                Some(GetParams::PageUpdates) => {
+                Some(GetParams::PageUpdates{follow, filter, room, receive_only, baseline}) => {
+                    let filter = match filter.map(Filter::try_from).transpose() {
+                        Ok(filter) => filter,
+                        Err(err) => return Ok(bad_request(err)),
+                    };
                     if method == Method::GET {
-                        body = page.update_stream().await.unwrap_or_else(Body::empty);
+                        let options = ClientOptions{
+                            protected: follow, filter, room, receive_only, lang: None,
+                        };
+                        body = page.update_stream(options, baseline.as_deref(),
+                                                  accept_language.as_deref())
+                            .await.unwrap_or_else(Body::empty);
+                        if ndjson {
+                            body = ndjson::from_sse(body);
+                        }
                     }
                     Response::builder()
-                        .header("Content-Type", "text/event-stream")
+                        .header("Content-Type", if ndjson { "application/x-ndjson" } else { "text/event-stream" })
                         .header("Cache-Control", "no-cache")
                         .header("Access-Control-Allow-Origin", "*")
                         .body(body)
                         .unwrap()
                 },
-                Some(GetParams::FullPage) => {
+                // Client wants a recording played back to it, on a page of its
+                // own:
+                Some(GetParams::Replay{name}) => {
+                    let replay = match recording_path(&name).await {
+                        Ok(file) => Page::replay_recording(&file).await,
+                        Err(err) => Err(err),
+                    };
+                    let (replay, playback) = match replay {
+                        Ok(replay) => replay,
+                        Err(err) => return Ok(bad_request(err)),
+                    };
+                    if method == Method::GET {
+                        body = replay.update_stream(ClientOptions::default(), None, None)
+                            .await.unwrap_or_else(Body::empty);
+                        if ndjson {
+                            body = ndjson::from_sse(body);
+                        }
+                        // Once it's played back, the page goes away, which ends
+                        // the stream
+                        tokio::spawn(async move {
+                            playback.await;
+                            drop(replay);
+                        });
+                    }
+                    Response::builder()
+                        .header("Content-Type", if ndjson { "application/x-ndjson" } else { "text/event-stream" })
+                        .header("Cache-Control", "no-cache")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .body(body)
+                        .unwrap()
+                },
+                // Client wants to see the state replayed to new clients:
+                Some(GetParams::StoredEvents) => {
+                    let events: Vec<_> = page.stored_events().await.into_iter()
+                        .map(|(event, data)| serde_json::json!({"event": event, "data": data}))
+                        .collect();
+                    let json = serde_json::to_string(&events)
+                        .expect("Serializing stored events to JSON shouldn't fail");
+                    Response::builder()
+                        .header("Content-Type", "application/json")
+                        .header("Cache-Control", "no-cache")
+                        .body(if method == Method::GET { json.into() } else { body })
+                        .unwrap()
+                },
+                // Client wants to know what a client of the page has been sent:
+                Some(GetParams::ClientSync{client}) => {
+                    let sync = match page.client_sync_state(client).await {
+                        Some(sync) => sync,
+                        None => return Ok(not_found(format!("No such client: {}", client))),
+                    };
+                    let json = serde_json::to_string(&sync)
+                        .expect("Serializing a client's sync state to JSON shouldn't fail");
+                    Response::builder()
+                        .header("Content-Type", "application/json")
+                        .header("Cache-Control", "no-cache")
+                        .body(if method == Method::GET { json.into() } else { body })
+                        .unwrap()
+                },
+                // Client wants to follow what's appended to the body, like
+                // `tail -f`:
+                Some(GetParams::Tail) => {
+                    let (current, mut cursor) = match page.body_cursor().await {
+                        Some(cursor) => cursor,
+                        None => return Ok(bad_request("Only a dynamic page's body can be followed")),
+                    };
+                    if method == Method::GET {
+                        let (mut sender, tail) = Body::channel();
+                        tokio::spawn(async move {
+                            if sender.send_data(current.into()).await.is_err() {
+                                return
+                            }
+                            // Once the body is replaced, there's nothing left
+                            // to follow, so the stream ends
+                            while let Some(BodyChange::Appended(text)) = cursor.next().await {
+                                if sender.send_data(text.to_string().into()).await.is_err() {
+                                    break
+                                }
+                            }
+                        });
+                        body = tail;
+                    }
+                    Response::builder()
+                        .header("Content-Type", "text/plain; charset=utf-8")
+                        .header("Cache-Control", "no-cache")
+                        .body(body)
+                        .unwrap()
+                },
+                // Client wants every whole body of the page, as it changes:
+                Some(GetParams::WatchBody) => {
+                    let mut bodies = match page.watch_body().await {
+                        Some(bodies) => bodies,
+                        None => return Ok(bad_request("Only a dynamic page's body can be watched")),
+                    };
+                    if method == Method::GET {
+                        let (mut sender, watched) = Body::channel();
+                        tokio::spawn(async move {
+                            // Each body is a JSON string on a line of its own
+                            while let Some(current) = bodies.next().await {
+                                let mut line = serde_json::to_string(&current)
+                                    .expect("Serializing a string to JSON shouldn't fail");
+                                line.push('\n');
+                                if sender.send_data(line.into()).await.is_err() {
+                                    break
+                                }
+                            }
+                        });
+                        body = watched;
+                    }
+                    Response::builder()
+                        .header("Content-Type", "application/x-ndjson")
+                        .header("Cache-Control", "no-cache")
+                        .body(body)
+                        .unwrap()
+                },
+                // Producer wants to know when to slow down its updates:
+                Some(GetParams::Backpressure) => {
+                    if method == Method::GET {
+                        body = match page.backpressure_stream().await {
+                            Some(stream) => stream,
+                            None => return Ok(bad_request("Only a dynamic page has backpressure")),
+                        };
+                    }
+                    Response::builder()
+                        .header("Content-Type", "text/event-stream")
+                        .header("Cache-Control", "no-cache")
+                        .body(body)
+                        .unwrap()
+                },
+                // Client wants to save the page as it is now as a file:
+                Some(GetParams::Download) => {
+                    let dynamic = page.content_hash().await.is_none();
+                    let (headers, bytes) = page.download_document(&download_filename(path, dynamic)).await;
+                    let mut response = Response::builder()
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Cache-Control", "no-cache")
+                        .body(if method == Method::GET { bytes.into() } else { body })
+                        .unwrap();
+                    response.headers_mut().extend(headers);
+                    response
+                },
+                #[cfg(feature = "screenshot")]
+                Some(GetParams::Screenshot{width, height}) => {
+                    match page.render_image(width, height).await {
+                        Ok(image) => Response::builder()
+                            .header("Content-Type", "image/png")
+                            .header("Cache-Control", "no-cache")
+                            .body(if method == Method::GET { image.into() } else { body })
+                            .unwrap(),
+                        Err(err) => return Ok(bad_request(err.to_string())),
+                    }
+                },
+                Some(GetParams::FullPage{lines, hash, prerender}) => {
                     let mut builder = Response::builder()
                         .header("Access-Control-Allow-Origin", "*")
                         .header("Content-Disposition", "inline");
+                    // An empty page is shown as the not-found page, if there is
+                    // one, which gets its updates from its own path
+                    let not_found = NOT_FOUND.lock().await.clone()
+                        .filter(|not_found| not_found != path);
+                    let not_found = match not_found {
+                        Some(not_found) if page.is_empty().await => Some(not_found),
+                        _ => None,
+                    };
+                    let page = match &not_found {
+                        Some(not_found) => get_page(not_found).await,
+                        None => page.clone(),
+                    };
                     if let Some(content_type) = page.content_type().await {
                         // If there's a custom content-type, set it here
                         builder = builder.header("Content-Type", content_type);
                     }
+                    // Static contents are tagged with their hash, or for a range
+                    // of their lines, with that of the whole and the range
+                    let content_hash = page.content_hash().await;
+                    match (&content_hash, lines) {
+                        (Some(content_hash), Some((start, end))) => {
+                            builder = builder.header("ETag", format!("\"{}-lines-{}-{}\"", content_hash, start, end));
+                        },
+                        (Some(content_hash), None) => {
+                            builder = builder.header("ETag", format!("\"{}\"", content_hash));
+                        },
+                        (None, _) => {
+                            // Dynamic pages may have a body in several languages,
+                            // and are shown differently to crawlers
+                            builder = builder.header("Vary", "Accept-Language, User-Agent");
+                        },
+                    }
+                    // ...and the metadata to check their integrity with, which
+                    // scripts on other pages need to be allowed to read (the
+                    // integrity of the whole doesn't hold for a range of lines)
+                    match page.integrity().await {
+                        Some(integrity) if lines.is_none() => {
+                            builder = builder
+                                .header("X-Integrity", integrity)
+                                .header("Access-Control-Expose-Headers", "ETag, X-Integrity");
+                        },
+                        _ => { },
+                    }
                     // We want to redirect to paths without slashes at the end
                     if path_ends_with_slash {
                         builder = builder.status(StatusCode::MOVED_PERMANENTLY);
                         builder = builder.header("Location", path);
+                    } else if not_found.is_some() {
+                        builder = builder
+                            .status(StatusCode::NOT_FOUND)
+                            .header("Cache-Control", "no-cache");
+                    } else if hash.is_some() && hash == content_hash {
+                        // The contents under a hashed URL never change (and so
+                        // neither does any range of their lines, which has its
+                        // own ETag), so they can be cached as long as the client
+                        // likes
+                        builder = builder.header("Cache-Control", "public, max-age=31536000, immutable");
                     } else {
                         // 301 redirects can be cached, but nothing else can
                         builder = builder.header("Cache-Control", "no-cache");
                     }
                     if method == Method::GET {
-                        let base_url = base_uri.to_string().trim_end_matches('/').to_owned();
-                        let this_page_url = base_url.clone() + &path;
-                        body = page.render(&base_url, &this_page_url).await.into();
+                        // If the client asked for particular lines of a static
+                        // text page, give them only those
+                        let lines = match lines {
+                            Some((start, end)) => page.static_lines(start, end).await,
+                            None => None,
+                        };
+                        body = match lines {
+                            Some(lines) => lines.into(),
+                            // Crawlers get the page as it is now, with no
+                            // script to keep it up to date
+                            None if (prerender || crawler) && content_hash.is_none() =>
+                                page.prerendered_document(accept_language.as_deref()).await.into(),
+                            None => {
+                                let base_url = base_uri.to_string().trim_end_matches('/').to_owned();
+                                let this_page_url = base_url.clone() + path;
+                                page.render(&base_url, &this_page_url, accept_language.as_deref(),
+                                            not_found.as_deref())
+                                    .await.into()
+                            },
+                        };
                     }
                     builder.body(body).unwrap()
                 },
@@ -220,6 +758,12 @@ async fn process_request(
                 body_bytes.extend_from_slice(&chunk);
             };
 
+            // Changes made here go to any peers too. The backend is dropped
+            // whenever the page becomes static, so it's set again each time.
+            if let Some(backend) = peers::backend(path) {
+                page.set_broadcast_backend(Some(backend)).await;
+            }
+
             let content_type: Option<&str> =
                 match headers.get("Content-Type").map(HeaderValue::to_str) {
                     None => None,
@@ -251,7 +795,13 @@ async fn process_request(
                             if cfg!(debug_assertions) {
                                 eprintln!("\n{}", body);
                             }
-                            page.set_title(title).await;
+                            match title {
+                                Some(title) => page.set_title(title).await,
+                                // A title template stays until a title is
+                                // given, since its slots change on their own
+                                None if page.has_title_template().await => { },
+                                None => page.set_title("").await,
+                            }
                             page.set_body(body).await;
                             Response::new(Body::empty())
                         },
@@ -259,6 +809,64 @@ async fn process_request(
                             return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
                     }
                 },
+                // Client wants to set the body without clobbering local edits:
+                Some(PostParams::CleanBody{title}) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(body) => {
+                            page.set_title(title).await;
+                            page.set_body_to_clean(body).await;
+                            Response::new(Body::empty())
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Browser wants to report whether it has local edits:
+                Some(PostParams::ClientState{client, dirty}) => {
+                    if page.set_client_dirty(client, dirty).await {
+                        Response::new(Body::empty())
+                    } else {
+                        return Ok(not_found(format!("No such client: {}", client)))
+                    }
+                },
+                // Browser wants to show it's still active:
+                Some(PostParams::ClientPing{client}) => {
+                    if page.touch_client(client).await {
+                        Response::new(Body::empty())
+                    } else {
+                        return Ok(not_found(format!("No such client: {}", client)))
+                    }
+                },
+                // Client wants another client of the page kicked off it:
+                Some(PostParams::ClientDisconnect{client}) => {
+                    if page.disconnect_client(client).await {
+                        Response::new(Body::empty())
+                    } else {
+                        return Ok(not_found(format!("No such client, or it's a protected follower: {}", client)))
+                    }
+                },
+                // Browser wants to say how far behind it is in rendering:
+                Some(PostParams::ClientLag{client, lag}) => {
+                    if page.report_lag(client, lag).await {
+                        Response::new(Body::empty())
+                    } else {
+                        return Ok(not_found(format!("No such client: {}", client)))
+                    }
+                },
+                // Browser wants to catch up from the body it already has:
+                Some(PostParams::ClientBaseline{client}) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(baseline) => {
+                            if page.send_body_diff(client, &baseline).await {
+                                Response::new(Body::empty())
+                            } else {
+                                return Ok(not_found(format!("No such client: {}", client)))
+                            }
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
                 // Client wants to subscribe to interface events on this page:
                 Some(PostParams::SubscribeEvents{uuid}) => {
                     if let Ok(subscription) = serde_json::from_slice(&body_bytes) {
@@ -284,6 +892,418 @@ async fn process_request(
                         return Ok(bad_request("Invalid subscription request."));
                     }
                 },
+                // Client wants to change the query string of the page's URL:
+                Some(PostParams::QueryUpdate{replace}) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(query) => {
+                            match page.send_query_update(query.trim(), replace).await {
+                                Ok(()) => Response::new(Body::empty()),
+                                Err(err) => return Ok(bad_request(err)),
+                            }
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Client wants to send a custom event to the page:
+                Some(PostParams::CustomEvent{event, room, ttl, id, retry}) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(data) => {
+                            let sent = match (room, ttl) {
+                                // Only a message can have the id and retry
+                                // fields of the event stream format
+                                (room, ttl) if id.is_some() || retry.is_some() => {
+                                    let mut message = EventMessage::builder(&event).data(&data);
+                                    if let Some(room) = room {
+                                        message = message.room(&room);
+                                    }
+                                    if let Some(ttl) = ttl {
+                                        message = message.ttl(ttl);
+                                    }
+                                    if let Some(id) = id {
+                                        message = message.id(&id);
+                                    }
+                                    if let Some(retry) = retry {
+                                        message = message.retry(retry);
+                                    }
+                                    match message.build() {
+                                        Ok(message) => {
+                                            page.send_message(message).await;
+                                            Ok(())
+                                        },
+                                        Err(err) => Err(err),
+                                    }
+                                },
+                                (Some(room), _) => page.send_to_room(&room, &event, &data).await,
+                                (None, Some(ttl)) => page.send_event_ttl(&event, &data, ttl).await,
+                                (None, None) => page.send_custom_event(&event, &data).await,
+                            };
+                            match sent {
+                                Ok(()) => Response::new(Body::empty()),
+                                Err(err) => return Ok(bad_request(err)),
+                            }
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Client wants to atomically increment a numeric body:
+                Some(PostParams::IncrementBody{delta}) => {
+                    match page.increment_body(delta).await {
+                        Ok(value) => Response::new(Body::from(value.to_string())),
+                        Err(NotNumeric) =>
+                            return Ok(bad_request("Page body is not an integer which can be incremented.")),
+                    }
+                },
+                // Client wants to set or remove an attribute on <html>/<body>:
+                Some(PostParams::RootAttr{element, name, remove}) => {
+                    let element = match RootElement::try_from(element.as_str()) {
+                        Ok(element) => element,
+                        Err(err) => return Ok(bad_request(err)),
+                    };
+                    let value = match String::from_utf8(body_bytes) {
+                        Ok(value) => value,
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    };
+                    let value = if remove { None } else { Some(value.as_str()) };
+                    match page.set_root_attr(element, &name, value).await {
+                        Ok(()) => Response::new(Body::empty()),
+                        Err(err) => return Ok(bad_request(err)),
+                    }
+                },
+                // Client wants to remove all attributes set on <html>/<body>:
+                Some(PostParams::ClearRootAttrs) => {
+                    page.clear_root_attrs().await;
+                    Response::new(Body::empty())
+                },
+                // Client wants to set an attribute on an element:
+                Some(PostParams::SetAttr{selector, name, remove}) => {
+                    let value = match String::from_utf8(body_bytes) {
+                        Ok(value) => value,
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    };
+                    let value = if remove { None } else { Some(value.as_str()) };
+                    match page.set_attr(&selector, &name, value).await {
+                        Ok(()) => Response::new(Body::empty()),
+                        Err(err) => return Ok(bad_request(err)),
+                    }
+                },
+                // Client wants new clients not to set attributes on elements:
+                Some(PostParams::ClearAttrs) => {
+                    page.clear_attrs().await;
+                    Response::new(Body::empty())
+                },
+                // Client wants to set the body in a particular language:
+                Some(PostParams::LangBody{lang}) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(body) => {
+                            page.set_body_lang(&lang, &body).await;
+                            Response::new(Body::empty())
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Client wants to set the title and head metadata together:
+                Some(PostParams::Seo) => {
+                    match serde_json::from_slice(&body_bytes) {
+                        Ok(seo) => {
+                            page.set_seo(seo).await;
+                            Response::new(Body::empty())
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Head metadata must be a JSON object with a title, description, and og tags.")),
+                    }
+                },
+                // Client wants to put the page in a namespace, or take it out:
+                Some(PostParams::Namespace{namespace}) => {
+                    page.set_namespace(namespace).await;
+                    Response::new(Body::empty())
+                },
+                // Client wants to see what setting the title or body would send,
+                // without setting it:
+                Some(PostParams::Preview{title}) => {
+                    let value = match String::from_utf8(body_bytes) {
+                        Ok(value) => value,
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    };
+                    let frames = if title {
+                        page.preview_set_title(&value).await
+                    } else {
+                        page.preview_set_body(&value).await
+                    };
+                    let frames: Vec<_> = frames.into_iter()
+                        .map(|frame| serde_json::json!({"event": frame.event_type, "frame": frame.frame}))
+                        .collect();
+                    let json = serde_json::to_string(&frames)
+                        .expect("Serializing previewed frames to JSON shouldn't fail");
+                    Response::builder()
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(json))
+                        .unwrap()
+                },
+                // Client wants to add to the end of the body:
+                Some(PostParams::AppendBody) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(text) => {
+                            page.append_body(&text).await;
+                            Response::new(Body::empty())
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Client wants to stream text into an element:
+                Some(PostParams::AppendTo{selector}) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(text) => match page.append_to(&selector, &text).await {
+                            Ok(()) => Response::new(Body::empty()),
+                            Err(err) => return Ok(bad_request(err)),
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Client wants to message the window embedding the page:
+                Some(PostParams::ParentMessage{channel, origin, persistent}) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(data) => match page.send_to_parent(&channel, &data, &origin, persistent).await {
+                            Ok(()) => Response::new(Body::empty()),
+                            Err(err) => return Ok(bad_request(err)),
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Client wants its clients to remember a value locally:
+                Some(PostParams::Persistent{key}) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(data) => match page.send_persistent(&key, &data).await {
+                            Ok(()) => Response::new(Body::empty()),
+                            Err(err) => return Ok(bad_request(err)),
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Client wants the title to be a template with slots:
+                Some(PostParams::TitleTemplate) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(template) => {
+                            page.set_title_template(&template).await;
+                            Response::new(Body::empty())
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Client wants to fill in a slot of the title template (which
+                // is ignored if there's no such slot):
+                Some(PostParams::TitleSlot{name}) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(value) => {
+                            page.set_title_slot(&name, &value).await;
+                            Response::new(Body::empty())
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Client wants to show the progress of a step of a job:
+                Some(PostParams::Step{step, status}) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(error) => {
+                            // A failed step's error message is the body, if any
+                            let error = if error.is_empty() { None } else { Some(error) };
+                            let status = match StepStatus::parse(&status, error) {
+                                Ok(status) => status,
+                                Err(err) => return Ok(bad_request(err)),
+                            };
+                            match page.set_step(&step, status).await {
+                                Ok(()) => Response::new(Body::empty()),
+                                Err(err) => return Ok(bad_request(err)),
+                            }
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Client wants to forget the steps of a job:
+                Some(PostParams::ClearSteps) => {
+                    page.clear_steps().await;
+                    Response::new(Body::empty())
+                },
+                // Client wants to empty an element:
+                Some(PostParams::ClearElement{selector}) => {
+                    page.clear_element(&selector).await;
+                    Response::new(Body::empty())
+                },
+                // Client wants to show validation errors on form fields:
+                Some(PostParams::Validation) => {
+                    match serde_json::from_slice(&body_bytes) {
+                        Ok(errors) => {
+                            page.send_validation(errors).await;
+                            Response::new(Body::empty())
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Validation errors must be a JSON object mapping fields to messages.")),
+                    }
+                },
+                // Client wants to get the attention of whoever's looking:
+                Some(PostParams::Alert) => {
+                    match serde_json::from_slice(&body_bytes) {
+                        Ok(alert) => {
+                            page.send_alert(alert).await;
+                            Response::new(Body::empty())
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("An alert must be {\"vibrate\": [milliseconds, ...]}, {\"sound\": url}, or \"beep\".")),
+                    }
+                },
+                // Client wants every client to get its updates from elsewhere:
+                Some(PostParams::ReconnectTo) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(url) => match page.send_reconnect_to(url.trim()).await {
+                            Ok(()) => Response::new(Body::empty()),
+                            Err(err) => return Ok(bad_request(err)),
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Client wants the user to confirm something, and waits for it:
+                Some(PostParams::Confirm{timeout}) => {
+                    match String::from_utf8(body_bytes) {
+                        Ok(message) => {
+                            let answer = page.confirm(&message, timeout).await;
+                            Response::new(Body::from(answer.as_str()))
+                        },
+                        Err(_) =>
+                            return Ok(bad_request("Invalid UTF-8 in POST data (only UTF-8 is supported).")),
+                    }
+                },
+                // Browser wants to answer a confirmation:
+                Some(PostParams::ConfirmAnswer{token, yes}) => {
+                    if page.answer_confirm(&token, yes).await {
+                        Response::new(Body::empty())
+                    } else {
+                        return Ok(bad_request(format!("No pending confirmation: {}", token)))
+                    }
+                },
+                // Client wants to make several changes at once, all or none:
+                Some(PostParams::Batch) => {
+                    let ops = match serde_json::from_slice(&body_bytes) {
+                        Ok(ops) => ops,
+                        Err(err) =>
+                            return Ok(bad_request(format!("A batch must be a JSON array of changes: {}", err))),
+                    };
+                    match page.apply_validated(ops).await {
+                        Ok(count) => Response::new(Body::from(count.to_string())),
+                        Err(err) => return Ok(bad_request(err.to_string())),
+                    }
+                },
+                // Client wants to change the rules every change in a batch must
+                // follow:
+                Some(PostParams::Validator) => {
+                    match serde_json::from_slice(&body_bytes) {
+                        Ok(validator) => page.set_validator(validator).await,
+                        Err(err) =>
+                            return Ok(bad_request(format!("Invalid validator: {}", err))),
+                    }
+                    Response::new(Body::empty())
+                },
+                // A peer wants to apply an update made to this page there:
+                Some(PostParams::Broadcast) => {
+                    match serde_json::from_slice(&body_bytes) {
+                        Ok(broadcast) => page.apply_broadcast(broadcast).await,
+                        Err(err) =>
+                            return Ok(bad_request(format!("Invalid broadcast: {}", err))),
+                    }
+                    Response::new(Body::empty())
+                },
+                // Client wants to copy this page to another path:
+                Some(PostParams::Fork{path: fork_path}) => {
+                    let fork_path = fork_path.trim_end_matches('/');
+                    if !fork_path.starts_with('/') || fork_path.starts_with("/.myxine/") {
+                        return Ok(bad_request(format!("Invalid path to fork to: {}", fork_path)))
+                    }
+                    let fork = Arc::new(page.fork().await);
+                    // Clone out the page there so we don't hold the lock on
+                    // PAGES while we wait on it
+                    let existing = PAGES.lock().await.get(fork_path).cloned();
+                    if let Some(existing) = &existing {
+                        if !existing.is_empty().await {
+                            return Ok(bad_request(format!("Can't fork onto a page which isn't empty: {}", fork_path)))
+                        }
+                    }
+                    // ...and don't replace a page put there in the meantime
+                    let mut pages = PAGES.lock().await;
+                    let replaced = match (pages.get(fork_path), &existing) {
+                        (Some(current), Some(existing)) => !Arc::ptr_eq(current, existing),
+                        (current, existing) => current.is_some() != existing.is_some(),
+                    };
+                    if replaced {
+                        return Ok(bad_request(format!("Can't fork onto a page which changed while forking: {}", fork_path)))
+                    }
+                    pages.insert(fork_path.to_string(), fork);
+                    heartbeat::hold_path(fork_path.to_string());
+                    Response::new(Body::empty())
+                },
+                // Client wants to start or stop recording the page's updates:
+                Some(PostParams::Record{name}) => {
+                    match name {
+                        Some(name) => {
+                            let started = match recording_path(&name).await {
+                                Ok(file) => page.start_recording(&file).await,
+                                Err(err) => Err(err),
+                            };
+                            if let Err(err) = started {
+                                return Ok(bad_request(err))
+                            }
+                        },
+                        None => page.stop_recording().await,
+                    }
+                    Response::new(Body::empty())
+                },
+                // Client wants to change one of the page's settings:
+                Some(PostParams::Configure(setting)) => {
+                    match setting {
+                        Setting::Mailbox(capacity) => page.set_mailbox(capacity).await,
+                        Setting::BufferSize(capacity) => page.resize_buffer(capacity).await,
+                        Setting::CompressAbove(threshold) =>
+                            page.set_event_compression_threshold(threshold).await,
+                        Setting::StuckTimeout(timeout) =>
+                            page.set_stuck_client_timeout(timeout).await,
+                        Setting::IdleTimeout(timeout) =>
+                            page.set_client_idle_timeout(timeout).await,
+                        Setting::CoalesceWindow(window) => page.set_coalesce_window(window).await,
+                        Setting::AdaptiveRate(target_lag) => page.set_adaptive_rate(target_lag).await,
+                        Setting::RenderCache(size) => page.set_render_cache_size(size).await,
+                        Setting::HoldAppends(enabled) =>
+                            page.set_append_flush_on_heartbeat(enabled).await,
+                        Setting::HeartbeatThrottle(throttle) =>
+                            page.set_heartbeat_throttle(throttle).await,
+                        Setting::MaxReplay(max) => page.set_max_replay_state(max).await,
+                        Setting::AttrCap(cap) => page.set_attr_cap(cap).await,
+                    }
+                    Response::new(Body::empty())
+                },
+                // Client wants to start or stop streaming the server log:
+                Some(PostParams::ServerLog{level}) => {
+                    let level = if level == "off" {
+                        None
+                    } else {
+                        match LogLevel::try_from(level.as_str()) {
+                            Ok(level) => Some(level),
+                            Err(err) => return Ok(bad_request(err)),
+                        }
+                    };
+                    page.set_log_streaming(level).await;
+                    Response::new(Body::empty())
+                },
                 // Browser wants to notify client of an event
                 Some(PostParams::PageEvent{event, path}) => {
                     if let Ok(event_data) = serde_json::from_slice(&body_bytes) {
@@ -328,6 +1348,28 @@ fn bad_request(message: impl Into<String>) -> Response<Body> {
         .unwrap()
 }
 
+/// Assemble a NOT_FOUND response with the given message, for something named
+/// in a request (other than the page itself) which doesn't exist
+fn not_found(message: impl Into<String>) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from(message.into()))
+        .unwrap()
+}
+
+/// The words in the `User-Agent` of search engine crawlers and link preview
+/// fetchers, which are served dynamic pages pre-rendered. These are matched
+/// case-insensitively.
+const CRAWLER_USER_AGENTS: &[&str] = &[
+    "bot", "crawler", "spider", "slurp", "facebookexternalhit", "embedly", "preview",
+];
+
+/// Test whether a `User-Agent` belongs to a crawler.
+fn is_crawler(user_agent: &str) -> bool {
+    let user_agent = user_agent.to_ascii_lowercase();
+    CRAWLER_USER_AGENTS.iter().any(|word| user_agent.contains(word))
+}
+
 /// Print a header value to stderr, for debugging purposes
 fn eprint_header(headers: &HeaderMap<HeaderValue>, header: &str) {
     if headers.get(header).is_some() {
@@ -337,3 +1379,406 @@ fn eprint_header(headers: &HeaderMap<HeaderValue>, header: &str) {
                  .format(","));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::sse::testing::read_until;
+
+    /// Send a request to the server, as if over HTTP.
+    async fn request(method: Method, uri: &str, body: &str) -> Response<Body> {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        process_request(Arc::new(Uri::from_static("http://localhost")), request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_connected_clients_can_be_dirty() {
+        request(Method::POST, "/tests/dirty", "<p>Hello</p>").await;
+        let mut updates = request(Method::GET, "/tests/dirty?updates", "").await.into_body();
+        let received = read_until(&mut updates, "event: client-id\ndata: ").await;
+        let id: u64 = received.split("event: client-id\ndata: ").nth(1).unwrap()
+            .lines().next().unwrap().parse().unwrap();
+
+        let dirty = format!("/tests/dirty?client={}&dirty", id);
+        assert_eq!(request(Method::POST, &dirty, "").await.status(), StatusCode::OK);
+        let unknown = format!("/tests/dirty?client={}&dirty", id + 1000);
+        assert_eq!(request(Method::POST, &unknown, "").await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn only_unprotected_clients_can_be_disconnected() {
+        request(Method::POST, "/tests/disconnect", "<p>Hello</p>").await;
+        let id = |received: &str| -> u64 {
+            received.split("event: client-id\ndata: ").nth(1).unwrap()
+                .lines().next().unwrap().parse().unwrap()
+        };
+        let mut follower = request(Method::GET, "/tests/disconnect?updates&follow", "").await.into_body();
+        let follower_id = id(&read_until(&mut follower, "event: connected").await);
+        let mut client = request(Method::GET, "/tests/disconnect?updates", "").await.into_body();
+        let client_id = id(&read_until(&mut client, "event: connected").await);
+
+        let metrics = get_page("/tests/disconnect").await.metrics().await;
+        assert!(metrics.contains(&(&metrics::PROTECTED_CLIENTS, 1)));
+
+        let uri = |id| format!("/tests/disconnect?client={}&disconnect", id);
+        assert_eq!(request(Method::POST, &uri(follower_id), "").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(request(Method::POST, &uri(client_id), "").await.status(), StatusCode::OK);
+        // Whatever was sent before it was disconnected comes first
+        while tokio::time::timeout(Duration::from_secs(1), client.next()).await
+            .expect("The client wasn't disconnected").is_some() { }
+        assert_eq!(request(Method::POST, &uri(client_id), "").await.status(), StatusCode::NOT_FOUND);
+        PAGES.lock().await.remove("/tests/disconnect");
+    }
+
+    #[tokio::test]
+    async fn idle_clients_are_disconnected_at_heartbeats() {
+        request(Method::POST, "/tests/idle", "<p>Hello</p>").await;
+        let page = get_page("/tests/idle").await;
+        let mut client = request(Method::GET, "/tests/idle?updates", "").await.into_body();
+        read_until(&mut client, "event: connected").await;
+        assert_eq!(request(Method::POST, "/tests/idle?idle-timeout=off", "").await.status(), StatusCode::OK);
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        page.send_heartbeat(Duration::from_secs(0)).await;
+        assert!(page.metrics().await.contains(&(&metrics::IDLE_DISCONNECTS, 0)));
+
+        assert_eq!(request(Method::POST, "/tests/idle?idle-timeout=50", "").await.status(), StatusCode::OK);
+        page.send_heartbeat(Duration::from_secs(0)).await;
+        while tokio::time::timeout(Duration::from_secs(1), client.next()).await
+            .expect("The idle client wasn't disconnected").is_some() { }
+        assert!(page.metrics().await.contains(&(&metrics::IDLE_DISCONNECTS, 1)));
+        PAGES.lock().await.remove("/tests/idle");
+    }
+
+    #[tokio::test]
+    async fn broadcasts_from_peers_are_applied() {
+        request(Method::POST, "/tests/broadcast", "<p>Here</p>").await;
+        let broadcast = |origin: &str, body: &str| serde_json::json!({
+            "origin": origin,
+            "update": {"type": "body", "body": body},
+        }).to_string();
+        let own = broadcast(crate::page::broadcast::origin(), "<p>Echoed</p>");
+        assert_eq!(request(Method::POST, "/tests/broadcast?broadcast", &own).await.status(), StatusCode::OK);
+        let peer = broadcast("peer", "<p>There</p>");
+        assert_eq!(request(Method::POST, "/tests/broadcast?broadcast", &peer).await.status(), StatusCode::OK);
+        let invalid = request(Method::POST, "/tests/broadcast?broadcast", "<p>Invalid</p>").await;
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        let body = request(Method::GET, "/tests/broadcast?stored-events", "").await.into_body();
+        let stored = String::from_utf8(hyper::body::to_bytes(body).await.unwrap().to_vec()).unwrap();
+        assert!(stored.contains("<p>There</p>") && !stored.contains("<p>Echoed</p>"), "{}", stored);
+        PAGES.lock().await.remove("/tests/broadcast");
+    }
+
+    #[tokio::test]
+    async fn watchers_get_each_whole_body() {
+        request(Method::POST, "/tests/watch-body", "<p>First</p>").await;
+        let mut watched = request(Method::GET, "/tests/watch-body?watch-body", "").await.into_body();
+        read_until(&mut watched, "\"<p>First</p>\"\n").await;
+        request(Method::POST, "/tests/watch-body?append", "<p>Second</p>").await;
+        read_until(&mut watched, "\"<p>First</p><p>Second</p>\"\n").await;
+        request(Method::POST, "/tests/watch-body?static", "Static").await;
+        // The stream ends once there's no dynamic body left to watch
+        while tokio::time::timeout(Duration::from_secs(1), watched.next()).await
+            .expect("The watch didn't end").is_some() { }
+        let static_watch = request(Method::GET, "/tests/watch-body?watch-body", "").await;
+        assert_eq!(static_watch.status(), StatusCode::BAD_REQUEST);
+        PAGES.lock().await.remove("/tests/watch-body");
+    }
+
+    #[tokio::test]
+    async fn attributes_are_capped() {
+        request(Method::POST, "/tests/attr-cap", "<p id=\"a\">A</p><p id=\"b\">B</p>").await;
+        assert_eq!(request(Method::POST, "/tests/attr-cap?attr-cap=1", "").await.status(), StatusCode::OK);
+        let set = |selector: &'static str| async move {
+            let uri = format!("/tests/attr-cap?set-attr=class&selector={}", selector);
+            request(Method::POST, &uri, "on").await.status()
+        };
+        assert_eq!(set("%23a").await, StatusCode::OK);
+        // Replacing a remembered attribute doesn't count against the cap
+        assert_eq!(set("%23a").await, StatusCode::OK);
+        assert_eq!(set("%23b").await, StatusCode::BAD_REQUEST);
+        PAGES.lock().await.remove("/tests/attr-cap");
+    }
+
+    #[tokio::test]
+    async fn heartbeat_throttling_can_be_turned_off() {
+        request(Method::POST, "/tests/heartbeat-throttle", "<p>Busy</p>").await;
+        let page = get_page("/tests/heartbeat-throttle").await;
+        let mut client = request(Method::GET, "/tests/heartbeat-throttle?updates", "").await.into_body();
+        read_until(&mut client, "<p>Busy</p>").await;
+        page.send_heartbeat(Duration::from_secs(60)).await;
+        assert!(page.metrics().await.contains(&(&metrics::HEARTBEATS_SKIPPED, 1)));
+
+        let uri = "/tests/heartbeat-throttle?heartbeat-throttle=off";
+        assert_eq!(request(Method::POST, uri, "").await.status(), StatusCode::OK);
+        page.send_heartbeat(Duration::from_secs(60)).await;
+        assert!(page.metrics().await.contains(&(&metrics::HEARTBEATS_SKIPPED, 1)));
+        PAGES.lock().await.remove("/tests/heartbeat-throttle");
+    }
+
+    #[tokio::test]
+    async fn clients_sync_state_shows_what_they_were_sent() {
+        request(Method::POST, "/tests/client-sync?title=Synced", "<p>Synced</p>").await;
+        let mut client = request(Method::GET, "/tests/client-sync?updates", "").await.into_body();
+        let received = read_until(&mut client, "event: connected").await;
+        let id: u64 = received.split("event: client-id\ndata: ").nth(1).unwrap()
+            .lines().next().unwrap().parse().unwrap();
+        let sync = request(Method::GET, &format!("/tests/client-sync?client-sync={}", id), "").await;
+        let json = hyper::body::to_bytes(sync.into_body()).await.unwrap();
+        let sync: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        let event_types = sync["event-types"].as_array().unwrap();
+        assert!(event_types.contains(&"title".into()) && event_types.contains(&"body".into()), "{}", sync);
+        assert!(sync["version"].as_u64().is_some() && sync["frames"].as_u64().unwrap() > 0, "{}", sync);
+        let unknown = format!("/tests/client-sync?client-sync={}", id + 1000);
+        assert_eq!(request(Method::GET, &unknown, "").await.status(), StatusCode::NOT_FOUND);
+        PAGES.lock().await.remove("/tests/client-sync");
+    }
+
+    #[tokio::test]
+    async fn pages_can_be_listed_by_namespace() {
+        let listed = || async {
+            let response = request(Method::GET, "/.myxine/namespace?name=tests", "").await;
+            let json = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<Vec<String>>(&json).unwrap()
+        };
+        for path in &["/tests/namespace/b", "/tests/namespace/a", "/tests/namespace/c"] {
+            request(Method::POST, path, "<p>Tenant</p>").await;
+        }
+        request(Method::POST, "/tests/namespace/b?namespace=tests", "").await;
+        request(Method::POST, "/tests/namespace/a?namespace=tests", "").await;
+        request(Method::POST, "/tests/namespace/c?namespace=other", "").await;
+        // Namespaces stay with pages which become static
+        request(Method::POST, "/tests/namespace/b?static", "Static").await;
+        assert_eq!(listed().await, vec!["/tests/namespace/a", "/tests/namespace/b"]);
+        request(Method::POST, "/tests/namespace/a?namespace=", "").await;
+        assert_eq!(listed().await, vec!["/tests/namespace/b"]);
+        let unnamed = request(Method::GET, "/.myxine/namespace?name=", "").await;
+        assert_eq!(unnamed.status(), StatusCode::BAD_REQUEST);
+        for path in &["/tests/namespace/a", "/tests/namespace/b", "/tests/namespace/c"] {
+            PAGES.lock().await.remove(*path);
+        }
+    }
+
+    #[tokio::test]
+    async fn the_frame_rate_adapts_to_lagging_clients() {
+        request(Method::POST, "/tests/adaptive-rate", "<p>Hello</p>").await;
+        let page = get_page("/tests/adaptive-rate").await;
+        assert_eq!(request(Method::POST, "/tests/adaptive-rate?coalesce=100", "").await.status(), StatusCode::OK);
+        assert!(page.metrics().await.contains(&(&metrics::FRAME_RATE, 10)));
+        let uri = "/tests/adaptive-rate?adaptive-rate=50";
+        assert_eq!(request(Method::POST, uri, "").await.status(), StatusCode::OK);
+        let mut client = request(Method::GET, "/tests/adaptive-rate?updates", "").await.into_body();
+        let received = read_until(&mut client, "event: connected").await;
+        let id: u64 = received.split("event: client-id\ndata: ").nth(1).unwrap()
+            .lines().next().unwrap().parse().unwrap();
+        let lag = format!("/tests/adaptive-rate?client={}&lag=500", id);
+        assert_eq!(request(Method::POST, &lag, "").await.status(), StatusCode::OK);
+        assert!(page.metrics().await.contains(&(&metrics::FRAME_RATE, 5)));
+        // Once adapting stops, the window stays where it got to
+        let uri = "/tests/adaptive-rate?adaptive-rate=off";
+        assert_eq!(request(Method::POST, uri, "").await.status(), StatusCode::OK);
+        assert_eq!(request(Method::POST, &lag, "").await.status(), StatusCode::OK);
+        assert!(page.metrics().await.contains(&(&metrics::FRAME_RATE, 5)));
+        PAGES.lock().await.remove("/tests/adaptive-rate");
+    }
+
+    #[tokio::test]
+    async fn custom_events_can_have_ids_and_retry_times() {
+        request(Method::POST, "/tests/emit-message", "<p>Hello</p>").await;
+        let mut client = request(Method::GET, "/tests/emit-message?updates", "").await.into_body();
+        read_until(&mut client, "event: connected").await;
+        let uri = "/tests/emit-message?emit=tick&id=7&retry=3000";
+        assert_eq!(request(Method::POST, uri, "tock").await.status(), StatusCode::OK);
+        let received = read_until(&mut client, "data: tock\n").await;
+        assert!(received.contains("retry: 3000\n") && received.contains("id: 7\n"), "{}", received);
+        let reserved = request(Method::POST, "/tests/emit-message?emit=body&id=8", "").await;
+        assert_eq!(reserved.status(), StatusCode::BAD_REQUEST);
+        PAGES.lock().await.remove("/tests/emit-message");
+    }
+
+    #[tokio::test]
+    async fn batches_follow_the_validator_set() {
+        request(Method::POST, "/tests/validator", "<p>Hello</p>").await;
+        let batch = r#"[{"title": "Valid"}, {"body": "<p onclick=\"steal()\">Hi</p>"}]"#;
+        let validator = r#"{"max-title-len": 10, "forbid-scripts": true}"#;
+        assert_eq!(request(Method::POST, "/tests/validator?validator", validator).await.status(), StatusCode::OK);
+        let rejected = request(Method::POST, "/tests/validator?batch", batch).await;
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+        let unknown = request(Method::POST, "/tests/validator?validator", r#"{"max-len": 1}"#).await;
+        assert_eq!(unknown.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(request(Method::POST, "/tests/validator?validator", "{}").await.status(), StatusCode::OK);
+        let applied = request(Method::POST, "/tests/validator?batch", batch).await;
+        assert_eq!(applied.status(), StatusCode::OK);
+        PAGES.lock().await.remove("/tests/validator");
+    }
+
+    #[tokio::test]
+    async fn multiplexed_streams_can_drop_pages() {
+        request(Method::POST, "/tests/multiplex/a", "<p>A 1</p>").await;
+        request(Method::POST, "/tests/multiplex/b", "<p>B 1</p>").await;
+        let uri = "/.myxine/multiplex?pages=/tests/multiplex/a,/tests/multiplex/b";
+        let mut stream = request(Method::GET, uri, "").await.into_body();
+        let received = read_until(&mut stream, "event: /tests/multiplex/b body").await;
+        let id = received.split("event: multiplex-id\ndata: ").nth(1).unwrap()
+            .lines().next().unwrap().to_string();
+        let unsubscribe = |id: &str, path: &str| {
+            format!("/.myxine/multiplex?stream={}&unsubscribe={}", id, path)
+        };
+        let response = request(Method::POST, &unsubscribe(&id, "/tests/multiplex/a"), "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = request(Method::POST, &unsubscribe(&id, "/tests/multiplex/a"), "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        request(Method::POST, "/tests/multiplex/a", "<p>A 2</p>").await;
+        request(Method::POST, "/tests/multiplex/b", "<p>B 2</p>").await;
+        let received = read_until(&mut stream, "<p>B 2</p>").await;
+        assert!(!received.contains("<p>A 2</p>"), "{}", received);
+        // Unsubscribing from the last page ends the stream
+        let response = request(Method::POST, &unsubscribe(&id, "/tests/multiplex/b"), "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        while tokio::time::timeout(Duration::from_secs(1), stream.next()).await
+            .expect("The stream didn't end").is_some() { }
+        let response = request(Method::POST, &unsubscribe(&id, "/tests/multiplex/b"), "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        PAGES.lock().await.remove("/tests/multiplex/a");
+        PAGES.lock().await.remove("/tests/multiplex/b");
+    }
+
+    #[tokio::test]
+    async fn pages_can_only_be_forked_onto_empty_pages() {
+        request(Method::POST, "/tests/fork-original", "<p>Original</p>").await;
+        get_page("/tests/fork-empty").await;
+        let response = request(Method::POST, "/tests/fork-original?fork=/tests/fork-empty", "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!get_page("/tests/fork-empty").await.is_empty().await);
+        let response = request(Method::POST, "/tests/fork-original?fork=/tests/fork-empty", "").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let mut pages = PAGES.lock().await;
+        pages.remove("/tests/fork-original");
+        pages.remove("/tests/fork-empty");
+    }
+
+    #[tokio::test]
+    async fn reserved_pages_cant_be_multiplexed() {
+        let response = request(Method::GET, "/.myxine/multiplex?pages=/tests/cpu,/.myxine/metrics", "").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn producers_can_follow_backpressure() {
+        request(Method::POST, "/tests/backpressure", "<p>0</p>").await;
+        let mut backpressure =
+            request(Method::GET, "/tests/backpressure?backpressure", "").await.into_body();
+        let mut client = request(Method::GET, "/tests/backpressure?updates", "").await.into_body();
+        // Until the client reads, updates to it back up
+        tokio::spawn(async {
+            for n in 1..=5 {
+                request(Method::POST, "/tests/backpressure", &format!("<p>{}</p>", n)).await;
+            }
+        });
+        read_until(&mut backpressure, "event: backpressure\ndata: begin\n").await;
+        read_until(&mut client, "<p>5</p>").await;
+        read_until(&mut backpressure, "event: backpressure\ndata: end\n").await;
+        PAGES.lock().await.remove("/tests/backpressure");
+    }
+
+    #[tokio::test]
+    async fn previews_show_frames_without_sending_them() {
+        request(Method::POST, "/tests/preview?title=Same", "<p>Same</p>").await;
+        let preview = |of: &'static str, value: &'static str| async move {
+            let uri = format!("/tests/preview?preview={}", of);
+            let body = request(Method::POST, &uri, value).await.into_body();
+            let json = hyper::body::to_bytes(body).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap()
+        };
+        assert_eq!(preview("body", "<p>Same</p>").await, serde_json::json!([]));
+        assert_eq!(preview("title", "Same").await, serde_json::json!([]));
+        let body = preview("body", "<p>New</p>").await;
+        assert_eq!(body[0]["event"], "body");
+        assert!(body[0]["frame"].as_str().unwrap().contains("data: <p>New</p>\n"), "{}", body);
+        let title = preview("title", "New").await;
+        assert_eq!(title[0]["event"], "title");
+
+        let page = get_page("/tests/preview").await;
+        let stored = page.stored_events().await;
+        assert!(stored.contains(&("title".to_string(), "Same".to_string())), "{:?}", stored);
+        assert!(stored.contains(&("body".to_string(), "<p>Same</p>".to_string())), "{:?}", stored);
+        let invalid = request(Method::POST, "/tests/preview?preview=query", "").await;
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        PAGES.lock().await.remove("/tests/preview");
+    }
+
+    #[tokio::test]
+    async fn line_ranges_have_their_own_etag() {
+        request(Method::POST, "/tests/lines?static", "one\ntwo\nthree\n").await;
+        let whole = request(Method::GET, "/tests/lines", "").await;
+        let etag = whole.headers()["ETag"].to_str().unwrap().to_string();
+        let hash = etag.trim_matches('"');
+        assert!(whole.headers().contains_key("X-Integrity"));
+
+        let uri = format!("/tests/lines?v={}&lines=2-3", hash);
+        let range = request(Method::GET, &uri, "").await;
+        let range_etag = range.headers()["ETag"].to_str().unwrap();
+        assert_ne!(range_etag, etag);
+        assert!(range_etag.starts_with(&etag[..etag.len() - 1]));
+        assert!(!range.headers().contains_key("X-Integrity"));
+        let body = hyper::body::to_bytes(range.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"two\nthree\n");
+    }
+
+    #[tokio::test]
+    async fn empty_pages_are_served_the_not_found_page() {
+        set_not_found_path("/tests/not-found").await.unwrap();
+        assert!(set_not_found_path("/tests/elsewhere").await.is_err());
+        request(Method::POST, "/tests/not-found?static", "Nothing here").await;
+
+        let missing = request(Method::GET, "/tests/missing", "").await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert_eq!(missing.headers()["Cache-Control"], "no-cache");
+        let body = hyper::body::to_bytes(missing.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"Nothing here");
+
+        let itself = request(Method::GET, "/tests/not-found", "").await;
+        assert_eq!(itself.status(), StatusCode::OK);
+        // Other tests shouldn't be shown the not-found page
+        *NOT_FOUND.lock().await = None;
+        let mut pages = PAGES.lock().await;
+        pages.remove("/tests/missing");
+        pages.remove("/tests/not-found");
+    }
+
+    #[tokio::test]
+    async fn tails_follow_appends_until_the_body_is_replaced() {
+        request(Method::POST, "/tests/tail", "<p>Start</p>").await;
+        let mut tail = request(Method::GET, "/tests/tail?tail", "").await.into_body();
+        read_until(&mut tail, "<p>Start</p>").await;
+        request(Method::POST, "/tests/tail?append", "<p>More</p>").await;
+        read_until(&mut tail, "<p>More</p>").await;
+        request(Method::POST, "/tests/tail", "<p>Replaced</p>").await;
+        assert!(tail.next().await.is_none());
+        PAGES.lock().await.remove("/tests/tail");
+    }
+
+    #[tokio::test]
+    async fn restored_pages_greet_new_clients() {
+        let page = Arc::new(Page::new().await);
+        page.set_title("Saved title").await;
+        page.set_body("<p>Saved body</p>").await;
+        let pages = Mutex::new(HashMap::new());
+        pages.lock().await.insert("/tests/restore".to_string(), page.clone());
+        let file = std::env::temp_dir()
+            .join(format!("myxine-restore-{}.json", std::process::id()));
+        assert_eq!(dump_pages(&pages, &file).await, Ok(1));
+        pages.lock().await.clear();
+        assert_eq!(load_pages(&pages, &file).await, Ok(1));
+        std::fs::remove_file(&file).unwrap();
+
+        let restored = pages.lock().await.get("/tests/restore").cloned().unwrap();
+        assert!(!Arc::ptr_eq(&page, &restored));
+        let mut updates = restored.update_stream(ClientOptions::default(), None, None).await.unwrap();
+        let received = read_until(&mut updates, "<p>Saved body</p>").await;
+        assert!(received.contains("data: Saved title"));
+    }
+}
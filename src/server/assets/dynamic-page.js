@@ -1,10 +1,16 @@
-export function activate(initialSubscription, innerHTML, debugMode) {
+export function activate(initialSubscription, innerHTML, debugMode, updatesPath) {
     // The initial subscription at page load time
     let subscription = JSON.parse(initialSubscription);
     // The new body, cached before it's put in place
     let body = "";
     // The initial set of listeners is empty
     let listeners = [];
+    // The URL of this page, without any query string (which the server may
+    // change out from under us)
+    const pageUrl = window.location.origin + window.location.pathname;
+    // The URL of the page we're showing, which isn't this one if we were
+    // served another page in its place (like the server's not-found page)
+    const homeUrl = updatesPath !== null ? window.location.origin + updatesPath : pageUrl;
     // Print debug info if in debug build mode
     function debug(string) {
         if (debugMode) {
@@ -40,8 +46,9 @@ export function activate(initialSubscription, innerHTML, debugMode) {
     let sendEventWorker =
         new Worker('http://' + window.location.host + '/.myxine/assets/send-event.js');
     // Tell the worker where it'll be sending its messages...
-    sendEventWorker.postMessage({thisUrl: window.location.href});
+    sendEventWorker.postMessage({thisUrl: homeUrl});
     function sendEvent(targetPath, eventType, returnData) {
+        ping();
         sendEventWorker.postMessage({
             targetPath: targetPath,
             eventType: eventType,
@@ -88,16 +95,51 @@ export function activate(initialSubscription, innerHTML, debugMode) {
             });
         });
     }
+    // Whether we've been sent a body yet, so that if we have to reconnect we
+    // can catch up from it instead of being sent the whole thing again
+    let haveBody = false;
+    // The same hash the server uses for bodies (64-bit FNV-1a over UTF-8, in
+    // hex), computed in two 32-bit halves
+    function hashText(text) {
+        let high = 0xcbf29ce4, low = 0x84222325;
+        for (const byte of new TextEncoder().encode(text)) {
+            low = (low ^ byte) >>> 0;
+            const product = low * 0x1b3;
+            high = (Math.imul(high, 0x1b3) + Math.floor(product / 0x100000000) + (low << 8)) >>> 0;
+            low = product >>> 0;
+        }
+        return high.toString(16).padStart(8, "0") + low.toString(16).padStart(8, "0");
+    }
     // Set the body
     function setBodyTo(string) {
         body = string;
+        haveBody = true;
+        const received = performance.now();
         // Introduce a yield point so that a burst of updates could mean only
         // one re-draw of the window
         setTimeout(() => {
             innerHTML(document.body, body);
             updateSubscription();
+            applyValidation();
+            applySteps();
+            noteLag(performance.now() - received);
         });
     }
+    // The latest page version we've seen, used to ignore stale frames
+    let version = 0;
+    // Returns true if the event is older than what's already been shown
+    function isStale(event) {
+        const eventVersion = parseInt(event.lastEventId, 10);
+        if (isNaN(eventVersion)) {
+            return false;
+        } else if (eventVersion < version) {
+            debug("Ignoring stale " + event.type + " event: " + eventVersion);
+            return true;
+        } else {
+            version = eventVersion;
+            return false;
+        }
+    }
     // These are the handlers for SSE events...
     function subscribe(event) {
         debug("Received new subscription: " + event.data);
@@ -105,29 +147,572 @@ export function activate(initialSubscription, innerHTML, debugMode) {
         updateSubscription();
     }
     function setBody(event) {
+        if (isStale(event)) return;
         setBodyTo(event.data);
+        setDirty(false);
+    }
+    function patchBody(event) {
+        if (isStale(event)) return;
+        const patch = JSON.parse(event.data);
+        // If the body has changed since we sent it as a baseline, we've been
+        // sent something newer anyway
+        if (hashText(body) !== patch.baseline) return;
+        setBodyTo(body.slice(0, patch.start) + patch.text + body.slice(patch.end));
+        setDirty(false);
+    }
+    function baselineMismatch(event) {
+        if (clientId !== null) {
+            fetch(clientUrl("&baseline"), {method: "POST", body: body})
+                .catch(error => console.error(error));
+        }
+    }
+    function appendBody(event) {
+        if (isStale(event)) return;
+        setBodyTo(body + event.data);
+    }
+    // Text streamed into an element is appended as text, never parsed as HTML
+    function appendTo(event) {
+        const stream = JSON.parse(event.data);
+        const element = querySelector(stream.selector);
+        if (element !== null) element.append(stream.text);
+    }
+    function clearElement(event) {
+        const element = querySelector(event.data);
+        if (element !== null) element.textContent = "";
+    }
+    function querySelector(selector) {
+        try {
+            return document.querySelector(selector);
+        } catch (error) {
+            console.error(error);
+            return null;
+        }
+    }
+    // Messages for the window embedding this page in a frame, or else the one
+    // which opened it; the browser drops them unless that window's origin
+    // matches the one they're meant for
+    function messageParent(event) {
+        const message = JSON.parse(event.data);
+        const target = window.parent !== window ? window.parent : window.opener;
+        if (target === null || target === undefined) {
+            debug("No parent window for message on " + message.channel);
+            return;
+        }
+        try {
+            target.postMessage({myxine: message.channel, data: message.data}, message.origin);
+        } catch (error) {
+            console.error(error);
+        }
+    }
+    // Values the server wants remembered across page loads, kept in
+    // localStorage per page and applied as soon as we load, so the page doesn't
+    // flash back to its defaults while we reconnect; once we have connected,
+    // the server's values replace ours, and any it didn't send are forgotten
+    const persistentPrefix = "myxine:" + window.location.pathname + ":";
+    let persistentReplayed = null;
+    function applyPersistent(key, data) {
+        try {
+            document.documentElement.setAttribute("data-myxine-" + key, data);
+        } catch (error) {
+            console.error(error);
+        }
+        document.dispatchEvent(new CustomEvent("myxine-persistent", {
+            detail: {key: key, data: data},
+        }));
+    }
+    function storedPersistentKeys() {
+        const keys = [];
+        try {
+            for (let i = 0; i < localStorage.length; i++) {
+                const name = localStorage.key(i);
+                if (name.startsWith(persistentPrefix)) {
+                    keys.push(name.substring(persistentPrefix.length));
+                }
+            }
+        } catch (error) {
+            // Storage may be disabled, in which case there's nothing to restore
+        }
+        return keys;
+    }
+    function restorePersistent() {
+        storedPersistentKeys().forEach(key => {
+            applyPersistent(key, localStorage.getItem(persistentPrefix + key));
+        });
+    }
+    function setPersistent(event) {
+        const persistent = JSON.parse(event.data);
+        if (persistentReplayed !== null) persistentReplayed.add(persistent.key);
+        try {
+            localStorage.setItem(persistentPrefix + persistent.key, persistent.data);
+        } catch (error) {
+            console.error(error);
+        }
+        applyPersistent(persistent.key, persistent.data);
+    }
+    function prunePersistent() {
+        storedPersistentKeys()
+            .filter(key => !persistentReplayed.has(key))
+            .forEach(key => {
+                localStorage.removeItem(persistentPrefix + key);
+                document.documentElement.removeAttribute("data-myxine-" + key);
+            });
+        persistentReplayed = null;
     }
     function clearBody(event) {
+        if (isStale(event)) return;
         setBodyTo("");
         updateSubscription();
+        setDirty(false);
+    }
+    // A title made from a template, with named {slot}s which the server fills
+    // in one at a time
+    let titleTemplate = null;
+    let titleSlots = {};
+    function showTitleTemplate() {
+        document.title = titleTemplate.replace(/\{([\p{L}\p{N}_-]+)\}/gu, (_, name) =>
+            Object.prototype.hasOwnProperty.call(titleSlots, name) ? titleSlots[name] : "");
+    }
+    function setTitleTemplate(event) {
+        if (isStale(event)) return;
+        const template = JSON.parse(event.data);
+        titleTemplate = template.template;
+        titleSlots = template.slots;
+        showTitleTemplate();
+    }
+    function setTitleSlot(event) {
+        if (isStale(event) || titleTemplate === null) return;
+        const slot = JSON.parse(event.data);
+        titleSlots[slot.name] = slot.value;
+        showTitleTemplate();
     }
     function setTitle(event) {
+        if (isStale(event)) return;
+        titleTemplate = null;
         document.title = event.data;
     }
     function clearTitle(event) {
+        if (isStale(event)) return;
+        titleTemplate = null;
         document.title = "";
     }
+    function setSeo(event) {
+        if (isStale(event)) return;
+        const seo = JSON.parse(event.data);
+        titleTemplate = null;
+        document.title = seo.title;
+        let description = document.head.querySelector('meta[name="description"]');
+        if (seo.description === "") {
+            if (description !== null) description.remove();
+        } else {
+            if (description === null) {
+                description = document.createElement("meta");
+                description.setAttribute("name", "description");
+                document.head.appendChild(description);
+            }
+            description.setAttribute("content", seo.description);
+        }
+        document.head.querySelectorAll('meta[property^="og:"]')
+            .forEach(tag => tag.remove());
+        for (const [property, content] of Object.entries(seo.og)) {
+            const tag = document.createElement("meta");
+            tag.setAttribute("property", "og:" + property);
+            tag.setAttribute("content", content);
+            document.head.appendChild(tag);
+        }
+    }
+    // Timers for the transient states set by timed events, which are shown as
+    // a data-myxine-* attribute on <html> until they expire
+    const timedEvents = {};
+    function setTimedEvent(event) {
+        const timed = JSON.parse(event.data);
+        const attribute = "data-myxine-" + timed.type;
+        clearTimeout(timedEvents[timed.type]);
+        try {
+            document.documentElement.setAttribute(attribute, timed.data);
+        } catch (error) {
+            console.error(error);
+            return;
+        }
+        timedEvents[timed.type] = setTimeout(() => {
+            document.documentElement.removeAttribute(attribute);
+            delete timedEvents[timed.type];
+        }, timed.ttl);
+    }
+    // Alerts vibrate the device or play a sound, as far as the browser allows:
+    // it may not be able to vibrate, and it blocks sounds until the user has
+    // interacted with the page
+    function showAlert(event) {
+        const alert = JSON.parse(event.data);
+        if (alert === "beep") {
+            beep();
+        } else if ("vibrate" in alert) {
+            if ("vibrate" in navigator) navigator.vibrate(alert.vibrate);
+        } else if ("sound" in alert) {
+            new Audio(alert.sound).play()
+                .catch(error => debug("Couldn't play alert sound: " + error));
+        }
+    }
+    function beep() {
+        const AudioContext = window.AudioContext || window.webkitAudioContext;
+        if (AudioContext === undefined) return;
+        const context = new AudioContext();
+        if (context.state === "suspended") {
+            debug("Couldn't beep: audio is blocked until the user interacts");
+            context.close();
+            return;
+        }
+        const oscillator = context.createOscillator();
+        oscillator.frequency.value = 880;
+        oscillator.connect(context.destination);
+        oscillator.onended = () => context.close();
+        oscillator.start();
+        oscillator.stop(context.currentTime + 0.2);
+    }
+    // The confirmation dialogs shown for the server, by token. The dialogs are
+    // attached outside the body, so body updates don't remove them; the first
+    // client to answer decides, and the server then dismisses the rest
+    const confirmations = {};
+    function showConfirm(event) {
+        const confirm = JSON.parse(event.data);
+        const dialog = document.createElement("div");
+        dialog.setAttribute("class", "myxine-confirm");
+        dialog.setAttribute("role", "alertdialog");
+        dialog.setAttribute("style", "position: fixed; inset: 0; z-index: 2147483647; "
+                            + "display: flex; align-items: center; justify-content: center; "
+                            + "background: rgba(0, 0, 0, 0.4);");
+        const box = document.createElement("div");
+        box.setAttribute("style", "background: white; color: black; padding: 1em; "
+                         + "border-radius: 0.5em; max-width: 30em; font-family: sans-serif;");
+        const message = document.createElement("p");
+        message.textContent = confirm.message;
+        box.appendChild(message);
+        for (const choice of ["yes", "no"]) {
+            const button = document.createElement("button");
+            button.textContent = choice === "yes" ? "Yes" : "No";
+            button.addEventListener("click", () => {
+                dismissConfirm(confirm.token);
+                fetch(homeUrl + "?answer=" + confirm.token + "&choice=" + choice,
+                      {method: "POST"})
+                    .catch(error => console.error(error));
+            });
+            box.appendChild(button);
+        }
+        dialog.appendChild(box);
+        document.documentElement.appendChild(dialog);
+        confirmations[confirm.token] = dialog;
+    }
+    function dismissConfirm(token) {
+        const dialog = confirmations[token];
+        if (dialog !== undefined) {
+            dialog.remove();
+            delete confirmations[token];
+        }
+    }
+    function confirmDone(event) {
+        dismissConfirm(JSON.parse(event.data).token);
+    }
     function refresh(event) {
         location.reload();
     }
+    // The names of the attributes the server has set on each root element
+    const rootAttrs = {html: new Set(), body: new Set()};
+    function rootElement(element) {
+        return element === "html" ? document.documentElement : document.body;
+    }
+    function applyRootAttr(element, name, value) {
+        if (value === null) {
+            rootElement(element).removeAttribute(name);
+            rootAttrs[element].delete(name);
+        } else {
+            rootElement(element).setAttribute(name, value);
+            rootAttrs[element].add(name);
+        }
+    }
+    function setRootAttr(event) {
+        const attr = JSON.parse(event.data);
+        applyRootAttr(attr.element, attr.name, attr.value);
+    }
+    function setAttr(event) {
+        const attr = JSON.parse(event.data);
+        const element = querySelector(attr.selector);
+        if (element === null) return;
+        try {
+            if (attr.value === null) {
+                element.removeAttribute(attr.name);
+            } else {
+                element.setAttribute(attr.name, attr.value);
+            }
+        } catch (error) {
+            console.error(error);
+            return;
+        }
+        // The attribute is only the initial value once the user has typed
+        if (attr.name === "value" && "value" in element) {
+            element.value = attr.value === null ? "" : attr.value;
+        }
+    }
+    function setRootAttrs(event) {
+        const attrs = JSON.parse(event.data);
+        Object.keys(rootAttrs).forEach(element => {
+            const wanted = attrs[element] || {};
+            Array.from(rootAttrs[element]).forEach(name => {
+                if (!wanted.hasOwnProperty(name)) {
+                    applyRootAttr(element, name, null);
+                }
+            });
+            Object.entries(wanted).forEach(([name, value]) => {
+                applyRootAttr(element, name, value);
+            });
+        });
+    }
+    function setQuery(event) {
+        const update = JSON.parse(event.data);
+        const url = pageUrl
+              + (update.query !== "" ? "?" + update.query : "")
+              + window.location.hash;
+        if (update.replace) {
+            history.replaceState(history.state, "", url);
+        } else {
+            history.pushState(history.state, "", url);
+        }
+    }
+    // Our id as a client of the server, and whether the user has changed
+    // anything on the page (like filling in a form) since the body was set:
+    // the server holds back some updates from dirty clients, so they don't
+    // clobber the user's input
+    let clientId = null;
+    let dirty = false;
+    // Requests about us as a client go wherever we're getting updates from
+    function clientUrl(params) {
+        const separator = updatesUrl.includes("?") ? "&" : "?";
+        return updatesUrl + separator + "client=" + clientId + params;
+    }
+    function reportDirty() {
+        if (clientId !== null) {
+            fetch(clientUrl(dirty ? "&dirty" : "&clean"), {method: "POST"})
+                .catch(error => console.error(error));
+        }
+    }
+    // Let the server know we're still active whenever we send it an event, but
+    // not more than once every few seconds
+    const PING_INTERVAL = 5000;
+    let lastPing = 0;
+    function ping() {
+        const now = Date.now();
+        if (clientId !== null && now - lastPing >= PING_INTERVAL) {
+            lastPing = now;
+            fetch(clientUrl("&ping"), {method: "POST"})
+                .catch(error => console.error(error));
+        }
+    }
+    // If the server adapts how often it sends updates to how far behind we
+    // are, tell it the worst lag in rendering a body every so often
+    const LAG_REPORT_INTERVAL = 2000;
+    let worstLag = null;
+    function noteLag(lag) {
+        if (capabilities.includes("adaptive-rate")) {
+            worstLag = Math.max(worstLag === null ? 0 : worstLag, lag);
+        }
+    }
+    setInterval(() => {
+        if (clientId !== null && worstLag !== null) {
+            fetch(clientUrl("&lag=" + Math.round(worstLag)), {method: "POST"})
+                .catch(error => console.error(error));
+            worstLag = null;
+        }
+    }, LAG_REPORT_INTERVAL);
+    function setDirty(newDirty) {
+        if (dirty !== newDirty) {
+            dirty = newDirty;
+            reportDirty();
+        }
+    }
+    document.addEventListener("input", () => setDirty(true), true);
+    // The optional features the server has turned on for this page, told us
+    // first thing on connecting; once the replay of the page's state is done,
+    // the page's own scripts can find out with a myxine-connected event
+    let capabilities = [];
+    function ready(event) {
+        capabilities = JSON.parse(event.data).capabilities;
+        persistentReplayed = new Set();
+        debug("Server capabilities: " + capabilities.join(", "));
+    }
+    function connected(event) {
+        if (persistentReplayed !== null) prunePersistent();
+        document.dispatchEvent(new CustomEvent("myxine-connected", {
+            detail: {capabilities: capabilities},
+        }));
+    }
+    function setClientId(event) {
+        clientId = event.data;
+        // If we've reconnected, the server doesn't know we're dirty yet
+        if (dirty) reportDirty();
+    }
+    // The server has held back a body update because we're dirty: let the
+    // page decide whether to accept it, by calling `event.detail.accept()`
+    function refreshAvailable(event) {
+        document.dispatchEvent(new CustomEvent("myxine-refresh-available", {
+            detail: {accept: () => setDirty(false)},
+        }));
+    }
+    // The validation errors the server wants shown, as a map from field names
+    // (or selectors) to messages, and the fields we've marked invalid
+    let validation = {};
+    let invalidFields = [];
+    function validationFields(field) {
+        try {
+            const matches = document.querySelectorAll(field);
+            if (matches.length > 0) return Array.from(matches);
+        } catch (error) {
+            // Not a valid selector, so it must be a field name
+        }
+        return Array.from(document.getElementsByName(field));
+    }
+    function applyValidation() {
+        invalidFields.forEach(element => {
+            if (element.setCustomValidity) element.setCustomValidity("");
+            element.removeAttribute("aria-invalid");
+        });
+        invalidFields = [];
+        Object.entries(validation).forEach(([field, message]) => {
+            validationFields(field).forEach(element => {
+                if (element.setCustomValidity) element.setCustomValidity(message);
+                element.setAttribute("aria-invalid", "true");
+                invalidFields.push(element);
+            });
+        });
+    }
+    function setValidation(event) {
+        validation = JSON.parse(event.data);
+        applyValidation();
+    }
+    // The status of each step of a job, shown on every element naming the step
+    // in its data-myxine-step attribute, including those in later bodies
+    let steps = {};
+    function stepElements(step) {
+        return Array.from(document.querySelectorAll("[data-myxine-step]"))
+            .filter(element => element.getAttribute("data-myxine-step") === step);
+    }
+    function showStep(step, state) {
+        stepElements(step).forEach(element => {
+            if (state === undefined) {
+                element.removeAttribute("data-myxine-step-status");
+            } else {
+                element.setAttribute("data-myxine-step-status", state.status);
+            }
+            if (state !== undefined && state.error !== null) {
+                element.setAttribute("data-myxine-step-error", state.error);
+            } else {
+                element.removeAttribute("data-myxine-step-error");
+            }
+        });
+    }
+    function applySteps() {
+        Object.entries(steps).forEach(([step, state]) => showStep(step, state));
+    }
+    function setStep(event) {
+        const state = JSON.parse(event.data);
+        steps[state.step] = state;
+        showStep(state.step, state);
+        document.dispatchEvent(new CustomEvent("myxine-step", {detail: state}));
+    }
+    function clearSteps(event) {
+        const cleared = Object.keys(steps);
+        steps = {};
+        cleared.forEach(step => showStep(step, undefined));
+    }
+    // Decompress gzipped, base64-encoded event data
+    async function decompress(data) {
+        const bytes = Uint8Array.from(atob(data), c => c.charCodeAt(0));
+        const stream = new Blob([bytes]).stream()
+              .pipeThrough(new DecompressionStream("gzip"));
+        return await new Response(stream).text();
+    }
     // Actually set up SSE...
-    let sse = new EventSource(window.location.href + "?updates");
-    sse.addEventListener("body", setBody);
-    sse.addEventListener("clear-body", clearBody);
-    sse.addEventListener("title", setTitle);
-    sse.addEventListener("clear-title", clearTitle);
-    sse.addEventListener("refresh", refresh);
-    sse.addEventListener("subscribe", subscribe);
+    const RECONNECT_DELAY = 1000;
+    const handlers = [];
+    let sse = null;
+    // Where updates come from, which the server can move with a
+    // "reconnect-to" event
+    let updatesUrl = homeUrl;
+    function connect() {
+        // If we're reconnecting, we already have a body to catch up from
+        const baseline = haveBody ? "&baseline=" + hashText(body) : "";
+        const separator = updatesUrl.includes("?") ? "&" : "?";
+        const source = new EventSource(updatesUrl + separator + "updates" + baseline);
+        sse = source;
+        handlers.forEach(([eventType, handler]) => source.addEventListener(eventType, handler));
+        let opened = false;
+        source.onopen = () => { opened = true; };
+        // Reconnect ourselves, since the browser would reconnect to the same
+        // URL, without the baseline
+        source.onerror = () => {
+            source.close();
+            if (sse !== source) return;
+            // If we were moved somewhere we can't reach, go back home
+            if (!opened && updatesUrl !== homeUrl) {
+                console.warn("Couldn't reconnect to " + updatesUrl + ", so reconnecting to " + homeUrl);
+                updatesUrl = homeUrl;
+            }
+            setTimeout(connect, RECONNECT_DELAY);
+        };
+    }
+    function reconnectTo(event) {
+        updatesUrl = new URL(event.data, pageUrl).href;
+        debug("Reconnecting to " + updatesUrl);
+        sse.close();
+        connect();
+    }
+    // Events are handled strictly in order, even though compressed events
+    // (whose type ends in ":gzip") have to wait to be decompressed first
+    let handled = Promise.resolve();
+    function listen(eventType, handler) {
+        function handleInOrder(getEvent) {
+            handled = handled
+                .then(async () => handler(await getEvent()))
+                .catch(error => console.error(error));
+        }
+        handlers.push([eventType, event => handleInOrder(() => event)]);
+        handlers.push([eventType + ":gzip", event => handleInOrder(async () => ({
+            type: eventType,
+            lastEventId: event.lastEventId,
+            data: await decompress(event.data),
+        }))]);
+    }
+    listen("body", setBody);
+    listen("body-patch", patchBody);
+    listen("baseline-mismatch", baselineMismatch);
+    listen("append-body", appendBody);
+    listen("append-to", appendTo);
+    listen("clear-element", clearElement);
+    listen("parent-message", messageParent);
+    listen("persistent", setPersistent);
+    listen("clear-body", clearBody);
+    listen("title", setTitle);
+    listen("clear-title", clearTitle);
+    listen("title-template", setTitleTemplate);
+    listen("title-slot", setTitleSlot);
+    listen("seo", setSeo);
+    listen("refresh", refresh);
+    listen("subscribe", subscribe);
+    listen("query", setQuery);
+    listen("root-attr", setRootAttr);
+    listen("root-attrs", setRootAttrs);
+    listen("set-attr", setAttr);
+    listen("ready", ready);
+    listen("client-id", setClientId);
+    listen("connected", connected);
+    listen("validation", setValidation);
+    listen("step", setStep);
+    listen("clear-steps", clearSteps);
+    listen("refresh-available", refreshAvailable);
+    listen("timed-event", setTimedEvent);
+    listen("alert", showAlert);
+    listen("reconnect-to", reconnectTo);
+    listen("confirm", showConfirm);
+    listen("confirm-done", confirmDone);
+    restorePersistent();
+    connect();
     // Make sure the subscription gets updated once the whole page is loaded
     if (document.readyState === "loading") {
         document.addEventListener('readystatechange', () => {
@@ -8,6 +8,8 @@ use tokio::time;
 use futures::{join, future};
 
 use super::PAGES;
+use crate::page::Page;
+use crate::page::gc::{GcPolicy, IsEmpty};
 
 /// The interval between heartbeats, in seconds.
 const HEARTBEAT_INTERVAL: u64 = 10;
@@ -25,6 +27,17 @@ lazy_static! {
     /// The set of paths which are currently active and in need of heartbeats.
     static ref ACTIVE_PATHS: Mutex<HashSet<String>>
         = Mutex::new(HashSet::new());
+
+    /// The policy deciding which pages to evict from memory at each heartbeat.
+    static ref GC_POLICY: std::sync::Mutex<Arc<dyn GcPolicy>>
+        = std::sync::Mutex::new(Arc::new(IsEmpty));
+}
+
+/// Replace the policy deciding which pages to evict from memory at each
+/// heartbeat. The default is `IsEmpty`, which only evicts pages identical to
+/// the initial dynamic page.
+pub fn set_gc_policy(policy: Arc<dyn GcPolicy>) {
+    *GC_POLICY.lock().unwrap() = policy;
 }
 
 /// Register a new server path as in need of page heartbeats. It will be
@@ -36,7 +49,7 @@ pub fn hold_path(path: String) {
 }
 
 /// Send a heartbeat message to keep all page connections alive, simultaneously
-/// pruning all pages from memory which have no content and no subscribers.
+/// pruning from memory all pages which the current `GcPolicy` evicts.
 pub async fn heartbeat_loop() {
 
     // Receive all new paths into the set of known active paths
@@ -48,40 +61,59 @@ pub async fn heartbeat_loop() {
     };
 
     // At the specified `HEARTBEAT_INTERVAL`, traverse all active paths, sending
-    // heartbeats to all pages, and removing all pages which are identical to
-    // the initial dynamic page (to free up memory).
+    // heartbeats to all pages, and removing all pages which the policy evicts
+    // (to free up memory).
     let heartbeat = async {
         loop {
             // Wait for next heartbeat interval...
             time::delay_for(Duration::from_secs(HEARTBEAT_INTERVAL)).await;
 
-            // Lock the active set of paths and send a heartbeat to each one,
-            // noting which paths are identical to the empty page
-            let mut paths = ACTIVE_PATHS.lock().await;
-            let pruned = Arc::new(Mutex::new(Vec::new()));
-            future::join_all(paths.iter().map(|path| {
-                let pruned = pruned.clone();
-                async move {
-                    let mut pages = PAGES.lock().await;
-                    if let Some((path, page)) = pages.remove_entry(path) {
-                        page.send_heartbeat().await;
-                        if !page.is_empty().await {
-                            pages.insert(path, page);
-                        } else {
-                            pruned.lock().await.push(path);
-                        }
-                    }
-                }
+            // Clone out the active pages, so we don't hold the locks on
+            // ACTIVE_PATHS and PAGES while we wait on each page
+            let active: Vec<(String, Arc<Page>)> = {
+                let paths = ACTIVE_PATHS.lock().await;
+                let pages = PAGES.lock().await;
+                paths.iter()
+                    .filter_map(|path| Some((path.clone(), pages.get(path)?.clone())))
+                    .collect()
+            };
+
+            // Send a heartbeat to each page, noting what the policy needs to
+            // know about it
+            let mut inputs = future::join_all(active.iter().map(|(path, page)| async move {
+                // Pages which were sent an update since the last beat don't
+                // need another one
+                page.send_heartbeat(Duration::from_secs(HEARTBEAT_INTERVAL)).await;
+                (path, page, page.gc_inputs().await)
             })).await;
 
-            // Remove all paths that are identical to the empty page
-            for path in pruned.lock().await.iter() {
-                paths.remove(path);
+            // Work out the footprint of the pages more recent than each page
+            inputs.sort_by_key(|(_, _, page)| std::cmp::Reverse(page.last_modified));
+            let mut more_recent_footprint = 0;
+            for (_, _, page) in inputs.iter_mut() {
+                page.more_recent_footprint = more_recent_footprint;
+                more_recent_footprint += page.footprint;
+            }
+
+            // Remove all pages and paths which the policy evicts, unless
+            // they've been replaced by another page in the meantime
+            let policy = GC_POLICY.lock().unwrap().clone();
+            let mut paths = ACTIVE_PATHS.lock().await;
+            let mut pages = PAGES.lock().await;
+            for (path, page, inputs) in inputs.iter() {
+                let replaced = match pages.get(*path) {
+                    Some(current) => !Arc::ptr_eq(current, page),
+                    None => true,
+                };
+                if !replaced && !policy.keep(inputs) {
+                    pages.remove(*path);
+                    paths.remove(*path);
+                }
             }
 
             // Free memory for all the removed pages and paths
             paths.shrink_to_fit();
-            PAGES.lock().await.shrink_to_fit();
+            pages.shrink_to_fit();
         }
     };
 
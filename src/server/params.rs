@@ -2,37 +2,346 @@ use std::collections::HashMap;
 use percent_encoding::percent_decode;
 use std::borrow::Cow;
 use std::convert::TryFrom;
+use std::time::Duration;
 use uuid::Uuid;
 
 use crate::page::events::AbsolutePath;
 
 /// Parsed parameters from a query string for a GET/HEAD request.
 pub(crate) enum GetParams {
-    FullPage,
-    PageUpdates,
+    FullPage{lines: Option<(usize, usize)>, hash: Option<String>, prerender: bool},
+    PageUpdates{
+        follow: bool,
+        filter: Option<String>,
+        room: Option<String>,
+        receive_only: bool,
+        baseline: Option<String>,
+    },
+    StoredEvents,
+    ClientSync{client: u64},
+    Download,
+    Tail,
+    WatchBody,
+    Backpressure,
+    Replay{name: String},
+    #[cfg(feature = "screenshot")]
+    Screenshot{width: u32, height: u32},
 }
 
 impl GetParams {
     /// Parse a query string from a GET request.
     pub fn parse(query: &str) -> Option<GetParams> {
         let params = query_params(query)?;
+        #[cfg(feature = "screenshot")]
+        {
+            if param_as_bool("screenshot", &params)?
+            && constrained_to_keys(&params, &["screenshot", "width", "height"]) {
+                let dimension = |name, default| match param_as_str(name, &params)? {
+                    Some(value) => value.parse().ok(),
+                    None => Some(default),
+                };
+                let width = dimension("width", DEFAULT_SCREENSHOT_WIDTH)?;
+                let height = dimension("height", DEFAULT_SCREENSHOT_HEIGHT)?;
+                return Some(GetParams::Screenshot{width, height})
+            }
+        }
+        if param_as_bool("stored-events", &params)?
+        && constrained_to_keys(&params, &["stored-events"]) {
+            return Some(GetParams::StoredEvents)
+        }
+        if let Some(client) = param_as_str("client-sync", &params)? {
+            if constrained_to_keys(&params, &["client-sync"]) {
+                return Some(GetParams::ClientSync{client: client.parse().ok()?})
+            }
+        }
+        if let Some(name) = param_as_str("replay", &params)? {
+            if constrained_to_keys(&params, &["replay"]) {
+                return Some(GetParams::Replay{name: name.to_string()})
+            }
+        }
+        if param_as_bool("tail", &params)? && constrained_to_keys(&params, &["tail"]) {
+            return Some(GetParams::Tail)
+        }
+        if param_as_bool("watch-body", &params)?
+        && constrained_to_keys(&params, &["watch-body"]) {
+            return Some(GetParams::WatchBody)
+        }
+        if param_as_bool("backpressure", &params)?
+        && constrained_to_keys(&params, &["backpressure"]) {
+            return Some(GetParams::Backpressure)
+        }
+        if params.contains_key("download") && constrained_to_keys(&params, &["download"]) {
+            return match param_as_str("download", &params)? {
+                Some("") | Some("1") | Some("true") => Some(GetParams::Download),
+                _ => None,
+            }
+        }
         if param_as_bool("updates", &params)?
-        && constrained_to_keys(&params, &["updates"]) {
-            Some(GetParams::PageUpdates)
-        } else if constrained_to_keys(&params, &[]) {
-            Some(GetParams::FullPage)
+        && constrained_to_keys(&params, &["updates", "follow", "filter", "room",
+                                          "receive-only", "baseline"]) {
+            let follow = param_as_bool("follow", &params)?;
+            let receive_only = param_as_bool("receive-only", &params)?;
+            let filter = param_as_str("filter", &params)?.map(String::from);
+            let room = param_as_str("room", &params)?.map(String::from);
+            let baseline = param_as_str("baseline", &params)?.map(String::from);
+            Some(GetParams::PageUpdates{follow, filter, room, receive_only, baseline})
+        } else if !params.contains_key("updates") {
+            // Any other query string is the page's own business (for instance,
+            // state set by a query update event), so serve the page as usual
+            let lines = match param_as_str("lines", &params)? {
+                Some(lines) => Some(parse_line_range(lines)?),
+                None => None,
+            };
+            // A `v` parameter names the hash of the contents being asked for
+            let hash = param_as_str("v", &params)?.map(String::from);
+            // A `prerender` parameter asks for the page as a crawler sees it
+            let prerender = match param_as_str("prerender", &params)? {
+                Some("") | Some("1") | Some("true") => true,
+                Some("0") | Some("false") | None => false,
+                Some(_) => return None,
+            };
+            Some(GetParams::FullPage{lines, hash, prerender})
         } else {
             None
         }
     }
 }
 
+/// The size of a screenshot if none is given: the size preferred for social
+/// media preview images.
+#[cfg(feature = "screenshot")]
+const DEFAULT_SCREENSHOT_WIDTH: u32 = 1200;
+#[cfg(feature = "screenshot")]
+const DEFAULT_SCREENSHOT_HEIGHT: u32 = 630;
+
+/// Parsed parameters from the query string of a request for a multiplexed
+/// stream of updates to several pages, like `?pages=/cpu,/memory`.
+pub(crate) struct MultiplexParams {
+    pub pages: Vec<String>,
+}
+
+impl MultiplexParams {
+    /// Parse a query string from a GET request. Every page must be an absolute
+    /// path, and not one reserved for the server itself.
+    pub fn parse(query: &str) -> Option<MultiplexParams> {
+        let params = query_params(query)?;
+        if !constrained_to_keys(&params, &["pages"]) {
+            return None
+        }
+        let pages: Vec<String> = params.get("pages")?.iter()
+            .map(|page| page.trim_end_matches('/').to_string())
+            .collect();
+        let valid = |page: &String| page.starts_with('/') && !page.starts_with("/.myxine/");
+        if pages.is_empty() || !pages.iter().all(valid) {
+            return None
+        }
+        Some(MultiplexParams{pages})
+    }
+}
+
+/// Parsed parameters from the query string of a request to stop sending the
+/// updates of one page on a multiplexed stream, like
+/// `?stream=<id>&unsubscribe=/cpu`.
+pub(crate) struct UnsubscribeParams {
+    pub stream: String,
+    pub path: String,
+}
+
+impl UnsubscribeParams {
+    /// Parse a query string from a POST request.
+    pub fn parse(query: &str) -> Option<UnsubscribeParams> {
+        let params = query_params(query)?;
+        if !constrained_to_keys(&params, &["stream", "unsubscribe"]) {
+            return None
+        }
+        let stream = param_as_str("stream", &params)??.to_string();
+        let path = param_as_str("unsubscribe", &params)??.trim_end_matches('/').to_string();
+        Some(UnsubscribeParams{stream, path})
+    }
+}
+
+/// Parsed parameters from the query string of a request for the pages in a
+/// namespace, like `?name=tenant`.
+pub(crate) struct NamespaceParams {
+    pub name: String,
+}
+
+impl NamespaceParams {
+    /// Parse a query string from a GET request. The name can't be empty, since
+    /// no page is in the empty namespace.
+    pub fn parse(query: &str) -> Option<NamespaceParams> {
+        let params = query_params(query)?;
+        if !constrained_to_keys(&params, &["name"]) {
+            return None
+        }
+        match param_as_str("name", &params)? {
+            Some(name) if !name.is_empty() => Some(NamespaceParams{name: name.to_string()}),
+            _ => None,
+        }
+    }
+}
+
+/// How long to wait for the user to answer a confirmation if no timeout is
+/// given, in milliseconds.
+const DEFAULT_CONFIRM_TIMEOUT: u64 = 60_000;
+
 /// Parsed parameters from a query string for a POST request.
 pub(crate) enum PostParams {
-    DynamicPage{title: String},
+    DynamicPage{title: Option<String>},
     StaticPage,
     SubscribeEvents{uuid: Option<Uuid>},
     PageEvent{event: String, path: AbsolutePath},
+    QueryUpdate{replace: bool},
+    CustomEvent{
+        event: String,
+        room: Option<String>,
+        ttl: Option<Duration>,
+        id: Option<String>,
+        retry: Option<Duration>,
+    },
+    RootAttr{element: String, name: String, remove: bool},
+    IncrementBody{delta: i64},
+    ClearRootAttrs,
+    SetAttr{selector: String, name: String, remove: bool},
+    ClearAttrs,
+    ServerLog{level: String},
+    CleanBody{title: String},
+    Fork{path: String},
+    Record{name: Option<String>},
+    Validation,
+    Alert,
+    ReconnectTo,
+    Confirm{timeout: Duration},
+    ConfirmAnswer{token: String, yes: bool},
+    Batch,
+    Validator,
+    Broadcast,
+    AppendBody,
+    AppendTo{selector: String},
+    ParentMessage{channel: String, origin: String, persistent: bool},
+    Persistent{key: String},
+    TitleTemplate,
+    TitleSlot{name: String},
+    Step{step: String, status: String},
+    ClearSteps,
+    ClearElement{selector: String},
+    LangBody{lang: String},
+    Seo,
+    Namespace{namespace: Option<String>},
+    Preview{title: bool},
+    ClientState{client: u64, dirty: bool},
+    ClientPing{client: u64},
+    ClientDisconnect{client: u64},
+    ClientLag{client: u64, lag: Duration},
+    ClientBaseline{client: u64},
+    Configure(Setting),
+}
+
+/// A setting of a page, changed by a POST whose only parameter names it with
+/// its new value, like `?mailbox=4`. Those settings which can be turned off
+/// take `off` as their value.
+pub(crate) enum Setting {
+    /// The capacity of the mailbox in front of body updates
+    Mailbox(Option<usize>),
+    /// The number of updates which can be buffered before backpressure
+    BufferSize(usize),
+    /// The size above which body updates are sent compressed
+    CompressAbove(Option<usize>),
+    /// How long a client can go without reading an update before it's
+    /// disconnected
+    StuckTimeout(Option<Duration>),
+    /// How long a client can go without sending anything upstream before it's
+    /// disconnected
+    IdleTimeout(Option<Duration>),
+    /// How long changes are held to merge them into fewer frames
+    CoalesceWindow(Duration),
+    /// How far behind clients can be before the coalescing window is widened
+    AdaptiveRate(Option<Duration>),
+    /// How many line ranges of static content are cached
+    RenderCache(usize),
+    /// Whether appends are held back until the next heartbeat
+    HoldAppends(bool),
+    /// Whether heartbeats are skipped while clients are being sent updates
+    HeartbeatThrottle(bool),
+    /// The most element state replayed to new clients apart from the body
+    MaxReplay(Option<usize>),
+    /// How many attributes set on elements are remembered for new clients
+    AttrCap(usize),
+}
+
+impl Setting {
+    /// Parse the parameters of a POST request as a setting, if that's what
+    /// they are.
+    fn parse(params: &HashMap<&str, Vec<Cow<str>>>) -> Option<Setting> {
+        if params.len() != 1 {
+            return None
+        }
+        let (name, value) = params.iter().next()?;
+        let value = match value.as_slice() {
+            [value] => value.as_ref(),
+            _ => return None,
+        };
+        match *name {
+            "mailbox" => Some(Setting::Mailbox(off_or_number(value)?)),
+            "buffer-size" => Some(Setting::BufferSize(value.parse().ok()?)),
+            "compress-above" => Some(Setting::CompressAbove(off_or_number(value)?)),
+            "stuck-timeout" => Some(Setting::StuckTimeout(off_or_millis(value)?)),
+            "idle-timeout" => Some(Setting::IdleTimeout(off_or_millis(value)?)),
+            "coalesce" => Some(Setting::CoalesceWindow(off_or_millis(value)?.unwrap_or_default())),
+            "adaptive-rate" => Some(Setting::AdaptiveRate(off_or_millis(value)?)),
+            "hold-appends" => Some(Setting::HoldAppends(on_or_off(value)?)),
+            "heartbeat-throttle" => Some(Setting::HeartbeatThrottle(on_or_off(value)?)),
+            "max-replay" => Some(Setting::MaxReplay(off_or_number(value)?)),
+            "attr-cap" => Some(Setting::AttrCap(value.parse().ok()?)),
+            "render-cache" => Some(Setting::RenderCache(off_or_number(value)?.unwrap_or_default())),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a setting's value as a number, or `off`. If it's neither, return
+/// `None`.
+#[allow(clippy::option_option)]
+fn off_or_number<T: std::str::FromStr>(value: &str) -> Option<Option<T>> {
+    match value {
+        "off" => Some(None),
+        number => number.parse().ok().map(Some),
+    }
+}
+
+/// Parse a setting's value as `on` or `off`. If it's neither, return `None`.
+fn on_or_off(value: &str) -> Option<bool> {
+    match value {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// The longest time, in milliseconds, which can be given for anything the
+/// server waits on: a year. Tokio's timer panics when asked to wait much past
+/// two years, so anything longer is rejected rather than taking down the task
+/// that waits.
+const MAX_MILLIS: u64 = 365 * 24 * 60 * 60 * 1000;
+
+/// Parse a number of milliseconds, up to `MAX_MILLIS`. If it's not a number,
+/// or it's too long, return `None`.
+fn millis(value: &str) -> Option<Duration> {
+    let millis: u64 = value.parse().ok()?;
+    if millis > MAX_MILLIS {
+        return None
+    }
+    Some(Duration::from_millis(millis))
+}
+
+/// Parse a setting's value as a number of milliseconds (see `millis`), or
+/// `off`. If it's neither, return `None`.
+#[allow(clippy::option_option)]
+fn off_or_millis(value: &str) -> Option<Option<Duration>> {
+    match value {
+        "off" => Some(None),
+        value => millis(value).map(Some),
+    }
 }
 
 impl PostParams {
@@ -52,6 +361,212 @@ impl PostParams {
             && constrained_to_keys(&params, &["static"])
         {
                 return Some(PostParams::StaticPage)
+        } else if param_as_bool("query", &params)?
+            && constrained_to_keys(&params, &["query", "replace"])
+        {
+                let replace = param_as_bool("replace", &params)?;
+                return Some(PostParams::QueryUpdate{replace})
+        } else if let Some(name) = param_as_str("root-attr", &params)? {
+            if let Some(element) = param_as_str("element", &params)? {
+                if constrained_to_keys(&params, &["root-attr", "element", "remove"]) {
+                    let remove = param_as_bool("remove", &params)?;
+                    return Some(PostParams::RootAttr{
+                        element: element.to_string(),
+                        name: name.to_string(),
+                        remove,
+                    })
+                }
+            }
+        } else if let Some(name) = param_as_str("set-attr", &params)? {
+            if let Some(selector) = param_as_str("selector", &params)? {
+                if constrained_to_keys(&params, &["set-attr", "selector", "remove"]) {
+                    let remove = param_as_bool("remove", &params)?;
+                    return Some(PostParams::SetAttr{
+                        selector: selector.to_string(),
+                        name: name.to_string(),
+                        remove,
+                    })
+                }
+            }
+        } else if param_as_bool("clear-attrs", &params)?
+            && constrained_to_keys(&params, &["clear-attrs"])
+        {
+                return Some(PostParams::ClearAttrs)
+        } else if param_as_bool("clear-root-attrs", &params)?
+            && constrained_to_keys(&params, &["clear-root-attrs"])
+        {
+                return Some(PostParams::ClearRootAttrs)
+        } else if let Some(delta) = param_as_str("increment", &params)? {
+            if constrained_to_keys(&params, &["increment"]) {
+                // A bare ?increment means to increment by one
+                let delta = if delta.is_empty() { 1 } else { delta.parse().ok()? };
+                return Some(PostParams::IncrementBody{delta})
+            }
+        } else if param_as_bool("clean-only", &params)?
+            && constrained_to_keys(&params, &["clean-only", "title"])
+        {
+                let title = param_as_str("title", &params)?.unwrap_or("").to_string();
+                return Some(PostParams::CleanBody{title})
+        } else if let Some(client) = param_as_str("client", &params)? {
+            let dirty = param_as_bool("dirty", &params)?;
+            let clean = param_as_bool("clean", &params)?;
+            let ping = param_as_bool("ping", &params)?;
+            let disconnect = param_as_bool("disconnect", &params)?;
+            let baseline = param_as_bool("baseline", &params)?;
+            let lag = param_as_str("lag", &params)?;
+            if ping && constrained_to_keys(&params, &["client", "ping"]) {
+                let client = client.parse().ok()?;
+                return Some(PostParams::ClientPing{client})
+            } else if disconnect && constrained_to_keys(&params, &["client", "disconnect"]) {
+                let client = client.parse().ok()?;
+                return Some(PostParams::ClientDisconnect{client})
+            } else if let Some(lag) = lag {
+                if constrained_to_keys(&params, &["client", "lag"]) {
+                    let client = client.parse().ok()?;
+                    let lag = Duration::from_millis(lag.parse().ok()?);
+                    return Some(PostParams::ClientLag{client, lag})
+                }
+            } else if baseline && constrained_to_keys(&params, &["client", "baseline"]) {
+                let client = client.parse().ok()?;
+                return Some(PostParams::ClientBaseline{client})
+            } else if dirty != clean && constrained_to_keys(&params, &["client", "dirty", "clean"]) {
+                let client = client.parse().ok()?;
+                return Some(PostParams::ClientState{client, dirty})
+            }
+        } else if let Some(lang) = param_as_str("lang", &params)? {
+            if constrained_to_keys(&params, &["lang"]) && !lang.is_empty() {
+                return Some(PostParams::LangBody{lang: lang.to_string()})
+            }
+        } else if let Some(of) = param_as_str("preview", &params)? {
+            if constrained_to_keys(&params, &["preview"]) {
+                return match of {
+                    "title" => Some(PostParams::Preview{title: true}),
+                    "body" => Some(PostParams::Preview{title: false}),
+                    _ => None,
+                }
+            }
+        } else if param_as_bool("seo", &params)?
+            && constrained_to_keys(&params, &["seo"])
+        {
+                return Some(PostParams::Seo)
+        } else if let Some(namespace) = param_as_str("namespace", &params)? {
+            if constrained_to_keys(&params, &["namespace"]) {
+                let namespace = Some(namespace.to_string()).filter(|name| !name.is_empty());
+                return Some(PostParams::Namespace{namespace})
+            }
+        } else if param_as_bool("append", &params)?
+            && constrained_to_keys(&params, &["append"])
+        {
+                return Some(PostParams::AppendBody)
+        } else if let Some(selector) = param_as_str("append-to", &params)? {
+            if constrained_to_keys(&params, &["append-to"]) {
+                return Some(PostParams::AppendTo{selector: selector.to_string()})
+            }
+        } else if let Some(channel) = param_as_str("parent", &params)? {
+            if constrained_to_keys(&params, &["parent", "origin", "persist"]) {
+                return Some(PostParams::ParentMessage{
+                    channel: channel.to_string(),
+                    origin: param_as_str("origin", &params)?.unwrap_or("*").to_string(),
+                    persistent: param_as_bool("persist", &params)?,
+                })
+            }
+        } else if let Some(key) = param_as_str("persistent", &params)? {
+            if constrained_to_keys(&params, &["persistent"]) {
+                return Some(PostParams::Persistent{key: key.to_string()})
+            }
+        } else if param_as_bool("title-template", &params)?
+            && constrained_to_keys(&params, &["title-template"])
+        {
+                return Some(PostParams::TitleTemplate)
+        } else if let Some(name) = param_as_str("title-slot", &params)? {
+            if constrained_to_keys(&params, &["title-slot"]) {
+                return Some(PostParams::TitleSlot{name: name.to_string()})
+            }
+        } else if let Some(step) = param_as_str("step", &params)? {
+            if constrained_to_keys(&params, &["step", "status"]) {
+                return Some(PostParams::Step{
+                    step: step.to_string(),
+                    status: param_as_str("status", &params)??.to_string(),
+                })
+            }
+        } else if param_as_bool("clear-steps", &params)?
+            && constrained_to_keys(&params, &["clear-steps"])
+        {
+                return Some(PostParams::ClearSteps)
+        } else if let Some(selector) = param_as_str("clear-element", &params)? {
+            if constrained_to_keys(&params, &["clear-element"]) {
+                return Some(PostParams::ClearElement{selector: selector.to_string()})
+            }
+        } else if param_as_bool("alert", &params)?
+            && constrained_to_keys(&params, &["alert"])
+        {
+                return Some(PostParams::Alert)
+        } else if param_as_bool("reconnect-to", &params)?
+            && constrained_to_keys(&params, &["reconnect-to"])
+        {
+                return Some(PostParams::ReconnectTo)
+        } else if param_as_bool("confirm", &params)?
+            && constrained_to_keys(&params, &["confirm", "timeout"])
+        {
+                let timeout = match param_as_str("timeout", &params)? {
+                    Some(timeout) => millis(timeout)?,
+                    None => Duration::from_millis(DEFAULT_CONFIRM_TIMEOUT),
+                };
+                return Some(PostParams::Confirm{timeout})
+        } else if let Some(token) = param_as_str("answer", &params)? {
+            if constrained_to_keys(&params, &["answer", "choice"]) {
+                let yes = match param_as_str("choice", &params)? {
+                    Some("yes") => true,
+                    Some("no") => false,
+                    _ => return None,
+                };
+                return Some(PostParams::ConfirmAnswer{token: token.to_string(), yes})
+            }
+        } else if param_as_bool("batch", &params)?
+            && constrained_to_keys(&params, &["batch"])
+        {
+                return Some(PostParams::Batch)
+        } else if param_as_bool("validator", &params)?
+            && constrained_to_keys(&params, &["validator"])
+        {
+                return Some(PostParams::Validator)
+        } else if param_as_bool("broadcast", &params)?
+            && constrained_to_keys(&params, &["broadcast"])
+        {
+                return Some(PostParams::Broadcast)
+        } else if param_as_bool("validation", &params)?
+            && constrained_to_keys(&params, &["validation"])
+        {
+                return Some(PostParams::Validation)
+        } else if let Some(path) = param_as_str("fork", &params)? {
+            if constrained_to_keys(&params, &["fork"]) {
+                return Some(PostParams::Fork{path: path.to_string()})
+            }
+        } else if let Some(name) = param_as_str("record", &params)? {
+            if constrained_to_keys(&params, &["record"]) {
+                let name = Some(name.to_string()).filter(|name| name != "off");
+                return Some(PostParams::Record{name})
+            }
+        } else if let Some(level) = param_as_str("server-log", &params)? {
+            if constrained_to_keys(&params, &["server-log"]) {
+                return Some(PostParams::ServerLog{level: level.to_string()})
+            }
+        } else if let Some(event) = param_as_str("emit", &params)? {
+            if constrained_to_keys(&params, &["emit", "room", "ttl", "id", "retry"]) {
+                let room = param_as_str("room", &params)?.map(String::from);
+                let ttl = match param_as_str("ttl", &params)? {
+                    // Timed events go to every client, not to rooms
+                    Some(_) if room.is_some() => return None,
+                    Some(ttl) => Some(Duration::from_millis(ttl.parse().ok()?)),
+                    None => None,
+                };
+                let id = param_as_str("id", &params)?.map(String::from);
+                let retry = match param_as_str("retry", &params)? {
+                    Some(retry) => Some(Duration::from_millis(retry.parse().ok()?)),
+                    None => None,
+                };
+                return Some(PostParams::CustomEvent{event: event.to_string(), room, ttl, id, retry})
+            }
         } else if let Some(event) =
             param_as_str("event", &params)?.map(String::from)
         {
@@ -70,14 +585,24 @@ impl PostParams {
                     }
                 }
             }
+        } else if let Some(setting) = Setting::parse(&params) {
+            return Some(PostParams::Configure(setting))
         } else if constrained_to_keys(&params, &["title"]) {
-            let title = param_as_str("title", &params)?.unwrap_or("").to_string();
+            let title = param_as_str("title", &params)?.map(String::from);
             return Some(PostParams::DynamicPage{title})
         }
         None
     }
 }
 
+/// Parse a range of lines of the form `start-end`.
+fn parse_line_range(range: &str) -> Option<(usize, usize)> {
+    match range.split('-').collect::<Vec<_>>().as_slice() {
+        [start, end] => Some((start.trim().parse().ok()?, end.trim().parse().ok()?)),
+        _ => None,
+    }
+}
+
 /// Parse a given parameter as a boolean, where its presence without a mapping
 /// is interpreted as true. If it is mapped to multiple values, or mapped to
 /// something other than "true" or "false", return `None`.
@@ -149,3 +674,64 @@ fn constrained_to_keys<'a, K: PartialEq + 'a, V>(
     }
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_can_only_wait_so_long() {
+        let setting = |query| PostParams::parse(query).and_then(|params| match params {
+            PostParams::Configure(Setting::StuckTimeout(timeout)) => Some(timeout),
+            _ => None,
+        });
+        assert_eq!(setting("stuck-timeout=off"), Some(None));
+        assert_eq!(setting("stuck-timeout=100"), Some(Some(Duration::from_millis(100))));
+        let longest = format!("stuck-timeout={}", MAX_MILLIS);
+        assert_eq!(setting(&longest), Some(Some(Duration::from_millis(MAX_MILLIS))));
+        let too_long = format!("stuck-timeout={}", MAX_MILLIS + 1);
+        assert_eq!(setting(&too_long), None);
+        assert_eq!(setting("stuck-timeout=100000000000"), None);
+    }
+
+    #[test]
+    fn multiplexed_pages_cant_be_reserved() {
+        let pages = |query| MultiplexParams::parse(query).map(|params| params.pages);
+        assert_eq!(pages("pages=/cpu,/memory/"), Some(vec!["/cpu".to_string(), "/memory".to_string()]));
+        assert_eq!(pages("pages=/cpu,memory"), None);
+        assert_eq!(pages("pages=/cpu,/.myxine/metrics"), None);
+    }
+
+    #[test]
+    fn coalescing_can_only_wait_so_long() {
+        let window = |query| PostParams::parse(query).and_then(|params| match params {
+            PostParams::Configure(Setting::CoalesceWindow(window)) => Some(window),
+            _ => None,
+        });
+        assert_eq!(window("coalesce=off"), Some(Duration::from_millis(0)));
+        assert_eq!(window("coalesce=50"), Some(Duration::from_millis(50)));
+        assert_eq!(window("coalesce=100000000000"), None);
+    }
+
+    #[test]
+    fn idle_timeouts_can_only_wait_so_long() {
+        let timeout = |query| PostParams::parse(query).and_then(|params| match params {
+            PostParams::Configure(Setting::IdleTimeout(timeout)) => Some(timeout),
+            _ => None,
+        });
+        assert_eq!(timeout("idle-timeout=off"), Some(None));
+        assert_eq!(timeout("idle-timeout=60000"), Some(Some(Duration::from_secs(60))));
+        assert_eq!(timeout("idle-timeout=100000000000"), None);
+    }
+
+    #[test]
+    fn confirmations_can_only_wait_so_long() {
+        let timeout = |query| PostParams::parse(query).and_then(|params| match params {
+            PostParams::Confirm{timeout} => Some(timeout),
+            _ => None,
+        });
+        assert_eq!(timeout("confirm"), Some(Duration::from_millis(DEFAULT_CONFIRM_TIMEOUT)));
+        assert_eq!(timeout("confirm&timeout=500"), Some(Duration::from_millis(500)));
+        assert_eq!(timeout("confirm&timeout=100000000000"), None);
+    }
+}
@@ -0,0 +1,87 @@
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use lazy_static::lazy_static;
+use std::sync::{Arc, Mutex};
+
+use crate::page::broadcast::{Broadcast, BroadcastBackend};
+
+lazy_static! {
+    /// The other processes serving the same pages, if any
+    static ref PEERS: Mutex<Arc<Vec<Uri>>> = Mutex::new(Arc::new(Vec::new()));
+
+    /// The client making requests to peers, which keeps their connections
+    /// alive between broadcasts
+    static ref CLIENT: Client<HttpConnector> = Client::new();
+}
+
+/// Parse the URL of a peer, like `http://10.0.0.2:1123`, which must be plain
+/// HTTP and name nothing but the server.
+pub fn parse_peer(url: &str) -> Result<Uri, String> {
+    let uri: Uri = url.parse().map_err(|err| format!("Invalid peer URL {}: {}", url, err))?;
+    if uri.scheme_str() != Some("http") || uri.authority().is_none() {
+        return Err(format!("Peer URL must start with http:// and a host: {}", url))
+    }
+    if !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+        return Err(format!("Peer URL must not have a path or query: {}", url))
+    }
+    Ok(uri)
+}
+
+/// Serve every page along with these other processes, each of which should
+/// have this process as a peer in turn: updates to a page made here are
+/// POSTed to the same page on each peer with `?broadcast`, and applied there
+/// for its own clients (see `Page::apply_broadcast`).
+pub fn set_peers(peers: Vec<Uri>) {
+    *PEERS.lock().unwrap() = Arc::new(peers);
+}
+
+/// Get the backend forwarding the updates to the page at this path to every
+/// peer, or `None` if there are no peers.
+pub fn backend(path: &str) -> Option<Arc<dyn BroadcastBackend>> {
+    let peers = PEERS.lock().unwrap().clone();
+    if peers.is_empty() {
+        return None
+    }
+    Some(Arc::new(PeerBackend{path: path.to_string(), peers}))
+}
+
+/// A broadcast backend which POSTs each update to the same page on every peer.
+/// Updates are sent in the background, so a slow or unreachable peer never
+/// holds up the page, and one which can't be reached misses the update.
+#[derive(Debug)]
+struct PeerBackend {
+    path: String,
+    peers: Arc<Vec<Uri>>,
+}
+
+impl BroadcastBackend for PeerBackend {
+    fn publish(&self, broadcast: Broadcast) {
+        let json = serde_json::to_string(&broadcast)
+            .expect("Serializing a broadcast to JSON shouldn't fail");
+        let path = if self.path.is_empty() { "/" } else { &self.path };
+        for peer in self.peers.iter() {
+            let authority = peer.authority().expect("Peer URLs always have a host");
+            let uri = format!("http://{}{}?broadcast", authority, path);
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(&uri)
+                .header("Content-Type", "application/json")
+                .body(Body::from(json.clone()));
+            let request = match request {
+                Ok(request) => request,
+                Err(err) => {
+                    eprintln!("Couldn't broadcast to {}: {}", uri, err);
+                    continue
+                },
+            };
+            tokio::spawn(async move {
+                match CLIENT.request(request).await {
+                    Ok(response) if response.status().is_success() => { },
+                    Ok(response) =>
+                        eprintln!("Couldn't broadcast to {}: {}", uri, response.status()),
+                    Err(err) => eprintln!("Couldn't broadcast to {}: {}", uri, err),
+                }
+            });
+        }
+    }
+}